@@ -1,3 +1,4 @@
+use crate::isa::Isa;
 use std::env;
 
 #[derive(Debug)]
@@ -6,6 +7,26 @@ pub struct Args {
     pub input_as: String,
     pub output_as: String,
     pub line_info: bool,
+    pub include_paths: Vec<String>,
+    pub definitions: Vec<(String, String)>,
+    pub ignore_case: bool,
+    pub isa: Option<Isa>,
+    pub no_pseudo: bool,
+    pub entry: Option<String>,
+    pub relax: bool,
+}
+
+/// Parses the NAME=value argument of -D
+fn parse_definition(definition: &str) -> Result<(String, String), &'static str> {
+    let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+
+    let mut name_chars = name.chars();
+    let valid_start = matches!(name_chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_');
+    if !valid_start || !name_chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err("Expected an identifier for the name given to -D");
+    }
+
+    Ok((name.to_string(), value.to_string()))
 }
 
 fn help() {
@@ -18,6 +39,19 @@ fn help() {
     println!("Optional:");
     println!("  --lineinfo");
     println!("   -l          Enables line information export");
+    println!("  -I DIR       Adds DIR to the directories searched by .include");
+    println!("  -D NAME=VAL  Substitutes VAL for every use of NAME, like .eqv");
+    println!("               (VAL defaults to 1)");
+    println!("  --ignore-case");
+    println!("               Accepts mnemonics, directives, and registers");
+    println!("               in any case, as MARS does");
+    println!("  -e SYMBOL    Begins execution at SYMBOL instead of main");
+    println!("               (or the start of .text without a .globl main)");
+    println!("  --no-pseudo  Rejects pseudo-instructions");
+    println!("  --relax      Rewrites branches that can't reach their");
+    println!("               targets into jumps");
+    println!("  --isa ISA    Rejects instructions outside of ISA, one of");
+    println!("               r2 (MIPS32 Release 2) or mars");
 }
 
 pub fn parse_args() -> Result<Args, &'static str> {
@@ -26,6 +60,13 @@ pub fn parse_args() -> Result<Args, &'static str> {
         input_as: String::new(),
         output_as: String::new(),
         line_info: false,
+        include_paths: vec![],
+        definitions: vec![],
+        ignore_case: false,
+        isa: None,
+        no_pseudo: false,
+        entry: None,
+        relax: false,
     };
     let args_strings: Vec<String> = env::args().collect();
 
@@ -35,11 +76,39 @@ pub fn parse_args() -> Result<Args, &'static str> {
     }
 
     let mut arg_index = 1;
-    for arg in args_strings.iter().skip(1) {
+    let mut args_iter = args_strings.iter().skip(1);
+    while let Some(arg) = args_iter.next() {
         let mut parsed_option = true;
         match arg.as_str() {
             "-l" | "--lineinfo" => args.line_info = true,
-            _ => parsed_option = false,
+            "--ignore-case" => args.ignore_case = true,
+            "--no-pseudo" => args.no_pseudo = true,
+            "--relax" => args.relax = true,
+            "-e" | "--entry" => match args_iter.next() {
+                Some(symbol) => args.entry = Some(symbol.to_string()),
+                None => return Err("Expected a symbol after -e"),
+            },
+            "--isa" => match args_iter.next() {
+                Some(name) => args.isa = Some(Isa::from_name(name)?),
+                None => return Err("Expected r2 or mars after --isa"),
+            },
+            "-I" => match args_iter.next() {
+                Some(dir) => args.include_paths.push(dir.to_string()),
+                None => return Err("Expected a directory after -I"),
+            },
+            "-D" => match args_iter.next() {
+                Some(definition) => args.definitions.push(parse_definition(definition)?),
+                None => return Err("Expected NAME=value after -D"),
+            },
+            _ => {
+                if let Some(dir) = arg.strip_prefix("-I") {
+                    args.include_paths.push(dir.to_string());
+                } else if let Some(definition) = arg.strip_prefix("-D") {
+                    args.definitions.push(parse_definition(definition)?);
+                } else {
+                    parsed_option = false;
+                }
+            }
         };
         if parsed_option {
             continue;
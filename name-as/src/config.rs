@@ -3,18 +3,24 @@ extern crate toml;
 use serde::Deserialize;
 
 use crate::args::Args;
+use name_const::layout::Layout;
 use std::fs;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub config_name: String,
     pub as_cmd: Vec<String>,
+    // Where NMA puts each segment. Configs without a [layout] table get the
+    // default, and any table may leave out what it doesn't change.
+    #[serde(default)]
+    pub layout: Layout,
 }
 
 pub fn backup_config() -> Config {
     Config {
         config_name: "backup config".to_string(),
         as_cmd: ["".to_string()].to_vec(),
+        layout: Layout::default(),
     }
 }
 
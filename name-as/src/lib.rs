@@ -0,0 +1,23 @@
+//! The NAME assembler as a library
+//!
+//! [assemble_source](nma::assemble_source) assembles a program held in memory
+//! and hands back its segment images, line info, and symbol table, so tools
+//! can embed the assembler without going through the command line or
+//! writing anything to disk. It prints nothing; errors and warnings come
+//! back as [Diagnostic]s, with the file and line they're about.
+
+extern crate pest;
+extern crate pest_derive;
+
+pub mod args;
+pub mod config;
+
+pub mod lint;
+pub mod nma;
+pub mod parser;
+pub mod preprocess;
+pub mod pseudo;
+pub mod section;
+
+pub use name_const::isa;
+pub use nma::{assemble_source, Assembly, Diagnostic, Options};
@@ -0,0 +1,124 @@
+//! Assembly-time lints for common mistakes that still assemble
+//!
+//! Lints only ever produce warnings. They run over the program as written,
+//! before pseudo-instructions are expanded, so they speak in terms the
+//! programmer will recognize. Each warning comes with the fragment of source
+//! it's about, which the assembler can trace back to a file and line.
+
+use crate::parser::MipsCST;
+use std::collections::HashSet;
+
+/// Labels that are entered from outside the program even when nothing references them
+const ENTRY_LABELS: &[&str] = &["main", "__start"];
+
+/// Instructions after which control never falls through to the next one
+const UNCONDITIONAL_JUMPS: &[&str] = &["j", "jr"];
+
+/// Extracts the symbol an operand refers to, if any, looking through
+/// `%hi(...)`/`%lo(...)` and `symbol+offset` expressions
+pub fn referenced_symbol(arg: &str) -> Option<&str> {
+    let inner = match arg.strip_prefix("%hi(").or(arg.strip_prefix("%lo(")) {
+        Some(rest) => rest.trim_end_matches(')'),
+        None => arg,
+    };
+
+    let symbol = inner.split(['+', '-']).next().unwrap_or(inner);
+    if symbol.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        Some(symbol)
+    } else {
+        None
+    }
+}
+
+/// Warns about labels nothing refers to. Exported labels and entry points are
+/// assumed to be referenced from elsewhere.
+fn unused_labels<'a>(program: &[MipsCST<'a>]) -> Vec<(&'a str, String)> {
+    let mut referenced: HashSet<&str> = ENTRY_LABELS.iter().copied().collect();
+
+    for node in program {
+        match node {
+            MipsCST::Instruction(_, args) => {
+                referenced.extend(args.iter().filter_map(|arg| referenced_symbol(arg)));
+            }
+            MipsCST::Directive(name, args) => match *name {
+                ".globl" | ".global" | ".weak" => referenced.extend(args.iter().copied()),
+                ".word" | ".half" | ".byte" => {
+                    referenced.extend(args.iter().filter_map(|arg| referenced_symbol(arg)));
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    program
+        .iter()
+        .filter_map(|node| match node {
+            MipsCST::Label(label) if !referenced.contains(label) => {
+                Some((*label, format!("Label {} is never used", label)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Warns about instructions following an unconditional jump that no label
+/// leads to, since nothing can ever branch to them. The jump's delay slot
+/// still executes, so it is the instruction after that which is unreachable.
+fn unreachable_code<'a>(program: &[MipsCST<'a>]) -> Vec<(&'a str, String)> {
+    let mut warnings = vec![];
+    // The jump that made the code after it unreachable, if we're past one
+    let mut after_jump: Option<String> = None;
+    let mut in_delay_slot = false;
+    // Only the first unreachable instruction of a run is reported
+    let mut reported = false;
+
+    for node in program {
+        match node {
+            MipsCST::Label(_) => {
+                after_jump = None;
+                in_delay_slot = false;
+                reported = false;
+            }
+            // A section switch moves somewhere else entirely
+            MipsCST::Directive(name, _) if matches!(*name, ".section" | ".text" | ".data" | ".ktext" | ".kdata") => {
+                after_jump = None;
+                in_delay_slot = false;
+                reported = false;
+            }
+            MipsCST::Instruction(_, _) if in_delay_slot => in_delay_slot = false,
+            MipsCST::Instruction(mnemonic, args) => {
+                if let Some(jump) = &after_jump {
+                    if !reported {
+                        warnings.push((
+                            *mnemonic,
+                            format!(
+                                "Unreachable code: {} {} follows {} with no label in between",
+                                mnemonic,
+                                args.join(", "),
+                                jump
+                            ),
+                        ));
+                        reported = true;
+                    }
+                    continue;
+                }
+
+                if UNCONDITIONAL_JUMPS.contains(mnemonic) {
+                    after_jump = Some(format!("{} {}", mnemonic, args.join(", ")));
+                    in_delay_slot = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    warnings
+}
+
+/// Runs every lint over a program, returning the warnings in the order found
+pub fn lint<'a>(program: &[MipsCST<'a>]) -> Vec<(&'a str, String)> {
+    let mut warnings = unused_labels(program);
+    warnings.extend(unreachable_code(program));
+    warnings
+}
@@ -1,15 +1,6 @@
-extern crate pest;
-extern crate pest_derive;
-//use name_const::LineInfo;
-
-pub mod args;
-pub mod config;
-
-pub mod nma;
-pub mod parser;
-
-use args::parse_args;
-use nma::assemble;
+use name::args::parse_args;
+use name::config;
+use name::nma::assemble;
 use std::process::Command;
 
 fn main() -> Result<(), String> {
@@ -19,14 +10,14 @@ fn main() -> Result<(), String> {
     let config: config::Config = match config::parse_config(&cmd_args) {
         Ok(v) => v,
         _ => {
-            println!("WARN : Failed to parse config file, defaulting to nma");
+            eprintln!("WARN : Failed to parse config file, defaulting to nma");
             config::backup_config()
         }
     };
 
     if config.as_cmd.is_empty() {
         // If no provided as config, default to NMA
-        assemble(&cmd_args)?;
+        assemble(&cmd_args, config.layout)?;
     } else {
         // Otherwise, use provided assembler command
         println!("Config Name:   {}", config.config_name);
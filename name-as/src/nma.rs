@@ -1,12 +1,20 @@
 /// NAME Mips Assembler
 use crate::args::Args;
 //use crate::lineinfo::*;
+use name_const::layout::Layout;
 use name_const::lineinfo::*;
-use crate::parser::print_cst;
-use std::collections::HashMap;
+use name_const::symbols::{SymbolBinding, SymbolInfo};
+use crate::isa::Isa;
+use crate::lint::{lint, referenced_symbol};
+use crate::preprocess::{expand_includes, fold_case, substitute_definitions, SourceLocation, SourceMap};
+use crate::pseudo::{
+    expand_pseudo, relax_branch, Expansion, CONDITIONAL_BRANCHES, PSEUDO_INSTRUCTION_SET,
+};
+use crate::section::{Segment, SectionTable};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str;
 
 fn mask_u8(n: u8, x: u8) -> Result<u8, &'static str> {
@@ -18,27 +26,40 @@ fn mask_u8(n: u8, x: u8) -> Result<u8, &'static str> {
     }
 }
 
-fn mask_u32(n: u32, x: u8) -> Result<u32, &'static str> {
-    let out = n & ((1 << x) - 1);
-    if out != n {
-        Err("Masking error")
-    } else {
-        Ok(out)
-    }
-}
-
-const TEXT_ADDRESS_BASE: u32 = 0x400000;
 const MIPS_INSTR_BYTE_WIDTH: u32 = 4;
 
+// Opcodes shared by R-type instructions, which are told apart by funct
+const SPECIAL: u8 = 0x00;
+const COP0: u8 = 0x10;
+const SPECIAL2: u8 = 0x1c;
+const SPECIAL3: u8 = 0x1f;
+
 /// The form of an R-type instruction, specificially
 /// which arguments it expects in which order
 enum RForm {
     RdRsRt,
-    RdRtShamt,
+    // The rs field is fixed, and tells rotates apart from logical shifts
+    RdRtShamt { rs: u8 },
+    RdRtRs,
+    RsRt,
+    Rd,
+    Rs,
+    RdRs,
+    RdRt,
+    // ext and ins describe a bit field by its position and size
+    RtRsPosSize { insert: bool },
+    // Takes no operands at all, only a fixed rs
+    NoOperands { rs: u8 },
+    // Coprocessor 0 moves use rs to pick the direction, rd for the coprocessor
+    // register, and the low bits of funct for an optional select
+    RtRdSel { rs: u8 },
+    // The rt field holds a condition code and whether to move on true
+    RdRsCc { tf: u8 },
 }
 
 /// The variable components of an R-type instruction
 pub struct R {
+    opcode: u8,
     shamt: u8,
     funct: u8,
     form: RForm,
@@ -51,6 +72,13 @@ enum IForm {
     RtImmRs,
     RtRsImm,
     RsRtLabel,
+    // Branches comparing rs against zero use the rt field to select the comparison
+    RsLabel { rt: u8 },
+    // REGIMM instructions use the rt field to select the operation
+    RsImm { rt: u8 },
+    // Floating-point branches fix rs, and put an optional condition code and
+    // whether to branch on true in rt
+    CcLabel { tf: u8 },
 }
 
 /// The variable components of an I-type instruction
@@ -59,6 +87,53 @@ pub struct I {
     form: IForm,
 }
 
+impl I {
+    /// Whether the immediate is zero-extended, as the logical immediates and
+    /// lui's are, rather than sign-extended
+    fn zero_extends(&self) -> bool {
+        matches!(self.opcode, 0xc..=0xf)
+    }
+}
+
+/// The form of a floating-point instruction, specifically
+/// which arguments it expects in which order
+enum FForm {
+    // Transfers name a general-purpose register and a floating-point register
+    RtFs,
+    // Control transfers name a general-purpose register and a control register
+    RtFcr,
+    FdFs,
+    // Comparisons set a condition code, which may be left implicit
+    CcFsFt,
+    // Multiply-add takes fr in the fmt field
+    FdFrFsFt,
+    // Indexed loads and stores, written like `lwxc1 $f0, $t1($t2)`
+    FsIndexBase { store: bool },
+    // The ft field holds a general-purpose register
+    FdFsRt,
+    // The ft field holds a condition code and whether to move on true
+    FdFsCc { tf: u8 },
+}
+
+/// The variable components of a floating-point (COP1) instruction
+pub struct F {
+    opcode: u8,
+    fmt: u8,
+    funct: u8,
+    form: FForm,
+}
+
+const COP1: u8 = 0x11;
+// COP1X instructions have four register operands, and leave out the fmt field
+const COP1X: u8 = 0x13;
+/// Comparison conditions for c.cond.fmt, in the order of their encoding
+const COMPARE_CONDITIONS: &[&str] = &[
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule", "sf", "ngle", "seq", "ngl", "lt", "nge",
+    "le", "ngt",
+];
+const FMT_SINGLE: u8 = 0x10;
+const FMT_DOUBLE: u8 = 0x11;
+
 /// The variable component of a J-type instruction
 pub struct J {
     opcode: u8,
@@ -68,30 +143,313 @@ pub struct J {
 pub fn r_operation(mnemonic: &str) -> Result<R, &'static str> {
     match mnemonic {
         "add" => Ok(R {
+            opcode: SPECIAL,
             shamt: 0,
             funct: 0x20,
             form: RForm::RdRsRt,
         }),
         "sub" => Ok(R {
+            opcode: SPECIAL,
             shamt: 0,
             funct: 0x22,
             form: RForm::RdRsRt,
         }),
         "sll" => Ok(R {
+            opcode: SPECIAL,
             shamt: 0,
             funct: 0x00,
-            form: RForm::RdRtShamt,
+            form: RForm::RdRtShamt { rs: 0 },
         }),
         "srl" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x02,
+            form: RForm::RdRtShamt { rs: 0 },
+        }),
+        "sra" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x03,
+            form: RForm::RdRtShamt { rs: 0 },
+        }),
+        "rotr" => Ok(R {
+            opcode: SPECIAL,
             shamt: 0,
             funct: 0x02,
-            form: RForm::RdRtShamt,
+            form: RForm::RdRtShamt { rs: 1 },
+        }),
+        "sllv" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x04,
+            form: RForm::RdRtRs,
+        }),
+        "srlv" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x06,
+            form: RForm::RdRtRs,
+        }),
+        "srav" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x07,
+            form: RForm::RdRtRs,
+        }),
+        // The shamt field tells a rotate apart from a logical shift
+        "rotrv" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 1,
+            funct: 0x06,
+            form: RForm::RdRtRs,
+        }),
+        "movf" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x01,
+            form: RForm::RdRsCc { tf: 0 },
+        }),
+        "movt" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x01,
+            form: RForm::RdRsCc { tf: 1 },
+        }),
+        "movz" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x0a,
+            form: RForm::RdRsRt,
+        }),
+        "movn" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x0b,
+            form: RForm::RdRsRt,
+        }),
+        "jr" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x8,
+            form: RForm::Rs,
+        }),
+        "teq" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x34,
+            form: RForm::RsRt,
+        }),
+        "tne" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x36,
+            form: RForm::RsRt,
+        }),
+        "tge" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x30,
+            form: RForm::RsRt,
+        }),
+        "tgeu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x31,
+            form: RForm::RsRt,
+        }),
+        "tlt" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x32,
+            form: RForm::RsRt,
+        }),
+        "tltu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x33,
+            form: RForm::RsRt,
+        }),
+        "mfhi" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x10,
+            form: RForm::Rd,
+        }),
+        "mflo" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x12,
+            form: RForm::Rd,
+        }),
+        "mthi" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x11,
+            form: RForm::Rs,
+        }),
+        "mtlo" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x13,
+            form: RForm::Rs,
+        }),
+        "mult" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x18,
+            form: RForm::RsRt,
+        }),
+        "multu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x19,
+            form: RForm::RsRt,
+        }),
+        "divu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x1b,
+            form: RForm::RsRt,
+        }),
+        "div" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x1a,
+            form: RForm::RsRt,
+        }),
+        "addu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x21,
+            form: RForm::RdRsRt,
+        }),
+        "subu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x23,
+            form: RForm::RdRsRt,
+        }),
+        "or" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x25,
+            form: RForm::RdRsRt,
         }),
         "xor" => Ok(R {
+            opcode: SPECIAL,
             shamt: 0,
             funct: 0x26,
             form: RForm::RdRsRt,
         }),
+        "nor" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x27,
+            form: RForm::RdRsRt,
+        }),
+        "slt" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x2a,
+            form: RForm::RdRsRt,
+        }),
+        "sltu" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x2b,
+            form: RForm::RdRsRt,
+        }),
+        "madd" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x00,
+            form: RForm::RsRt,
+        }),
+        "maddu" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x01,
+            form: RForm::RsRt,
+        }),
+        "msub" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x04,
+            form: RForm::RsRt,
+        }),
+        "msubu" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x05,
+            form: RForm::RsRt,
+        }),
+        "mfc0" => Ok(R {
+            opcode: COP0,
+            shamt: 0,
+            funct: 0,
+            form: RForm::RtRdSel { rs: 0x00 },
+        }),
+        "mtc0" => Ok(R {
+            opcode: COP0,
+            shamt: 0,
+            funct: 0,
+            form: RForm::RtRdSel { rs: 0x04 },
+        }),
+        "syscall" => Ok(R {
+            opcode: SPECIAL,
+            shamt: 0,
+            funct: 0x0c,
+            form: RForm::NoOperands { rs: 0 },
+        }),
+        "eret" => Ok(R {
+            opcode: COP0,
+            shamt: 0,
+            funct: 0x18,
+            form: RForm::NoOperands { rs: 0x10 },
+        }),
+        "clz" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x20,
+            form: RForm::RdRs,
+        }),
+        "clo" => Ok(R {
+            opcode: SPECIAL2,
+            shamt: 0,
+            funct: 0x21,
+            form: RForm::RdRs,
+        }),
+        "ext" => Ok(R {
+            opcode: SPECIAL3,
+            shamt: 0,
+            funct: 0x00,
+            form: RForm::RtRsPosSize { insert: false },
+        }),
+        "ins" => Ok(R {
+            opcode: SPECIAL3,
+            shamt: 0,
+            funct: 0x04,
+            form: RForm::RtRsPosSize { insert: true },
+        }),
+        // The BSHFL instructions share a funct, and are told apart by shamt
+        "wsbh" => Ok(R {
+            opcode: SPECIAL3,
+            shamt: 0x02,
+            funct: 0x20,
+            form: RForm::RdRt,
+        }),
+        "seb" => Ok(R {
+            opcode: SPECIAL3,
+            shamt: 0x10,
+            funct: 0x20,
+            form: RForm::RdRt,
+        }),
+        "seh" => Ok(R {
+            opcode: SPECIAL3,
+            shamt: 0x18,
+            funct: 0x20,
+            form: RForm::RdRt,
+        }),
         _ => Err("Failed to match R-instr mnemonic"),
     }
 }
@@ -99,6 +457,42 @@ pub fn r_operation(mnemonic: &str) -> Result<R, &'static str> {
 /// Parses an I-type instruction mnemonic into an [I]
 pub fn i_operation(mnemonic: &str) -> Result<I, &'static str> {
     match mnemonic {
+        "tgei" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0x8 },
+        }),
+        "tgeiu" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0x9 },
+        }),
+        "tlti" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0xa },
+        }),
+        "tltiu" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0xb },
+        }),
+        "teqi" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0xc },
+        }),
+        "tnei" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsImm { rt: 0xe },
+        }),
+        "addi" => Ok(I {
+            opcode: 0x8,
+            form: IForm::RtRsImm,
+        }),
+        "addiu" => Ok(I {
+            opcode: 0x9,
+            form: IForm::RtRsImm,
+        }),
+        "sltiu" => Ok(I {
+            opcode: 0xb,
+            form: IForm::RtRsImm,
+        }),
         "ori" => Ok(I {
             opcode: 0xd,
             form: IForm::RtRsImm,
@@ -127,6 +521,22 @@ pub fn i_operation(mnemonic: &str) -> Result<I, &'static str> {
             opcode: 0x30,
             form: IForm::RtImmRs,
         }),
+        "lwl" => Ok(I {
+            opcode: 0x22,
+            form: IForm::RtImmRs,
+        }),
+        "lwr" => Ok(I {
+            opcode: 0x26,
+            form: IForm::RtImmRs,
+        }),
+        "swl" => Ok(I {
+            opcode: 0x2a,
+            form: IForm::RtImmRs,
+        }),
+        "swr" => Ok(I {
+            opcode: 0x2e,
+            form: IForm::RtImmRs,
+        }),
         "lui" => Ok(I {
             opcode: 0xf,
             form: IForm::RtImm,
@@ -155,10 +565,248 @@ pub fn i_operation(mnemonic: &str) -> Result<I, &'static str> {
             opcode: 0x5,
             form: IForm::RsRtLabel,
         }),
+        "blez" => Ok(I {
+            opcode: 0x6,
+            form: IForm::RsLabel { rt: 0 },
+        }),
+        "bgtz" => Ok(I {
+            opcode: 0x7,
+            form: IForm::RsLabel { rt: 0 },
+        }),
+        "bltz" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x0 },
+        }),
+        "bgez" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x1 },
+        }),
+        // Linking branches set $ra whether or not they're taken
+        "bltzal" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x10 },
+        }),
+        "bgezal" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x11 },
+        }),
+        "bc1f" => Ok(I {
+            opcode: COP1,
+            form: IForm::CcLabel { tf: 0 },
+        }),
+        "bc1t" => Ok(I {
+            opcode: COP1,
+            form: IForm::CcLabel { tf: 1 },
+        }),
+        // Branch-likely instructions only execute their delay slot when taken
+        "beql" => Ok(I {
+            opcode: 0x14,
+            form: IForm::RsRtLabel,
+        }),
+        "bnel" => Ok(I {
+            opcode: 0x15,
+            form: IForm::RsRtLabel,
+        }),
+        "blezl" => Ok(I {
+            opcode: 0x16,
+            form: IForm::RsLabel { rt: 0 },
+        }),
+        "bgtzl" => Ok(I {
+            opcode: 0x17,
+            form: IForm::RsLabel { rt: 0 },
+        }),
+        "bltzl" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x2 },
+        }),
+        "bgezl" => Ok(I {
+            opcode: 0x1,
+            form: IForm::RsLabel { rt: 0x3 },
+        }),
         _ => Err("Failed to match I-instr mnemonic"),
     }
 }
 
+/// Parses a floating-point instruction mnemonic, such as movz.d, into an [F]
+pub fn f_operation(mnemonic: &str) -> Result<F, &'static str> {
+    // Transfers between register files have no format, and reuse its field
+    // to select the direction. Indexed loads and stores have no format either.
+    match mnemonic {
+        "lwxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x00,
+                form: FForm::FsIndexBase { store: false },
+            })
+        }
+        "ldxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x01,
+                form: FForm::FsIndexBase { store: false },
+            })
+        }
+        "luxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x05,
+                form: FForm::FsIndexBase { store: false },
+            })
+        }
+        "swxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x08,
+                form: FForm::FsIndexBase { store: true },
+            })
+        }
+        "sdxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x09,
+                form: FForm::FsIndexBase { store: true },
+            })
+        }
+        "suxc1" => {
+            return Ok(F {
+                opcode: COP1X,
+                fmt: 0,
+                funct: 0x0d,
+                form: FForm::FsIndexBase { store: true },
+            })
+        }
+        "mfc1" => {
+            return Ok(F {
+                opcode: COP1,
+                fmt: 0x00,
+                funct: 0,
+                form: FForm::RtFs,
+            })
+        }
+        "cfc1" => {
+            return Ok(F {
+                opcode: COP1,
+                fmt: 0x02,
+                funct: 0,
+                form: FForm::RtFcr,
+            })
+        }
+        "mtc1" => {
+            return Ok(F {
+                opcode: COP1,
+                fmt: 0x04,
+                funct: 0,
+                form: FForm::RtFs,
+            })
+        }
+        "ctc1" => {
+            return Ok(F {
+                opcode: COP1,
+                fmt: 0x06,
+                funct: 0,
+                form: FForm::RtFcr,
+            })
+        }
+        _ => (),
+    }
+
+    let (operation, fmt) = match mnemonic.rsplit_once('.') {
+        Some((operation, "s")) => (operation, FMT_SINGLE),
+        Some((operation, "d")) => (operation, FMT_DOUBLE),
+        _ => return Err("Failed to match F-instr mnemonic"),
+    };
+
+    if let Some(condition) = operation.strip_prefix("c.") {
+        return match COMPARE_CONDITIONS.iter().position(|c| *c == condition) {
+            Some(index) => Ok(F {
+                opcode: COP1,
+                fmt,
+                funct: 0x30 | index as u8,
+                form: FForm::CcFsFt,
+            }),
+            None => Err("Failed to match F-instr mnemonic"),
+        };
+    }
+
+    // COP1X encodes the format in the low three bits of funct instead
+    let fmt3 = fmt - FMT_SINGLE;
+
+    match operation {
+        "madd" => Ok(F {
+            opcode: COP1X,
+            fmt,
+            funct: 0x20 | fmt3,
+            form: FForm::FdFrFsFt,
+        }),
+        "msub" => Ok(F {
+            opcode: COP1X,
+            fmt,
+            funct: 0x28 | fmt3,
+            form: FForm::FdFrFsFt,
+        }),
+        "nmadd" => Ok(F {
+            opcode: COP1X,
+            fmt,
+            funct: 0x30 | fmt3,
+            form: FForm::FdFrFsFt,
+        }),
+        "nmsub" => Ok(F {
+            opcode: COP1X,
+            fmt,
+            funct: 0x38 | fmt3,
+            form: FForm::FdFrFsFt,
+        }),
+        "sqrt" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x04,
+            form: FForm::FdFs,
+        }),
+        "recip" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x15,
+            form: FForm::FdFs,
+        }),
+        "rsqrt" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x16,
+            form: FForm::FdFs,
+        }),
+        "movf" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x11,
+            form: FForm::FdFsCc { tf: 0 },
+        }),
+        "movt" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x11,
+            form: FForm::FdFsCc { tf: 1 },
+        }),
+        "movz" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x12,
+            form: FForm::FdFsRt,
+        }),
+        "movn" => Ok(F {
+            opcode: COP1,
+            fmt,
+            funct: 0x13,
+            form: FForm::FdFsRt,
+        }),
+        _ => Err("Failed to match F-instr mnemonic"),
+    }
+}
+
 /// Parses a J-type instruction mnemonic into a [J]
 fn j_operation(mnemonic: &str) -> Result<J, &'static str> {
     match mnemonic {
@@ -168,29 +816,143 @@ fn j_operation(mnemonic: &str) -> Result<J, &'static str> {
     }
 }
 
-/// Write a u32 into a file, zero-padded to 32 bits (4 bytes)
-pub fn write_u32(mut file: &File, data: u32) -> std::io::Result<()> {
-    fn convert_endianness(input: u32) -> u32 {
-        ((input & 0x000000FF) << 24)
-            | ((input & 0x0000FF00) << 8)
-            | ((input & 0x00FF0000) >> 8)
-            | ((input & 0xFF000000) >> 24)
+/// Append a u32 to a buffer in little-endian byte order
+pub fn write_u32(buffer: &mut Vec<u8>, data: u32) {
+    buffer.extend_from_slice(&data.to_le_bytes());
+}
+
+/// Parses an integer literal in decimal, hex (0x), binary (0b), or octal (0o),
+/// optionally negated
+pub fn parse_int(literal: &str) -> Option<i64> {
+    let (negative, literal) = match literal.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, literal),
+    };
+
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x") | Some("0X") => (&literal[2..], 16),
+        Some("0b") | Some("0B") => (&literal[2..], 2),
+        Some("0o") | Some("0O") => (&literal[2..], 8),
+        _ => (literal, 10),
+    };
+
+    let magnitude = i64::from(u32::from_str_radix(digits, radix).ok()?);
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Whether a value can be encoded in a field of the given width, read either
+/// as a signed or an unsigned quantity
+pub fn fits_in_bits(value: i64, bits: u32) -> bool {
+    -(1 << (bits - 1)) <= value && value < (1 << bits)
+}
+
+/// Parses the offset of a `.org` directive, rejecting any that would move backwards
+/// over bytes already placed in the section
+fn org_offset(args: &[&str], section_name: &str, section_size: u32) -> Result<u32, String> {
+    let offset = match args {
+        [offset] => match parse_int(offset).map(u32::try_from) {
+            Some(Ok(v)) => v,
+            _ => return Err(format!("Failed to parse .org offset {}", offset)),
+        },
+        _ => return Err(".org expects a single offset".to_string()),
+    };
+
+    if offset < section_size {
+        return Err(format!(
+            ".org {:#x} overlaps {:#x} bytes already placed in section {}",
+            offset, section_size, section_name
+        ));
+    }
+
+    Ok(offset)
+}
+
+/// The width in bytes of each value emitted by a data directive
+fn data_width(directive: &str) -> Option<u32> {
+    match directive {
+        ".word" => Some(4),
+        ".half" => Some(2),
+        ".byte" => Some(1),
+        _ => None,
+    }
+}
+
+/// Rounds an offset up to the next multiple of a power-of-two width
+fn align_up(offset: u32, width: u32) -> u32 {
+    (offset + width - 1) & !(width - 1)
+}
+
+/// A tentative definition made by `.comm`, allocated once every label is known
+struct CommonSymbol<'a> {
+    name: &'a str,
+    size: u32,
+    alignment: u32,
+}
+
+/// Records a `.comm name, size[, alignment]` directive. Repeated declarations
+/// of the same symbol merge into the largest of each, as ELF linkers do.
+fn declare_common<'a>(commons: &mut Vec<CommonSymbol<'a>>, args: &[&'a str]) -> Result<(), String> {
+    let (name, size, alignment) = match args {
+        [name, size] => (*name, *size, "4"),
+        [name, size, alignment] => (*name, *size, *alignment),
+        _ => return Err(".comm expects a name, a size, and an optional alignment".to_string()),
+    };
+
+    let size = match parse_int(size).map(u32::try_from) {
+        Some(Ok(v)) => v,
+        _ => return Err(format!("Invalid size {} for common symbol {}", size, name)),
+    };
+    let alignment = match parse_int(alignment).map(u32::try_from) {
+        Some(Ok(v)) if v.is_power_of_two() => v,
+        _ => return Err(format!("Alignment of common symbol {} must be a power of two", name)),
+    };
+
+    match commons.iter_mut().find(|common| common.name == name) {
+        Some(common) => {
+            common.size = common.size.max(size);
+            common.alignment = common.alignment.max(alignment);
+        }
+        None => commons.push(CommonSymbol {
+            name,
+            size,
+            alignment,
+        }),
     }
 
-    const PADDED_LENGTH: usize = 4;
+    Ok(())
+}
+
+/// Assembles the values of a `.word`, `.half`, or `.byte` directive into a buffer.
+/// Values may be literals or labels, the latter allowing jump tables in data.
+fn assemble_data(
+    buffer: &mut Vec<u8>,
+    directive: &str,
+    args: &[&str],
+    labels: &HashMap<&str, u32>,
+) -> Result<(), String> {
+    let width = match data_width(directive) {
+        Some(v) => v,
+        None => return Err(format!("Unknown data directive {}", directive)),
+    };
 
-    // Create a 4-length buffer
-    let mut padded_buffer: [u8; PADDED_LENGTH] = [0; PADDED_LENGTH];
+    if args.is_empty() {
+        return Err(format!("{} expects at least one value", directive));
+    }
 
-    // Convert data into bytes
-    let bytes: [u8; PADDED_LENGTH] = (convert_endianness(data)).to_be_bytes();
+    for arg in args {
+        let value: u32 = match parse_int(arg) {
+            Some(v) if fits_in_bits(v, width * 8) => v as u32,
+            Some(_) => return Err(format!("Value {} does not fit in {}", arg, directive)),
+            None => match resolve_symbol_expr(arg, labels) {
+                Ok(address) => address,
+                Err(_) => return Err(format!("Failed to parse {} value {}", directive, arg)),
+            },
+        };
 
-    // Copy bytes into buffer at offset s.t. value is left-padded with 0s
-    let copy_index = PADDED_LENGTH - bytes.len();
-    padded_buffer[copy_index..].copy_from_slice(&bytes);
+        buffer.extend_from_slice(&value.to_le_bytes()[..width as usize]);
+    }
 
-    // Write to file
-    file.write_all(&padded_buffer)
+    Ok(())
 }
 
 /// Converts a numbered mnemonic ($t0, $s8, etc) or literal (55, 67, etc) to its integer representation
@@ -224,6 +986,11 @@ fn assemble_reg(mnemonic: &str) -> Result<u8, &'static str> {
         "sp" => Ok(29),
         "fp" => Ok(30),
         "ra" => Ok(31),
+        // Registers can also be named by number, as in $31
+        number if number.starts_with(|c: char| c.is_ascii_digit()) => match number.parse::<u8>() {
+            Ok(reg) if reg <= 31 => Ok(reg),
+            _ => Err("Register out of bounds"),
+        },
         _ => {
             let n = reg_number(mnemonic)?;
             let reg = match mnemonic.chars().nth(1) {
@@ -239,6 +1006,8 @@ fn assemble_reg(mnemonic: &str) -> Result<u8, &'static str> {
                     }
                 }
                 Some('s') => n + 16,
+                // Reserved for exception handlers
+                Some('k') => n + 26,
                 _ => {
                     // Catch registers like $0
                     mnemonic.parse::<u8>().unwrap_or(99)
@@ -253,44 +1022,289 @@ fn assemble_reg(mnemonic: &str) -> Result<u8, &'static str> {
     }
 }
 
-/// Enforce a specific length for a given vector
-fn enforce_length(arr: &Vec<&str>, len: usize) -> Result<u32, &'static str> {
-    if arr.len() != len {
-        Err("Failed length enforcement")
-    } else {
-        Ok(0)
+/// Assembles a floating-point register ($f0 through $f31) into its number
+fn assemble_fp_reg(mnemonic: &str) -> Result<u8, &'static str> {
+    match mnemonic.strip_prefix("$f").map(str::parse::<u8>) {
+        Some(Ok(n)) if n <= 31 => Ok(n),
+        _ => Err("Expected a floating-point register $f0 through $f31"),
     }
 }
 
-/// Assembles an R-type instruction
-fn assemble_r(r_struct: R, r_args: Vec<&str>) -> Result<u32, &'static str> {
-    let mut rs: u8;
-    let mut rt: u8;
-    let mut rd: u8;
-    let mut shamt: u8;
+/// Floating-point control registers that cfc1 and ctc1 can name:
+/// FIR, FCCR, FEXR, FENR, and FCSR
+const FP_CONTROL_REGISTERS: &[u8] = &[0, 25, 26, 28, 31];
 
-    match r_struct.form {
-        RForm::RdRsRt => {
-            enforce_length(&r_args, 3)?;
-            rd = assemble_reg(r_args[0])?;
-            rs = assemble_reg(r_args[1])?;
-            rt = assemble_reg(r_args[2])?;
-            shamt = r_struct.shamt;
-        }
-        RForm::RdRtShamt => {
-            enforce_length(&r_args, 3)?;
-            rd = assemble_reg(r_args[0])?;
-            rs = 0;
-            rt = assemble_reg(r_args[1])?;
-            shamt = match r_args[2].parse::<u8>() {
-                Ok(v) => v,
-                Err(_) => return Err("Failed to parse shamt"),
+/// Assembles a floating-point control register, written as $31 or 31
+fn assemble_fcr(mnemonic: &str) -> Result<u8, &'static str> {
+    match mnemonic.trim_start_matches('$').parse::<u8>() {
+        Ok(n) if FP_CONTROL_REGISTERS.contains(&n) => Ok(n),
+        _ => Err("Expected a floating-point control register: 0, 25, 26, 28, or 31"),
+    }
+}
+
+/// Assembles an optional condition code operand, which defaults to 0
+fn assemble_cc(arg: Option<&&str>) -> Result<u8, &'static str> {
+    match arg.map(|arg| parse_int(arg)) {
+        None => Ok(0),
+        Some(Some(cc)) if (0..8).contains(&cc) => Ok(cc as u8),
+        Some(_) => Err("Expected a condition code from 0 to 7"),
+    }
+}
+
+/// The visibility a symbol directive requests for a label
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SymbolVisibility {
+    Global,
+    Weak,
+    // Declared as defined in another object. NAME emits a single flat binary,
+    // so an extern symbol can never actually be resolved against anything.
+    Extern,
+}
+
+/// Records the visibility requested by `.globl`, `.extern`, and `.weak`
+fn declare_symbols<'a>(
+    visibilities: &mut HashMap<&'a str, SymbolVisibility>,
+    directive: &str,
+    args: &[&'a str],
+) -> Result<(), String> {
+    let visibility = match directive {
+        ".globl" | ".global" => SymbolVisibility::Global,
+        ".weak" => SymbolVisibility::Weak,
+        ".extern" => SymbolVisibility::Extern,
+        _ => return Err(format!("Unknown directive {}", directive)),
+    };
+
+    if args.is_empty() {
+        return Err(format!("{} expects at least one symbol", directive));
+    }
+
+    for symbol in args {
+        match visibilities.get(symbol) {
+            Some(previous) if *previous != visibility => {
+                return Err(format!(
+                    "Symbol {} declared as both {:?} and {:?}",
+                    symbol, previous, visibility
+                ))
+            }
+            _ => {
+                visibilities.insert(symbol, visibility);
             }
         }
+    }
+
+    Ok(())
+}
+
+/// Builds the exported symbol table from the label addresses and requested visibilities
+fn export_symbols(
+    labels: &HashMap<&str, u32>,
+    visibilities: &HashMap<&str, SymbolVisibility>,
+) -> Vec<SymbolInfo> {
+    let mut symbols: Vec<SymbolInfo> = vec![];
+
+    for (name, address) in labels {
+        let binding = match visibilities.get(name) {
+            None => SymbolBinding::Local,
+            // A definition satisfies an .extern declaration the same way it would
+            // satisfy a reference from another object
+            Some(SymbolVisibility::Global) | Some(SymbolVisibility::Extern) => {
+                SymbolBinding::Global
+            }
+            Some(SymbolVisibility::Weak) => SymbolBinding::Weak,
+        };
+        symbols.push(SymbolInfo {
+            name: name.to_string(),
+            address: *address,
+            binding,
+        });
+    }
+
+    // HashMap iteration order is unstable, keep the exported table deterministic
+    symbols.sort_by(|a, b| a.address.cmp(&b.address).then(a.name.cmp(&b.name)));
+
+    symbols
+}
+
+/// Resolves a `symbol`, `symbol+offset`, or `symbol-offset` expression to an address
+pub fn resolve_symbol_expr(expr: &str, labels: &HashMap<&str, u32>) -> Result<u32, &'static str> {
+    let (symbol, offset) = match expr.find(['+', '-']) {
+        Some(i) => match parse_int(&expr[i + 1..]) {
+            Some(v) if expr.as_bytes()[i] == b'-' => (&expr[..i], -v),
+            Some(v) => (&expr[..i], v),
+            None => return Err("Malformed symbol offset"),
+        },
+        None => (expr, 0),
     };
 
+    match labels.get(symbol) {
+        Some(address) => Ok(address.wrapping_add(offset as u32)),
+        None => Err("Undeclared label"),
+    }
+}
+
+/// Resolves a `%hi(symbol)` or `%lo(symbol)` operand against the label table.
+/// Addresses are final by the time NAME assembles an instruction, so these are
+/// resolved immediately instead of being emitted as Hi16/Lo16 relocations.
+fn assemble_relocation(arg: &str, labels: &HashMap<&str, u32>) -> Result<u16, &'static str> {
+    let (operator, rest) = arg.split_at(3);
+    let symbol = match rest.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+        Some(v) => v,
+        None => return Err("Malformed relocation operand"),
+    };
+    let address = resolve_symbol_expr(symbol, labels)?;
+
+    match operator {
+        // Matches gas: %hi is adjusted for the sign extension of the paired %lo,
+        // so that lui + addiu (or a load/store offset) reconstructs the address
+        "%hi" => Ok((address.wrapping_add(0x8000) >> 16) as u16),
+        "%lo" => Ok(address as u16),
+        _ => Err("Unknown relocation operator"),
+    }
+}
+
+/// Given an immediate operand, assemble it into its 16-bit representation.
+/// Literals have to fit the field as the instruction extends it, while %hi and
+/// %lo are already the exact bits.
+fn assemble_imm(arg: &str, labels: &HashMap<&str, u32>, zero_extended: bool) -> Result<u16, &'static str> {
+    if arg.starts_with('%') {
+        return assemble_relocation(arg, labels);
+    }
+
+    match parse_int(arg) {
+        Some(v) if zero_extended && (0..=0xFFFF).contains(&v) => Ok(v as u16),
+        Some(v) if !zero_extended && (-0x8000..=0x7FFF).contains(&v) => Ok(v as u16),
+        Some(_) if zero_extended => Err("Immediate must be between 0 and 0xFFFF"),
+        Some(_) => Err("Immediate must be between -0x8000 and 0x7FFF"),
+        None => Err("Failed to parse imm"),
+    }
+}
+
+/// Enforce a specific length for a given vector
+fn enforce_length(arr: &[&str], len: usize) -> Result<u32, &'static str> {
+    if arr.len() != len {
+        Err("Failed length enforcement")
+    } else {
+        Ok(0)
+    }
+}
+
+/// Assembles an R-type instruction
+fn assemble_r(r_struct: R, r_args: Vec<&str>) -> Result<u32, &'static str> {
+    let mut rs: u8;
+    let mut rt: u8;
+    let mut rd: u8;
+    let mut shamt: u8;
     let mut funct = r_struct.funct;
 
+    match r_struct.form {
+        RForm::RdRsRt => {
+            enforce_length(&r_args, 3)?;
+            rd = assemble_reg(r_args[0])?;
+            rs = assemble_reg(r_args[1])?;
+            rt = assemble_reg(r_args[2])?;
+            shamt = r_struct.shamt;
+        }
+        RForm::RdRtShamt { rs: fixed_rs } => {
+            enforce_length(&r_args, 3)?;
+            rd = assemble_reg(r_args[0])?;
+            rs = fixed_rs;
+            rt = assemble_reg(r_args[1])?;
+            shamt = match parse_int(r_args[2]).map(u8::try_from) {
+                Some(Ok(v)) => v,
+                _ => return Err("Failed to parse shamt"),
+            }
+        }
+        RForm::RdRtRs => {
+            enforce_length(&r_args, 3)?;
+            rd = assemble_reg(r_args[0])?;
+            rt = assemble_reg(r_args[1])?;
+            rs = assemble_reg(r_args[2])?;
+            shamt = r_struct.shamt;
+        }
+        RForm::RsRt => {
+            enforce_length(&r_args, 2)?;
+            rd = 0;
+            rs = assemble_reg(r_args[0])?;
+            rt = assemble_reg(r_args[1])?;
+            shamt = r_struct.shamt;
+        }
+        RForm::Rd => {
+            enforce_length(&r_args, 1)?;
+            rd = assemble_reg(r_args[0])?;
+            rs = 0;
+            rt = 0;
+            shamt = r_struct.shamt;
+        }
+        RForm::Rs => {
+            enforce_length(&r_args, 1)?;
+            rd = 0;
+            rs = assemble_reg(r_args[0])?;
+            rt = 0;
+            shamt = r_struct.shamt;
+        }
+        RForm::RdRsCc { tf } => {
+            if !(2..=3).contains(&r_args.len()) {
+                return Err("Failed length enforcement");
+            }
+            rd = assemble_reg(r_args[0])?;
+            rs = assemble_reg(r_args[1])?;
+            rt = assemble_cc(r_args.get(2))? << 2 | tf;
+            shamt = r_struct.shamt;
+        }
+        RForm::RdRs => {
+            enforce_length(&r_args, 2)?;
+            rd = assemble_reg(r_args[0])?;
+            rs = assemble_reg(r_args[1])?;
+            // Release 2 requires rt to repeat rd
+            rt = rd;
+            shamt = r_struct.shamt;
+        }
+        RForm::RdRt => {
+            enforce_length(&r_args, 2)?;
+            rd = assemble_reg(r_args[0])?;
+            rs = 0;
+            rt = assemble_reg(r_args[1])?;
+            shamt = r_struct.shamt;
+        }
+        RForm::NoOperands { rs: fixed_rs } => {
+            enforce_length(&r_args, 0)?;
+            rs = fixed_rs;
+            rt = 0;
+            rd = 0;
+            shamt = r_struct.shamt;
+        }
+        RForm::RtRdSel { rs: fixed_rs } => {
+            if !(2..=3).contains(&r_args.len()) {
+                return Err("Failed length enforcement");
+            }
+            rs = fixed_rs;
+            rt = assemble_reg(r_args[0])?;
+            rd = assemble_reg(r_args[1])?;
+            shamt = r_struct.shamt;
+            // The select defaults to 0 when omitted
+            if let Some(sel) = r_args.get(2) {
+                match parse_int(sel) {
+                    Some(v) if (0..8).contains(&v) => funct |= v as u8,
+                    _ => return Err("Expected a select from 0 to 7"),
+                }
+            }
+        }
+        RForm::RtRsPosSize { insert } => {
+            enforce_length(&r_args, 4)?;
+            rt = assemble_reg(r_args[0])?;
+            rs = assemble_reg(r_args[1])?;
+            let (pos, size) = match (parse_int(r_args[2]), parse_int(r_args[3])) {
+                (Some(pos), Some(size)) if pos >= 0 && size > 0 && pos + size <= 32 => {
+                    (pos as u8, size as u8)
+                }
+                _ => return Err("Bit field must lie within the register and be at least one bit"),
+            };
+            // The field's lsb goes in shamt, and its msb (ins) or size - 1 (ext) in rd
+            shamt = pos;
+            rd = if insert { pos + size - 1 } else { size - 1 };
+        }
+    };
+
     // Mask
     rs = mask_u8(rs, 5)?;
     rt &= mask_u8(rt, 5)?;
@@ -299,37 +1313,158 @@ fn assemble_r(r_struct: R, r_args: Vec<&str>) -> Result<u32, &'static str> {
     funct &= mask_u8(funct, 6)?;
 
     // opcode : 31 - 26
-    let mut result = 0x000000;
+    let mut result = u32::from(r_struct.opcode);
 
     // rs :     25 - 21
-    println!("rs: {}", rs);
-    result = (result << 6) | u32::from(rs);
+    result = (result << 5) | u32::from(rs);
 
     // rt :     20 - 16
-    println!("rt: {}", rt);
     result = (result << 5) | u32::from(rt);
 
     // rd :     15 - 11
-    println!("rd: {}", rd);
     result = (result << 5) | u32::from(rd);
 
     // shamt : 10 - 6
-    println!("shamt: {}", shamt);
     result = (result << 5) | u32::from(shamt);
 
     // funct : 5 - 0
     result = (result << 6) | u32::from(funct);
 
-    println!(
-        "0x{:0shortwidth$x} {:0width$b}",
-        result,
-        result,
-        shortwidth = 8,
-        width = 32
-    );
     Ok(result)
 }
 
+/// Assembles a floating-point (COP1) instruction
+fn assemble_f(f_struct: F, f_args: Vec<&str>) -> Result<u32, &'static str> {
+    let mut fmt = f_struct.fmt;
+
+    let (ft, fs, fd) = match f_struct.form {
+        FForm::FdFrFsFt => {
+            enforce_length(&f_args, 4)?;
+            fmt = assemble_fp_reg(f_args[1])?;
+            (
+                assemble_fp_reg(f_args[3])?,
+                assemble_fp_reg(f_args[2])?,
+                assemble_fp_reg(f_args[0])?,
+            )
+        }
+        FForm::FsIndexBase { store } => {
+            enforce_length(&f_args, 3)?;
+            // The base register goes in the fmt field, and the index in ft
+            fmt = assemble_reg(f_args[2])?;
+            let index = assemble_reg(f_args[1])?;
+            let register = assemble_fp_reg(f_args[0])?;
+            if store {
+                (index, register, 0)
+            } else {
+                (index, 0, register)
+            }
+        }
+        FForm::RtFs => {
+            enforce_length(&f_args, 2)?;
+            (assemble_reg(f_args[0])?, assemble_fp_reg(f_args[1])?, 0)
+        }
+        FForm::RtFcr => {
+            enforce_length(&f_args, 2)?;
+            (assemble_reg(f_args[0])?, assemble_fcr(f_args[1])?, 0)
+        }
+        FForm::FdFs => {
+            enforce_length(&f_args, 2)?;
+            (0, assemble_fp_reg(f_args[1])?, assemble_fp_reg(f_args[0])?)
+        }
+        FForm::CcFsFt => {
+            // The condition code goes in the upper bits of the fd field
+            let (cc, registers) = match f_args.len() {
+                2 => (0, &f_args[..]),
+                3 => (assemble_cc(f_args.first())?, &f_args[1..]),
+                _ => return Err("Failed length enforcement"),
+            };
+            (
+                assemble_fp_reg(registers[1])?,
+                assemble_fp_reg(registers[0])?,
+                cc << 2,
+            )
+        }
+        FForm::FdFsRt => {
+            enforce_length(&f_args, 3)?;
+            (
+                assemble_reg(f_args[2])?,
+                assemble_fp_reg(f_args[1])?,
+                assemble_fp_reg(f_args[0])?,
+            )
+        }
+        FForm::FdFsCc { tf } => {
+            if !(2..=3).contains(&f_args.len()) {
+                return Err("Failed length enforcement");
+            }
+            (
+                assemble_cc(f_args.get(2))? << 2 | tf,
+                assemble_fp_reg(f_args[1])?,
+                assemble_fp_reg(f_args[0])?,
+            )
+        }
+    };
+
+    // opcode : 31 - 26, fmt : 25 - 21, ft : 20 - 16, fs : 15 - 11, fd : 10 - 6, funct : 5 - 0
+    let result = u32::from(f_struct.opcode) << 26
+        | u32::from(fmt) << 21
+        | u32::from(ft) << 16
+        | u32::from(fs) << 11
+        | u32::from(fd) << 6
+        | u32::from(f_struct.funct);
+
+    Ok(result)
+}
+
+/// Encodes the word offset from a branch's delay slot to its target
+fn branch_offset(target: u32, instr_address: u32) -> Result<u16, &'static str> {
+    let delay_slot = instr_address + MIPS_INSTR_BYTE_WIDTH;
+    let distance = target as i64 - delay_slot as i64;
+
+    if distance % 4 != 0 {
+        return Err("Branch target is not word-aligned");
+    }
+    match i16::try_from(distance / 4) {
+        Ok(words) => Ok(words as u16),
+        Err(_) => Err("Branch target is out of range, branches reach 128KiB either way"),
+    }
+}
+
+/// Whether an instruction is a branch to a symbol it can't reach
+fn branch_out_of_range(
+    mnemonic: &str,
+    args: &[&str],
+    labels: &HashMap<&str, u32>,
+    instr_address: u32,
+) -> bool {
+    if !CONDITIONAL_BRANCHES.contains(&mnemonic) {
+        return false;
+    }
+
+    match args.last() {
+        Some(target) if parse_int(target).is_none() => match resolve_symbol_expr(target, labels) {
+            Ok(address) => branch_offset(address, instr_address).is_err(),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Assembles a branch's target operand into its word offset
+fn assemble_branch_target(
+    target: &str,
+    labels: &HashMap<&str, u32>,
+    instr_address: u32,
+) -> Result<u16, &'static str> {
+    match parse_int(target) {
+        // A literal is already an offset in words, as relaxed branches use
+        Some(offset) => match i16::try_from(offset) {
+            Ok(words) => Ok(words as u16),
+            Err(_) => Err("Branch offset is out of range"),
+        },
+        None => branch_offset(resolve_symbol_expr(target, labels)?, instr_address),
+    }
+}
+
 /// Assembles an I-type instruction
 fn assemble_i(
     i_struct: I,
@@ -340,55 +1475,62 @@ fn assemble_i(
     let mut rs: u8;
     let mut rt: u8;
     let imm: u16;
+    let zero_extended = i_struct.zero_extends();
 
     match i_struct.form {
         IForm::RtImm => {
             enforce_length(&i_args, 2)?;
             rs = 0;
             rt = assemble_reg(i_args[0])?;
-            imm = match i_args[1].parse::<u16>() {
-                Ok(v) => v,
-                Err(_) => return Err("Failed to parse imm"),
-            }
+            imm = assemble_imm(i_args[1], labels, zero_extended)?;
         }
         IForm::RtImmRs => {
             enforce_length(&i_args, 3)?;
             rt = assemble_reg(i_args[0])?;
-            imm = match i_args[1].parse::<u16>() {
-                Ok(v) => v,
-                Err(_) => return Err("Failed to parse imm"),
-            };
+            imm = assemble_imm(i_args[1], labels, zero_extended)?;
             rs = assemble_reg(i_args[2])?;
         }
         IForm::RsRtLabel => {
             enforce_length(&i_args, 3)?;
             rs = assemble_reg(i_args[0])?;
             rt = assemble_reg(i_args[1])?;
-            match labels.get(i_args[2]) {
-                // Subtract byte width due to branch delay
-                Some(v) => imm = ((*v) - instr_address - MIPS_INSTR_BYTE_WIDTH) as u16,
-                None => return Err("Undeclared label"),
-            }
+            imm = assemble_branch_target(i_args[2], labels, instr_address)?;
+        }
+        IForm::RsLabel { rt: fixed_rt } => {
+            enforce_length(&i_args, 2)?;
+            rs = assemble_reg(i_args[0])?;
+            rt = fixed_rt;
+            imm = assemble_branch_target(i_args[1], labels, instr_address)?;
+        }
+        IForm::CcLabel { tf } => {
+            let (cc, target) = match i_args.len() {
+                1 => (0, i_args[0]),
+                2 => (assemble_cc(i_args.first())?, i_args[1]),
+                _ => return Err("Failed length enforcement"),
+            };
+            rs = 0x08;
+            rt = cc << 2 | tf;
+            imm = assemble_branch_target(target, labels, instr_address)?;
+        }
+        IForm::RsImm { rt: fixed_rt } => {
+            enforce_length(&i_args, 2)?;
+            rs = assemble_reg(i_args[0])?;
+            rt = fixed_rt;
+            imm = assemble_imm(i_args[1], labels, zero_extended)?;
         }
         IForm::RtRsImm => {
             enforce_length(&i_args, 3)?;
             rt = assemble_reg(i_args[0])?;
             rs = assemble_reg(i_args[1])?;
-            imm = match i_args[2].parse::<u16>() {
-                Ok(v) => v,
-                Err(_) => return Err("Failed to parse imm"),
-            };
+            imm = assemble_imm(i_args[2], labels, zero_extended)?;
         }
     };
 
     let mut opcode = i_struct.opcode;
 
     // Mask
-    println!("Masking rs");
     rs = mask_u8(rs, 5)?;
-    println!("Masking rt");
     rt = mask_u8(rt, 5)?;
-    println!("Masking opcode");
     opcode = mask_u8(opcode, 6)?;
     // No need to mask imm, it's already a u16
 
@@ -396,24 +1538,14 @@ fn assemble_i(
     let mut result: u32 = opcode.into();
 
     // rs :     25 - 21
-    println!("rs: {}", rs);
     result = (result << 5) | u32::from(rs);
 
     // rt :     20 - 16
-    println!("rt: {}", rt);
     result = (result << 5) | u32::from(rt);
 
     // imm :    15 - 0
-    println!("imm: {}", imm);
     result = (result << 16) | u32::from(imm);
 
-    println!(
-        "0x{:0shortwidth$x} {:0width$b}",
-        result,
-        result,
-        shortwidth = 8,
-        width = 32
-    );
     Ok(result)
 }
 
@@ -422,25 +1554,30 @@ fn assemble_j(
     j_struct: J,
     j_args: Vec<&str>,
     labels: &HashMap<&str, u32>,
+    instr_address: u32,
 ) -> Result<u32, &'static str> {
     enforce_length(&j_args, 1)?;
 
-    let jump_address: u32 = labels[j_args[0]];
-    println!("Masking jump address");
-    println!("Jump address original: {}", jump_address);
-    let mut masked_jump_address = mask_u32(jump_address, 28)?;
-    println!("Jump address masked: {}", masked_jump_address);
-    if jump_address != masked_jump_address {
-        return Err("Tried to assemble illegal jump address");
+    let jump_address: u32 = resolve_symbol_expr(j_args[0], labels)?;
+    if !jump_address.is_multiple_of(4) {
+        return Err("Jump target is not word-aligned");
     }
 
+    // Jumps keep the upper four bits of the delay slot's address, so they can't
+    // leave the 256MB region they start in
+    let region = (instr_address + MIPS_INSTR_BYTE_WIDTH) & 0xF0000000;
+    if jump_address & 0xF0000000 != region {
+        return Err("Jump target is outside of the current 256MB region, use jr instead");
+    }
+
+    let mut masked_jump_address = jump_address & 0x0FFFFFFF;
+
     // Byte-align jump address
     masked_jump_address >>= 2;
 
     let mut opcode = j_struct.opcode;
 
     // Mask
-    println!("Masking opcode");
     opcode = mask_u8(opcode, 6)?;
     // No need to mask imm, it's already a u16
 
@@ -448,52 +1585,400 @@ fn assemble_j(
     let mut result: u32 = opcode.into();
 
     // imm :    25 - 0
-    println!("imm: {}", masked_jump_address);
     result = (result << 26) | masked_jump_address;
 
-    println!(
-        "0x{:0shortwidth$x} {:0width$b}",
-        result,
-        result,
-        shortwidth = 8,
-        width = 32
-    );
     Ok(result)
 }
 
+/// Every real instruction the assembler can encode
+pub const INSTRUCTION_SET: &[&str] = &[
+    "add", "sub", "sll", "srl", "sra", "rotr", "sllv", "srlv", "srav", "rotrv", "movf", "movt",
+    "movz", "movn", "madd", "maddu", "msub", "msubu", "clz", "clo", "syscall", "mfc0", "mtc0",
+    "eret", "ext", "ins", "wsbh", "seb", "seh", "jr", "teq", "tne", "tge", "tgeu", "tlt", "tltu",
+    "tgei", "tgeiu", "tlti", "tltiu", "teqi", "tnei", "mfhi", "mflo", "mthi", "mtlo", "mult",
+    "multu", "div", "divu", "addu", "subu", "or", "xor", "nor", "slt", "sltu", "addi", "addiu",
+    "sltiu", "ori", "lb", "lbu", "lh", "lhu", "lw", "lwl", "lwr", "ll", "lui", "sb", "sh", "sw",
+    "swl", "swr", "sc", "beq", "bne", "blez", "bgtz", "bltz", "bgez", "bltzal", "bgezal", "bc1f",
+    "bc1t", "beql", "bnel", "blezl", "bgtzl", "bltzl", "bgezl", "j",
+    "jal", "lwxc1", "ldxc1", "luxc1", "swxc1", "sdxc1", "suxc1", "mfc1", "mtc1", "cfc1", "ctc1",
+    "sqrt.s", "sqrt.d", "recip.s", "recip.d", "rsqrt.s", "rsqrt.d", "c.f.s", "c.f.d", "c.un.s",
+    "c.un.d", "c.eq.s", "c.eq.d", "c.ueq.s", "c.ueq.d", "c.olt.s", "c.olt.d", "c.ult.s", "c.ult.d",
+    "c.ole.s", "c.ole.d", "c.ule.s", "c.ule.d", "c.sf.s", "c.sf.d", "c.ngle.s", "c.ngle.d",
+    "c.seq.s", "c.seq.d", "c.ngl.s", "c.ngl.d", "c.lt.s", "c.lt.d", "c.nge.s", "c.nge.d", "c.le.s",
+    "c.le.d", "c.ngt.s", "c.ngt.d", "madd.s", "madd.d", "msub.s", "msub.d", "nmadd.s", "nmadd.d",
+    "nmsub.s", "nmsub.d", "movf.s", "movf.d", "movt.s", "movt.d", "movz.s", "movz.d", "movn.s",
+    "movn.d",
+];
+
+/// Levenshtein distance between two mnemonics
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Builds the error for an unknown mnemonic, suggesting the closest known ones
+fn unknown_mnemonic(mnemonic: &str) -> String {
+    // Allow roughly one typo per three characters, but always at least one
+    let max_distance = (mnemonic.len() / 3).max(1);
+
+    let mut candidates: Vec<(usize, &str)> = INSTRUCTION_SET
+        .iter()
+        .chain(PSEUDO_INSTRUCTION_SET)
+        .map(|candidate| (edit_distance(mnemonic, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+
+    match candidates.first() {
+        Some((closest, _)) => {
+            let suggestions: Vec<&str> = candidates
+                .iter()
+                .filter(|(distance, _)| distance == closest)
+                .map(|(_, candidate)| *candidate)
+                .take(3)
+                .collect();
+            format!("Unknown instruction {}, did you mean {}?", mnemonic, suggestions.join(" or "))
+        }
+        None => format!("Unknown instruction {}", mnemonic),
+    }
+}
+
+/// Assembles a single real instruction of any type
+fn assemble_instruction(
+    mnemonic: &str,
+    args: Vec<&str>,
+    labels: &HashMap<&str, u32>,
+    current_addr: u32,
+) -> Result<u32, String> {
+    let assembled = if let Ok(instr_info) = r_operation(mnemonic) {
+        assemble_r(instr_info, args)
+    } else if let Ok(instr_info) = i_operation(mnemonic) {
+        assemble_i(instr_info, args, labels, current_addr)
+    } else if let Ok(instr_info) = j_operation(mnemonic) {
+        assemble_j(instr_info, args, labels, current_addr)
+    } else if let Ok(instr_info) = f_operation(mnemonic) {
+        assemble_f(instr_info, args)
+    } else {
+        return Err(unknown_mnemonic(mnemonic));
+    };
+
+    assembled.map_err(|e| e.to_string())
+}
+
 use crate::parser::*;
+use pest::error::LineColLocation;
 use pest::Parser;
 
-// General assembler entrypoint
-pub fn assemble(program_arguments: &Args) -> Result<(), String> {
-    // IO Setup
-    let input_fn = &program_arguments.input_as;
-    let output_fn = &program_arguments.output_as;
+/// Builds the error for a pseudo-instruction used under --no-pseudo, showing
+/// what should have been written instead
+fn forbidden_pseudo(mnemonic: &str, args: &[&str], expansion: &[Expansion]) -> String {
+    let expanded: Vec<String> = expansion
+        .iter()
+        .map(|instruction| format!("{} {}", instruction.mnemonic, instruction.args.join(", ")))
+        .collect();
+
+    format!(
+        "{} {} is a pseudo-instruction, which --no-pseudo forbids. Write it as: {}",
+        mnemonic,
+        args.join(", "),
+        expanded.join("; ")
+    )
+}
 
-    let output_file: File = match File::create(output_fn) {
-        Ok(v) => v,
-        Err(_) => return Err("Failed to open output file".to_string()),
+/// Finds every reference to a symbol nothing defines, so they can all be
+/// reported at once rather than one per assembly attempt. The error is placed
+/// at the first of them.
+fn undefined_symbols(
+    program: &[MipsCST],
+    labels: &HashMap<&str, u32>,
+    source_map: &SourceMap,
+) -> Result<(), Diagnostic> {
+    let mut undefined: Vec<(&str, Vec<String>)> = vec![];
+    let mut first: Option<&SourceLocation> = None;
+
+    for node in program {
+        let args = match node {
+            MipsCST::Instruction(_, args) => args,
+            MipsCST::Directive(name, args) if data_width(name).is_some() => args,
+            _ => continue,
+        };
+
+        for arg in args {
+            let symbol = match referenced_symbol(arg) {
+                Some(symbol) if !labels.contains_key(symbol) => symbol,
+                _ => continue,
+            };
+            first.get_or_insert(source_map.locate(arg));
+            let location = source_map.locate(arg).to_string();
+
+            match undefined.iter_mut().find(|(name, _)| *name == symbol) {
+                Some((_, locations)) if !locations.contains(&location) => locations.push(location),
+                Some(_) => (),
+                None => undefined.push((symbol, vec![location])),
+            }
+        }
+    }
+
+    if undefined.is_empty() {
+        return Ok(());
+    }
+
+    let report: Vec<String> = undefined
+        .iter()
+        .map(|(symbol, locations)| format!("{} (referenced at {})", symbol, locations.join(", ")))
+        .collect();
+    Err(Diagnostic {
+        location: first.cloned(),
+        message: format!("Undefined symbols: {}", report.join("; ")),
+    })
+}
+
+/// Expands an instruction as written into the real instructions it assembles
+/// to, relaxing any branches an earlier attempt found out of range. Each
+/// instruction is tagged with its position in the unrelaxed expansion, and
+/// the whole is flagged if it differs from what was written.
+fn expand_instruction<'a>(
+    mnemonic: &'a str,
+    args: &[&'a str],
+    node_index: usize,
+    relaxed: &HashSet<(usize, usize)>,
+    reorder: bool,
+) -> Result<(Vec<(usize, Expansion<'a>)>, bool), String> {
+    let (expansion, mut rewritten) = match expand_pseudo(mnemonic, args) {
+        Some(expansion) => (expansion?, true),
+        None => (
+            vec![Expansion {
+                mnemonic,
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+            }],
+            false,
+        ),
     };
 
-    // Read input
-    let file_contents: String = match fs::read_to_string(input_fn) {
-        Ok(v) => v,
-        Err(_) => return Err("Failed to read input file contents".to_string()),
+    let mut expanded = vec![];
+    let positions = expansion.len();
+    for (position, instruction) in expansion.into_iter().enumerate() {
+        if relaxed.contains(&(node_index, position)) {
+            rewritten = true;
+            expanded.extend(relax_branch(instruction)?.into_iter().map(|i| (position, i)));
+        } else {
+            expanded.push((position, instruction));
+        }
+    }
+
+    // Under .set reorder the assembler owns delay slots, and fills them with a nop
+    let needs_delay_slot = matches!(expanded.last(), Some((_, last)) if DELAY_SLOT_INSTRUCTIONS.contains(&last.mnemonic));
+    if reorder && needs_delay_slot {
+        rewritten = true;
+        expanded.push((positions, nop()));
+    }
+
+    Ok((expanded, rewritten))
+}
+
+/// Instructions followed by a delay slot
+const DELAY_SLOT_INSTRUCTIONS: &[&str] = &[
+    "beq", "bne", "blez", "bgtz", "bltz", "bgez", "bltzal", "bgezal", "bc1f", "bc1t", "beql",
+    "bnel", "blezl", "bgtzl", "bltzl", "bgezl", "j", "jal", "jr",
+];
+
+fn nop<'a>() -> Expansion<'a> {
+    Expansion {
+        mnemonic: "sll",
+        args: vec!["$zero".to_string(), "$zero".to_string(), "0".to_string()],
+    }
+}
+
+/// Applies a `.set` directive. Only `reorder` and `noreorder` change anything;
+/// other options are accepted so code written for other assemblers still
+/// assembles, but warned about, and the warning is given back.
+fn set_option(reorder: &mut bool, args: &[&str]) -> Result<Option<String>, String> {
+    match args {
+        ["reorder"] => *reorder = true,
+        ["noreorder"] => *reorder = false,
+        [option] => return Ok(Some(format!(".set {} has no effect in NAME", option))),
+        _ => return Err(".set expects a single option".to_string()),
+    }
+    Ok(None)
+}
+
+/// Where a node of the program was written
+fn node_location<'a>(source_map: &'a SourceMap, node: &MipsCST) -> &'a SourceLocation {
+    match node {
+        MipsCST::Label(fragment) | MipsCST::Instruction(fragment, _) | MipsCST::Directive(fragment, _) => {
+            source_map.locate(fragment)
+        }
+        MipsCST::Sequence(_) => unreachable!(),
+    }
+}
+
+/// How a single attempt at assembling a program went
+enum Attempt {
+    Assembled(Assembly),
+    // Branches that couldn't reach their targets, which relaxation will rewrite
+    Relax(Vec<(usize, usize)>),
+}
+
+/// Everything that shapes an assembly besides the source text itself
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Where the source came from, used to resolve relative `.include`s.
+    /// Without one they are resolved against the working directory.
+    pub source_path: Option<PathBuf>,
+    /// Extra directories searched by `.include`, in order
+    pub include_paths: Vec<String>,
+    /// Symbols substituted throughout the source, as given by -D
+    pub definitions: Vec<(String, String)>,
+    /// Accept mnemonics, directives, and registers in any case
+    pub ignore_case: bool,
+    /// Reject instructions outside of this profile, if given
+    pub isa: Option<Isa>,
+    /// Reject pseudo-instructions, requiring every instruction to be written out
+    pub no_pseudo: bool,
+    /// The symbol execution begins at, defaulting to a global `main`
+    pub entry: Option<String>,
+    /// Rewrite branches that can't reach their targets instead of failing
+    pub relax: bool,
+    /// Where the text, data, heap, and stack segments go
+    pub layout: Layout,
+}
+
+impl From<&Args> for Options {
+    fn from(args: &Args) -> Self {
+        Options {
+            source_path: Some(PathBuf::from(&args.input_as)),
+            include_paths: args.include_paths.clone(),
+            definitions: args.definitions.clone(),
+            ignore_case: args.ignore_case,
+            isa: args.isa,
+            no_pseudo: args.no_pseudo,
+            entry: args.entry.clone(),
+            relax: args.relax,
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// A problem found while assembling: an error that stopped it, or a warning
+/// that didn't
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// Where in the source the problem is, when it's about something written there
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn at(location: &SourceLocation, message: impl Into<String>) -> Self {
+        Diagnostic {
+            location: Some(location.clone()),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Diagnostic {
+            location: None,
+            message,
+        }
+    }
+}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Self {
+        Diagnostic::from(message.to_string())
+    }
+}
+
+/// The result of assembling a program, ready to be written out or loaded directly
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    /// Image of the text segment, based at the layout's text base
+    pub text: Vec<u8>,
+    /// Image of the data segment, based at the layout's data base
+    pub data: Vec<u8>,
+    /// Image of the kernel text segment, based at [KTEXT_ADDRESS_BASE](crate::section::KTEXT_ADDRESS_BASE)
+    pub ktext: Vec<u8>,
+    /// Image of the kernel data segment, based at [KDATA_ADDRESS_BASE](crate::section::KDATA_ADDRESS_BASE)
+    pub kdata: Vec<u8>,
+    pub lineinfo: Vec<LineInfo>,
+    pub symbols: Vec<SymbolInfo>,
+    /// The address execution begins at
+    pub entry: u32,
+    /// Problems that didn't stop assembly, such as data that had to be realigned
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Assembles a program held in memory.
+///
+/// Nothing is written to disk; the only files read are those named by `.include`.
+pub fn assemble_source(source: &str, options: &Options) -> Result<Assembly, Diagnostic> {
+    options.layout.check()?;
+
+    // Relaxing a branch moves everything after it, which can push other
+    // branches out of range, so keep going until nothing else needs it
+    let mut relaxed: HashSet<(usize, usize)> = HashSet::new();
+    loop {
+        match assemble_attempt(source, options, &relaxed)? {
+            Attempt::Assembled(assembly) => return Ok(assembly),
+            Attempt::Relax(branches) => relaxed.extend(branches),
+        }
+    }
+}
+
+/// Assembles a program once, with the given branches relaxed
+fn assemble_attempt(
+    source: &str,
+    options: &Options,
+    relaxed: &HashSet<(usize, usize)>,
+) -> Result<Attempt, Diagnostic> {
+    let source_path = options.source_path.as_deref().unwrap_or(Path::new("<input>"));
+    let (file_contents, origins) = expand_includes(source_path, source, &options.include_paths, 0)?;
+    let file_contents = substitute_definitions(&file_contents, &options.definitions);
+    let file_contents = if options.ignore_case {
+        fold_case(&file_contents)
+    } else {
+        file_contents
     };
 
-    // Parse into CST
-    let cst = parse_rule(
-        MipsParser::parse(Rule::vernacular, file_contents.as_str())
-            .expect("Failed to parse")
-            .next()
-            .unwrap(),
-    );
-    print_cst(&cst);
+    // Parse into CST. Pest counts lines in the preprocessed source, which
+    // the origins map back to where they were written.
+    let cst = match MipsParser::parse(Rule::vernacular, file_contents.as_str()) {
+        Ok(mut pairs) => parse_rule(pairs.next().unwrap()),
+        Err(e) => {
+            let (LineColLocation::Pos((line, _)) | LineColLocation::Span((line, _), _)) = e.line_col;
+            return Err(Diagnostic {
+                location: origins.get(line - 1).cloned(),
+                message: format!("Failed to parse: {}", e.variant.message()),
+            });
+        }
+    };
+    let source_map = SourceMap::new(&file_contents, origins);
 
     // Set up line info
-    let lineinfo_fn = format!("{}.li", &program_arguments.output_as);
     let mut lineinfo: Vec<LineInfo> = vec![];
-    let mut line_number: u32 = 1;
 
     let vernac_sequence: Vec<MipsCST> = if let MipsCST::Sequence(v) = cst {
         v
@@ -501,96 +1986,350 @@ pub fn assemble(program_arguments: &Args) -> Result<(), String> {
         vec![cst]
     };
 
-    // Assign addresses to labels
-    let mut current_addr: u32 = TEXT_ADDRESS_BASE;
-    let mut labels: HashMap<&str, u32> = HashMap::new();
-    for sub_cst in &vernac_sequence {
+    // Measure every section and note where in it each label falls
+    let mut sections = SectionTable::new(options.layout);
+    let mut label_offsets: Vec<(&str, usize, u32)> = vec![];
+    let mut visibilities: HashMap<&str, SymbolVisibility> = HashMap::new();
+    let mut commons: Vec<CommonSymbol> = vec![];
+    let mut warnings: Vec<Diagnostic> = lint(&vernac_sequence)
+        .into_iter()
+        .map(|(fragment, warning)| Diagnostic::at(source_map.locate(fragment), warning))
+        .collect();
+    // NAME leaves delay slots to the programmer unless told otherwise
+    let mut reorder = false;
+    for (node_index, sub_cst) in vernac_sequence.iter().enumerate() {
+        let here = node_location(&source_map, sub_cst);
+        let at = |message: String| Diagnostic::at(here, message);
         match sub_cst {
             MipsCST::Label(label_str) => {
-                println!("Inserting label {} at {:x}", label_str, current_addr);
-                labels.insert(label_str, current_addr);
-                continue;
+                if let Some((previous, _, _)) = label_offsets.iter().find(|(l, _, _)| l == label_str) {
+                    return Err(at(format!(
+                        "Label {} defined more than once, first at {}",
+                        label_str,
+                        source_map.locate(previous)
+                    )));
+                }
+
+                let section_index = sections.current_index();
+                label_offsets.push((label_str, section_index, sections.current().size));
+            }
+            MipsCST::Directive(name, args) => match *name {
+                ".section" | ".text" | ".data" | ".ktext" | ".kdata" => sections.switch_to(name, args).map_err(at)?,
+                ".word" | ".half" | ".byte" => {
+                    let section_index = sections.current_index();
+                    let section = sections.current();
+                    let width = data_width(name).unwrap();
+
+                    // Misaligned data would fault when loaded, so pad it out to its
+                    // natural alignment, carrying along any labels that point at it
+                    let aligned = align_up(section.size, width);
+                    if aligned != section.size {
+                        warnings.push(at(format!(
+                            "{} at {}+{:#x} is not {}-byte aligned, padding to {}+{:#x}",
+                            name, section.name, section.size, width, section.name, aligned
+                        )));
+                        for (_, label_section, offset) in label_offsets.iter_mut() {
+                            if *label_section == section_index && *offset == section.size {
+                                *offset = aligned;
+                            }
+                        }
+                    }
+
+                    section.size = aligned + width * args.len() as u32;
+                }
+                ".org" => {
+                    let section = sections.current();
+                    section.size = org_offset(args, section.name, section.size).map_err(at)?;
+                }
+                ".comm" => declare_common(&mut commons, args).map_err(at)?,
+                ".set" => {
+                    if let Some(warning) = set_option(&mut reorder, args).map_err(at)? {
+                        warnings.push(at(warning));
+                    }
+                }
+                _ => declare_symbols(&mut visibilities, name, args).map_err(at)?,
+            },
+            MipsCST::Instruction(mnemonic, args) => {
+                if let Some(expansion) = expand_pseudo(mnemonic, args).filter(|_| options.no_pseudo) {
+                    return Err(at(forbidden_pseudo(mnemonic, args, &expansion.map_err(at)?)));
+                }
+                let instruction_count =
+                    expand_instruction(mnemonic, args, node_index, relaxed, reorder).map_err(at)?.0.len() as u32;
+                sections.current().size += MIPS_INSTR_BYTE_WIDTH * instruction_count;
             }
-            MipsCST::Instruction(_, _) => (),
             MipsCST::Sequence(_) => unreachable!(),
         };
+    }
+
+    // Common symbols nothing else defines are allocated zeroed in .bss. NAME's
+    // images are flat, so those zeros are written out like any other data.
+    commons.retain(|common| !label_offsets.iter().any(|(label, _, _)| *label == common.name));
+    let bss_index = if commons.is_empty() {
+        None
+    } else {
+        sections.switch_to(".section", &[".bss"])?;
+        let bss_index = sections.current_index();
+        let bss = sections.current();
+
+        for common in &commons {
+            let offset = align_up(bss.size, common.alignment);
+            label_offsets.push((common.name, bss_index, offset));
+            bss.size = offset + common.size;
+            bss.alignment = bss.alignment.max(common.alignment);
+            // Common symbols are always visible to other objects
+            visibilities.entry(common.name).or_insert(SymbolVisibility::Global);
+        }
+
+        Some(bss_index)
+    };
 
-        current_addr += MIPS_INSTR_BYTE_WIDTH
+    // Place sections, after which every label has a final address
+    sections.layout()?;
+    let mut labels: HashMap<&str, u32> = HashMap::new();
+    for (label_str, section_index, offset) in label_offsets {
+        let address = sections.sections[section_index].base + offset;
+        labels.insert(label_str, address);
     }
+    let symbols = export_symbols(&labels, &visibilities);
 
-    current_addr = TEXT_ADDRESS_BASE;
+    let entry = match &options.entry {
+        Some(symbol) => match labels.get(symbol.as_str()) {
+            Some(address) => *address,
+            None => return Err(format!("Entry symbol {} is not defined anywhere", symbol).into()),
+        },
+        None => match (labels.get("main"), visibilities.get("main")) {
+            (Some(address), Some(SymbolVisibility::Global)) => *address,
+            _ => options.layout.text_base,
+        },
+    };
+
+    // A weak reference nothing defines resolves to 0, so optional hooks can be
+    // tested for with a branch against $zero
+    for (symbol, visibility) in &visibilities {
+        if *visibility == SymbolVisibility::Weak && !labels.contains_key(symbol) {
+            warnings.push(Diagnostic::at(
+                source_map.locate(symbol),
+                format!("Undefined weak symbol {} resolves to 0", symbol),
+            ));
+            labels.insert(symbol, 0);
+        }
+    }
+
+    undefined_symbols(&vernac_sequence, &labels, &source_map)?;
+
+    sections.rewind();
 
     // Assemble instructions
-    for sub_cst in vernac_sequence {
+    let mut out_of_range: Vec<(usize, usize)> = vec![];
+    reorder = false;
+    for (node_index, sub_cst) in vernac_sequence.into_iter().enumerate() {
+        let here = node_location(&source_map, &sub_cst);
+        let at = |message: String| Diagnostic::at(here, message);
         match sub_cst {
             MipsCST::Instruction(mnemonic, args) => {
-                // Update line info
-                lineinfo.push(LineInfo {
-                    instr_addr: current_addr,
-                    line_number: line_number,
-                    line_contents: instr_to_str(mnemonic, &args),
-                    psuedo_op: "".to_string(),
-                });
-
-                if let Ok(instr_info) = r_operation(mnemonic) {
-                    println!("-----------------------------------");
-                    println!(
-                        "[R] {} - shamt [{:x}] - funct [{:x}]",
-                        mnemonic, instr_info.shamt, instr_info.funct
-                    );
-                    match assemble_r(instr_info, args) {
-                        Ok(assembled_r) => {
-                            if write_u32(&output_file, assembled_r).is_err() {
-                                return Err("Failed to write to output binary".to_string());
-                            }
-                        }
-                        Err(e) => return Err(e.to_string()),
-                    }
-                } else if let Ok(instr_info) = i_operation(mnemonic) {
-                    println!("-----------------------------------");
-                    println!("[I] {} - opcode [{:x}]", mnemonic, instr_info.opcode);
-
-                    match assemble_i(instr_info, args, &labels, current_addr) {
-                        Ok(assembled_i) => {
-                            if write_u32(&output_file, assembled_i).is_err() {
-                                return Err("Failed to write to output binary".to_string());
-                            }
-                        }
-                        Err(e) => return Err(e.to_string()),
+                // Pseudo-instructions and relaxed branches are recorded in the
+                // line info of every instruction they expand into
+                let (expansion, rewritten) =
+                    expand_instruction(mnemonic, &args, node_index, relaxed, reorder).map_err(at)?;
+                let psuedo_op = if rewritten {
+                    instr_to_str(mnemonic, &args)
+                } else {
+                    "".to_string()
+                };
+
+                for (position, instruction) in expansion {
+                    let current_addr = sections.current().current_address();
+                    let instr_args: Vec<&str> = instruction.args.iter().map(|arg| arg.as_str()).collect();
+
+                    if options.relax && branch_out_of_range(instruction.mnemonic, &instr_args, &labels, current_addr) {
+                        // This attempt will be thrown away, just keep later addresses right
+                        out_of_range.push((node_index, position));
+                        write_u32(&mut sections.current().contents, 0);
+                        continue;
                     }
-                } else if let Ok(instr_info) = j_operation(mnemonic) {
-                    println!("-----------------------------------");
-                    println!("[J] {} - opcode [{:x}]", mnemonic, instr_info.opcode);
-
-                    match assemble_j(instr_info, args, &labels) {
-                        Ok(assembled_j) => {
-                            if write_u32(&output_file, assembled_j).is_err() {
-                                return Err("Failed to write to output binary".to_string());
-                            }
-                        }
-                        Err(e) => return Err(e.to_string()),
+
+                    // Update line info
+                    lineinfo.push(LineInfo {
+                        instr_addr: current_addr,
+                        source_file: source_map.locate(mnemonic).file.clone(),
+                        line_number: source_map.locate(mnemonic).line,
+                        line_contents: instr_to_str(instruction.mnemonic, &instr_args),
+                        psuedo_op: psuedo_op.clone(),
+                    });
+
+                    let assembled =
+                        assemble_instruction(instruction.mnemonic, instr_args, &labels, current_addr).map_err(at)?;
+                    if let Some(isa) = options.isa.filter(|isa| !isa.accepts(assembled)) {
+                        return Err(at(format!(
+                            "{} is not part of the {} instruction set",
+                            instruction.mnemonic,
+                            isa.display_name()
+                        )));
                     }
-                } else {
-                    return Err("Failed to match instruction".to_string());
+                    write_u32(&mut sections.current().contents, assembled);
                 }
             }
-            // For the record, label is not yet used. I've prefixed it with an underscore to denote this for now,
-            // but that can and should change.
-            MipsCST::Label(_label) => {
-                continue;
-            }
+            MipsCST::Directive(name, args) => match name {
+                ".section" | ".text" | ".data" | ".ktext" | ".kdata" => sections.switch_to(name, &args).map_err(at)?,
+                ".word" | ".half" | ".byte" => {
+                    // Alignment was already warned about while measuring, just pad
+                    let contents = &mut sections.current().contents;
+                    let aligned = align_up(contents.len() as u32, data_width(name).unwrap());
+                    contents.resize(aligned as usize, 0);
+                    assemble_data(contents, name, &args, &labels).map_err(at)?
+                }
+                ".org" => {
+                    // Overlaps were already reported while measuring, just pad
+                    let section = sections.current();
+                    let offset = org_offset(&args, section.name, section.contents.len() as u32).map_err(at)?;
+                    section.contents.resize(offset as usize, 0);
+                }
+                // Warnings were already given while measuring
+                ".set" => {
+                    set_option(&mut reorder, &args).map_err(at)?;
+                }
+                // Symbol directives were fully handled while measuring
+                _ => (),
+            },
+            // Labels were fully handled while measuring
             _ => continue,
         };
+    }
 
-        current_addr += MIPS_INSTR_BYTE_WIDTH;
-        line_number += 1;
+    if !out_of_range.is_empty() {
+        return Ok(Attempt::Relax(out_of_range));
+    }
+
+    if let Some(bss_index) = bss_index {
+        let bss = &mut sections.sections[bss_index];
+        bss.contents.resize(bss.size as usize, 0);
+    }
+
+    Ok(Attempt::Assembled(Assembly {
+        text: sections.segment_image(Segment::Text),
+        data: sections.segment_image(Segment::Data),
+        ktext: sections.segment_image(Segment::KernelText),
+        kdata: sections.segment_image(Segment::KernelData),
+        lineinfo,
+        symbols,
+        entry,
+        warnings,
+    }))
+}
+
+// General assembler entrypoint, laying the program out as the config says
+pub fn assemble(program_arguments: &Args, layout: Layout) -> Result<(), String> {
+    // IO Setup
+    let input_fn = &program_arguments.input_as;
+    let output_fn = &program_arguments.output_as;
+
+    // Read input
+    let file_contents: String = match fs::read_to_string(input_fn) {
+        Ok(v) => v,
+        Err(_) => return Err("Failed to read input file contents".to_string()),
+    };
+
+    let options = Options { layout, ..Options::from(program_arguments) };
+    let assembly = assemble_source(&file_contents, &options).map_err(|e| e.to_string())?;
+    for warning in &assembly.warnings {
+        eprintln!("WARN : {}", warning);
+    }
+
+    // Write out each segment. The text segment is the output file proper.
+    let images = [
+        (Segment::Text, assembly.text),
+        (Segment::Data, assembly.data),
+        (Segment::KernelText, assembly.ktext),
+        (Segment::KernelData, assembly.kdata),
+    ];
+    for (segment, image) in images {
+        let segment_fn = format!("{}{}", output_fn, segment.output_suffix());
+
+        if image.is_empty() && segment != Segment::Text {
+            // Don't leave a stale image from a previous assembly lying around
+            let _ = fs::remove_file(&segment_fn);
+            continue;
+        }
+
+        if fs::write(&segment_fn, image).is_err() {
+            return Err("Failed to write to output binary".to_string());
+        }
     }
 
     if program_arguments.line_info {
-        if let Err(e) = lineinfo_export(lineinfo_fn, lineinfo) {
+        let lineinfo_fn = format!("{}.li", output_fn);
+        if let Err(e) = lineinfo_export(
+            lineinfo_fn,
+            assembly.lineinfo,
+            assembly.symbols,
+            Some(assembly.entry),
+            layout,
+            program_arguments.isa,
+        ) {
             return Err(e.to_string());
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(mnemonic: &str, args: &[&str]) -> Result<u32, String> {
+        assemble_instruction(mnemonic, args.to_vec(), &HashMap::new(), 0x00400000)
+    }
+
+    /// The words of a program's text segment
+    fn text(source: &str, options: &Options) -> Result<Vec<u32>, Diagnostic> {
+        let assembly = assemble_source(source, options)?;
+        Ok(assembly.text.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect())
+    }
+
+    #[test]
+    fn sign_extended_immediates_take_signed_halfwords() {
+        assert_eq!(assemble("addiu", &["$t0", "$t1", "-0x8000"]), Ok(0x25288000));
+        assert_eq!(assemble("addiu", &["$t0", "$t1", "0x7FFF"]), Ok(0x25287FFF));
+        assert!(assemble("addiu", &["$t0", "$t1", "0x8000"]).is_err());
+        assert!(assemble("sltiu", &["$t0", "$t1", "0xFFFF"]).is_err());
+        assert!(assemble("lw", &["$t0", "0xFFFC", "$t1"]).is_err());
+    }
+
+    #[test]
+    fn zero_extended_immediates_take_unsigned_halfwords() {
+        assert_eq!(assemble("ori", &["$t0", "$t1", "0xFFFF"]), Ok(0x3528FFFF));
+        assert_eq!(assemble("lui", &["$t0", "0xFFFF"]), Ok(0x3C08FFFF));
+        assert!(assemble("ori", &["$t0", "$t1", "-1"]).is_err());
+        assert!(assemble("lui", &["$t0", "-1"]).is_err());
+    }
+
+    #[test]
+    fn li_assembles_to_the_shortest_sequence() {
+        let source = ".globl main\nmain:\nli $t0, 0xffff\nli $t0, -1\nli $t0, 0x12340000\nli $t0, 0x12345678\n";
+        assert_eq!(
+            text(source, &Options::default()),
+            Ok(vec![0x3408FFFF, 0x2408FFFF, 0x3C081234, 0x3C011234, 0x34285678])
+        );
+    }
+
+    #[test]
+    fn branches_out_of_reach_are_relaxed_only_when_asked() {
+        // far is 0x8004 words past the branch's delay slot, one too many
+        let source = ".globl main\nmain:\nbeq $t0, $t1, far\n.org 0x20010\nfar:\nsll $zero, $zero, 0\n";
+        let layout = Layout { text_size: 0x40000, ..Layout::default() };
+        assert!(text(source, &Options { layout, ..Options::default() }).is_err());
+
+        let relaxed = text(source, &Options { layout, relax: true, ..Options::default() }).unwrap();
+        // bne $t0, $t1, 2; sll $zero, $zero, 0; j far
+        assert_eq!(relaxed[..3], [0x15090002, 0x00000000, 0x08108004]);
+    }
+
+    #[test]
+    fn relocations_are_taken_as_their_bits() {
+        let labels = HashMap::from([("far", 0x1001_8004)]);
+        let assemble = |mnemonic, args: &[&str]| assemble_instruction(mnemonic, args.to_vec(), &labels, 0x00400000);
+        assert_eq!(assemble("lui", &["$at", "%hi(far)"]), Ok(0x3C011002));
+        assert_eq!(assemble("addiu", &["$t0", "$at", "%lo(far)"]), Ok(0x24288004));
+    }
+}
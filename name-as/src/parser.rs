@@ -5,22 +5,47 @@ use pest_derive::Parser;
 #[grammar_inline = r#"
 alpha = _{ 'a'..'z' | 'A'..'Z' }
 digit = _{ '0'..'9' }
-WHITESPACE = _{ " " | NEWLINE }
+hex_digit = _{ '0'..'9' | 'a'..'f' | 'A'..'F' }
+integer = @{
+    "-"? ~ (
+        ("0x" | "0X") ~ hex_digit+
+      | ("0b" | "0B") ~ ('0'..'1')+
+      | ("0o" | "0O") ~ ('0'..'7')+
+      | digit+
+    )
+}
+inline_ws = _{ " " | "\t" }
+WHITESPACE = _{ inline_ws | NEWLINE }
 
-ident = @{ alpha ~ (alpha | digit)* }
+ident = @{ (alpha | "_") ~ (alpha | digit | "_")* }
 
 label = { ident ~ ":" }
 
-register = @{ "$" ~ ident }
-instruction_arg = @{ ident | register | digit+ }
+register = @{ "$" ~ (ident | digit+) }
+symbol_expr = @{ ident ~ (("+" | "-") ~ integer)? }
+relocation_arg = @{ "%" ~ ("hi" | "lo") ~ "(" ~ symbol_expr ~ ")" }
+instruction_arg = @{ relocation_arg | symbol_expr | register | integer }
 standard_args = _{ 
-   instruction_arg ~ ("," ~ WHITESPACE* ~ instruction_arg){, 2}
+   instruction_arg ~ ("," ~ WHITESPACE* ~ instruction_arg){, 3}
 }
 mem_access_args = _{ instruction_arg ~ "," ~ instruction_arg ~ "(" ~ instruction_arg ~ ")" }
 instruction_args = _{ mem_access_args | standard_args }
-instruction = { ident ~ instruction_args }
+// Floating-point mnemonics carry a format suffix, as in movz.d
+mnemonic = @{ ident ~ ("." ~ ident)* }
+instruction = { mnemonic ~ instruction_args }
+// Instructions without operands, such as eret, end at the line break. This has
+// to be tried before instruction, which would take the next line's mnemonic
+// for an operand.
+bare_instruction = ${ mnemonic ~ &(inline_ws* ~ (NEWLINE | EOI)) }
+
+// Directives are line-oriented, so their arguments must not spill onto the next line
+directive_name = @{ "." ~ ident }
+string = @{ "\"" ~ (!"\"" ~ ANY)* ~ "\"" }
+section_name = @{ "." ~ ident ~ ("." ~ ident)* }
+directive_arg = @{ string | section_name | symbol_expr | integer }
+directive = ${ directive_name ~ (inline_ws+ ~ directive_arg ~ (inline_ws* ~ "," ~ inline_ws* ~ directive_arg)*)? }
 
-vernacular = { (instruction | label)* }
+vernacular = { (directive | bare_instruction | instruction | label)* }
 "#]
 pub struct MipsParser;
 
@@ -28,6 +53,7 @@ pub struct MipsParser;
 pub enum MipsCST<'a> {
     Label(&'a str),
     Instruction(&'a str, Vec<&'a str>),
+    Directive(&'a str, Vec<&'a str>),
     Sequence(Vec<MipsCST<'a>>),
 }
 
@@ -35,16 +61,19 @@ pub fn parse_rule(pair: Pair<Rule>) -> MipsCST {
     match pair.as_rule() {
         Rule::vernacular => MipsCST::Sequence(pair.into_inner().map(parse_rule).collect()),
         Rule::label => MipsCST::Label(pair.into_inner().next().unwrap().as_str()),
-        Rule::instruction => {
+        Rule::instruction | Rule::bare_instruction => {
             let mut inner = pair.into_inner();
             let opcode = inner.next().unwrap().as_str();
             let args = inner.clone().map(|p| p.as_str()).collect::<Vec<&str>>();
             MipsCST::Instruction(opcode, args)
         }
-        _ => {
-            println!("Unreachable: {:?}", pair.as_rule());
-            unreachable!()
+        Rule::directive => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().unwrap().as_str();
+            let args = inner.map(|p| p.as_str()).collect::<Vec<&str>>();
+            MipsCST::Directive(name, args)
         }
+        rule => unreachable!("{:?}", rule),
     }
 }
 
@@ -57,18 +86,6 @@ pub fn cst_map(cst: &MipsCST, f: fn(&MipsCST) -> ()) {
     }
 }
 
-pub fn print_cst(cst: &MipsCST) {
-    match cst {
-        MipsCST::Label(s) => println!("{}:", s),
-        MipsCST::Instruction(mnemonic, args) => println!("\t{} {}", mnemonic, args.join(", ")),
-        MipsCST::Sequence(v) => {
-            for sub_cst in v {
-                print_cst(sub_cst)
-            }
-        }
-    }
-}
-
 pub fn instr_to_str(mnemonic: &str, args: &[&str]) -> String {
     format!("{} {}", mnemonic, args.join(" "))
 }
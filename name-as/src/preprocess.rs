@@ -0,0 +1,272 @@
+//! Textual preprocessing applied to a source file before it is parsed
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Deep enough for any sensible library layout, shallow enough to catch include cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Finds the file named by an `.include`, first next to the including file
+/// and then in each search path in the order they were given
+fn resolve_include(
+    name: &str,
+    including_file: &Path,
+    search_paths: &[String],
+) -> Result<PathBuf, String> {
+    let parent = including_file.parent().unwrap_or(Path::new(""));
+
+    std::iter::once(parent.to_path_buf())
+        .chain(search_paths.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+        .ok_or(format!(
+            "Failed to find included file {} (from {})",
+            name,
+            including_file.display()
+        ))
+}
+
+/// The file and line a line of preprocessed source originally came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    /// Counts from 1, so 0 marks a location that couldn't be found
+    pub line: u32,
+}
+
+impl SourceLocation {
+    fn unknown() -> Self {
+        SourceLocation {
+            file: String::new(),
+            line: 0,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "unknown location"),
+            line => write!(f, "{}:{}", self.file, line),
+        }
+    }
+}
+
+/// Maps text in the preprocessed source back to where it was written.
+/// Preprocessing never adds or removes lines other than by `.include`, so
+/// each line of the result has exactly one origin.
+pub struct SourceMap<'a> {
+    text: &'a str,
+    line_starts: Vec<usize>,
+    origins: Vec<SourceLocation>,
+    unknown: SourceLocation,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(text: &'a str, origins: Vec<SourceLocation>) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        SourceMap {
+            text,
+            line_starts,
+            origins,
+            unknown: SourceLocation::unknown(),
+        }
+    }
+
+    /// Finds where a fragment of the preprocessed source was written. Only
+    /// slices of that source, as everything in the CST is, can be found;
+    /// anything else, like text a pseudo-instruction made up, is at an
+    /// unknown location.
+    pub fn locate(&self, fragment: &str) -> &SourceLocation {
+        let offset = (fragment.as_ptr() as usize).checked_sub(self.text.as_ptr() as usize);
+        let Some(offset) = offset.filter(|offset| offset + fragment.len() <= self.text.len()) else {
+            return &self.unknown;
+        };
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        self.origins
+            .get(line)
+            .or(self.origins.last())
+            .unwrap_or(&self.unknown)
+    }
+}
+
+/// Replaces every `.include "file"` line with the contents of that file,
+/// returning the expanded source along with the origin of each of its lines
+pub fn expand_includes(
+    source_fn: &Path,
+    contents: &str,
+    search_paths: &[String],
+    depth: usize,
+) -> Result<(String, Vec<SourceLocation>), String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!(
+            "Includes nested too deeply at {}, is there an include cycle?",
+            source_fn.display()
+        ));
+    }
+
+    let mut expanded = String::new();
+    let mut origins: Vec<SourceLocation> = vec![];
+
+    for (line_index, line) in contents.lines().enumerate() {
+        let included = match line.trim().strip_prefix(".include") {
+            Some(rest) => rest.trim(),
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+                origins.push(SourceLocation {
+                    file: source_fn.display().to_string(),
+                    line: line_index as u32 + 1,
+                });
+                continue;
+            }
+        };
+
+        let name = match included.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(v) => v,
+            None => return Err(format!(".include expects a quoted filename, found {}", included)),
+        };
+
+        let include_fn = resolve_include(name, source_fn, search_paths)?;
+        let include_contents = match fs::read_to_string(&include_fn) {
+            Ok(v) => v,
+            Err(_) => return Err(format!("Failed to read included file {}", include_fn.display())),
+        };
+
+        let (included_text, included_origins) =
+            expand_includes(&include_fn, &include_contents, search_paths, depth + 1)?;
+        expanded.push_str(&included_text);
+        origins.extend(included_origins);
+    }
+
+    Ok((expanded, origins))
+}
+
+/// Replaces every whole-word use of a defined name with its value, the same
+/// way MARS substitutes `.eqv` symbols. Register names, directives, and
+/// quoted strings are left alone.
+pub fn substitute_definitions(contents: &str, definitions: &[(String, String)]) -> String {
+    if definitions.is_empty() {
+        return contents.to_string();
+    }
+
+    let mut substituted = String::with_capacity(contents.len());
+    let mut chars = contents.char_indices().peekable();
+    let mut in_string = false;
+    let mut previous: Option<char> = None;
+
+    while let Some((start, c)) = chars.next() {
+        if c == '"' {
+            in_string = !in_string;
+        }
+
+        let starts_word = c.is_ascii_alphabetic() || c == '_';
+        // Don't substitute in the middle of a literal like 0xABC or a name like $t0
+        let protected = matches!(previous, Some(p) if p.is_ascii_alphanumeric() || "$.%_".contains(p));
+        if in_string || !starts_word || protected {
+            substituted.push(c);
+            previous = Some(c);
+            continue;
+        }
+
+        // Consume the rest of the word
+        let mut end = start + c.len_utf8();
+        while let Some((i, next)) = chars.peek() {
+            if !(next.is_ascii_alphanumeric() || *next == '_') {
+                break;
+            }
+            end = *i + next.len_utf8();
+            chars.next();
+        }
+
+        let word = &contents[start..end];
+        match definitions.iter().rev().find(|(name, _)| name == word) {
+            Some((_, value)) => substituted.push_str(value),
+            None => substituted.push_str(word),
+        }
+        previous = word.chars().last();
+    }
+
+    substituted
+}
+
+/// Lowercases mnemonics, directive names, and register names, so that
+/// `ADDI $T0, $ZERO, 1` assembles the way it does in MARS. Labels and
+/// strings are left alone, since those stay case-sensitive.
+pub fn fold_case(contents: &str) -> String {
+    let mut folded = String::with_capacity(contents.len());
+
+    for line in contents.lines() {
+        let mut in_string = false;
+        // The mnemonic or directive is the first word that isn't a label
+        let mut seen_operation = false;
+        let mut chars = line.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            if c == '"' {
+                in_string = !in_string;
+            }
+            if in_string || !(c.is_ascii_alphanumeric() || "$._".contains(c)) {
+                folded.push(c);
+                continue;
+            }
+
+            // Consume the rest of the word
+            let mut end = start + c.len_utf8();
+            while let Some((i, next)) = chars.peek() {
+                if !(next.is_ascii_alphanumeric() || "._".contains(*next)) {
+                    break;
+                }
+                end = *i + next.len_utf8();
+                chars.next();
+            }
+
+            let word = &line[start..end];
+            let is_label = matches!(chars.peek(), Some((_, ':')));
+            if word.starts_with('$') || (!seen_operation && !is_label) {
+                folded.push_str(&word.to_ascii_lowercase());
+            } else {
+                folded.push_str(word);
+            }
+            seen_operation |= !is_label;
+        }
+
+        folded.push('\n');
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(lines: u32) -> Vec<SourceLocation> {
+        (1..=lines)
+            .map(|line| SourceLocation {
+                file: "main.asm".to_string(),
+                line,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locates_slices_of_the_source() {
+        let text = "main:\naddi $t0, $t0, 1\n";
+        let source_map = SourceMap::new(text, origins(2));
+        assert_eq!(source_map.locate(&text[6..10]).to_string(), "main.asm:2");
+    }
+
+    #[test]
+    fn text_from_elsewhere_is_at_an_unknown_location() {
+        let text = "main:\naddi $t0, $t0, 1\n";
+        let source_map = SourceMap::new(text, origins(2));
+        let made_up = String::from("$at");
+        assert_eq!(source_map.locate(&made_up).to_string(), "unknown location");
+        assert_eq!(source_map.locate("$zero").to_string(), "unknown location");
+    }
+}
@@ -0,0 +1,368 @@
+//! Pseudo-instructions and their expansions into real instructions
+//!
+//! Expansions depend only on the pseudo-instruction as written, never on
+//! label addresses, so the layout pass and the assembly pass always agree
+//! on how many instructions a pseudo-instruction occupies.
+
+use crate::nma::{fits_in_bits, parse_int};
+
+/// Every mnemonic that [expand_pseudo] knows how to expand
+pub const PSEUDO_INSTRUCTION_SET: &[&str] = &[
+    "blt", "bgt", "ble", "bge", "seq", "sne", "rem", "abs", "not", "neg", "rol", "ror", "li", "la",
+];
+
+/// Loads and stores, which become pseudo-instructions when addressing a symbol
+const MEMORY_INSTRUCTIONS: &[&str] = &[
+    "lb", "lbu", "lh", "lhu", "lw", "lwl", "lwr", "ll", "sb", "sh", "sw", "swl", "swr", "sc",
+];
+
+/// Whether an operand names a symbol (optionally plus an offset) rather than
+/// a register, literal, or relocation operator
+fn is_symbolic(arg: &str) -> bool {
+    arg.starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+/// A real instruction produced by expanding a pseudo-instruction
+pub struct Expansion<'a> {
+    pub mnemonic: &'a str,
+    pub args: Vec<String>,
+}
+
+fn instr<'a>(mnemonic: &'a str, args: &[&str]) -> Expansion<'a> {
+    Expansion {
+        mnemonic,
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+    }
+}
+
+fn enforce_length(mnemonic: &str, args: &[&str], len: usize) -> Result<(), String> {
+    if args.len() != len {
+        Err(format!("{} expects {} arguments, found {}", mnemonic, len, args.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Expands a rotate by a constant amount into a pair of opposing shifts.
+/// `toward` shifts in the direction of the rotation, `away` in the other.
+fn expand_rotate<'a>(
+    toward: &'a str,
+    away: &'a str,
+    args: &[&str],
+) -> Result<Vec<Expansion<'a>>, String> {
+    let amount = match parse_int(args[2]) {
+        Some(v) if (0..32).contains(&v) => v,
+        _ => return Err(format!("Invalid rotate amount {}", args[2])),
+    };
+
+    if amount == 0 {
+        return Ok(vec![instr("sll", &[args[0], args[1], "0"])]);
+    }
+
+    Ok(vec![
+        instr(away, &["$at", args[1], &(32 - amount).to_string()]),
+        instr(toward, &[args[0], args[1], &amount.to_string()]),
+        instr("or", &[args[0], args[0], "$at"]),
+    ])
+}
+
+/// Expands a rotate by a register amount, using the fact that variable
+/// shifts only consider the low five bits of the amount
+fn expand_rotate_variable<'a>(
+    toward: &'a str,
+    away: &'a str,
+    args: &[&str],
+) -> Vec<Expansion<'a>> {
+    vec![
+        instr("subu", &["$at", "$zero", args[2]]),
+        instr(away, &["$at", args[1], "$at"]),
+        instr(toward, &[args[0], args[1], args[2]]),
+        instr("or", &[args[0], args[0], "$at"]),
+    ]
+}
+
+/// Expands a load immediate into the shortest sequence that produces the value
+fn expand_li<'a>(args: &[&str]) -> Result<Vec<Expansion<'a>>, String> {
+    let value = match parse_int(args[1]) {
+        Some(v) if fits_in_bits(v, 32) => v,
+        _ => return Err(format!("li expects a 32-bit immediate, found {}", args[1])),
+    };
+
+    let bits = value as u32;
+    let upper = format!("{:#x}", bits >> 16);
+    let lower = format!("{:#x}", bits & 0xffff);
+
+    let expansion = if (0..=0xffff).contains(&value) {
+        // Zero-extended by ori
+        vec![instr("ori", &[args[0], "$zero", &lower])]
+    } else if (-0x8000..0).contains(&value) {
+        // Sign-extended by addiu
+        vec![instr("addiu", &[args[0], "$zero", &value.to_string()])]
+    } else if bits & 0xffff == 0 {
+        vec![instr("lui", &[args[0], &upper])]
+    } else {
+        vec![
+            instr("lui", &["$at", &upper]),
+            instr("ori", &[args[0], "$at", &lower]),
+        ]
+    };
+
+    Ok(expansion)
+}
+
+/// Expands a load or store whose offset is a symbol expression, such as
+/// `lw $t0, array+4($t1)`, by materializing the upper half of the address in $at
+fn expand_symbolic_memory<'a>(mnemonic: &'a str, args: &[&str]) -> Vec<Expansion<'a>> {
+    let mut expansion = vec![instr("lui", &["$at", &format!("%hi({})", args[1])])];
+
+    // A base register other than $zero is added on top of the symbol's address
+    if let Some(base) = args.get(2).filter(|base| **base != "$zero") {
+        expansion.push(instr("addu", &["$at", "$at", base]));
+    }
+
+    expansion.push(instr(mnemonic, &[args[0], &format!("%lo({})", args[1]), "$at"]));
+    expansion
+}
+
+/// Branches that compare something, which are the ones --relax can rewrite
+pub const CONDITIONAL_BRANCHES: &[&str] = &[
+    "beq", "bne", "blez", "bgtz", "bltz", "bgez", "bltzal", "bgezal", "bc1f", "bc1t", "beql",
+    "bnel", "blezl", "bgtzl", "bltzl", "bgezl",
+];
+
+/// Rewrites a branch whose target is out of reach into the inverted branch
+/// around a jump. The inverted branch skips to the original delay slot, which
+/// is also the jump's delay slot, so it still runs whichever way things go.
+pub fn relax_branch(branch: Expansion) -> Result<Vec<Expansion>, String> {
+    let inverted = match branch.mnemonic {
+        "beq" => "bne",
+        "bne" => "beq",
+        "blez" => "bgtz",
+        "bgtz" => "blez",
+        "bltz" => "bgez",
+        "bgez" => "bltz",
+        "bc1f" => "bc1t",
+        "bc1t" => "bc1f",
+        // A linking branch sets $ra whether or not it's taken, and the jal
+        // that would replace it only does when it is
+        "bltzal" | "bgezal" => {
+            return Err(format!(
+                "{} can't reach its target, and linking branches can't be relaxed",
+                branch.mnemonic
+            ))
+        }
+        // A branch-likely skips its delay slot when not taken, and the inverted
+        // branch would have to run it then instead, which nothing encodes
+        "beql" | "bnel" | "blezl" | "bgtzl" | "bltzl" | "bgezl" => {
+            return Err(format!(
+                "{} can't reach its target, and branch-likely instructions can't be relaxed",
+                branch.mnemonic
+            ))
+        }
+        mnemonic => return Err(format!("{} is not a conditional branch, and can't be relaxed", mnemonic)),
+    };
+
+    // The target is always last, after whatever the branch compares
+    let Some((target, conditions)) = branch.args.split_last() else {
+        return Err(format!("{} is missing its target", branch.mnemonic));
+    };
+    let mut inverted_args: Vec<&str> = conditions.iter().map(|arg| arg.as_str()).collect();
+    inverted_args.push("2");
+
+    Ok(vec![
+        instr(inverted, &inverted_args),
+        instr("sll", &["$zero", "$zero", "0"]),
+        instr("j", &[target]),
+    ])
+}
+
+/// Expands a pseudo-instruction into the real instructions that implement it,
+/// or returns None if the mnemonic is not a pseudo-instruction
+pub fn expand_pseudo<'a>(
+    mnemonic: &str,
+    args: &[&str],
+) -> Option<Result<Vec<Expansion<'a>>, String>> {
+    if let Some(memory_mnemonic) = MEMORY_INSTRUCTIONS.iter().find(|m| **m == mnemonic) {
+        return match args.get(1) {
+            Some(offset) if (2..=3).contains(&args.len()) && is_symbolic(offset) => {
+                Some(Ok(expand_symbolic_memory(memory_mnemonic, args)))
+            }
+            _ => None,
+        };
+    }
+
+    if !PSEUDO_INSTRUCTION_SET.contains(&mnemonic) {
+        return None;
+    }
+
+    let arg_count = match mnemonic {
+        "abs" | "not" | "neg" | "li" | "la" => 2,
+        _ => 3,
+    };
+    if let Err(e) = enforce_length(mnemonic, args, arg_count) {
+        return Some(Err(e));
+    }
+
+    let expansion = match mnemonic {
+        // Comparison branches compute the comparison into $at, then branch on it
+        "blt" => vec![
+            instr("slt", &["$at", args[0], args[1]]),
+            instr("bne", &["$at", "$zero", args[2]]),
+        ],
+        "bgt" => vec![
+            instr("slt", &["$at", args[1], args[0]]),
+            instr("bne", &["$at", "$zero", args[2]]),
+        ],
+        "ble" => vec![
+            instr("slt", &["$at", args[1], args[0]]),
+            instr("beq", &["$at", "$zero", args[2]]),
+        ],
+        "bge" => vec![
+            instr("slt", &["$at", args[0], args[1]]),
+            instr("beq", &["$at", "$zero", args[2]]),
+        ],
+        // Two values are equal exactly when their difference is zero
+        "seq" => vec![
+            instr("subu", &[args[0], args[1], args[2]]),
+            instr("sltiu", &[args[0], args[0], "1"]),
+        ],
+        "sne" => vec![
+            instr("subu", &[args[0], args[1], args[2]]),
+            instr("sltu", &[args[0], "$zero", args[0]]),
+        ],
+        "rem" => vec![instr("div", &[args[1], args[2]]), instr("mfhi", &[args[0]])],
+        // $at holds all ones for a negative value and zero otherwise, so this
+        // is a two's complement negation only when the value is negative
+        "abs" => vec![
+            instr("sra", &["$at", args[1], "31"]),
+            instr("xor", &[args[0], args[1], "$at"]),
+            instr("subu", &[args[0], args[0], "$at"]),
+        ],
+        "not" => vec![instr("nor", &[args[0], args[1], "$zero"])],
+        "neg" => vec![instr("sub", &[args[0], "$zero", args[1]])],
+        "li" => match expand_li(args) {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        },
+        // Always two instructions, since the address isn't known until after layout
+        "la" => vec![
+            instr("lui", &["$at", &format!("%hi({})", args[1])]),
+            instr("addiu", &[args[0], "$at", &format!("%lo({})", args[1])]),
+        ],
+        "rol" | "ror" => {
+            let (toward, away, toward_variable, away_variable) = match mnemonic {
+                "rol" => ("sll", "srl", "sllv", "srlv"),
+                _ => ("srl", "sll", "srlv", "sllv"),
+            };
+
+            if args[2].starts_with('$') {
+                expand_rotate_variable(toward_variable, away_variable, args)
+            } else {
+                match expand_rotate(toward, away, args) {
+                    Ok(v) => v,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+        _ => unreachable!(),
+    };
+
+    Some(Ok(expansion))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each instruction of an expansion as it would be written
+    fn written(expansion: Vec<Expansion>) -> Vec<String> {
+        expansion
+            .iter()
+            .map(|instruction| format!("{} {}", instruction.mnemonic, instruction.args.join(", ")))
+            .collect()
+    }
+
+    fn expand(mnemonic: &str, args: &[&str]) -> Vec<String> {
+        written(expand_pseudo(mnemonic, args).unwrap().unwrap())
+    }
+
+    #[test]
+    fn comparison_branches_branch_on_slt() {
+        let args = ["$t0", "$t1", "target"];
+        assert_eq!(expand("blt", &args), ["slt $at, $t0, $t1", "bne $at, $zero, target"]);
+        assert_eq!(expand("bgt", &args), ["slt $at, $t1, $t0", "bne $at, $zero, target"]);
+        assert_eq!(expand("ble", &args), ["slt $at, $t1, $t0", "beq $at, $zero, target"]);
+        assert_eq!(expand("bge", &args), ["slt $at, $t0, $t1", "beq $at, $zero, target"]);
+    }
+
+    #[test]
+    fn set_on_equality_tests_the_difference() {
+        let args = ["$t0", "$t1", "$t2"];
+        assert_eq!(expand("seq", &args), ["subu $t0, $t1, $t2", "sltiu $t0, $t0, 1"]);
+        assert_eq!(expand("sne", &args), ["subu $t0, $t1, $t2", "sltu $t0, $zero, $t0"]);
+    }
+
+    #[test]
+    fn abs_negates_through_the_sign_mask() {
+        assert_eq!(
+            expand("abs", &["$t0", "$t1"]),
+            ["sra $at, $t1, 31", "xor $t0, $t1, $at", "subu $t0, $t0, $at"]
+        );
+    }
+
+    #[test]
+    fn rotates_by_a_constant_shift_both_ways() {
+        assert_eq!(
+            expand("rol", &["$t0", "$t1", "8"]),
+            ["srl $at, $t1, 24", "sll $t0, $t1, 8", "or $t0, $t0, $at"]
+        );
+        assert_eq!(
+            expand("ror", &["$t0", "$t1", "8"]),
+            ["sll $at, $t1, 24", "srl $t0, $t1, 8", "or $t0, $t0, $at"]
+        );
+        // Shifting by 32 would shift by 0, so a rotate by 0 is just a move
+        assert_eq!(expand("rol", &["$t0", "$t1", "0"]), ["sll $t0, $t1, 0"]);
+        assert!(expand_pseudo("ror", &["$t0", "$t1", "32"]).unwrap().is_err());
+    }
+
+    #[test]
+    fn rotates_by_a_register_negate_the_amount() {
+        assert_eq!(
+            expand("rol", &["$t0", "$t1", "$t2"]),
+            ["subu $at, $zero, $t2", "srlv $at, $t1, $at", "sllv $t0, $t1, $t2", "or $t0, $t0, $at"]
+        );
+        assert_eq!(
+            expand("ror", &["$t0", "$t1", "$t2"]),
+            ["subu $at, $zero, $t2", "sllv $at, $t1, $at", "srlv $t0, $t1, $t2", "or $t0, $t0, $at"]
+        );
+    }
+
+    #[test]
+    fn li_picks_the_shortest_sequence() {
+        assert_eq!(expand("li", &["$t0", "0xffff"]), ["ori $t0, $zero, 0xffff"]);
+        assert_eq!(expand("li", &["$t0", "-0x8000"]), ["addiu $t0, $zero, -32768"]);
+        assert_eq!(expand("li", &["$t0", "0x12340000"]), ["lui $t0, 0x1234"]);
+        assert_eq!(expand("li", &["$t0", "0x12345678"]), ["lui $at, 0x1234", "ori $t0, $at, 0x5678"]);
+        assert_eq!(expand("li", &["$t0", "-0x8001"]), ["lui $at, 0xffff", "ori $t0, $at, 0x7fff"]);
+        assert!(expand_pseudo("li", &["$t0", "0x100000000"]).unwrap().is_err());
+    }
+
+    #[test]
+    fn relaxed_branches_invert_around_a_jump() {
+        let relaxed = |mnemonic, args: &[&str]| written(relax_branch(instr(mnemonic, args)).unwrap());
+        assert_eq!(
+            relaxed("beq", &["$t0", "$t1", "far"]),
+            ["bne $t0, $t1, 2", "sll $zero, $zero, 0", "j far"]
+        );
+        assert_eq!(relaxed("bgez", &["$t0", "far"]), ["bltz $t0, 2", "sll $zero, $zero, 0", "j far"]);
+        assert_eq!(relaxed("bc1t", &["3", "far"]), ["bc1f 3, 2", "sll $zero, $zero, 0", "j far"]);
+    }
+
+    #[test]
+    fn branches_that_cant_be_inverted_arent_relaxed() {
+        for mnemonic in ["bltzal", "bgezal", "beql", "bgezl", "j", "blt"] {
+            assert!(relax_branch(instr(mnemonic, &["$t0", "far"])).is_err(), "{}", mnemonic);
+        }
+        assert!(relax_branch(instr("beq", &[])).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Sections and the segments they are laid out into
+//!
+//! NAME emits flat images rather than relocatable objects, so the assembler
+//! doubles as the layout step: every section is placed into one of a fixed
+//! set of segments, back to back, in the order it first appeared. Where the
+//! user segments are comes from a [Layout], which the emulator is given too.
+
+use name_const::layout::Layout;
+
+// Kernel text begins at the general exception vector, so a program's
+// exception handler is simply the start of its .ktext
+pub const KTEXT_ADDRESS_BASE: u32 = 0x80000180;
+pub const KDATA_ADDRESS_BASE: u32 = 0x90000000;
+
+/// Every section starts at least on a word boundary within its segment
+const SECTION_ALIGNMENT: u32 = 4;
+
+/// A contiguous region of memory that NAME emits an image for
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Segment {
+    Text,
+    Data,
+    KernelText,
+    KernelData,
+}
+
+impl Segment {
+    pub const ALL: [Segment; 4] = [
+        Segment::Text,
+        Segment::Data,
+        Segment::KernelText,
+        Segment::KernelData,
+    ];
+
+    pub fn base_address(&self, layout: &Layout) -> u32 {
+        match self {
+            Segment::Text => layout.text_base,
+            Segment::Data => layout.data_base,
+            Segment::KernelText => KTEXT_ADDRESS_BASE,
+            Segment::KernelData => KDATA_ADDRESS_BASE,
+        }
+    }
+
+    /// The most the segment can hold, where the layout sets a limit
+    pub fn size_limit(&self, layout: &Layout) -> Option<u32> {
+        match self {
+            Segment::Text => Some(layout.text_size),
+            Segment::Data => Some(layout.data_size),
+            Segment::KernelText | Segment::KernelData => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Text => "text",
+            Segment::Data => "data",
+            Segment::KernelText => "kernel text",
+            Segment::KernelData => "kernel data",
+        }
+    }
+
+    /// The text segment is written to the output file itself, every other
+    /// segment is written next to it with this suffix appended
+    pub fn output_suffix(&self) -> &'static str {
+        match self {
+            Segment::Text => "",
+            Segment::Data => ".data",
+            Segment::KernelText => ".ktext",
+            Segment::KernelData => ".kdata",
+        }
+    }
+}
+
+/// A named section and everything assembled into it
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub segment: Segment,
+    // Size in bytes, as measured by the layout pass
+    pub size: u32,
+    // Assigned by [SectionTable::layout] once every size is known
+    pub base: u32,
+    // The boundary the base must fall on, a power of two
+    pub alignment: u32,
+    // Filled in by the assembly pass
+    pub contents: Vec<u8>,
+}
+
+impl Section<'_> {
+    /// The address the next byte assembled into this section will land on
+    pub fn current_address(&self) -> u32 {
+        self.base + self.contents.len() as u32
+    }
+}
+
+/// Whether a section name is `base` or one of its subsections, as in `.text.startup`
+fn is_named(name: &str, base: &str) -> bool {
+    name.strip_prefix(base)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Picks a segment for a section from its gas-style flags, falling back on its
+/// name. Kernel sections are always recognized by name.
+fn section_segment(name: &str, flags: Option<&str>) -> Segment {
+    let kernel = is_named(name, ".ktext") || is_named(name, ".kdata");
+    let executable = match flags {
+        Some(flags) => flags.contains('x'),
+        None => is_named(name, ".text") || is_named(name, ".ktext"),
+    };
+
+    match (kernel, executable) {
+        (false, true) => Segment::Text,
+        (false, false) => Segment::Data,
+        (true, true) => Segment::KernelText,
+        (true, false) => Segment::KernelData,
+    }
+}
+
+/// All sections of a program, along with the one currently being assembled into
+pub struct SectionTable<'a> {
+    pub sections: Vec<Section<'a>>,
+    current: usize,
+    layout: Layout,
+}
+
+impl Default for SectionTable<'_> {
+    fn default() -> Self {
+        Self::new(Layout::default())
+    }
+}
+
+impl<'a> SectionTable<'a> {
+    pub fn new(layout: Layout) -> Self {
+        SectionTable {
+            sections: vec![Section {
+                name: ".text",
+                segment: Segment::Text,
+                size: 0,
+                base: layout.text_base,
+                alignment: SECTION_ALIGNMENT,
+                contents: vec![],
+            }],
+            current: 0,
+            layout,
+        }
+    }
+
+    pub fn current(&mut self) -> &mut Section<'a> {
+        &mut self.sections[self.current]
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    /// Returns to the first section, ready for another pass over the program
+    pub fn rewind(&mut self) {
+        self.current = 0;
+    }
+
+    /// Handles `.section name[, "flags"]` and the `.text`/`.data`/`.ktext`/`.kdata` shorthands
+    pub fn switch_to(&mut self, directive: &'a str, args: &[&'a str]) -> Result<(), String> {
+        let (name, flags) = match directive {
+            ".section" => match args {
+                [name] => (*name, None),
+                [name, flags] => (*name, Some(flags.trim_matches('"'))),
+                _ => return Err(".section expects a name and optional flags".to_string()),
+            },
+            _ => {
+                if !args.is_empty() {
+                    return Err(format!("{} does not take arguments", directive));
+                }
+                (directive, None)
+            }
+        };
+
+        let segment = section_segment(name, flags);
+
+        match self.sections.iter().position(|s| s.name == name) {
+            Some(index) => {
+                if self.sections[index].segment != segment {
+                    return Err(format!("Section {} redeclared with different flags", name));
+                }
+                self.current = index;
+            }
+            None => {
+                self.sections.push(Section {
+                    name,
+                    segment,
+                    size: 0,
+                    base: 0,
+                    alignment: SECTION_ALIGNMENT,
+                    contents: vec![],
+                });
+                self.current = self.sections.len() - 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Places every section within its segment in order of first appearance,
+    /// failing if a segment outgrows the room the layout gives it
+    pub fn layout(&mut self) -> Result<(), String> {
+        for segment in Segment::ALL {
+            let base = segment.base_address(&self.layout);
+            let mut cursor = base;
+            for section in self.sections.iter_mut().filter(|s| s.segment == segment) {
+                cursor = (cursor + section.alignment - 1) & !(section.alignment - 1);
+                section.base = cursor;
+                cursor += section.size;
+            }
+
+            if let Some(limit) = segment.size_limit(&self.layout) {
+                if cursor - base > limit {
+                    return Err(format!(
+                        "The {} segment needs {} bytes, but the layout only has room for {}",
+                        segment.name(),
+                        cursor - base,
+                        limit
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Concatenates the contents of every section in a segment, padding between sections
+    pub fn segment_image(&self, segment: Segment) -> Vec<u8> {
+        let mut image: Vec<u8> = vec![];
+        for section in self.sections.iter().filter(|s| s.segment == segment) {
+            image.resize((section.base - segment.base_address(&self.layout)) as usize, 0);
+            image.extend_from_slice(&section.contents);
+        }
+        image
+    }
+}
@@ -0,0 +1,92 @@
+// Instruction set profiles, selected with name-as's --isa
+//
+// NAME's instruction set is an amalgamation of several MIPS releases. A
+// profile narrows it to what one particular target accepts, so programs
+// written against NAME don't quietly depend on instructions their target
+// lacks. The assembler rejects instructions outside the profile and records
+// it alongside the line information, and the emulator treats anything outside
+// it as a reserved instruction. Both read the extension an instruction belongs
+// to off its encoding, so they can't disagree.
+
+extern crate serde;
+use serde::Deserialize;
+use serde::Serialize;
+
+const SPECIAL: u32 = 0x00;
+const COP1: u32 = 0x11;
+const COP1X: u32 = 0x13;
+const SPECIAL3: u32 = 0x1F;
+
+const FMT_S: u32 = 0x10;
+const FMT_D: u32 = 0x11;
+
+// Groups of instructions beyond the MIPS32 Release 1 base set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extension {
+    // Rotates and the SPECIAL3 bit field and byte shuffle instructions
+    Release2,
+    // Indexed floating-point loads and stores, and fused multiply-add
+    Cop1x,
+    // recip and rsqrt, which MIPS32 only gained alongside COP1X
+    Reciprocal,
+}
+
+impl Extension {
+    // Which extension an encoded instruction belongs to, or None for the base
+    // set (and for words that aren't instructions at all)
+    pub fn of(word: u32) -> Option<Extension> {
+        let (opcode, rs, shamt, funct) = (word >> 26, word >> 21 & 0x1F, word >> 6 & 0x1F, word & 0x3F);
+        match opcode {
+            SPECIAL3 => Some(Extension::Release2),
+            // rotr and rotrv are srl and srlv with a bit Release 1 leaves zero
+            SPECIAL if (funct == 0x02 && rs == 1) || (funct == 0x06 && shamt == 1) => Some(Extension::Release2),
+            COP1X => Some(Extension::Cop1x),
+            COP1 if matches!(rs, FMT_S | FMT_D) && matches!(funct, 0x15 | 0x16) => Some(Extension::Reciprocal),
+            _ => None,
+        }
+    }
+}
+
+// A target instruction set to assemble and run strictly against
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Isa {
+    #[serde(rename = "r2")]
+    Mips32R2,
+    #[serde(rename = "mars")]
+    Mars,
+}
+
+impl Isa {
+    // Parses the argument given to --isa
+    pub fn from_name(name: &str) -> Result<Isa, &'static str> {
+        match name {
+            "r2" => Ok(Isa::Mips32R2),
+            "mars" => Ok(Isa::Mars),
+            _ => Err("Expected one of r2 or mars after --isa"),
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Isa::Mips32R2 => "MIPS32 Release 2",
+            Isa::Mars => "MARS",
+        }
+    }
+
+    // Whether instructions from an extension exist in this profile
+    pub fn supports(&self, extension: Extension) -> bool {
+        match self {
+            // Everything NAME encodes is part of Release 2
+            Isa::Mips32R2 => true,
+            // MARS implements Release 1 with the basic COP1 arithmetic only
+            Isa::Mars => match extension {
+                Extension::Release2 | Extension::Cop1x | Extension::Reciprocal => false,
+            },
+        }
+    }
+
+    // Whether an encoded instruction exists in this profile
+    pub fn accepts(&self, word: u32) -> bool {
+        Extension::of(word).is_none_or(|extension| self.supports(extension))
+    }
+}
@@ -0,0 +1,75 @@
+// Where a program's segments go in memory. The assembler lays a program out
+// by one of these, taken from its config file, and records it alongside the
+// line information. The emulator builds memory from that record, so the two
+// always agree on where things are.
+//
+// Kernel text and data aren't part of it. Kernel text begins at the
+// exception vector, which is fixed by the hardware.
+
+extern crate serde;
+use serde::Deserialize;
+use serde::Serialize;
+
+// User programs live below here
+const KERNEL_SPACE_START: u64 = 0x80000000;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Layout {
+    pub text_base: u32,
+    pub text_size: u32,
+    pub data_base: u32,
+    pub data_size: u32,
+    // The heap grows up from its base with sbrk
+    pub heap_base: u32,
+    pub heap_size: u32,
+    // The stack grows down from the top, its base plus its size
+    pub stack_base: u32,
+    pub stack_size: u32,
+}
+
+// The layout MARS uses
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            text_base: 0x00400000,
+            text_size: 0x1000,
+            data_base: 0x10010000,
+            data_size: 0x30000,
+            heap_base: 0x10040000,
+            heap_size: 0x3C0000,
+            stack_base: 0x7FF00000,
+            stack_size: 0x100000,
+        }
+    }
+}
+
+impl Layout {
+    // Checks that every segment is word aligned, fits in user space, and
+    // keeps clear of the others
+    pub fn check(&self) -> Result<(), String> {
+        let segments = [
+            ("text", self.text_base, self.text_size),
+            ("data", self.data_base, self.data_size),
+            ("heap", self.heap_base, self.heap_size),
+            ("stack", self.stack_base, self.stack_size),
+        ];
+
+        for (i, &(name, base, size)) in segments.iter().enumerate() {
+            let (start, end) = (base as u64, base as u64 + size as u64);
+            if base % 4 != 0 || size % 4 != 0 {
+                return Err(format!("The {} segment must be word aligned", name));
+            }
+            if end > KERNEL_SPACE_START {
+                return Err(format!("The {} segment runs into kernel space", name));
+            }
+            for &(other, other_base, other_size) in &segments[i + 1..] {
+                if start < other_base as u64 + other_size as u64 && (other_base as u64) < end {
+                    return Err(format!("The {} and {} segments overlap", name, other));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1 +1,4 @@
-pub mod lineinfo;
\ No newline at end of file
+pub mod isa;
+pub mod layout;
+pub mod lineinfo;
+pub mod symbols;
@@ -9,9 +9,17 @@ use serde::Serialize;
 use std::fs;
 use serde::Deserialize;
 
+use crate::isa::Isa;
+use crate::layout::Layout;
+use crate::symbols::SymbolInfo;
+
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct LineInfo {
     pub instr_addr: u32,
+    // The file line_number refers to, which differs from the assembled file for
+    // anything pulled in by .include. Older line info files don't record it.
+    #[serde(default)]
+    pub source_file: String,
     pub line_number: u32,
     pub line_contents: String,
     pub psuedo_op: String,
@@ -19,7 +27,21 @@ pub struct LineInfo {
 
 #[derive(Deserialize, Serialize)]
 struct LineInfoFile {
+    // Where execution begins. Older line info files leave it to the start of .text.
+    #[serde(default)]
+    pub entry: Option<u32>,
     pub lineinfo: Vec<LineInfo>,
+    // Older line info files carry no symbol table
+    #[serde(default)]
+    pub symbols: Vec<SymbolInfo>,
+    // The layout the program was assembled for. Older line info files were
+    // all assembled for the default one.
+    #[serde(default)]
+    pub layout: Layout,
+    // The instruction set the program was assembled against, if it named
+    // one. Older line info files never did.
+    #[serde(default)]
+    pub isa: Option<Isa>,
 }
 
 pub fn lineinfo_import(
@@ -34,11 +56,48 @@ pub fn lineinfo_import(
     
     Ok(out)
 }
+
+pub fn symbols_import(
+    file_contents: &str
+) -> Result<Vec<SymbolInfo>, Box<dyn std::error::Error>> {
+    let line_info: LineInfoFile = toml::from_str(file_contents)?;
+
+    Ok(line_info.symbols)
+}
+
+pub fn entry_import(
+    file_contents: &str
+) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    let line_info: LineInfoFile = toml::from_str(file_contents)?;
+
+    Ok(line_info.entry)
+}
+
+pub fn layout_import(
+    file_contents: &str
+) -> Result<Layout, Box<dyn std::error::Error>> {
+    let line_info: LineInfoFile = toml::from_str(file_contents)?;
+
+    Ok(line_info.layout)
+}
+
+pub fn isa_import(
+    file_contents: &str
+) -> Result<Option<Isa>, Box<dyn std::error::Error>> {
+    let line_info: LineInfoFile = toml::from_str(file_contents)?;
+
+    Ok(line_info.isa)
+}
+
 pub fn lineinfo_export(
     filename: String,
     li: Vec<LineInfo>,
+    symbols: Vec<SymbolInfo>,
+    entry: Option<u32>,
+    layout: Layout,
+    isa: Option<Isa>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let toml_data = toml::to_string(&LineInfoFile { lineinfo: li })?;
+    let toml_data = toml::to_string(&LineInfoFile { entry, lineinfo: li, symbols, layout, isa })?;
 
     fs::write(filename, toml_data)?;
 
@@ -0,0 +1,23 @@
+// Symbol information is exported alongside line information so that tools
+// further down the pipeline (name-emu, name-ext) can map addresses back to labels.
+
+extern crate serde;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
+pub enum SymbolBinding {
+    // Visible only within the file that defined it
+    Local,
+    // Declared with .globl, visible to every object in a program
+    Global,
+    // Declared with .weak, a fallback definition that a global may override
+    Weak,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub address: u32,
+    pub binding: SymbolBinding,
+}
@@ -0,0 +1,146 @@
+// The chain of calls that led to where the program is, worked out the way
+// a debugger does without debugging information: from the stack and the
+// calling convention. A function that calls others makes its frame with
+// addiu $sp, $sp, -N and saves $ra in it with sw $ra, OFFSET($sp), so the
+// instructions before pc in a function say how big its frame is and where
+// to find the address it returns to. A function that hasn't saved $ra, like
+// a leaf, still has it in $ra, but only the innermost function can be that.
+//
+// Code that doesn't follow the convention, like a function that moves $sp
+// more than once, can cut the chain short or send it somewhere wrong, so
+// it's a guide rather than the truth.
+
+use name_const::symbols::SymbolInfo;
+
+use crate::mips::Mips;
+use crate::symbols::nearest;
+
+// How far back to look for a function's prologue, and how many frames to
+// follow before deciding the stack isn't making sense
+const PROLOGUE_SEARCH_LIMIT: u32 = 1024;
+const MAX_FRAMES: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    // Where the function is: pc for the innermost, and the call for the rest
+    pub pc: u32,
+    pub sp: u32,
+    pub function: Option<String>,
+}
+
+// What the instructions leading up to pc say about a function's frame
+struct Prologue {
+    // Where the function seems to start
+    start: u32,
+    // How far it's moved $sp down, and where it saved $ra relative to $sp.
+    // Once the function has put $sp back on its way out, it has no frame,
+    // and $ra has the address it returns to again.
+    frame_size: u32,
+    ra_offset: Option<u32>,
+}
+
+pub fn backtrace(mips: &Mips, symbols: &[SymbolInfo]) -> Vec<Frame> {
+    // The instruction that made a call is before the return address, and
+    // before the delay slot if there is one
+    let call_length = if mips.delay_slots { 8 } else { 4 };
+
+    let (mut pc, mut sp, mut ra) = (mips.pc as u32, mips.regs[29], Some(mips.regs[31]));
+    // Where the function a return address comes back from starts, if a jal
+    // called it
+    let called = |return_address: u32| {
+        let call = return_address.wrapping_sub(call_length);
+        let word = mips.memory.peek_u32(call).filter(|word| word >> 26 == 0x3)?;
+        Some((call + 4) & 0xF0000000 | (word & 0x03FFFFFF) << 2)
+    };
+
+    let mut frames: Vec<Frame> = vec![];
+    while frames.len() < MAX_FRAMES {
+        // Right after a call, before the function has done anything, $ra
+        // says where it starts, which keeps the search from running back
+        // into the code before it
+        let floor = ra.and_then(called).filter(|start| *start <= pc).unwrap_or(0);
+        let prologue = prologue(mips, pc, floor);
+        frames.push(Frame { pc, sp, function: function_at(symbols, prologue.start) });
+
+        // $ra is left over from a call this function made itself, once
+        // that call has been made, so it says nothing about this function
+        let return_address = match prologue.ra_offset {
+            Some(offset) => mips.memory.peek_u32(sp.wrapping_add(offset)),
+            None => ra.filter(|ra| !(prologue.start..pc).contains(&ra.wrapping_sub(call_length))),
+        };
+        // The outermost function returns to nowhere, or to somewhere that
+        // isn't code
+        let Some(call) = return_address.filter(|address| *address >= call_length && is_code(mips, *address - call_length)).map(|address| address - call_length) else {
+            break;
+        };
+        let caller_sp = sp.wrapping_add(prologue.frame_size);
+        if caller_sp < sp {
+            break;
+        }
+
+        // A jal says exactly where the function it called starts, which is
+        // better than where its prologue seemed to
+        if let (Some(start), Some(frame)) = (called(call + call_length), frames.last_mut()) {
+            frame.function = function_at(symbols, start);
+        }
+
+        (pc, sp, ra) = (call, caller_sp, None);
+    }
+    frames
+}
+
+// Works back from pc to the start of its function, noting where $sp is
+// moved down and $ra is saved. Anything after pc hasn't run yet, so it
+// doesn't count. The function starts at the instruction that makes its
+// frame, just after the jr $ra that ends the one before it, or at `floor`.
+fn prologue(mips: &Mips, pc: u32, floor: u32) -> Prologue {
+    let mut found = Prologue { start: pc, frame_size: 0, ra_offset: None };
+    let mut returning = false;
+    let mut address = pc;
+    for _ in 0..PROLOGUE_SEARCH_LIMIT {
+        let Some(previous) = address.checked_sub(4).filter(|previous| *previous >= floor && is_code(mips, *previous)) else {
+            break;
+        };
+        let Some(word) = mips.memory.peek_u32(previous) else {
+            break;
+        };
+        let (opcode, rs, rt, imm) = (word >> 26, (word >> 21) & 0x1F, (word >> 16) & 0x1F, word as u16 as i16);
+
+        // jr $ra
+        if opcode == 0 && word & 0x3F == 0x8 && rs == 31 {
+            break;
+        }
+        address = previous;
+        found.start = address;
+        // sw $ra, OFFSET($sp)
+        if opcode == 0x2B && rs == 29 && rt == 31 {
+            found.ra_offset = Some(imm as i32 as u32);
+        }
+        // addiu $sp, $sp, N, which takes the frame apart when N is positive
+        if opcode == 0x9 && rs == 29 && rt == 29 {
+            if imm > 0 {
+                returning = true;
+            } else if imm < 0 {
+                found.frame_size = imm.unsigned_abs() as u32;
+                break;
+            }
+        }
+    }
+    if returning {
+        (found.frame_size, found.ra_offset) = (0, None);
+    }
+    found
+}
+
+// Code is whatever's in memory outside of the data, heap, and stack, which
+// takes in kernel text and the segments of an ELF executable
+fn is_code(mips: &Mips, address: u32) -> bool {
+    let layout = mips.layout;
+    let elsewhere = [(layout.data_base, layout.data_size), (layout.heap_base, layout.heap_size), (layout.stack_base, layout.stack_size)];
+    mips.memory.peek_u32(address).is_some() && elsewhere.iter().all(|&(base, size)| !(base as u64..base as u64 + size as u64).contains(&(address as u64)))
+}
+
+// The label a function starts at, or failing that the nearest one before it
+fn function_at(symbols: &[SymbolInfo], address: u32) -> Option<String> {
+    nearest(symbols, address).map(|symbol| symbol.name.clone())
+}
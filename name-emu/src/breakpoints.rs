@@ -0,0 +1,266 @@
+// Breakpoints set from the debugger, each at an instruction's address and
+// remembered by the source location it was set at. Source locations are
+// resolved to addresses through the line info name-as writes. A line with
+// no instruction of its own, like a comment, a label, or a directive,
+// snaps to the next line that has one.
+//
+// Each breakpoint counts how often it's been hit, and can be told to let
+// the program past it some number of times first, so a loop can run for a
+// while before it stops. Temporary breakpoints go away the first time they
+// stop the program.
+//
+// Catchpoints stop the program for something it does rather than somewhere
+// it gets to: right before a syscall, or as soon as an exception is raised
+// that the program's own handler is about to take. They're numbered along
+// with breakpoints, and removed the same way.
+//
+// A watched register stops the program after an instruction changes it,
+// and it's numbered along with the rest too. Its value is noted after
+// every instruction to tell.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use name_const::lineinfo::LineInfo;
+
+use crate::cop0::exception_name;
+use crate::expression::Register;
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    // Numbered from 1 in the order they're set, for removing them by
+    pub id: u32,
+    pub address: u32,
+    // Where it was set, as file:line
+    pub location: String,
+    // How many times the program has reached it, and how many more times
+    // it'll be let past before it stops
+    pub hits: u32,
+    pub ignore: u32,
+    pub temporary: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Catch {
+    // A syscall for the service given, or for any service
+    Syscall(Option<u32>),
+    // An exception with the ExcCode given, or any exception
+    Exception(Option<u32>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Catchpoint {
+    pub id: u32,
+    pub catch: Catch,
+    pub hits: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegisterWatch {
+    pub id: u32,
+    // As it was given, like $s3 or $19
+    pub name: String,
+    pub register: Register,
+    // What it was after the last instruction
+    pub value: u32,
+    pub hits: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    catchpoints: Vec<Catchpoint>,
+    register_watches: Vec<RegisterWatch>,
+    next_id: u32,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, address: u32, location: String, temporary: bool) -> &Breakpoint {
+        self.next_id += 1;
+        self.list.push(Breakpoint { id: self.next_id, address, location, hits: 0, ignore: 0, temporary });
+        &self.list[self.list.len() - 1]
+    }
+
+    // Lets the program past a breakpoint the next `count` times it reaches
+    // it. Returns false if there's no breakpoint with that ID.
+    pub fn ignore(&mut self, id: u32, count: u32) -> bool {
+        match self.list.iter_mut().find(|breakpoint| breakpoint.id == id) {
+            Some(breakpoint) => {
+                breakpoint.ignore = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn catch(&mut self, catch: Catch) -> &Catchpoint {
+        self.next_id += 1;
+        self.catchpoints.push(Catchpoint { id: self.next_id, catch, hits: 0 });
+        &self.catchpoints[self.catchpoints.len() - 1]
+    }
+
+    pub fn watch_register(&mut self, name: String, register: Register, value: u32) -> &RegisterWatch {
+        self.next_id += 1;
+        self.register_watches.push(RegisterWatch { id: self.next_id, name, register, value, hits: 0 });
+        &self.register_watches[self.register_watches.len() - 1]
+    }
+
+    // Returns false if the register isn't being watched
+    pub fn unwatch_register(&mut self, register: Register) -> bool {
+        let length = self.register_watches.len();
+        self.register_watches.retain(|watch| watch.register != register);
+        self.register_watches.len() != length
+    }
+
+    // Returns false if there's no breakpoint, catchpoint, or watched register
+    // with that ID
+    pub fn remove(&mut self, id: u32) -> bool {
+        let length = self.list.len() + self.catchpoints.len() + self.register_watches.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.catchpoints.retain(|catchpoint| catchpoint.id != id);
+        self.register_watches.retain(|watch| watch.id != id);
+        self.list.len() + self.catchpoints.len() + self.register_watches.len() != length
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.catchpoints.clear();
+        self.register_watches.clear();
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        self.list.iter().any(|breakpoint| breakpoint.address == address)
+    }
+
+    // Counts the program reaching an address, giving back the breakpoint
+    // it should stop at, if any. One being ignored lets it past, and a
+    // temporary one is removed.
+    pub fn hit(&mut self, address: u32) -> Option<Breakpoint> {
+        let position = self.list.iter().position(|breakpoint| breakpoint.address == address)?;
+        let breakpoint = &mut self.list[position];
+        breakpoint.hits += 1;
+        if breakpoint.ignore > 0 {
+            breakpoint.ignore -= 1;
+            return None;
+        }
+
+        if breakpoint.temporary {
+            Some(self.list.remove(position))
+        } else {
+            Some(breakpoint.clone())
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn catchpoints(&self) -> impl Iterator<Item = &Catchpoint> {
+        self.catchpoints.iter()
+    }
+
+    pub fn catchpoint(&self, id: u32) -> Option<&Catchpoint> {
+        self.catchpoints.iter().find(|catchpoint| catchpoint.id == id)
+    }
+
+    pub fn register_watches(&self) -> impl Iterator<Item = &RegisterWatch> {
+        self.register_watches.iter()
+    }
+
+    pub fn register_watch(&self, id: u32) -> Option<&RegisterWatch> {
+        self.register_watches.iter().find(|watch| watch.id == id)
+    }
+
+    // Notes the watched registers' values, given in the order
+    // register_watches() has them, and counts a change to the first one
+    // that's changed, giving back its ID and what it was
+    pub fn registers_changed(&mut self, values: &[u32]) -> Option<(u32, u32)> {
+        let mut changed = None;
+        for (watch, value) in self.register_watches.iter_mut().zip(values) {
+            if watch.value != *value && changed.is_none() {
+                watch.hits += 1;
+                changed = Some((watch.id, watch.value));
+            }
+            watch.value = *value;
+        }
+        changed
+    }
+
+    // Notes the watched registers' values without counting any changes
+    pub fn note_registers(&mut self, values: &[u32]) {
+        for (watch, value) in self.register_watches.iter_mut().zip(values) {
+            watch.value = *value;
+        }
+    }
+
+    // Whether a syscall for a service would stop the program, which is
+    // asked before it's carried out
+    pub fn catches_syscall(&self, service: u32) -> bool {
+        self.catchpoints.iter().any(|catchpoint| catchpoint.catch.syscall(service))
+    }
+
+    // Counts a syscall for a service, giving back the catchpoint it should
+    // stop at, if any
+    pub fn syscall_hit(&mut self, service: u32) -> Option<&Catchpoint> {
+        let catchpoint = self.catchpoints.iter_mut().find(|catchpoint| catchpoint.catch.syscall(service))?;
+        catchpoint.hits += 1;
+        Some(catchpoint)
+    }
+
+    // Counts an exception with an ExcCode, giving back the catchpoint it
+    // should stop at, if any
+    pub fn exception_hit(&mut self, code: u32) -> Option<&Catchpoint> {
+        let catchpoint = self.catchpoints.iter_mut().find(|catchpoint| catchpoint.catch.exception(code))?;
+        catchpoint.hits += 1;
+        Some(catchpoint)
+    }
+}
+
+// As the catch command takes it, like syscall 4 or exception AdEL
+impl fmt::Display for Catch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Catch::Syscall(None) => write!(f, "syscall"),
+            Catch::Syscall(Some(service)) => write!(f, "syscall {}", service),
+            Catch::Exception(None) => write!(f, "exception"),
+            Catch::Exception(Some(code)) => match exception_name(*code).split_once(',') {
+                Some((mnemonic, _)) => write!(f, "exception {}", mnemonic),
+                None => write!(f, "exception {}", code),
+            },
+        }
+    }
+}
+
+impl Catch {
+    fn syscall(&self, service: u32) -> bool {
+        matches!(self, Catch::Syscall(caught) if caught.is_none_or(|caught| caught == service))
+    }
+
+    fn exception(&self, code: u32) -> bool {
+        matches!(self, Catch::Exception(caught) if caught.is_none_or(|caught| caught == code))
+    }
+}
+
+// The address of the first instruction on a line, or on the next line
+// after it that has one, along with the line it's on. Instructions from
+// the assembled file itself have no source file in the line info, so
+// they're matched against the program's name. A file matches by its whole
+// path or by the last parts of it, so main.asm finds src/main.asm.
+pub fn resolve(lineinfo: &HashMap<u32, LineInfo>, program_name: &str, file: &str, line: u32) -> Result<(u32, u32), String> {
+    let in_file = |info: &&LineInfo| {
+        let source = if info.source_file.is_empty() { program_name } else { &info.source_file };
+        Path::new(source).ends_with(file)
+    };
+
+    let mut lines = lineinfo.values().filter(in_file).peekable();
+    if lines.peek().is_none() {
+        return Err(format!("No code from {}", file));
+    }
+
+    lines
+        .filter(|info| info.line_number >= line)
+        .min_by_key(|info| (info.line_number, info.instr_addr))
+        .map(|info| (info.instr_addr, info.line_number))
+        .ok_or_else(|| format!("No code at or after line {} of {}", line, file))
+}
@@ -0,0 +1,207 @@
+// Coprocessor 0, which holds the registers that control exceptions and interrupts.
+// Only the registers exception handling code needs are modeled.
+
+use std::fmt::Write;
+
+pub const BAD_VADDR: usize = 8;
+pub const COUNT: usize = 9;
+pub const COMPARE: usize = 11;
+pub const STATUS: usize = 12;
+pub const CAUSE: usize = 13;
+pub const EPC: usize = 14;
+
+// The bits of each register that mtc0 can change: Status's CU, BEV, IM,
+// UM, ERL, EXL, and IE fields, and Cause's two software interrupt bits.
+// BadVAddr is read-only.
+const STATUS_WRITABLE: u32 = 0xF040FF1F;
+const CAUSE_WRITABLE: u32 = 0x00000300;
+
+// Status fields
+const STATUS_IE: u32 = 1 << 0;
+const STATUS_EXL: u32 = 1 << 1;
+const STATUS_ERL: u32 = 1 << 2;
+const STATUS_UM: u32 = 1 << 4;
+const STATUS_IM: u32 = 0xFF << 8;
+
+// Cause fields
+const CAUSE_BD: u32 = 1 << 31;
+const CAUSE_TI: u32 = 1 << 30;
+const CAUSE_IP: u32 = 0xFF << 8;
+// The timer raises hardware interrupt 5, the highest priority IP bit
+const CAUSE_IP_TIMER: u32 = 1 << 15;
+// The keyboard raises IP bit 0, where MARS puts it
+pub const CAUSE_IP_KEYBOARD: u32 = 1 << 8;
+const CAUSE_EXC_CODE: u32 = 0b11111 << 2;
+
+// Status at reset, as in MARS: user mode with interrupts enabled and unmasked
+const STATUS_RESET: u32 = 0x0000FF11;
+
+#[derive(Debug, Clone)]
+pub struct Coprocessor0 {
+    pub bad_vaddr: u32,
+    pub count: u32,
+    pub compare: u32,
+    pub status: u32,
+    pub cause: u32,
+    pub epc: u32,
+    // The IP bits devices are raising. They're kept apart from Cause so a
+    // device sharing a bit with a software interrupt can't clear it.
+    device_interrupts: u32,
+}
+
+impl Default for Coprocessor0 {
+    fn default() -> Self {
+        Self {
+            bad_vaddr: 0,
+            count: 0,
+            compare: 0,
+            status: STATUS_RESET,
+            cause: 0,
+            epc: 0,
+            device_interrupts: 0,
+        }
+    }
+}
+
+impl Coprocessor0 {
+    // Reads a register for mfc0, or None if it isn't modeled
+    pub fn read(&self, reg: usize) -> Option<u32> {
+        match reg {
+            BAD_VADDR => Some(self.bad_vaddr),
+            COUNT => Some(self.count),
+            COMPARE => Some(self.compare),
+            STATUS => Some(self.status),
+            CAUSE => Some(self.cause | self.device_interrupts),
+            EPC => Some(self.epc),
+            _ => None,
+        }
+    }
+
+    // Writes a register for mtc0, changing only its writable bits. Returns
+    // false if the register isn't modeled.
+    pub fn write(&mut self, reg: usize, value: u32) -> bool {
+        match reg {
+            BAD_VADDR => (),
+            COUNT => self.count = value,
+            // Writing Compare acknowledges the timer interrupt
+            COMPARE => {
+                self.compare = value;
+                self.cause &= !(CAUSE_TI | CAUSE_IP_TIMER);
+            }
+            STATUS => self.status = self.status & !STATUS_WRITABLE | value & STATUS_WRITABLE,
+            CAUSE => self.cause = self.cause & !CAUSE_WRITABLE | value & CAUSE_WRITABLE,
+            EPC => self.epc = value,
+            _ => return false,
+        }
+        true
+    }
+
+    // Advances Count by one instruction. The timer interrupt is raised when
+    // Count reaches Compare, and stays pending until Compare is written.
+    pub fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.cause |= CAUSE_TI | CAUSE_IP_TIMER;
+        }
+    }
+
+    // Raises or lowers an interrupt for a device, which keeps it raised for
+    // as long as it needs attention
+    pub fn set_interrupt(&mut self, line: u32, raised: bool) {
+        self.device_interrupts = self.device_interrupts & !line | if raised { line } else { 0 };
+    }
+
+    // Whether an interrupt should be taken before the next instruction: one
+    // is pending and unmasked, interrupts are enabled, and no exception is
+    // already being handled
+    pub fn interrupt_pending(&self) -> bool {
+        self.status & STATUS_IE != 0
+            && self.status & (STATUS_EXL | STATUS_ERL) == 0
+            && (self.cause | self.device_interrupts) & self.status & CAUSE_IP & STATUS_IM != 0
+    }
+
+    // The processor runs in kernel mode while handling an exception, or
+    // whenever Status says it isn't in user mode
+    pub fn kernel_mode(&self) -> bool {
+        self.status & (STATUS_EXL | STATUS_ERL) != 0 || self.status & STATUS_UM == 0
+    }
+
+    // Records an exception and enters kernel mode. EPC holds the instruction
+    // to return to, which is the branch when the exception was in its delay
+    // slot. An exception taken inside a handler leaves EPC alone, so the
+    // handler can still return to the original instruction.
+    pub fn enter_exception(&mut self, code: u32, epc: u32, delay_slot: bool) {
+        self.cause = self.cause & !CAUSE_EXC_CODE | code << 2 & CAUSE_EXC_CODE;
+        if self.status & STATUS_EXL == 0 {
+            self.epc = epc;
+            self.cause = self.cause & !CAUSE_BD | if delay_slot { CAUSE_BD } else { 0 };
+        }
+        self.status |= STATUS_EXL;
+    }
+
+    // Leaves the exception handler for eret, returning where execution resumes
+    pub fn return_from_exception(&mut self) -> u32 {
+        self.status &= !STATUS_EXL;
+        self.epc
+    }
+
+    // The registers with their fields taken apart, to say what the last
+    // exception was and where it happened
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let cause = self.cause | self.device_interrupts;
+        let code = (cause & CAUSE_EXC_CODE) >> 2;
+        let flag = |set: bool, name: &'static str| set.then_some(name);
+
+        let status_flags: Vec<&str> = [
+            flag(self.status & STATUS_IE != 0, "interrupts enabled"),
+            flag(self.status & STATUS_EXL != 0, "handling an exception (EXL)"),
+            flag(self.status & STATUS_ERL != 0, "error level (ERL)"),
+            Some(if self.kernel_mode() { "kernel mode" } else { "user mode" }),
+        ].into_iter().flatten().collect();
+        let _ = writeln!(summary, "Status    0x{:08x}  {}, interrupt mask 0b{:08b}", self.status, status_flags.join(", "), (self.status & STATUS_IM) >> 8);
+
+        let cause_flags: Vec<&str> = [
+            flag(cause & CAUSE_BD != 0, "in a delay slot (BD)"),
+            flag(cause & CAUSE_TI != 0, "timer interrupt (TI)"),
+        ].into_iter().flatten().collect();
+        let mut cause_line = format!("Cause     0x{:08x}  ExcCode {} ({}), pending interrupts 0b{:08b}", cause, code, exception_name(code), (cause & CAUSE_IP) >> 8);
+        if !cause_flags.is_empty() {
+            cause_line.push_str(&format!(", {}", cause_flags.join(", ")));
+        }
+        let _ = writeln!(summary, "{}", cause_line);
+
+        // A delay slot's exception returns to the branch before it
+        let epc_note = if cause & CAUSE_BD != 0 { ", the branch before the instruction that raised it" } else { "" };
+        let _ = writeln!(summary, "EPC       0x{:08x}{}", self.epc, epc_note);
+        let _ = writeln!(summary, "BadVAddr  0x{:08x}", self.bad_vaddr);
+        summary
+    }
+}
+
+// What each ExcCode means, by the MIPS32 names
+pub fn exception_name(code: u32) -> &'static str {
+    match code {
+        0 => "Int, interrupt",
+        1 => "Mod, TLB modification",
+        2 => "TLBL, TLB miss on a load or fetch",
+        3 => "TLBS, TLB miss on a store",
+        4 => "AdEL, address error on a load or fetch",
+        5 => "AdES, address error on a store",
+        6 => "IBE, bus error on a fetch",
+        7 => "DBE, bus error on a load or store",
+        8 => "Sys, syscall",
+        9 => "Bp, breakpoint",
+        10 => "RI, reserved instruction",
+        11 => "CpU, coprocessor unusable",
+        12 => "Ov, arithmetic overflow",
+        13 => "Tr, trap",
+        15 => "FPE, floating-point exception",
+        _ => "reserved",
+    }
+}
+
+// The ExcCode with a MIPS32 name, like AdEL, in any case
+pub fn exception_code(name: &str) -> Option<u32> {
+    (0..32).find(|code| exception_name(*code).split_once(',').is_some_and(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(name)))
+}
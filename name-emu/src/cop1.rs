@@ -0,0 +1,319 @@
+// Coprocessor 1, the floating-point unit.
+// Registers hold raw bits. A double occupies an even/odd register pair,
+// with its low word in the even register.
+//
+// Arithmetic follows IEEE 754-2008: NaNs with the top fraction bit set are
+// quiet, and results are rounded to nearest. That's the only rounding mode
+// implemented, so selecting another is an Unimplemented Operation.
+
+use std::fmt::Write;
+
+use crate::exception::ExecutionErrors;
+
+// FCSR fields
+const FCSR_RM: u32 = 0b11;
+const FCSR_FLAGS: u32 = 0b11111 << 2;
+const FCSR_ENABLES: u32 = 0b11111 << 7;
+const FCSR_CAUSE: u32 = 0b111111 << 12;
+const FCSR_FS: u32 = 1 << 24;
+const FCSR_FCC: u32 = 0b1111111 << 25 | 1 << 23;
+
+// The IEEE exceptions, as they sit in the flags field. The enables field
+// sits 5 bits above the flags, and cause 5 bits above that. Cause alone has
+// an extra bit for Unimplemented Operation, which can't be disabled.
+pub const FP_INEXACT: u32 = 1 << 2;
+pub const FP_UNDERFLOW: u32 = 1 << 3;
+pub const FP_OVERFLOW: u32 = 1 << 4;
+pub const FP_DIVIDE_BY_ZERO: u32 = 1 << 5;
+pub const FP_INVALID: u32 = 1 << 6;
+const FP_UNIMPLEMENTED: u32 = 1 << 17;
+
+// Every FCSR bit a program can write; the rest are reserved and read as zero
+const FCSR_WRITABLE: u32 = FCSR_RM | FCSR_FLAGS | FCSR_ENABLES | FCSR_CAUSE | FCSR_FS | FCSR_FCC;
+
+// Implementation register: single, double, word, and long formats are supported
+const FIR_VALUE: u32 = 1 << 16 | 1 << 17 | 1 << 20 | 1 << 21;
+
+// The format field of an arithmetic instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Single,
+    Double,
+}
+
+impl Format {
+    pub fn from_field(fmt: u8) -> Option<Format> {
+        match fmt {
+            0x10 => Some(Format::Single),
+            0x11 => Some(Format::Double),
+            _ => None,
+        }
+    }
+
+    // How many registers a value of this format occupies
+    pub fn width(&self) -> usize {
+        match self {
+            Format::Single => 1,
+            Format::Double => 2,
+        }
+    }
+
+    // Rounds an exact (or double-precision) result to this format
+    pub fn round(&self, value: f64) -> f64 {
+        match self {
+            Format::Single => value as f32 as f64,
+            Format::Double => value,
+        }
+    }
+
+    fn min_normal(&self) -> f64 {
+        match self {
+            Format::Single => f32::MIN_POSITIVE as f64,
+            Format::Double => f64::MIN_POSITIVE,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Coprocessor1 {
+    pub regs: [u32; 32],
+    // Floating-point control and status register
+    pub fcsr: u32,
+}
+
+impl Coprocessor1 {
+    // Reads one of the eight condition codes.
+    // Code 0 sits at bit 23, and codes 1-7 at bits 25-31.
+    pub fn condition(&self, cc: usize) -> bool {
+        self.fcsr >> Self::condition_bit(cc) & 1 == 1
+    }
+
+    pub fn set_condition(&mut self, cc: usize, value: bool) {
+        let bit = Self::condition_bit(cc);
+        self.fcsr = self.fcsr & !(1 << bit) | (value as u32) << bit;
+    }
+
+    fn condition_bit(cc: usize) -> usize {
+        if cc == 0 { 23 } else { 24 + cc }
+    }
+
+    // Reads a control register by its cfc1 number, or None if it doesn't exist
+    pub fn read_control(&self, fcr: usize) -> Option<u32> {
+        match fcr {
+            // FIR
+            0 => Some(FIR_VALUE),
+            // FCCR: the condition codes, packed together
+            25 => Some((self.fcsr >> 24 & 0b11111110) | (self.fcsr >> 23 & 1)),
+            // FEXR: cause and flags
+            26 => Some(self.fcsr & (FCSR_CAUSE | FCSR_FLAGS)),
+            // FENR: enables, flush-to-zero, and rounding mode
+            28 => Some((self.fcsr & (FCSR_ENABLES | FCSR_RM)) | (self.fcsr >> 22 & 0b100)),
+            31 => Some(self.fcsr),
+            _ => None,
+        }
+    }
+
+    // Writes a control register by its ctc1 number, returning false if it
+    // doesn't exist or is read-only. A write selecting a rounding mode other
+    // than round to nearest changes nothing but the cause field, where it
+    // leaves an Unimplemented Operation for the caller to raise.
+    pub fn write_control(&mut self, fcr: usize, value: u32) -> bool {
+        let (mask, bits) = match fcr {
+            25 => (FCSR_FCC, (value & 0b11111110) << 24 | (value & 1) << 23),
+            26 => (FCSR_CAUSE | FCSR_FLAGS, value),
+            28 => (FCSR_ENABLES | FCSR_RM | FCSR_FS, value | (value & 0b100) << 22),
+            31 => (FCSR_WRITABLE, value),
+            _ => return false,
+        };
+
+        if bits & mask & FCSR_RM != 0 {
+            self.fcsr = self.fcsr & !FCSR_CAUSE | FP_UNIMPLEMENTED;
+        } else {
+            self.fcsr = self.fcsr & !mask | bits & mask;
+        }
+        true
+    }
+
+    // Whether a cause bit is set whose exception is enabled, which raises a
+    // floating-point exception. Unimplemented Operation is always enabled.
+    pub fn pending_exception(&self) -> bool {
+        let enabled = (self.fcsr & FCSR_ENABLES) << 5 | FP_UNIMPLEMENTED;
+        self.fcsr & FCSR_CAUSE & enabled != 0
+    }
+
+    // Reads a register as a value of the given format. Singles widen to f64 exactly.
+    pub fn read(&self, fmt: Format, reg: usize) -> f64 {
+        match fmt {
+            Format::Single => f32::from_bits(self.regs[reg]) as f64,
+            Format::Double => f64::from_bits(self.read_double_bits(reg)),
+        }
+    }
+
+    pub fn write(&mut self, fmt: Format, reg: usize, value: f64) {
+        match fmt {
+            Format::Single => self.regs[reg] = (value as f32).to_bits(),
+            Format::Double => {
+                let bits = value.to_bits();
+                self.regs[reg] = bits as u32;
+                self.regs[(reg + 1) % 32] = (bits >> 32) as u32;
+            }
+        }
+    }
+
+    fn read_double_bits(&self, reg: usize) -> u64 {
+        (self.regs[(reg + 1) % 32] as u64) << 32 | self.regs[reg] as u64
+    }
+
+    // Signaling NaNs have to be recognized from their bits, since widening
+    // a single quiets them
+    pub fn is_signaling_nan(&self, fmt: Format, reg: usize) -> bool {
+        match fmt {
+            Format::Single => {
+                let bits = self.regs[reg];
+                f32::from_bits(bits).is_nan() && bits & (1 << 22) == 0
+            }
+            Format::Double => {
+                let bits = self.read_double_bits(reg);
+                f64::from_bits(bits).is_nan() && bits & (1 << 51) == 0
+            }
+        }
+    }
+
+    // Records the IEEE exceptions an operation raised in the cause field,
+    // replacing what was there. If any of them is enabled, the exception is
+    // taken and the operation must not write its result. Otherwise they
+    // accumulate in the flags field.
+    pub fn signal(&mut self, exceptions: u32) -> Result<(), ExecutionErrors> {
+        self.fcsr = self.fcsr & !FCSR_CAUSE | (exceptions << 10 & FCSR_CAUSE);
+        if self.pending_exception() {
+            return Err(ExecutionErrors::FloatingPoint { fcsr: self.fcsr });
+        }
+        self.fcsr |= exceptions & FCSR_FLAGS;
+        Ok(())
+    }
+
+    // Carries out an arithmetic instruction on the given operand registers.
+    // `compute` returns the result along with any exceptions it detected
+    // itself, such as an invalid operation or division by zero, and `exact`
+    // whether a rounded result is exactly the true result. NaN operands,
+    // rounding, overflow, and underflow are handled here, the same way for
+    // every operation.
+    pub fn operation(
+        &mut self,
+        fmt: Format,
+        fd: usize,
+        operands: &[usize],
+        compute: impl Fn(&[f64]) -> (f64, u32),
+        exact: impl Fn(&[f64], f64) -> bool,
+    ) -> Result<(), ExecutionErrors> {
+        let values: Vec<f64> = operands.iter().map(|reg| self.read(fmt, *reg)).collect();
+
+        let (result, exceptions) = if operands.iter().any(|reg| self.is_signaling_nan(fmt, *reg)) {
+            (f64::NAN, FP_INVALID)
+        } else if let Some(nan) = values.iter().find(|x| x.is_nan()) {
+            (*nan, 0)
+        } else {
+            let (unrounded, mut exceptions) = compute(&values);
+            let result = fmt.round(unrounded);
+
+            if result.is_nan() {
+                // Only an invalid operation makes a NaN out of numbers
+                exceptions |= FP_INVALID;
+            } else if result.is_infinite() {
+                if values.iter().all(|x| x.is_finite()) && exceptions & FP_DIVIDE_BY_ZERO == 0 {
+                    exceptions |= FP_OVERFLOW | FP_INEXACT;
+                }
+            } else if !exact(&values, result) {
+                exceptions |= FP_INEXACT;
+                if result.abs() < fmt.min_normal() {
+                    exceptions |= FP_UNDERFLOW;
+                }
+            }
+
+            // Invalid operations always produce the default NaN
+            if exceptions & FP_INVALID != 0 {
+                (f64::NAN, exceptions)
+            } else {
+                (result, exceptions)
+            }
+        };
+
+        self.signal(exceptions)?;
+        self.write(fmt, fd, result);
+        Ok(())
+    }
+
+    // Carries out c.cond.fmt. The low three bits of the condition pick which
+    // of unordered, equal, and less than make it true. The fourth makes even
+    // quiet NaNs an Invalid Operation, as signaling NaNs always are.
+    pub fn compare(
+        &mut self,
+        fmt: Format,
+        cc: usize,
+        fs: usize,
+        ft: usize,
+        condition: u8,
+    ) -> Result<(), ExecutionErrors> {
+        let (x, y) = (self.read(fmt, fs), self.read(fmt, ft));
+        let unordered = x.is_nan() || y.is_nan();
+
+        let signaling = self.is_signaling_nan(fmt, fs) || self.is_signaling_nan(fmt, ft);
+        let exceptions = if signaling || (unordered && condition & 0b1000 != 0) {
+            FP_INVALID
+        } else {
+            0
+        };
+        self.signal(exceptions)?;
+
+        let result = (condition & 0b100 != 0 && x < y)
+            || (condition & 0b10 != 0 && x == y)
+            || (condition & 0b1 != 0 && unordered);
+        self.set_condition(cc, result);
+        Ok(())
+    }
+
+    // Every register as its bits, as a single, and, for even registers, as
+    // the double in it and the one after, then FCSR taken apart
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let _ = writeln!(summary, "{:<6}{:<12}{:<16}Double", "", "Bits", "Single");
+        for (reg, bits) in self.regs.iter().enumerate() {
+            let name = format!("$f{}", reg);
+            let single = format!("{:?}", f32::from_bits(*bits));
+            let double = if reg % 2 == 0 { format!("{:?}", f64::from_bits(self.read_double_bits(reg))) } else { String::new() };
+            let line = format!("{:<6}0x{:08x}  {:<16}{}", name, bits, single, double);
+            let _ = writeln!(summary, "{}", line.trim_end());
+        }
+
+        let rounding = match self.fcsr & FCSR_RM {
+            0 => "to nearest (RN)",
+            1 => "toward zero (RZ)",
+            2 => "toward +infinity (RP)",
+            _ => "toward -infinity (RM)",
+        };
+        let conditions: Vec<String> = (0..8).map(|cc| format!("{}={}", cc, self.condition(cc) as u8)).collect();
+        let _ = writeln!(summary, "FCSR  0x{:08x}", self.fcsr);
+        let _ = writeln!(summary, "  Rounding mode: {}", rounding);
+        let _ = writeln!(summary, "  Flags: {}", exception_names(self.fcsr & FCSR_FLAGS));
+        let _ = writeln!(summary, "  Enables: {}", exception_names((self.fcsr & FCSR_ENABLES) >> 5));
+        let _ = writeln!(summary, "  Cause: {}", exception_names((self.fcsr & FCSR_CAUSE) >> 10));
+        let _ = writeln!(summary, "  Flush to zero: {}", if self.fcsr & FCSR_FS != 0 { "on" } else { "off" });
+        let _ = writeln!(summary, "  Condition codes: {}", conditions.join(" "));
+        summary
+    }
+}
+
+// The IEEE exceptions set in a field, lined up with the flags field, or
+// "none". Unimplemented Operation only shows up from the cause field.
+fn exception_names(field: u32) -> String {
+    let names = [
+        (FP_INEXACT, "inexact"),
+        (FP_UNDERFLOW, "underflow"),
+        (FP_OVERFLOW, "overflow"),
+        (FP_DIVIDE_BY_ZERO, "divide by zero"),
+        (FP_INVALID, "invalid"),
+        (FP_UNIMPLEMENTED >> 10, "unimplemented"),
+    ];
+    let set: Vec<&str> = names.iter().filter(|(bit, _)| field & bit != 0).map(|(_, name)| *name).collect();
+    if set.is_empty() { "none".into() } else { set.join(", ") }
+}
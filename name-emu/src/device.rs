@@ -0,0 +1,33 @@
+// Memory-mapped devices. Each one owns a range of addresses in the MMIO
+// region at the top of memory, and loads and stores there go to the device
+// instead of to memory. Devices see every access a byte at a time, so a
+// word access reaches a register's bytes in order.
+
+use std::fmt::Debug;
+use std::ops::Range;
+
+// Where device registers begin. Nothing else is mapped above here.
+pub const MMIO_START_ADDRESS: u32 = 0xFFFF0000;
+
+pub trait Device: Debug {
+    // The addresses the device's registers occupy
+    fn range(&self) -> Range<u32>;
+
+    // Loads a byte from one of the device's registers
+    fn read(&mut self, address: u32) -> u8;
+
+    // Stores a byte to one of the device's registers
+    fn write(&mut self, address: u32, value: u8);
+
+    // The Cause IP bit the device interrupts on. Devices that interrupt
+    // each need one of their own.
+    fn interrupt_line(&self) -> u32 {
+        0
+    }
+
+    // Runs before every instruction, so the device can make progress.
+    // Returns whether it's raising its interrupt.
+    fn tick(&mut self) -> bool {
+        false
+    }
+}
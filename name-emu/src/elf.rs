@@ -0,0 +1,174 @@
+// Executables from other toolchains, like gcc and binutils targeting
+// mipsel, loaded as the system would load them: each loadable segment goes
+// where the executable says, with whatever of it isn't in the file (.bss)
+// zeroed, and the program starts at the executable's entry point.
+//
+// Only statically linked, little-endian, 32-bit executables can be run,
+// since NAME's memory is little-endian and there's no dynamic linker. The
+// program can only use NAME's own syscalls, so it has to be built without
+// the C library, with -nostdlib and a _start of its own.
+
+use name_const::symbols::{SymbolBinding, SymbolInfo};
+
+use crate::mips::Mips;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+// Register information, including where $gp should point
+const PT_MIPS_REGINFO: u32 = 0x70000000;
+// The gp value is the last word of the register information
+const REGINFO_GP_OFFSET: usize = 20;
+
+// Segment flags
+const PF_W: u32 = 0x2;
+
+// The symbol table, and the size of each symbol in it
+const SHT_SYMTAB: u32 = 2;
+const SYMBOL_SIZE: usize = 16;
+// Symbol types and bindings worth naming addresses by. Sections and files
+// have symbols too.
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+#[derive(Debug)]
+pub struct Segment {
+    pub address: u32,
+    // What's in the file. The rest of the segment, up to its size, is zeros.
+    pub contents: Vec<u8>,
+    pub size: u32,
+    pub writable: bool,
+}
+
+#[derive(Debug)]
+pub struct Executable {
+    pub entry: u32,
+    // Where $gp starts, for code that addresses small data through it
+    pub gp: Option<u32>,
+    pub segments: Vec<Segment>,
+    // From the symbol table, which a stripped executable doesn't have
+    pub symbols: Vec<SymbolInfo>,
+}
+
+// Whether a file is an ELF file, and so should be loaded as one rather
+// than as name-as's output
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+pub fn parse(image: &[u8]) -> Result<Executable, String> {
+    let byte = |offset: usize| image.get(offset).copied().ok_or("The executable is truncated");
+    let half = |offset: usize| image.get(offset..offset + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]])).ok_or("The executable is truncated");
+    let word = |offset: usize| image.get(offset..offset + 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok_or("The executable is truncated");
+
+    if !is_elf(image) {
+        return Err("Not an ELF file".into());
+    }
+    if byte(4)? != ELFCLASS32 {
+        return Err("Only 32-bit executables can be run".into());
+    }
+    if byte(5)? != ELFDATA2LSB {
+        return Err("Only little-endian executables can be run. Build for mipsel, or with -EL.".into());
+    }
+    if half(18)? != EM_MIPS {
+        return Err("The executable isn't for MIPS".into());
+    }
+    if half(16)? != ET_EXEC {
+        return Err("Only executables can be run, not object files or shared libraries".into());
+    }
+
+    let entry = word(24)?;
+    let (table, entry_size, count) = (word(28)? as usize, half(42)? as usize, half(44)? as usize);
+
+    let mut executable = Executable { entry, gp: None, segments: vec![], symbols: vec![] };
+    for i in 0..count {
+        let header = table + i * entry_size;
+        let (kind, offset, address, file_size, size, flags) = (word(header)?, word(header + 4)? as usize, word(header + 8)?, word(header + 16)? as usize, word(header + 20)?, word(header + 24)?);
+
+        match kind {
+            PT_LOAD => {
+                if file_size > size as usize {
+                    return Err(format!("The segment at 0x{:08x} is bigger in the file than in memory", address));
+                }
+                let contents = image.get(offset..offset + file_size).ok_or("The executable is truncated")?.to_vec();
+                executable.segments.push(Segment { address, contents, size, writable: flags & PF_W != 0 });
+            }
+            PT_DYNAMIC | PT_INTERP => return Err("Only statically linked executables can be run".into()),
+            PT_MIPS_REGINFO => executable.gp = Some(word(offset + REGINFO_GP_OFFSET)?),
+            _ => (),
+        }
+    }
+
+    let (sections, section_size, section_count) = (word(32)? as usize, half(46)? as usize, half(48)? as usize);
+    for i in 0..section_count {
+        let header = sections + i * section_size;
+        if word(header + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let (offset, size) = (word(header + 16)? as usize, word(header + 20)? as usize);
+        // The names are in the string table the symbol table links to
+        let strings = word(sections + word(header + 24)? as usize * section_size + 16)? as usize;
+
+        for symbol in (offset..offset + size).step_by(SYMBOL_SIZE) {
+            let (name, address, info, section) = (word(symbol)? as usize, word(symbol + 4)?, byte(symbol + 12)?, half(symbol + 14)?);
+            // Section 0 means the symbol isn't defined here
+            if !matches!(info & 0xF, 0 | STT_OBJECT | STT_FUNC) || section == 0 {
+                continue;
+            }
+            let name = image.get(strings + name..).and_then(|rest| rest.split(|byte| *byte == 0).next()).ok_or("The executable is truncated")?;
+            if name.is_empty() {
+                continue;
+            }
+            let binding = match info >> 4 {
+                STB_GLOBAL => SymbolBinding::Global,
+                STB_WEAK => SymbolBinding::Weak,
+                _ => SymbolBinding::Local,
+            };
+            executable.symbols.push(SymbolInfo { name: String::from_utf8_lossy(name).into_owned(), address, binding });
+        }
+    }
+
+    Ok(executable)
+}
+
+impl Executable {
+    // Puts the executable's segments in memory and starts it at its entry
+    // point. Segments it doesn't mark writable are write protected when
+    // `protect` is set. The heap and stack are where the machine's layout
+    // puts them, so segments can't overlap them.
+    pub fn load(&self, mips: &mut Mips, protect: bool) -> Result<(), String> {
+        let layout = mips.layout;
+        let reserved = [("heap", layout.heap_base, layout.heap_size), ("stack", layout.stack_base, layout.stack_size)];
+
+        for segment in &self.segments {
+            let (start, end) = (segment.address as u64, segment.address as u64 + segment.size as u64);
+            for (name, base, size) in reserved {
+                if start < base as u64 + size as u64 && (base as u64) < end {
+                    return Err(format!("The segment at 0x{:08x} overlaps the {}", segment.address, name));
+                }
+            }
+
+            mips.memory.map(segment.address, segment.size, &segment.contents);
+            mips.memory.resize(segment.address, segment.size);
+            if protect && !segment.writable {
+                mips.memory.protect(segment.address, false);
+            }
+        }
+
+        if let Some(gp) = self.gp {
+            mips.regs[28] = gp;
+        }
+        mips.pc = self.entry as usize;
+        // An executable only ends by exiting, not by running off its end
+        mips.stop_address = usize::MAX;
+        Ok(())
+    }
+}
@@ -15,9 +15,31 @@ pub enum ExecutionErrors {
     // The program attempted to read from an area for which no valid range existed.
     MemoryIllegalAccess { load_address: u32 },
 
+    // A load or store to an address not aligned to the width of the access
+    AddressExceptionLoad { address: u32 },
+    AddressExceptionStore { address: u32 },
+    // A store into memory the program can't write, like its own instructions
+    WriteProtected { address: u32 },
+
     UndefinedInstruction { instruction: u32 },
     // Can also refer to underflow
     IntegerOverflow { rt: usize, rs: usize, value1: u32, value2: u32 },
+    // A trap instruction's condition held
+    Trap { instruction: u32 },
+    // A floating-point exception was enabled when its cause bit was set
+    FloatingPoint { fcsr: u32 },
+    // A syscall asked for a service that doesn't exist
+    UnknownSyscall { service: u32 },
+    // A syscall that reads from the console didn't get what it expected
+    InvalidInput { service: u32 },
+    // A syscall was given an argument it can't work with
+    InvalidArgument { service: u32 },
+    // sbrk asked for more memory than the heap has room for
+    HeapExhausted { requested: u32 },
+    // $sp went below the stack's limit
+    StackOverflow { sp: u32 },
+    // A store into the stack below $sp, where no frame has been allocated
+    StoreBelowStackPointer { address: u32, sp: u32 },
 
     Event { event: ExecutionEvents }
 }
@@ -26,9 +48,45 @@ pub enum ExecutionErrors {
 #[derive(PartialEq, Copy, Clone)]
 pub enum ExecutionEvents {
     // The program is done executing.
-    ProgramComplete
+    ProgramComplete,
+    // The program ran as many instructions as it was allowed to, and was stopped
+    StepLimit,
+    // The last instruction read or wrote a watched address
+    Watchpoint { address: u32, store: bool },
+    // The program reached a breakpoint, and stopped before running it
+    Breakpoint { id: u32 },
+    // A catchpoint caught a syscall before it ran, or an exception as the
+    // program's handler took it
+    Catchpoint { id: u32 },
+    // The last instruction changed a watched register
+    RegisterWatchpoint { id: u32, old: u32, new: u32 },
+}
 
-    // Eventually instruction/data/etc. breakpoints will go here too
+impl ExecutionErrors {
+    // The ExcCode that reports this error to a program's exception handler,
+    // or None for the errors that only the debugger sees
+    pub fn exception_code(&self) -> Option<u32> {
+        match self {
+            ExecutionErrors::AddressExceptionLoad { .. } => Some(4),
+            ExecutionErrors::AddressExceptionStore { .. } | ExecutionErrors::WriteProtected { .. } => Some(5),
+            ExecutionErrors::UndefinedInstruction { .. } => Some(10),
+            ExecutionErrors::IntegerOverflow { .. } => Some(12),
+            ExecutionErrors::Trap { .. } => Some(13),
+            ExecutionErrors::FloatingPoint { .. } => Some(15),
+            ExecutionErrors::UnknownSyscall { .. } => Some(8),
+            _ => None,
+        }
+    }
+
+    // The address that caused an address error, which goes in BadVAddr
+    pub fn bad_address(&self) -> Option<u32> {
+        match self {
+            ExecutionErrors::AddressExceptionLoad { address }
+            | ExecutionErrors::AddressExceptionStore { address }
+            | ExecutionErrors::WriteProtected { address } => Some(*address),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for ExecutionErrors {
@@ -76,6 +134,26 @@ pub fn exception_pretty_print(reason: Result<(), ExecutionErrors>) -> ExceptionI
             ), 
             type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
         },
+        ExecutionErrors::AddressExceptionLoad { address } | ExecutionErrors::AddressExceptionStore { address } =>
+        ExceptionInfoResponse { 
+            exception_id: "Address Error".into(), 
+            description: Some("The program attempted to load or store a halfword or word at an address that isn't a multiple of its size. Use lwl/lwr or swl/swr for unaligned words.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Access location: {:x}", address)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::WriteProtected { address } =>
+        ExceptionInfoResponse { 
+            exception_id: "Write to Text".into(), 
+            description: Some("The program attempted to store over its own instructions. Self-modifying code must be allowed with --self-modifying-code.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Access location: {:x}", address)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
         ExecutionErrors::UndefinedInstruction { instruction } =>
         ExceptionInfoResponse { 
             exception_id: "Undefined Instruction".into(), 
@@ -96,6 +174,86 @@ pub fn exception_pretty_print(reason: Result<(), ExecutionErrors>) -> ExceptionI
             ), 
             type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
         },
+        ExecutionErrors::Trap { instruction } =>
+        ExceptionInfoResponse { 
+            exception_id: "Trap".into(), 
+            description: Some("The program executed a trap instruction whose condition was true.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Instruction: {:x}", instruction)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::FloatingPoint { fcsr } =>
+        ExceptionInfoResponse { 
+            exception_id: "Floating-Point Exception".into(), 
+            description: Some("A floating-point exception occurred while it was enabled in the FCSR. The cause field shows which.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("FCSR: {:x}", fcsr)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::UnknownSyscall { service } =>
+        ExceptionInfoResponse { 
+            exception_id: "Unknown Syscall".into(), 
+            description: Some("The program executed a syscall, but the service number in $v0 isn't one NAME provides.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Service: {}", service)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::InvalidInput { service } =>
+        ExceptionInfoResponse { 
+            exception_id: "Invalid Input".into(), 
+            description: Some("The program asked for input from the console, but what was entered couldn't be read as the requested type.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Service: {}", service)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::InvalidArgument { service } =>
+        ExceptionInfoResponse { 
+            exception_id: "Invalid Syscall Argument".into(), 
+            description: Some("The program passed a syscall an argument outside the range it accepts.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Service: {}", service)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::HeapExhausted { requested } =>
+        ExceptionInfoResponse { 
+            exception_id: "Out of Memory".into(), 
+            description: Some("The program called sbrk for more memory than is left in the heap.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Requested: {:x} bytes", requested)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::StackOverflow { sp } =>
+        ExceptionInfoResponse { 
+            exception_id: "Stack Overflow".into(), 
+            description: Some("The program moved $sp below the bottom of the stack. This usually means recursion that never stops, or a frame too big for the stack.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("$sp would have been: {:x}", sp)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
+        ExecutionErrors::StoreBelowStackPointer { address, sp } =>
+        ExceptionInfoResponse { 
+            exception_id: "Store Below Stack Pointer".into(), 
+            description: Some("The program stored below $sp, into stack space it hasn't allocated. Move $sp down to make room for the frame before storing into it.".into()), 
+            break_mode: ExceptionBreakMode::Always, 
+            details: Some(ExceptionDetails { 
+                message: Some( format!("Access location: {:x}\n$sp: {:x}", address, sp)
+            ), 
+            type_name: None, full_type_name: None, evaluate_name: None, stack_trace: None, inner_exception: None })
+        },
             
     }
     }
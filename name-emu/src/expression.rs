@@ -0,0 +1,159 @@
+// Expressions typed into the debugger, like $t0, *($sp + 8), or buffer+4.
+// They're made of registers, numbers, and labels, added and subtracted,
+// and * reads the word at an address. Reading memory this way doesn't
+// disturb devices or watchpoints, so an expression can be evaluated as
+// often as it's shown. Registers can be changed by the same names.
+
+use name_const::symbols::SymbolInfo;
+
+use crate::mips::{Mips, HI_NAME, LO_NAME, PC_NAME, REGISTER_NAMES};
+use crate::symbols::named;
+
+pub fn evaluate(mips: &Mips, symbols: &[SymbolInfo], expression: &str) -> Result<u32, String> {
+    let mut parser = Parser { mips, symbols, text: expression, position: 0 };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    match parser.rest().chars().next() {
+        None => Ok(value),
+        Some(unexpected) => Err(format!("Unexpected {} in {}", unexpected, expression)),
+    }
+}
+
+struct Parser<'a> {
+    mips: &'a Mips,
+    symbols: &'a [SymbolInfo],
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &str {
+        &self.text[self.position..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.position = self.text.len() - self.rest().trim_start().len();
+    }
+
+    // Takes the given character if it's next
+    fn take(&mut self, expected: char) -> bool {
+        self.skip_spaces();
+        let taken = self.rest().starts_with(expected);
+        if taken {
+            self.position += expected.len_utf8();
+        }
+        taken
+    }
+
+    // Takes the name or number that's next, if there is one
+    fn word(&mut self) -> &'a str {
+        self.skip_spaces();
+        let start = self.position;
+        let length = self.rest().find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$' || c == '.')).unwrap_or(self.rest().len());
+        self.position += length;
+        &self.text[start..self.position]
+    }
+
+    // Terms added and subtracted, left to right
+    fn sum(&mut self) -> Result<u32, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.take('+') {
+                value = value.wrapping_add(self.term()?);
+            } else if self.take('-') {
+                value = value.wrapping_sub(self.term()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<u32, String> {
+        if self.take('*') {
+            let address = self.term()?;
+            return self.mips.memory.peek_u32(address).ok_or_else(|| format!("Nothing is at 0x{:08x}", address));
+        }
+        if self.take('(') {
+            let value = self.sum()?;
+            return if self.take(')') { Ok(value) } else { Err("Missing )".into()) };
+        }
+        if self.take('-') {
+            return Ok(self.term()?.wrapping_neg());
+        }
+
+        let word = self.word();
+        if word.is_empty() {
+            return Err("Expected a register, number, or label".into());
+        }
+        self.atom(word)
+    }
+
+    fn atom(&self, word: &str) -> Result<u32, String> {
+        if word.starts_with('$') {
+            return register(word).map(|register| register.read(self.mips)).ok_or_else(|| format!("There's no register {}", word));
+        }
+
+        let number = match word.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => word.parse::<u32>().ok(),
+        };
+        number
+            .or_else(|| named(self.symbols, word).map(|symbol| symbol.address))
+            .ok_or_else(|| format!("There's no label {}", word))
+    }
+}
+
+// The registers an expression can name: $t0 or $8, $f0, $pc, $hi, and $lo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    General(usize),
+    Float(usize),
+    Pc,
+    Hi,
+    Lo,
+}
+
+pub fn register(name: &str) -> Option<Register> {
+    if let Some(index) = REGISTER_NAMES.iter().position(|register| *register == name) {
+        return Some(Register::General(index));
+    }
+    let number = name.strip_prefix('$')?;
+    match number {
+        _ if name == PC_NAME => Some(Register::Pc),
+        _ if name == HI_NAME => Some(Register::Hi),
+        _ if name == LO_NAME => Some(Register::Lo),
+        _ => match (number.parse::<usize>(), number.strip_prefix('f').map(str::parse::<usize>)) {
+            (Ok(index), _) if index < 32 => Some(Register::General(index)),
+            (_, Some(Ok(index))) if index < 32 => Some(Register::Float(index)),
+            _ => None,
+        },
+    }
+}
+
+impl Register {
+    pub fn read(&self, mips: &Mips) -> u32 {
+        match *self {
+            Register::General(index) => mips.regs[index],
+            Register::Float(index) => mips.cop1.regs[index],
+            Register::Pc => mips.pc as u32,
+            Register::Hi => mips.mult_hi,
+            Register::Lo => mips.mult_lo,
+        }
+    }
+}
+
+// Changes a register by name, the way the debugger does. $zero stays zero.
+// A watched register takes the new value as its own rather than stopping
+// the program for it.
+pub fn set_register(mips: &mut Mips, name: &str, value: u32) -> Result<(), String> {
+    match register(name).ok_or_else(|| format!("There's no register {}", name))? {
+        Register::General(0) => return Err("$zero is always 0".into()),
+        Register::General(index) => mips.regs[index] = value,
+        Register::Float(index) => mips.cop1.regs[index] = value,
+        Register::Pc => mips.pc = value as usize,
+        Register::Hi => mips.mult_hi = value,
+        Register::Lo => mips.mult_lo = value,
+    }
+    mips.note_watched_registers();
+    Ok(())
+}
@@ -0,0 +1,346 @@
+// A stub for GDB's remote serial protocol, so gdb-multiarch and other
+// tools that speak it can debug a program running in NAME. GDB connects
+// over TCP and sends packets one at a time, each answered in turn:
+// registers and memory are read and written, breakpoints and watchpoints
+// set and cleared, and the program stepped or continued until something
+// stops it. GDB can interrupt a running program with Ctrl-C.
+//
+// GDB has to be told what it's talking to:
+//   set architecture mips
+//   set endian little
+//   target remote localhost:PORT
+//
+// This is also the way to debug from a terminal rather than an editor.
+// gdb -tui, or layout asm and layout regs once connected, shows the code
+// around pc and the registers as the program runs. Source lines need an
+// ELF executable built with -g, since name-as's line info is only for the
+// debug adapter.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::exception::{ExecutionErrors, ExecutionEvents};
+use crate::memory::Watchpoint;
+use crate::mips::Mips;
+
+// GDB's own numbers for the signals stops are reported with
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 12;
+const SIGXCPU: u8 = 24;
+
+// GDB numbers MIPS registers as the general registers, then Status, LO,
+// HI, BadVAddr, Cause, and PC, then the floating-point registers, FCSR,
+// and FIR
+const STATUS: usize = 32;
+const LO: usize = 33;
+const HI: usize = 34;
+const BAD_VADDR: usize = 35;
+const CAUSE: usize = 36;
+const PC: usize = 37;
+const F0: usize = 38;
+const FCSR: usize = 70;
+const FIR: usize = 71;
+const REGISTER_COUNT: usize = 72;
+
+// How many instructions run between checks for an interrupt from GDB
+const INTERRUPT_INTERVAL: u32 = 1024;
+
+// Ctrl-C, sent on its own rather than in a packet
+const INTERRUPT: u8 = 0x03;
+
+// The most packet data GDB is told it can send or receive at once. A
+// memory read is answered with two hex digits a byte, so it gets half.
+const PACKET_SIZE: u32 = 0x4000;
+
+struct Session {
+    stream: TcpStream,
+    breakpoints: HashSet<u32>,
+    // Why the program last stopped, for when GDB asks again
+    stop: String,
+}
+
+// Answers GDB's packets until it detaches, kills the program, or hangs up
+pub fn serve(mips: &mut Mips, stream: TcpStream) -> io::Result<()> {
+    let mut session = Session { stream, breakpoints: HashSet::new(), stop: format!("S{:02x}", SIGTRAP) };
+
+    // GDB hanging up is how a session usually ends, not a failure
+    match session.run(mips) {
+        Err(error) if matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset) => Ok(()),
+        result => result,
+    }
+}
+
+impl Session {
+    fn run(&mut self, mips: &mut Mips) -> io::Result<()> {
+        loop {
+            let packet = self.receive()?;
+            match self.handle(mips, &packet) {
+                Some(reply) => self.send(&reply)?,
+                None => {
+                    // Detaching is acknowledged, but killing isn't
+                    if packet.starts_with('D') {
+                        self.send("OK")?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Reads the next packet, acknowledging it. Acknowledgements of our own
+    // packets, and interrupts when nothing's running, are skipped.
+    fn receive(&mut self) -> io::Result<String> {
+        // A packet with a bad checksum is refused, and GDB sends it again
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    // The next byte is escaped
+                    b'}' => data.push(self.read_byte()? ^ 0x20),
+                    byte => data.push(byte),
+                }
+            }
+
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16) == Ok(expected) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+
+    // Whether GDB has sent an interrupt, without waiting for one
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        let _ = self.stream.set_nonblocking(true);
+        let read = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        matches!(read, Ok(1)) && byte[0] == INTERRUPT
+    }
+
+    // The reply to a packet, or None to end the session. Packets that
+    // aren't supported get an empty reply, which GDB takes to mean so.
+    fn handle(&mut self, mips: &mut Mips, packet: &str) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.stop.clone(),
+            "g" => (0..REGISTER_COUNT).map(|n| encode(register(mips, n))).collect(),
+            "G" => {
+                for (n, value) in arguments.as_bytes().chunks(8).enumerate() {
+                    if let Some(value) = decode(std::str::from_utf8(value).unwrap_or_default()) {
+                        set_register(mips, n, value);
+                    }
+                }
+                "OK".into()
+            }
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(n) if n < REGISTER_COUNT => encode(register(mips, n)),
+                _ => "E00".into(),
+            },
+            "P" => {
+                let parsed = arguments.split_once('=').and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, decode(value)?)));
+                match parsed {
+                    Some((n, value)) if n < REGISTER_COUNT => {
+                        set_register(mips, n, value);
+                        "OK".into()
+                    }
+                    _ => "E00".into(),
+                }
+            }
+            "m" => match parse_range(arguments) {
+                // As much as can be read, up to the first byte that isn't there
+                // or as much as fits in a packet. GDB asks again for the rest.
+                Some((address, length)) => {
+                    let bytes: String = (0..length.min(PACKET_SIZE / 2))
+                        .map_while(|i| mips.memory.peek_byte(address.wrapping_add(i)))
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    if bytes.is_empty() && length > 0 { "E01".into() } else { bytes }
+                }
+                None => "E00".into(),
+            },
+            "M" => {
+                let parsed = arguments.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, hex_bytes(data)?)));
+                match parsed {
+                    Some(((address, length), bytes)) if bytes.len() == length as usize => match mips.poke_bytes(address, &bytes) {
+                        Ok(()) => "OK".into(),
+                        Err(_) => "E01".into(),
+                    },
+                    _ => "E00".into(),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(address) = u32::from_str_radix(arguments, 16) {
+                    mips.pc = address as usize;
+                }
+                self.stop = self.resume(mips, command == "s");
+                self.stop.clone()
+            }
+            "Z" | "z" => self.set_breakpoint(mips, arguments, command == "Z"),
+            "q" => match arguments.split(':').next().unwrap_or_default() {
+                "Supported" => format!("PacketSize={:x}", PACKET_SIZE),
+                "Attached" => "1".into(),
+                "C" => "QC1".into(),
+                "fThreadInfo" => "m1".into(),
+                "sThreadInfo" => "l".into(),
+                _ => String::new(),
+            },
+            // There's only the one thread
+            "H" | "T" => "OK".into(),
+            "D" | "k" => return None,
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Runs until a breakpoint, a watchpoint, the end of the program, an
+    // error, or an interrupt, or for only one step. Gives back the stop reply.
+    fn resume(&mut self, mips: &mut Mips, single_step: bool) -> String {
+        let mut steps: u32 = 0;
+        loop {
+            let signal = match mips.step_one(None) {
+                Ok(()) if single_step || self.breakpoints.contains(&(mips.pc as u32)) => SIGTRAP,
+                Ok(()) => {
+                    steps = steps.wrapping_add(1);
+                    if steps.is_multiple_of(INTERRUPT_INTERVAL) && self.interrupted() {
+                        return format!("S{:02x}", SIGINT);
+                    }
+                    continue;
+                }
+                Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete }) => return format!("W{:02x}", mips.os.exit_code as u8),
+                Err(ExecutionErrors::Event { event: ExecutionEvents::Watchpoint { address, store } }) => {
+                    let access = mips.memory.watchpoints.iter().any(|watchpoint| watchpoint.range.contains(&address) && watchpoint.read && watchpoint.write);
+                    let kind = if access { "awatch" } else if store { "watch" } else { "rwatch" };
+                    return format!("T{:02x}{}:{:08x};", SIGTRAP, kind, address);
+                }
+                Err(ExecutionErrors::Event { event: ExecutionEvents::StepLimit }) => SIGXCPU,
+                Err(error) => signal(error),
+            };
+            return format!("S{:02x}", signal);
+        }
+    }
+
+    // Sets or clears a breakpoint or watchpoint, given as type,address,kind.
+    // For watchpoints, the kind is how many bytes are watched.
+    fn set_breakpoint(&mut self, mips: &mut Mips, arguments: &str, set: bool) -> String {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(address), Some(length)) = (fields.next(), fields.next(), fields.next()) else {
+            return "E00".into();
+        };
+        let (Ok(address), Ok(length)) = (u32::from_str_radix(address, 16), u32::from_str_radix(length, 16)) else {
+            return "E00".into();
+        };
+
+        let (read, write) = match kind {
+            // Software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if set {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".into();
+            }
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return String::new(),
+        };
+
+        let range = address..=address.wrapping_add(length.max(1) - 1);
+        if set {
+            mips.memory.watchpoints.push(Watchpoint { range, read, write });
+        } else {
+            mips.memory.watchpoints.retain(|watchpoint| (&watchpoint.range, watchpoint.read, watchpoint.write) != (&range, read, write));
+        }
+        "OK".into()
+    }
+}
+
+// The signal GDB is told an error stopped the program with
+fn signal(error: ExecutionErrors) -> u8 {
+    match error {
+        ExecutionErrors::UndefinedInstruction { .. } => SIGILL,
+        ExecutionErrors::IntegerOverflow { .. } | ExecutionErrors::FloatingPoint { .. } => SIGFPE,
+        ExecutionErrors::AddressExceptionLoad { .. } | ExecutionErrors::AddressExceptionStore { .. } => SIGBUS,
+        ExecutionErrors::UnknownSyscall { .. } | ExecutionErrors::InvalidInput { .. } | ExecutionErrors::InvalidArgument { .. } => SIGSYS,
+        ExecutionErrors::Trap { .. } | ExecutionErrors::Event { .. } => SIGTRAP,
+        _ => SIGSEGV,
+    }
+}
+
+fn register(mips: &Mips, n: usize) -> u32 {
+    match n {
+        0..=31 => mips.regs[n],
+        STATUS => mips.cop0.status,
+        LO => mips.mult_lo,
+        HI => mips.mult_hi,
+        BAD_VADDR => mips.cop0.bad_vaddr,
+        CAUSE => mips.cop0.cause,
+        PC => mips.pc as u32,
+        F0..FCSR => mips.cop1.regs[n - F0],
+        FCSR => mips.cop1.fcsr,
+        FIR => mips.cop1.read_control(0).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// $zero and FIR can't be written, so writes to them are ignored
+fn set_register(mips: &mut Mips, n: usize, value: u32) {
+    match n {
+        1..=31 => mips.regs[n] = value,
+        STATUS => mips.cop0.status = value,
+        LO => mips.mult_lo = value,
+        HI => mips.mult_hi = value,
+        BAD_VADDR => mips.cop0.bad_vaddr = value,
+        CAUSE => mips.cop0.cause = value,
+        PC => mips.pc = value as usize,
+        F0..FCSR => mips.cop1.regs[n - F0] = value,
+        FCSR => mips.cop1.fcsr = value,
+        _ => (),
+    }
+}
+
+// Register values go over the wire as hex in the target's byte order,
+// which is little-endian
+fn encode(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn decode(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 8).map(u32::swap_bytes)
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// An address and length, as in m and M packets
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (address, length) = range.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
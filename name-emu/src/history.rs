@@ -0,0 +1,48 @@
+// What the machine was like before each of its most recent steps, so the
+// debugger can run a program backwards. Each step keeps the state it
+// started from and the old contents of the memory it stored over, which is
+// all it takes to put things back. Only so many steps are kept, and the
+// oldest are forgotten first.
+
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+struct Step<T> {
+    state: T,
+    // Addresses and the bytes that were there, in the order they were
+    // overwritten
+    stores: Vec<(u32, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+pub struct History<T> {
+    steps: VecDeque<Step<T>>,
+    capacity: usize,
+}
+
+impl<T> History<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self { steps: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    // Starts a step from the given state
+    pub fn push(&mut self, state: T) {
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(Step { state, stores: vec![] });
+    }
+
+    // Remembers what a store in the current step overwrote
+    pub fn stored(&mut self, address: u32, old: Vec<u8>) {
+        if let Some(step) = self.steps.back_mut() {
+            step.stores.push((address, old));
+        }
+    }
+
+    // Forgets the last step, giving back the state it started from and
+    // what it overwrote, most recent first, to be put back in that order
+    pub fn pop(&mut self) -> Option<(T, impl Iterator<Item = (u32, Vec<u8>)>)> {
+        self.steps.pop_back().map(|step| (step.state, step.stores.into_iter().rev()))
+    }
+}
@@ -0,0 +1,92 @@
+// The keyboard, a memory-mapped receiver laid out as in MARS's keyboard and
+// display simulator. Keys arrive asynchronously: when one is waiting, the
+// ready bit of the control register is set and the key is in the data
+// register, and reading the data register takes it. While the interrupt
+// enable bit is set, a waiting key also raises an interrupt.
+
+use std::io::{self, Read};
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::cop0::CAUSE_IP_KEYBOARD;
+use crate::device::Device;
+
+const RECEIVER_CONTROL: u32 = 0xFFFF0000;
+const RECEIVER_DATA: u32 = 0xFFFF0004;
+
+// Control register fields. Only interrupt enable can be written.
+const CONTROL_READY: u32 = 1 << 0;
+const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    control: u32,
+    data: u32,
+    // Where keys come from. They're shared so a source outlives the
+    // machines it's connected to across restarts.
+    keys: Option<Rc<Receiver<u8>>>,
+}
+
+impl Keyboard {
+    // Feeds the keyboard from a source of keys
+    pub fn connect(&mut self, keys: Rc<Receiver<u8>>) {
+        self.keys = Some(keys);
+    }
+}
+
+impl Device for Keyboard {
+    fn range(&self) -> Range<u32> {
+        RECEIVER_CONTROL..RECEIVER_DATA + 4
+    }
+
+    // Reading the data register takes the key
+    fn read(&mut self, address: u32) -> u8 {
+        let (register, shift) = (address & !0b11, (address & 0b11) * 8);
+        if register == RECEIVER_DATA {
+            self.control &= !CONTROL_READY;
+            (self.data >> shift) as u8
+        } else {
+            (self.control >> shift) as u8
+        }
+    }
+
+    // Only interrupt enable can be changed
+    fn write(&mut self, address: u32, value: u8) {
+        if address == RECEIVER_CONTROL {
+            self.control = self.control & !CONTROL_INTERRUPT_ENABLE | value as u32 & CONTROL_INTERRUPT_ENABLE;
+        }
+    }
+
+    fn interrupt_line(&self) -> u32 {
+        CAUSE_IP_KEYBOARD
+    }
+
+    // Takes the next key once the last one has been read
+    fn tick(&mut self) -> bool {
+        if self.control & CONTROL_READY == 0 {
+            if let Some(key) = self.keys.as_ref().and_then(|keys| keys.try_recv().ok()) {
+                self.data = key as u32;
+                self.control |= CONTROL_READY;
+            }
+        }
+        self.control & (CONTROL_READY | CONTROL_INTERRUPT_ENABLE) == CONTROL_READY | CONTROL_INTERRUPT_ENABLE
+    }
+}
+
+// Keys typed at the terminal, read on their own thread so the program
+// never waits for them
+pub fn terminal() -> Receiver<u8> {
+    let (sender, keys) = mpsc::channel();
+    thread::spawn(move || {
+        // One key at a time, so none are held back in a buffer
+        let mut key = [0];
+        while let Ok(1) = io::stdin().read(&mut key) {
+            if sender.send(key[0]).is_err() {
+                break;
+            }
+        }
+    });
+    keys
+}
@@ -0,0 +1,31 @@
+//! The NAME emulator as a library
+//!
+//! [Simulator] loads an assembled program and runs it an instruction at a
+//! time, with its registers and memory open to inspection and hooks that
+//! are told about each instruction, syscall, and exception, so GUIs and
+//! tests can drive the machine directly. name-emu's debug adapter is built
+//! on the same library.
+
+pub mod backtrace;
+pub mod breakpoints;
+pub mod cop0;
+pub mod cop1;
+pub mod device;
+pub mod elf;
+pub mod exception;
+pub mod expression;
+pub mod gdb;
+pub mod history;
+pub mod keyboard;
+pub mod memory;
+pub mod mips;
+pub mod mnemonic;
+pub mod os;
+pub mod random;
+pub mod replay;
+pub mod simulator;
+pub mod stats;
+pub mod symbols;
+pub mod trace;
+
+pub use simulator::{Hooks, Simulator};
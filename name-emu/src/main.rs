@@ -1,63 +1,1101 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 
-use dap::events::{StoppedEventBody, ExitedEventBody, TerminatedEventBody};
-use dap::responses::{ReadMemoryResponse, SetExceptionBreakpointsResponse, ThreadsResponse, StackTraceResponse, ScopesResponse, VariablesResponse, ContinueResponse};
-use dap::types::{StoppedEventReason, Thread, StackFrame, Scope, Source, Variable};
+use dap::events::{StoppedEventBody, ExitedEventBody, TerminatedEventBody, OutputEventBody};
+use dap::responses::{EvaluateResponse, ReadMemoryResponse, SetExceptionBreakpointsResponse, ThreadsResponse, StackTraceResponse, ScopesResponse, VariablesResponse, ContinueResponse};
+use dap::types::{StoppedEventReason, OutputEventCategory, Thread, StackFrame, Scope, Source, Variable};
+use serde::Serialize;
 use thiserror::Error;
 
 use dap::prelude::*;
 
-mod mips;
-use mips::Mips;
-
-mod exception;
-use exception::{ExecutionErrors, exception_pretty_print, ExecutionEvents};
-
-use name_const::lineinfo::{/*LineInfo, */lineinfo_import}; // Resolved unused import warning for now
+use name::mips::{self, Mips};
+use name::memory::Watchpoint;
+use name::keyboard::{self, Keyboard};
+use name::os::Profile;
+use name::replay::Replay;
+use name::trace::Trace;
+use name::backtrace::backtrace;
+use name::breakpoints::{self, Catch};
+use name::cop0;
+use name::symbols;
+use name::elf;
+use name::gdb;
+use name::expression;
+use name::exception::{ExecutionErrors, exception_pretty_print, ExecutionEvents};
+
+use name_const::isa::Isa;
+use name_const::layout::Layout;
+use name_const::lineinfo::{LineInfo, entry_import, isa_import, layout_import, lineinfo_import, symbols_import};
+use name_const::symbols::{SymbolBinding, SymbolInfo};
 
 use base64::{Engine as _, engine::general_purpose};
 use std::env;
+use std::path::{Path, PathBuf};
 use std::net::TcpListener;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Error, Debug)]
 enum MyAdapterError {
   /*
   // Commenting out dead code
   #[error("Unhandled command")]
-  UnhandledCommandError,
+  UnhandledCommand,
   */
 
   #[error("Missing command")]
-  MissingCommandError,
+  MissingCommand,
 
   #[error("Command argument error")]
-  CommandArgumentError,
+  CommandArgument,
   
   #[error("Argument parsing error")]
-  ArgumentParsingError
+  ArgumentParsing
 }
 
 type DynResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-fn reset_mips(program_data: &[u8]) -> Mips {
+// Everything name-as emitted for a program besides its text segment
+struct Segments {
+  data: Vec<u8>,
+  ktext: Vec<u8>,
+  kdata: Vec<u8>,
+  // Where the program was laid out to go
+  layout: Layout,
+  // The instruction set it was assembled against, if it named one
+  isa: Option<Isa>,
+}
+
+// Settings from the command line. Each one's default is what's used when
+// it isn't given.
+struct Options {
+  // Branch delay slots are simulated unless turned off
+  delay_slots: bool,
+  // Programs may open any file unless sandboxed to a directory
+  sandbox: Option<std::path::PathBuf>,
+  // Random numbers differ from run to run unless seeded
+  seed: Option<u64>,
+  // How much longer than asked a sleep takes, so sleeps are as long as asked
+  // by default
+  sleep_scale: f64,
+  // Time is the host's unless a virtual clock is asked for
+  virtual_clock: bool,
+  // Syscalls are MARS's unless SPIM's are
+  profile: Profile,
+  // Passed to the program as argc and argv
+  arguments: Vec<String>,
+  // Where the keyboard's keys come from, if anywhere. It only gets the
+  // terminal when asked to, since syscalls read it too.
+  keys: Option<Rc<Receiver<u8>>>,
+  // Run whole blocks at a time when continuing, rather than an instruction
+  // at a time
+  fast: bool,
+  // Print what the program did when name-emu exits
+  stats: bool,
+  // Where to record executed instructions, if anywhere, and which ones.
+  // Everything is recorded unless it's filtered.
+  trace: Option<String>,
+  trace_range: Option<std::ops::RangeInclusive<u32>>,
+  trace_mnemonics: Vec<String>,
+  // How many steps to keep for stepping back
+  history: usize,
+  // How many instructions the program may run, if it's limited
+  max_steps: Option<u64>,
+  // Let the program store into its own instructions
+  self_modifying_code: bool,
+  // Start registers and fresh memory out with garbage made from this seed,
+  // rather than zeros
+  random_init: Option<u64>,
+  // Catch stack overflows and stores below $sp unless told not to, and how
+  // low $sp may go if not the bottom of the stack
+  stack_checks: bool,
+  stack_limit: Option<u32>,
+  // Where to write down what the program took in from outside, and a
+  // recording to take it from instead. Neither happens unless there's a file
+  // for it.
+  record: Option<String>,
+  replay: Option<Replay>,
+  // Run every program given without a debugger, and where their console
+  // input comes from. Without a directory they get no input.
+  batch: bool,
+  stdin_dir: Option<std::path::PathBuf>,
+  // Debug the program with GDB on this port instead of a DAP client
+  gdb: Option<u16>,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      delay_slots: true,
+      sandbox: None,
+      seed: None,
+      sleep_scale: 1.0,
+      virtual_clock: false,
+      profile: Profile::default(),
+      arguments: vec![],
+      keys: None,
+      fast: false,
+      stats: false,
+      trace: None,
+      trace_range: None,
+      trace_mnemonics: vec![],
+      history: 10_000,
+      max_steps: None,
+      self_modifying_code: false,
+      random_init: None,
+      stack_checks: true,
+      stack_limit: None,
+      record: None,
+      replay: None,
+      batch: false,
+      stdin_dir: None,
+      gdb: None,
+    }
+  }
+}
+
+// How a program run in batch mode went
+#[derive(Serialize)]
+struct BatchResult {
+  program: String,
+  // What the program printed to its console
+  stdout: String,
+  exit_code: i32,
+  steps: u64,
+  // "completed", "step limit", or the error that stopped it
+  outcome: String,
+}
+
+// Reads the segments name-as wrote next to an object file, if the program
+// has them, to be laid out however name-as laid the program out
+fn read_segments(object: &str, lineinfo: &str) -> DynResult<Segments> {
+  let read_segment = |suffix: &str| std::fs::read(format!("{}{}", object, suffix)).unwrap_or_default();
+  let segments = Segments {
+    data: read_segment(".data"),
+    ktext: read_segment(".ktext"),
+    kdata: read_segment(".kdata"),
+    layout: layout_import(lineinfo)?,
+    isa: isa_import(lineinfo)?,
+  };
+  segments.layout.check()?;
+  Ok(segments)
+}
+
+fn reset_mips(program_data: &[u8], segments: &Segments, entry: u32, options: &Options) -> DynResult<Mips> {
   // Reset execution and begin again.
-  let mut mips: Mips = Default::default();  
+  let mut mips = Mips::new(segments.layout);
+  mips.isa = segments.isa;
+  mips.delay_slots = options.delay_slots;
+  mips.keep_history(options.history);
+  mips.max_steps = options.max_steps;
+  mips.stack_limit = options.stack_checks.then(|| options.stack_limit.unwrap_or(segments.layout.stack_base));
+  mips.os.set_profile(options.profile);
+  mips.os.sandbox = options.sandbox.clone();
+  mips.os.seed = options.seed;
+  mips.os.sleep_scale = options.sleep_scale;
+  mips.os.virtual_clock = options.virtual_clock.then_some(0);
+  // Each run records afresh, so the file ends up with the last one
+  if options.record.is_some() {
+    mips.os.recording = Some(Replay { garbage: options.random_init, ..Default::default() });
+  }
+  if let Some(replay) = &options.replay {
+    mips.os.stdin = Box::new(std::io::Cursor::new(replay.input.clone()));
+    mips.os.replaying = Some(replay.clone());
+  }
+
+  if elf::is_elf(program_data) {
+    elf::parse(program_data)?.load(&mut mips, !options.self_modifying_code)?;
+  } else {
+    mips.load(program_data, &segments.data, &segments.ktext, &segments.kdata, entry);
+  }
+
+  // Code can't be written unless self-modifying code is allowed
+  if !options.self_modifying_code {
+    mips.memory.protect(segments.layout.text_base, false);
+    mips.memory.protect(mips::DOT_KTEXT_START_ADDRESS, false);
+  }
+
+  if let Some(keys) = &options.keys {
+    let mut keyboard = Keyboard::default();
+    keyboard.connect(keys.clone());
+    mips.memory.attach(Box::new(keyboard));
+  }
+
+  if let Some(seed) = options.random_init {
+    mips.randomize(seed);
+  }
+
+  if !options.arguments.is_empty() {
+    mips.pass_arguments(&options.arguments).map_err(|error| format!("Couldn't pass the program its arguments: {}", error))?;
+  }
+
+  // Each run starts its trace over
+  if let Some(path) = &options.trace {
+    let mut trace = Trace::create(path).map_err(|error| format!("Couldn't create trace file {}: {}", path, error))?;
+    trace.range = options.trace_range.clone();
+    trace.mnemonics = options.trace_mnemonics.clone();
+    mips.trace = Some(trace);
+  }
+
+  Ok(mips)
+}
+
+// Addresses on the command line are hexadecimal with 0x, or decimal
+fn parse_address(address: &str) -> DynResult<u32> {
+  let parsed = match address.strip_prefix("0x") {
+    Some(hex) => u32::from_str_radix(hex, 16),
+    None => address.parse(),
+  };
+  parsed.map_err(|_| format!("Invalid address {}", address).into())
+}
+
+// Says where a program that ran too long was when it was stopped, for
+// whoever has to work out why it never finished
+fn report_step_limit(mips: &Mips) {
+  eprint!("Stopped after {} steps at 0x{:08x}. The last instructions were:\n{}", mips.stats.instructions(), mips.pc, mips.stats.recent());
+}
+
+// Where the program stopped for a watchpoint
+fn watchpoint_stopped(address: u32, store: bool) -> StoppedEventBody {
+  StoppedEventBody {
+    reason: StoppedEventReason::Data,
+    description: Some(format!("{} 0x{:08x}", if store { "Wrote" } else { "Read" }, address)),
+    thread_id: Some(0),
+    preserve_focus_hint: None,
+    text: None,
+    all_threads_stopped: None,
+    hit_breakpoint_ids: None
+  }
+}
+
+// Where the program stopped for a breakpoint
+fn breakpoint_stopped(id: u32) -> StoppedEventBody {
+  StoppedEventBody {
+    reason: StoppedEventReason::Breakpoint,
+    description: Some(format!("Breakpoint {}", id)),
+    thread_id: Some(0),
+    preserve_focus_hint: None,
+    text: None,
+    all_threads_stopped: None,
+    hit_breakpoint_ids: None
+  }
+}
+
+// Where the program stopped for a watched register, and how it changed
+fn register_watchpoint_stopped(mips: &Mips, id: u32, old: u32, new: u32) -> StoppedEventBody {
+  let name = mips.breakpoints.register_watch(id).map_or("A register", |watch| watch.name.as_str());
+  StoppedEventBody {
+    reason: StoppedEventReason::Data,
+    description: Some(format!("{} changed from 0x{:08x} ({}) to 0x{:08x} ({})", name, old, old as i32, new, new as i32)),
+    thread_id: Some(0),
+    preserve_focus_hint: None,
+    text: None,
+    all_threads_stopped: None,
+    hit_breakpoint_ids: None
+  }
+}
+
+// Where the program stopped for a catchpoint, and what it caught
+fn catchpoint_stopped(mips: &Mips, id: u32) -> StoppedEventBody {
+  let caught = mips.breakpoints.catchpoint(id).map(|catchpoint| format!(" ({})", catchpoint.catch)).unwrap_or_default();
+  StoppedEventBody {
+    reason: StoppedEventReason::Breakpoint,
+    description: Some(format!("Catchpoint {}{}", id, caught)),
+    thread_id: Some(0),
+    preserve_focus_hint: None,
+    text: None,
+    all_threads_stopped: None,
+    hit_breakpoint_ids: None
+  }
+}
+
+// Runs the program, a block at a time when running fast, until `done` says
+// it's where it was going. It's asked before each step. Whatever stops the
+// program first is what happened, even if it isn't an instruction's doing,
+// and comes back as an error. The program stops before the instruction at a
+// breakpoint, or a syscall that's caught, so running from one runs it.
+fn run_until(mips: &mut Mips, file: &mut File, fast: bool, mut done: impl FnMut(&Mips) -> bool) -> Result<(), ExecutionErrors> {
+  loop {
+    if done(mips) {
+      return Ok(());
+    }
+    if fast { mips.step_block()? } else { mips.step_one(Some(file))? }
+    if let Some(breakpoint) = mips.breakpoints.hit(mips.pc as u32) {
+      return Err(ExecutionErrors::Event { event: ExecutionEvents::Breakpoint { id: breakpoint.id } });
+    }
+    if let Some(catchpoint) = mips.syscall_at_pc().and_then(|service| mips.breakpoints.syscall_hit(service)) {
+      return Err(ExecutionErrors::Event { event: ExecutionEvents::Catchpoint { id: catchpoint.id } });
+    }
+  }
+}
+
+// What the debug console knows about the program, and the expressions it's
+// been asked to show whenever the program stops
+struct Console<'a> {
+  lineinfo: &'a HashMap<u32, LineInfo>,
+  program_name: &'a str,
+  symbols: Vec<SymbolInfo>,
+  // Numbered from 1 by where they are in the list
+  displays: Vec<String>,
+  aliases: HashMap<String, String>,
+}
+
+impl Console<'_> {
+  // The file and line an instruction came from. Instructions from an
+  // .include'd file point at that file instead of the program's own.
+  fn source_line(&self, address: u32) -> Option<(&str, u32)> {
+    let info = self.lineinfo.get(&address)?;
+    let file = if info.source_file.is_empty() { self.program_name } else { &info.source_file };
+    Some((file, info.line_number))
+  }
+
+  // The display expressions as they are now, one per line, or None if
+  // there aren't any. One that can't be evaluated, like a pointer that
+  // doesn't point anywhere yet, shows why instead.
+  fn displayed(&self, mips: &Mips) -> Option<String> {
+    if self.displays.is_empty() {
+      return None;
+    }
+    Some(self.displays.iter().enumerate()
+      .map(|(i, display)| match expression::evaluate(mips, &self.symbols, display) {
+        Ok(value) => format!("{}: {}", i + 1, show_value(display, value)),
+        Err(message) => format!("{}: {} ({})", i + 1, display, message),
+      })
+      .collect::<Vec<_>>()
+      .join("\n"))
+  }
+
+  // The console commands that would set up the breakpoints, catchpoints,
+  // watchpoints, displays, and aliases there are now. Aliases come last, so
+  // the commands before them mean what they meant when they were saved.
+  fn session(&self, mips: &Mips) -> Vec<String> {
+    let mut commands: Vec<String> = mips.breakpoints.iter()
+      .map(|breakpoint| format!("{} {}", if breakpoint.temporary { "tbreak" } else { "b" }, breakpoint.location))
+      .collect();
+    commands.extend(mips.breakpoints.catchpoints().map(|catchpoint| format!("catch {}", catchpoint.catch)));
+    commands.extend(mips.memory.watchpoints.iter().map(|watchpoint| {
+      let command = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "awatch",
+        (true, false) => "rwatch",
+        _ => "watch",
+      };
+      format!("{} 0x{:08x}-0x{:08x}", command, watchpoint.range.start(), watchpoint.range.end())
+    }));
+    commands.extend(mips.breakpoints.register_watches().map(|watch| format!("watch {}", watch.name)));
+    commands.extend(self.displays.iter().map(|display| format!("display {}", display)));
+    let mut aliases: Vec<String> = self.aliases.iter().map(|(name, command)| format!("alias {} {}", name, command)).collect();
+    aliases.sort();
+    commands.extend(aliases);
+    commands
+  }
+}
+
+fn show_value(expression: &str, value: u32) -> String {
+  format!("{} = 0x{:08x} ({})", expression, value, value as i32)
+}
+
+// Tells the editor the program has stopped, then shows the display
+// expressions as they are now
+fn stopped<R: Read, W: Write>(server: &mut Server<R, W>, body: StoppedEventBody, mips: &Mips, console: &Console) -> DynResult<()> {
+  server.send_event(Event::Stopped(body))?;
+  if let Some(output) = console.displayed(mips) {
+    server.send_event(console_output(output))?;
+  }
+  Ok(())
+}
+
+// Text for the debug console, as though a command had printed it
+fn console_output(output: String) -> Event {
+  Event::Output(OutputEventBody {
+    category: Some(OutputEventCategory::Console),
+    output: output + "\n",
+    group: None,
+    variables_reference: None,
+    source: None,
+    line: None,
+    column: None,
+    data: None
+  })
+}
+
+// Debug console commands to run when the debugger starts, from .namedbrc in
+// the home directory and then in the program's own directory, so a lab can
+// set up the same breakpoints, displays, and aliases every time. Each comes
+// with where it's from, as file:line. Blank lines and lines starting with #
+// are skipped.
+fn init_commands(program_name: &str) -> Vec<(String, String)> {
+  let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(PathBuf::from);
+  let project = Path::new(program_name).parent().map(|directory| directory.to_path_buf());
+  let mut scripts: Vec<PathBuf> = vec![];
+  for directory in [home, project].into_iter().flatten() {
+    // The program can be in the home directory, and its script only runs once
+    let Ok(script) = directory.join(".namedbrc").canonicalize() else {
+      continue;
+    };
+    if script.is_file() && !scripts.contains(&script) {
+      scripts.push(script);
+    }
+  }
+
+  let mut commands = vec![];
+  for script in scripts {
+    let Ok(contents) = std::fs::read_to_string(&script) else {
+      continue;
+    };
+    for (i, line) in contents.lines().enumerate() {
+      let line = line.trim();
+      if !line.is_empty() && !line.starts_with('#') {
+        commands.push((format!("{}:{}", script.display(), i + 1), line.to_string()));
+      }
+    }
+  }
+  commands
+}
+
+// Runs a command from a script, showing it as though it had been typed.
+// An error says where the command came from.
+fn run_command(mips: &mut Mips, console: &mut Console, location: &str, command: &str) -> String {
+  match evaluate(mips, command, console) {
+    Ok(result) => format!("> {}\n{}", command, result),
+    Err(message) => format!("> {}\n{}: {}", command, location, message),
+  }
+}
+
+// Where the debugger's state is kept from one session of a program to the
+// next, beside the program's source
+fn session_path(program_name: &str) -> PathBuf {
+  PathBuf::from(format!("{}.namedb", program_name))
+}
+
+// A fingerprint of the program's source, including the files it includes,
+// to tell whether lines and addresses saved with a session still mean the
+// same thing. It's FNV-1a, so it's the same from one build of NAME to the
+// next.
+fn source_hash(lineinfo: &HashMap<u32, LineInfo>, program_name: &str) -> u64 {
+  let mut files: Vec<&str> = lineinfo.values().map(|info| info.source_file.as_str()).filter(|file| !file.is_empty()).collect();
+  files.push(program_name);
+  files.sort();
+  files.dedup();
+
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for file in files {
+    for byte in std::fs::read(file).unwrap_or_default() {
+      hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+  }
+  hash
+}
+
+// Saves the debugger's state for the next session, as the console commands
+// that set it up, after a line with the source's fingerprint. With nothing
+// to save, the last session's state is removed instead, so what was
+// deleted stays deleted.
+fn save_session(mips: &Mips, console: &Console) -> std::io::Result<()> {
+  let path = session_path(console.program_name);
+  let commands = console.session(mips);
+  if commands.is_empty() {
+    return match std::fs::remove_file(&path) {
+      Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+      result => result,
+    };
+  }
+
+  let mut contents = format!("# Debugger state from the last session of {}, restored at the next one\n", console.program_name);
+  contents.push_str(&format!("# source {:016x}\n", source_hash(console.lineinfo, console.program_name)));
+  for command in commands {
+    contents.push_str(&command);
+    contents.push('\n');
+  }
+  std::fs::write(path, contents)
+}
+
+// Sets up the last session's state again, less whatever the init scripts
+// already set up, giving back what to show for it. If the source has
+// changed since, saved lines and addresses may be somewhere else now, so
+// breakpoints and watchpoints are left out, and displays and the rest are
+// kept. Displays are kept as they were, since what they show may not be
+// there until the program has run a while.
+fn restore_session(mips: &mut Mips, console: &mut Console) -> Vec<String> {
+  let path = session_path(console.program_name);
+  let Ok(contents) = std::fs::read_to_string(&path) else {
+    return vec![];
+  };
+  let hash = format!("# source {:016x}", source_hash(console.lineinfo, console.program_name));
+  let changed = !contents.lines().any(|line| line.trim() == hash);
+  let already = console.session(mips);
+
+  let mut output = vec![];
+  let mut skipped = 0;
+  for (i, command) in contents.lines().enumerate() {
+    let command = command.trim();
+    if command.is_empty() || command.starts_with('#') || already.iter().any(|set_up| set_up == command) {
+      continue;
+    }
+    // A register is the same register whatever the source says
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    if changed && matches!(name, "b" | "tbreak" | "watch" | "rwatch" | "awatch") && !argument.trim().starts_with('$') {
+      skipped += 1;
+      continue;
+    }
+
+    match command.strip_prefix("display ") {
+      Some(display) => {
+        console.displays.push(display.trim().to_string());
+        output.push(format!("> {}\n{}: {}", command, console.displays.len(), display.trim()));
+      }
+      None => output.push(run_command(mips, console, &format!("{}:{}", path.display(), i + 1), command)),
+    }
+  }
+  if skipped > 0 {
+    output.push(format!("The source has changed since {} was saved, so its breakpoints and watchpoints weren't restored ({} left out)", path.display(), skipped));
+  }
+  output
+}
+
+// Changes a register or memory for the m commands. Writes are checked
+// against the program's own permissions, so a mistyped address can't land
+// in a device or somewhere the program never put anything, and code can
+// only be changed when the command says it's meant to, with a !.
+fn modify(mips: &mut Mips, command: &str, argument: &str, symbols: &[SymbolInfo]) -> Result<String, String> {
+  let usage = "Usage: m $REG EXPR, m[w|h|b] ADDRESS EXPR, or ms ADDRESS \"TEXT\"";
+  let (target, value) = argument.trim().split_once(' ').ok_or(usage)?;
+  let (width, force) = (command.trim_start_matches('m').trim_end_matches('!'), command.ends_with('!'));
+
+  if width.is_empty() && target.starts_with('$') {
+    let value = expression::evaluate(mips, symbols, value.trim())?;
+    expression::set_register(mips, target, value)?;
+    return Ok(show_value(target, value));
+  }
+
+  let address = expression::evaluate(mips, symbols, target)?;
+  let bytes = match width {
+    "s" => {
+      let mut bytes = unescape(value.trim())?;
+      bytes.push(0);
+      bytes
+    }
+    _ => {
+      let size = match width { "h" => 2, "b" => 1, _ => 4 };
+      let value = expression::evaluate(mips, symbols, value.trim())?;
+      // Negative values are fine, as long as they fit
+      let bits = size * 8;
+      if bits < 32 && value >> bits != 0 && (value as i32) >> (bits - 1) != -1 {
+        return Err(format!("{} doesn't fit in {} byte{}", value as i32, size, if size == 1 { "" } else { "s" }));
+      }
+      value.to_le_bytes()[..size as usize].to_vec()
+    }
+  };
+
+  for offset in 0..bytes.len() as u32 {
+    let byte_address = address.wrapping_add(offset);
+    let code = matches!(symbols::section(&mips.layout, byte_address), Some(".text" | ".ktext"));
+    match mips.memory.writable(byte_address) {
+      None => return Err(format!("0x{:08x} isn't in use", byte_address)),
+      Some(writable) if (code || !writable) && !force => {
+        let what = if code { "is code" } else { "is write protected" };
+        return Err(format!("0x{:08x} {}. Use {}! to change it anyway.", byte_address, what, command));
+      }
+      Some(_) => (),
+    }
+  }
+
+  mips.poke_bytes(address, &bytes).map_err(|error| error.to_string())?;
+  Ok(format!("Stored {} byte{} at 0x{:08x}", bytes.len(), if bytes.len() == 1 { "" } else { "s" }, address))
+}
+
+// The bytes of a string in double quotes, which can have \n, \t, \0, \\,
+// and \" in it
+fn unescape(quoted: &str) -> Result<Vec<u8>, String> {
+  let text = quoted.strip_prefix('"').and_then(|text| text.strip_suffix('"')).ok_or("Put the string in double quotes")?;
+  let mut bytes = vec![];
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    let c = match c {
+      '\\' => match chars.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some(c @ ('\\' | '"')) => c,
+        Some(c) => return Err(format!("Unknown escape \\{}", c)),
+        None => return Err("The string ends in the middle of an escape".into()),
+      },
+      c => c,
+    };
+    let mut buffer = [0; 4];
+    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+  }
+  Ok(bytes)
+}
+
+// Runs a debug console command, giving back what to show for it. The
+// commands are:
+//   b [FILE:]LINE          stop at a line when continuing, in the program's
+//                          own file if none is given
+//   b ADDRESS              stop at an instruction
+//   tbreak LOCATION        stop there only once
+//   ignore ID COUNT        let the program past a breakpoint COUNT times
+//   catch syscall [N]      stop before a syscall for service N, or any syscall
+//   catch exception [TYPE] stop in the program's handler once it's taken an
+//                          exception, by ExcCode name like AdEL or Ov, or
+//                          number, or any exception
+//   pb                     list breakpoints, catchpoints, and watched
+//                          registers, and how often they've been hit
+//   delete [ID]            remove any of them, or all of them
+//   stats                  what the program has done so far
+//   watch START[-END]      stop after the addresses are written
+//   rwatch START[-END]     stop after they're read
+//   awatch START[-END]     stop after either
+//   watch $REG             stop after a register changes
+//   unwatch [START[-END]]  stop watching them, or everything
+//   unwatch $REG           stop watching a register
+//   p EXPR                 an expression's value, like $t0, *($sp+8), or
+//                          buffer+4
+//   display [EXPR]         show an expression every time the program stops,
+//                          or show them all now
+//   undisplay [N]          stop showing one, or all of them
+//   bt                     the calls that led to where the program is
+//   pf, pa fp              the floating-point registers, and FCSR's fields
+//   cp0                    Status, Cause, EPC, and BadVAddr taken apart, and
+//                          the error that stopped the program, if one did
+//   alias [NAME COMMAND]   make NAME short for COMMAND, or list the aliases
+//   info symbol EXPR       the label an address is at, or just after
+//   info address LABEL     where a label is
+//   m $REG EXPR            change a register
+//   m[w|h|b] ADDRESS EXPR  store a word, halfword, or byte, where ADDRESS can
+//                          be any expression without spaces, like a label
+//   ms ADDRESS "TEXT"      store a string, with a zero after it
+//   m!, mw!, ...           store to .text or other write-protected memory
+fn evaluate(mips: &mut Mips, expression: &str, console: &mut Console) -> Result<String, String> {
+  // An alias stands for the start of a command, and isn't expanded again
+  let (name, rest) = expression.trim().split_once(' ').unwrap_or((expression.trim(), ""));
+  let expression = match console.aliases.get(name) {
+    Some(command) => format!("{} {}", command, rest),
+    None => expression.to_string(),
+  };
+  let (command, argument) = expression.trim().split_once(' ').unwrap_or((expression.trim(), ""));
+  let range = || -> Result<std::ops::RangeInclusive<u32>, String> {
+    let (start, end) = argument.trim().split_once('-').unwrap_or((argument.trim(), argument.trim()));
+    let (start, end) = (parse_address(start).map_err(|error| error.to_string())?, parse_address(end).map_err(|error| error.to_string())?);
+    Ok(start..=end)
+  };
+
+  let (lineinfo, program_name) = (console.lineinfo, console.program_name);
+
+  match command {
+    "b" | "break" | "tbreak" => {
+      let location = argument.trim();
+      let (address, description, snapped) = if location.starts_with("0x") {
+        let address = parse_address(location).map_err(|error| error.to_string())?;
+        (address, format!("0x{:08x}", address), None)
+      } else {
+        let (file, line) = location.rsplit_once(':').unwrap_or((program_name, location));
+        let line: u32 = line.parse().map_err(|_| format!("Invalid location {}", location))?;
+        let (address, found) = breakpoints::resolve(lineinfo, program_name, file, line)?;
+        (address, format!("{}:{}", file, found), (found != line).then_some(line))
+      };
+
+      let breakpoint = mips.breakpoints.add(address, description, command == "tbreak");
+      let kind = if breakpoint.temporary { "Temporary breakpoint" } else { "Breakpoint" };
+      let mut result = format!("{} {} at {} (0x{:08x})", kind, breakpoint.id, breakpoint.location, breakpoint.address);
+      if let Some(line) = snapped {
+        result.push_str(&format!(". Line {} has no instruction.", line));
+      }
+      Ok(result)
+    }
+    "pb" => {
+      let mut lines: Vec<String> = mips.breakpoints.iter()
+        .map(|breakpoint| {
+          let mut line = format!("{}  {}  0x{:08x}  hit {} time{}", breakpoint.id, breakpoint.location, breakpoint.address, breakpoint.hits, if breakpoint.hits == 1 { "" } else { "s" });
+          if breakpoint.ignore > 0 {
+            line.push_str(&format!(", ignoring the next {}", breakpoint.ignore));
+          }
+          if breakpoint.temporary {
+            line.push_str(", temporary");
+          }
+          line
+        })
+        .collect();
+      lines.extend(mips.breakpoints.catchpoints().map(|catchpoint| {
+        format!("{}  catch {}  hit {} time{}", catchpoint.id, catchpoint.catch, catchpoint.hits, if catchpoint.hits == 1 { "" } else { "s" })
+      }));
+      lines.extend(mips.breakpoints.register_watches().map(|watch| {
+        format!("{}  watch {}  changed {} time{}", watch.id, watch.name, watch.hits, if watch.hits == 1 { "" } else { "s" })
+      }));
+      Ok(match lines.is_empty() {
+        true => "No breakpoints".into(),
+        false => lines.join("\n"),
+      })
+    }
+    "catch" => {
+      let (kind, which) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+      let which = which.trim();
+      let catch = match kind {
+        "syscall" if which.is_empty() => Catch::Syscall(None),
+        "syscall" => Catch::Syscall(Some(which.parse().map_err(|_| format!("Invalid syscall {}", which))?)),
+        "exception" if which.is_empty() => Catch::Exception(None),
+        "exception" => {
+          let code = cop0::exception_code(which)
+            .or_else(|| which.parse().ok().filter(|code| *code < 32))
+            .ok_or_else(|| format!("There's no exception {}", which))?;
+          Catch::Exception(Some(code))
+        }
+        _ => return Err("Usage: catch syscall [SERVICE], or catch exception [TYPE]".into()),
+      };
+
+      let exception_handler = mips.exception_handler;
+      let catchpoint = mips.breakpoints.catch(catch);
+      let mut result = format!("Catchpoint {} ({})", catchpoint.id, catchpoint.catch);
+      if matches!(catchpoint.catch, Catch::Exception(_)) && !exception_handler {
+        result.push_str(". The program has no exception handler, so any exception stops it anyway.");
+      }
+      Ok(result)
+    }
+    "ignore" => {
+      let (id, count) = argument.trim().split_once(' ').ok_or("Usage: ignore ID COUNT")?;
+      let (Ok(id), Ok(count)) = (id.trim().parse::<u32>(), count.trim().parse::<u32>()) else {
+        return Err("Usage: ignore ID COUNT".into());
+      };
+      if mips.breakpoints.ignore(id, count) {
+        Ok(format!("Breakpoint {} will be passed {} time{}", id, count, if count == 1 { "" } else { "s" }))
+      } else {
+        Err(format!("There's no breakpoint {}", id))
+      }
+    }
+    "delete" if argument.trim().is_empty() => {
+      mips.breakpoints.clear();
+      Ok("Removed all breakpoints".into())
+    }
+    "delete" => {
+      let id: u32 = argument.trim().parse().map_err(|_| format!("Invalid breakpoint {}", argument.trim()))?;
+      if mips.breakpoints.remove(id) {
+        Ok(format!("Removed breakpoint {}", id))
+      } else {
+        Err(format!("There's no breakpoint {}", id))
+      }
+    }
+    "stats" => Ok(mips.stats.summary()),
+    "watch" if argument.trim().starts_with('$') => {
+      let name = argument.trim();
+      let register = expression::register(name).ok_or_else(|| format!("There's no register {}", name))?;
+      if register == expression::Register::Pc {
+        return Err("$pc changes with every instruction".into());
+      }
+      let value = register.read(mips);
+      let watch = mips.breakpoints.watch_register(name.to_string(), register, value);
+      Ok(format!("Watchpoint {}: {}", watch.id, show_value(name, value)))
+    }
+    "rwatch" | "awatch" if argument.trim().starts_with('$') => Err("Registers can only be watched for changes, with watch".into()),
+    "watch" | "rwatch" | "awatch" => {
+      let range = range()?;
+      let description = format!("Watching 0x{:08x}-0x{:08x}", range.start(), range.end());
+      mips.memory.watchpoints.push(Watchpoint { range, read: command != "watch", write: command != "rwatch" });
+      Ok(description)
+    }
+    "unwatch" if argument.trim().is_empty() => {
+      mips.memory.watchpoints.clear();
+      let registers: Vec<u32> = mips.breakpoints.register_watches().map(|watch| watch.id).collect();
+      for id in registers {
+        mips.breakpoints.remove(id);
+      }
+      Ok("Removed all watchpoints".into())
+    }
+    "unwatch" if argument.trim().starts_with('$') => {
+      let name = argument.trim();
+      let register = expression::register(name).ok_or_else(|| format!("There's no register {}", name))?;
+      if mips.breakpoints.unwatch_register(register) {
+        Ok(format!("Stopped watching {}", name))
+      } else {
+        Err(format!("{} isn't being watched", name))
+      }
+    }
+    "unwatch" => {
+      let range = range()?;
+      mips.memory.watchpoints.retain(|watchpoint| watchpoint.range != range);
+      Ok(format!("Stopped watching 0x{:08x}-0x{:08x}", range.start(), range.end()))
+    }
+    "p" | "print" => {
+      let value = expression::evaluate(mips, &console.symbols, argument.trim())?;
+      Ok(show_value(argument.trim(), value))
+    }
+    "display" if argument.trim().is_empty() => Ok(console.displayed(mips).unwrap_or_else(|| "Nothing is displayed".into())),
+    "display" => {
+      // Checked now, so a typo doesn't show up as an error at every stop
+      let value = expression::evaluate(mips, &console.symbols, argument.trim())?;
+      console.displays.push(argument.trim().to_string());
+      Ok(format!("{}: {}", console.displays.len(), show_value(argument.trim(), value)))
+    }
+    "undisplay" if argument.trim().is_empty() => {
+      console.displays.clear();
+      Ok("Removed all displays".into())
+    }
+    "undisplay" => {
+      let number: usize = argument.trim().parse().map_err(|_| format!("Invalid display {}", argument.trim()))?;
+      if number == 0 || number > console.displays.len() {
+        return Err(format!("There's no display {}", number));
+      }
+      console.displays.remove(number - 1);
+      Ok(format!("Removed display {}", number))
+    }
+    "pf" => Ok(mips.cop1.summary()),
+    "pa" if argument.trim() == "fp" => Ok(mips.cop1.summary()),
+    "cp0" => {
+      let mut result = mips.cop0.summary();
+      // Without a handler of its own, an exception stops the program before
+      // it gets to CP0
+      match mips.prev_ins_result {
+        Err(ExecutionErrors::Event { .. }) | Ok(()) => (),
+        Err(error) => result.push_str(&format!("Stopped by: {}\n", error)),
+      }
+      Ok(result)
+    }
+    "alias" if argument.trim().is_empty() => Ok(match console.aliases.is_empty() {
+      true => "No aliases".into(),
+      false => {
+        let mut aliases: Vec<_> = console.aliases.iter().map(|(name, command)| format!("{} = {}", name, command)).collect();
+        aliases.sort();
+        aliases.join("\n")
+      }
+    }),
+    "alias" => {
+      let (name, command) = argument.trim().split_once(' ').ok_or("Usage: alias NAME COMMAND")?;
+      console.aliases.insert(name.to_string(), command.trim().to_string());
+      Ok(format!("{} = {}", name, command.trim()))
+    }
+    "info" => {
+      let (topic, argument) = argument.trim().split_once(' ').ok_or("Usage: info symbol EXPR, or info address LABEL")?;
+      let argument = argument.trim();
+      let in_section = |address| symbols::section(&mips.layout, address).map(|section| format!(" in {}", section)).unwrap_or_default();
+      match topic {
+        "symbol" | "symbols" => {
+          let address = expression::evaluate(mips, &console.symbols, argument)?;
+          // A label in another part of memory says nothing about an address
+          let section = symbols::section(&mips.layout, address);
+          match symbols::nearest(&console.symbols, address).filter(|symbol| symbols::section(&mips.layout, symbol.address) == section) {
+            Some(symbol) if symbol.address == address => Ok(format!("{}{}", symbol.name, in_section(address))),
+            Some(symbol) => Ok(format!("{} + {}{}", symbol.name, address - symbol.address, in_section(address))),
+            None => Err(format!("No label is at or before 0x{:08x}", address)),
+          }
+        }
+        "address" => {
+          let symbol = symbols::named(&console.symbols, argument).ok_or_else(|| format!("There's no label {}", argument))?;
+          let binding = match symbol.binding {
+            SymbolBinding::Local => "local",
+            SymbolBinding::Global => "global",
+            SymbolBinding::Weak => "weak",
+          };
+          Ok(format!("{} is at 0x{:08x}{}, {}", symbol.name, symbol.address, in_section(symbol.address), binding))
+        }
+        _ => Err("Usage: info symbol EXPR, or info address LABEL".into()),
+      }
+    }
+    "m" | "mw" | "mh" | "mb" | "ms" | "m!" | "mw!" | "mh!" | "mb!" | "ms!" => modify(mips, command, argument, &console.symbols),
+    "bt" | "backtrace" => Ok(backtrace(mips, &console.symbols)
+      .iter()
+      .enumerate()
+      .map(|(i, frame)| {
+        let mut line = format!("#{:<3}0x{:08x} in {}", i, frame.pc, frame.function.as_deref().unwrap_or("??"));
+        if let Some((file, line_number)) = console.source_line(frame.pc) {
+          line.push_str(&format!(" at {}:{}", file, line_number));
+        }
+        line
+      })
+      .collect::<Vec<_>>()
+      .join("\n")),
+    _ => Err("Unknown command. Try b, tbreak, ignore, catch, pb, delete, stats, watch, rwatch, awatch, unwatch, p, display, undisplay, bt, pf, cp0, alias, info, or m.".into()),
+  }
+}
+
+// Reads an object file and everything that goes with it. Its line info
+// is beside it, as name-as writes it, with .li added to its name. An ELF
+// executable has everything in it, and goes in the default layout.
+fn read_program(object: &str) -> DynResult<(Vec<u8>, Segments, u32)> {
+  let program_data = std::fs::read(object).map_err(|error| format!("Couldn't read {}: {}", object, error))?;
+  if elf::is_elf(&program_data) {
+    let entry = elf::parse(&program_data)?.entry;
+    return Ok((program_data, Segments { data: vec![], ktext: vec![], kdata: vec![], layout: Layout::default(), isa: None }, entry));
+  }
+  let lineinfo_path = format!("{}.li", object);
+  let program_lineinfo = std::fs::read_to_string(&lineinfo_path).map_err(|error| format!("Couldn't read {}: {}", lineinfo_path, error))?;
+  let segments = read_segments(object, &program_lineinfo)?;
+  let entry = entry_import(&program_lineinfo)?.unwrap_or(segments.layout.text_base);
+  Ok((program_data, segments, entry))
+}
+
+// Console output a program wrote, kept to be looked at once it's done
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
 
-  for (i, byte) in program_data.iter().enumerate() {
-    mips.write_b(mips::DOT_TEXT_START_ADDRESS + i as u32, *byte).unwrap();
+impl Write for Captured {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.borrow_mut().extend_from_slice(buf);
+    Ok(buf.len())
   }
-  mips.stop_address = mips::DOT_TEXT_START_ADDRESS as usize + program_data.len();
 
-  mips
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+// Runs each object file to the end without a debugger, as an autograder or
+// a regression suite would, and prints how each went as JSON. The console
+// input for foo.o is foo.in in the input directory, if there's one there. A program that
+// couldn't be loaded is reported rather than stopping the rest.
+fn run_batch(objects: &[String], options: &Options) -> DynResult<()> {
+  let mut results = vec![];
+  for object in objects {
+    let run = || -> DynResult<BatchResult> {
+      let (program_data, segments, entry) = read_program(object)?;
+      let mut mips = reset_mips(&program_data, &segments, entry, options)?;
+      let stdout = Captured::default();
+      mips.os.stdout = Box::new(stdout.clone());
+      if options.replay.is_none() {
+        let stem = std::path::Path::new(object).file_stem().unwrap_or_default();
+        let input = options.stdin_dir.as_ref()
+          .and_then(|dir| std::fs::read(dir.join(format!("{}.in", stem.to_string_lossy()))).ok())
+          .unwrap_or_default();
+        mips.os.stdin = Box::new(std::io::Cursor::new(input));
+      }
+
+      let error = loop {
+        let result = if options.fast { mips.step_block() } else { mips.step_one(None) };
+        match result {
+          Ok(()) | Err(ExecutionErrors::Event { event: ExecutionEvents::Watchpoint { .. } }) => (),
+          Err(error) => break error,
+        }
+      };
+      if let Some(trace) = &mut mips.trace {
+        trace.flush();
+      }
+
+      let stdout = String::from_utf8_lossy(&stdout.0.borrow()).into_owned();
+      Ok(BatchResult {
+        program: object.clone(),
+        stdout,
+        exit_code: mips.os.exit_code,
+        steps: mips.stats.instructions(),
+        outcome: match error {
+          ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete } => "completed".into(),
+          ExecutionErrors::Event { event: ExecutionEvents::StepLimit } => "step limit".into(),
+          error => error.to_string(),
+        },
+      })
+    };
+
+    results.push(run().unwrap_or_else(|error| BatchResult {
+      program: object.clone(),
+      stdout: String::new(),
+      exit_code: 1,
+      steps: 0,
+      outcome: error.to_string(),
+    }));
+  }
+
+  println!("{}", serde_json::to_string_pretty(&results)?);
+  Ok(())
+}
+
+// Waits for GDB to connect and debugs the program with it until it's done
+fn run_gdb(port: u16, object: &str, options: &Options) -> DynResult<()> {
+  let (program_data, segments, entry) = read_program(object)?;
+  let mut mips = reset_mips(&program_data, &segments, entry, options)?;
+
+  let listener = TcpListener::bind(("127.0.0.1", port))?;
+  eprintln!("Waiting for GDB on port {}", port);
+  let (stream, _) = listener.accept()?;
+  gdb::serve(&mut mips, stream)?;
+
+  if let Some(trace) = &mut mips.trace {
+    trace.flush();
+  }
+  if let (Some(path), Some(recording)) = (&options.record, &mips.os.recording) {
+    recording.save(path)?;
+  }
+  std::process::exit(mips.os.exit_code)
 }
 
 fn main() -> DynResult<()> {
 
-  let args_strings: Vec<String> = env::args().collect();
+  // Everything after -- is for the program. Options may appear anywhere
+  // among the positional arguments before it.
+  let mut args: Vec<String> = env::args().collect();
+  let program_arguments = match args.iter().position(|arg| arg == "--") {
+    Some(separator) => args.split_off(separator)[1..].to_vec(),
+    None => vec![],
+  };
+  let (option_strings, args_strings): (Vec<String>, Vec<String>) = args.into_iter().partition(|arg| arg.starts_with("--"));
+
+  let mut options = Options { arguments: program_arguments, ..Default::default() };
+  for option in &option_strings {
+    match option.as_str() {
+      "--delay-slots" => options.delay_slots = true,
+      "--no-delay-slots" => options.delay_slots = false,
+      "--virtual-clock" => options.virtual_clock = true,
+      "--syscalls=mars" => options.profile = Profile::Mars,
+      "--syscalls=spim" => options.profile = Profile::Spim,
+      "--fast" => options.fast = true,
+      "--stats" => options.stats = true,
+      "--batch" => options.batch = true,
+      "--self-modifying-code" => options.self_modifying_code = true,
+      "--no-stack-checks" => options.stack_checks = false,
+      "--random-init" => options.random_init = Some(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64)),
+      "--keyboard" => options.keys = Some(Rc::new(keyboard::terminal())),
+      _ => if let Some(dir) = option.strip_prefix("--sandbox=") {
+        options.sandbox = Some(dir.into());
+      } else if let Some(seed) = option.strip_prefix("--seed=") {
+        options.seed = Some(seed.parse().map_err(|_| format!("Invalid seed {}", seed))?);
+      } else if let Some(scale) = option.strip_prefix("--sleep-scale=") {
+        options.sleep_scale = match scale.parse::<f64>() {
+          Ok(scale) if scale >= 0.0 && scale.is_finite() => scale,
+          _ => return Err(format!("Invalid sleep scale {}", scale).into()),
+        };
+      } else if let Some(seed) = option.strip_prefix("--random-init=") {
+        options.random_init = Some(seed.parse().map_err(|_| format!("Invalid seed {}", seed))?);
+      } else if let Some(limit) = option.strip_prefix("--stack-limit=") {
+        options.stack_limit = Some(parse_address(limit)?);
+      } else if let Some(steps) = option.strip_prefix("--max-steps=") {
+        options.max_steps = Some(steps.parse().map_err(|_| format!("Invalid step limit {}", steps))?);
+      } else if let Some(steps) = option.strip_prefix("--history=") {
+        options.history = steps.parse().map_err(|_| format!("Invalid history length {}", steps))?;
+      } else if let Some(port) = option.strip_prefix("--gdb=") {
+        options.gdb = Some(port.parse().map_err(|_| format!("Invalid port {}", port))?);
+      } else if let Some(dir) = option.strip_prefix("--stdin-dir=") {
+        options.stdin_dir = Some(dir.into());
+      } else if let Some(path) = option.strip_prefix("--record=") {
+        options.record = Some(path.into());
+      } else if let Some(path) = option.strip_prefix("--replay=") {
+        options.replay = Some(Replay::load(path)?);
+      } else if let Some(path) = option.strip_prefix("--trace=") {
+        options.trace = Some(path.into());
+      } else if let Some(range) = option.strip_prefix("--trace-range=") {
+        let (start, end) = range.split_once('-').ok_or_else(|| format!("Invalid trace range {}", range))?;
+        let (start, end) = (parse_address(start)?, parse_address(end)?);
+        options.trace_range = Some(start..=end);
+      } else if let Some(mnemonics) = option.strip_prefix("--trace-mnemonics=") {
+        options.trace_mnemonics = mnemonics.split(',').map(|mnemonic| mnemonic.to_lowercase()).collect();
+      } else {
+        return Err(format!("Unknown option {}", option).into());
+      }
+    }
+  }
+  // A replayed program starts from the same garbage it was recorded with
+  if let Some(replay) = &options.replay {
+    options.random_init = replay.garbage;
+  }
+
+  let positional = if options.gdb.is_some() { 2 } else { 5 };
+  if args_strings.len() != positional && !options.batch {
+      return Err("USAGE: name-emu [--delay-slots | --no-delay-slots] [--sandbox=DIR] [--seed=N] [--sleep-scale=F] [--virtual-clock] [--syscalls=mars|spim] [--keyboard] [--fast] [--stats] [--trace=FILE] [--trace-range=START-END] [--trace-mnemonics=NAME,...] [--history=STEPS] [--max-steps=N] [--self-modifying-code] [--stack-limit=ADDRESS | --no-stack-checks] [--random-init[=SEED]] [--record=FILE | --replay=FILE] [port number] [source file] [object file] [line info file] [-- program arguments...]\n       name-emu --batch [--stdin-dir=DIR] [options] [object file or executable...]\n       name-emu --gdb=PORT [options] [object file or executable]".into());
+  }
 
-  if args_strings.len() != 5 {
-      return Err("USAGE: name-emu [port number] [source file] [object file] [line info file]".into());
+  if options.batch {
+    return run_batch(&args_strings[1..], &options);
+  }
+  if let Some(port) = options.gdb {
+    return run_gdb(port, &args_strings[1], &options);
   }
   let log_path = std::path::Path::join(env::temp_dir().as_path(), "name_log.txt");
   let mut file = File::create(log_path)?;
@@ -75,12 +1113,12 @@ fn main() -> DynResult<()> {
       }
       else {
         println!("Failed to bind port {}", port_number);
-        return Err(Box::new(MyAdapterError::ArgumentParsingError));
+        return Err(Box::new(MyAdapterError::ArgumentParsing));
       }
   }
   else {
     println!("Failed to parse port number");
-    return Err(Box::new(MyAdapterError::ArgumentParsingError));
+    return Err(Box::new(MyAdapterError::ArgumentParsing));
   };
 
   let program_name = args_strings.get(2).unwrap();
@@ -89,7 +1127,7 @@ fn main() -> DynResult<()> {
     Ok(program_data) => program_data,
     Err(why) => {
       println!("Failed to open provided object file. Reason: {}", why);
-      return Err(Box::new(MyAdapterError::ArgumentParsingError));      
+      return Err(Box::new(MyAdapterError::ArgumentParsing));      
     }
   };
 
@@ -97,11 +1135,20 @@ fn main() -> DynResult<()> {
     Ok(program_lineinfo) => program_lineinfo,
     Err(why) => {
       println!("Failed to open provided line info file. Reason: {}", why);
-      return Err(Box::new(MyAdapterError::CommandArgumentError));      
+      return Err(Box::new(MyAdapterError::CommandArgument));      
     }
   };
+  let segments = read_segments(args_strings.get(3).unwrap(), &program_lineinfo)?;
+  // Programs begin at the start of .text unless name-as recorded an entry point
+  let entry = entry_import(&program_lineinfo)?.unwrap_or(segments.layout.text_base);
+  // An ELF executable brings its own symbols
+  let mut symbols = symbols_import(&program_lineinfo)?;
+  if elf::is_elf(&program_data) {
+    symbols.extend(elf::parse(&program_data)?.symbols);
+  }
   let lineinfo = lineinfo_import(program_lineinfo)?;
   writeln!(file, "Lineinfo read: {:?}", lineinfo)?;
+  let mut console = Console { lineinfo: &lineinfo, program_name, symbols, displays: vec![], aliases: HashMap::new() };
 
 
   let mut server = Server::new(BufReader::new(in_port), BufWriter::new(out_port));
@@ -113,7 +1160,7 @@ fn main() -> DynResult<()> {
     supports_hit_conditional_breakpoints: Some(false),
     supports_evaluate_for_hovers: Some(false),
     exception_breakpoint_filters: None,
-    supports_step_back: Some(false),
+    supports_step_back: Some(options.history > 0),
     supports_set_variable: Some(false),
     supports_restart_frame: Some(false),
     supports_goto_targets_request: Some(false),
@@ -153,7 +1200,7 @@ fn main() -> DynResult<()> {
 loop {
   let req = match server.poll_request()? {
     Some(req) => req,
-    None => return Err(Box::new(MyAdapterError::MissingCommandError)),
+    None => return Err(Box::new(MyAdapterError::MissingCommand)),
   };
   writeln!(file, "Request {:?} received", req.command)?;
   writeln!(file)?;
@@ -167,7 +1214,7 @@ loop {
   
       server.send_event(Event::Initialized)?;
 
-      mips = reset_mips(&program_data);
+      mips = reset_mips(&program_data, &segments, entry, &options)?;
 
     }
 
@@ -179,6 +1226,16 @@ loop {
       );
       server.respond(rsp)?;
 
+      // What the init scripts say, shown as though it had been typed, and
+      // then the rest of what was set up last time
+      let mut output: Vec<String> = init_commands(program_name).into_iter()
+        .map(|(location, command)| run_command(&mut mips, &mut console, &location, &command))
+        .collect();
+      output.extend(restore_session(&mut mips, &mut console));
+      if !output.is_empty() {
+        server.send_event(console_output(output.join("\n")))?;
+      }
+
       let stopped_event_body = StoppedEventBody {
         reason: StoppedEventReason::Step,
         description: None,
@@ -188,7 +1245,7 @@ loop {
         all_threads_stopped: None,
         hit_breakpoint_ids: None
       };
-      server.send_event(Event::Stopped(stopped_event_body))?;
+      stopped(&mut server, stopped_event_body, &mips, &console)?;
     }
 
     Command::WriteMemory(write_mem_args) => {
@@ -199,7 +1256,7 @@ loop {
         
       //   match mips.write_w(mips::DOT_TEXT + i, word) {
       //     Ok(_) => (),
-      //     Err(_) => return Err(Box::new(MyAdapterError::CommandArgumentError))
+      //     Err(_) => return Err(Box::new(MyAdapterError::CommandArgument))
       //   }
 
       //   i += 1;
@@ -207,24 +1264,21 @@ loop {
 
       let address = match write_mem_args.memory_reference.parse::<u32>() {
         Ok(i) => i,
-        Err(_) => return Err(Box::new(MyAdapterError::CommandArgumentError))
+        Err(_) => return Err(Box::new(MyAdapterError::CommandArgument))
       } + match write_mem_args.offset {
         Some(value) => value as u32,
         None => 0
       };
 
-      for (i, byte) in bytes.iter().enumerate() {
-        match mips.write_b(address + i as u32, *byte) {
-          Ok(_) => (),
-          Err(_) => return Err(Box::new(MyAdapterError::CommandArgumentError))
-        }
+      if mips.poke_bytes(address, &bytes).is_err() {
+        return Err(Box::new(MyAdapterError::CommandArgument));
       }
     }
 
     Command::ReadMemory(ref read_mem_args) => {
       let address = match read_mem_args.memory_reference.parse::<u32>() {
         Ok(i) => i,
-        Err(_) => return Err(Box::new(MyAdapterError::CommandArgumentError))
+        Err(_) => return Err(Box::new(MyAdapterError::CommandArgument))
       } + match read_mem_args.offset {
         Some(value) => value as u32,
         None => 0
@@ -255,11 +1309,38 @@ loop {
       server.respond(rsp)?;
     }
     
-    Command::Next(_) | Command::StepIn(_) => {
-      
-      let result = mips.step_one(&mut file);
+    // Stepping over a call runs until it returns, and stepping out runs
+    // until the function it's in does, so neither stops inside another
+    // function unless something else stops it there
+    Command::Next(_) | Command::StepIn(_) | Command::StepOut(_) => {
+      let result = match (&req.command, mips.call_at_pc()) {
+        (Command::Next(_), Some(return_address)) => {
+          // A recursive call comes back to the same address deeper in the stack
+          let sp = mips.regs[29];
+          run_until(&mut mips, &mut file, options.fast, |mips| mips.pc as u32 == return_address && mips.regs[29] >= sp)
+        }
+        (Command::StepOut(_), _) => {
+          // Calls made on the way out have to return before this one can
+          let (mut depth, mut returning_to) = (0, None);
+          run_until(&mut mips, &mut file, false, |mips| {
+            if returning_to.is_some() {
+              return returning_to == Some(mips.pc as u32);
+            }
+            if mips.call_at_pc().is_some() {
+              depth += 1;
+            } else if mips.return_at_pc() {
+              match depth {
+                0 => returning_to = Some(mips.regs[31]),
+                _ => depth -= 1,
+              }
+            }
+            false
+          })
+        }
+        _ => mips.step_one(Some(&mut file)),
+      };
       let stopped_event_body = match result {
-        Ok(()) | Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete }) => {
+        Ok(()) | Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete | ExecutionEvents::StepLimit }) => {
           StoppedEventBody {
             reason: StoppedEventReason::Step,
             description: None,
@@ -270,6 +1351,10 @@ loop {
             hit_breakpoint_ids: None
           }
         }
+        Err(ExecutionErrors::Event { event: ExecutionEvents::Watchpoint { address, store } }) => watchpoint_stopped(address, store),
+        Err(ExecutionErrors::Event { event: ExecutionEvents::Breakpoint { id } }) => breakpoint_stopped(id),
+        Err(ExecutionErrors::Event { event: ExecutionEvents::Catchpoint { id } }) => catchpoint_stopped(&mips, id),
+        Err(ExecutionErrors::Event { event: ExecutionEvents::RegisterWatchpoint { id, old, new } }) => register_watchpoint_stopped(&mips, id, old, new),
         Err(_) => {
           StoppedEventBody {
             reason: StoppedEventReason::Exception,
@@ -283,24 +1368,54 @@ loop {
         }
       };
 
-      let rsp = req.success(
-        ResponseBody::Next
-      );
+      let body = match req.command {
+        Command::StepIn(_) => ResponseBody::StepIn,
+        Command::StepOut(_) => ResponseBody::StepOut,
+        _ => ResponseBody::Next,
+      };
+      let rsp = req.success(body);
       server.respond(rsp)?;
 
-      if let Err(ExecutionErrors::Event{event}) = result {
-        if event == ExecutionEvents::ProgramComplete {
-          server.send_event(Event::Terminated(None))?;
-          server.send_event(Event::Exited(ExitedEventBody{ exit_code: 0 }))?;
+      if let Err(ExecutionErrors::Event{event: event @ (ExecutionEvents::ProgramComplete | ExecutionEvents::StepLimit)}) = result {
+        if event == ExecutionEvents::StepLimit {
+          report_step_limit(&mips);
         }
+        server.send_event(Event::Terminated(None))?;
+        server.send_event(Event::Exited(ExitedEventBody{ exit_code: mips.os.exit_code as i64 }))?;
       }
       else {
         writeln!(file, "{:?}", stopped_event_body)?;
         writeln!(file, "{:?}", mips)?;
-        server.send_event(Event::Stopped(stopped_event_body))?;
+        stopped(&mut server, stopped_event_body, &mips, &console)?;
       }
     }
 
+    // Stepping back undoes a step, and reverse continuing undoes every step
+    // that's been kept
+    Command::StepBack(_) | Command::ReverseContinue(_) => {
+      let reverse_continue = matches!(req.command, Command::ReverseContinue(_));
+      if !mips.step_back() {
+        server.respond(req.error("There are no steps to go back to."))?;
+        continue;
+      }
+      while reverse_continue && mips.step_back() {}
+
+      let rsp = req.success(
+        if reverse_continue { ResponseBody::ReverseContinue } else { ResponseBody::StepBack }
+      );
+      server.respond(rsp)?;
+
+      stopped(&mut server, StoppedEventBody {
+        reason: StoppedEventReason::Step,
+        description: None,
+        thread_id: Some(0),
+        preserve_focus_hint: None,
+        text: None,
+        all_threads_stopped: None,
+        hit_breakpoint_ids: None
+      }, &mips, &console)?;
+    }
+
     Command::SetExceptionBreakpoints(_) => {
       let rsp = req.success(
         ResponseBody::SetExceptionBreakpoints(SetExceptionBreakpointsResponse{breakpoints: None})
@@ -335,27 +1450,38 @@ loop {
       server.respond(rsp)?;
       
       if let None | Some(false) = rst {
+        // The breakpoints and the rest are there for next time
+        if let Err(error) = save_session(&mips, &console) {
+          writeln!(file, "Couldn't save the debugger's state: {}", error)?;
+        }
         break;
       }
     }
 
+    // A frame for each call that led to where the program is, innermost first
     Command::StackTrace(_) => {
-      let rsp = req.success(
-        ResponseBody::StackTrace(StackTraceResponse{stack_frames: vec![
+      let stack_frames = backtrace(&mips, &console.symbols).into_iter().enumerate()
+        .map(|(i, frame)| {
+          let source_line = console.source_line(frame.pc);
           StackFrame{
-            id: 0,
-            name: "mips".to_string(),
-            source: Some(Source { name: Some(program_name.to_string()), path: None, source_reference: Some(0), presentation_hint: None, origin: None, sources: None, adapter_data: None, checksums: None }),
-            line: lineinfo[&(mips.pc as u32)].line_number as i64,
+            id: i as i64,
+            name: frame.function.unwrap_or_else(|| "mips".to_string()),
+            source: source_line.map(|(file, _)| Source { name: Some(file.to_string()), path: None, source_reference: Some(0), presentation_hint: None, origin: None, sources: None, adapter_data: None, checksums: None }),
+            line: source_line.map_or(0, |(_, line)| line as i64),
             column: 0,
             end_line: None,
             end_column: None,
             can_restart: None,
-            instruction_pointer_reference: None,
+            instruction_pointer_reference: Some(format!("0x{:08x}", frame.pc)),
             module_id: None,
             presentation_hint: None
           }
-        ], total_frames: Some(3)})
+        })
+        .collect::<Vec<_>>();
+
+      let total_frames = Some(stack_frames.len() as i64);
+      let rsp = req.success(
+        ResponseBody::StackTrace(StackTraceResponse{stack_frames, total_frames})
       );
       server.respond(rsp)?;
     }
@@ -407,19 +1533,26 @@ loop {
             }
           );
         }
-        registers.push(
-          Variable {
-            name: mips::PC_NAME.to_string(),
-            value:format!("0x{:X}", mips.pc),
-            type_field: None,
-            presentation_hint: None,
-            evaluate_name: None, // But I'm sure this should be something
-            variables_reference: 0, // Apparently I should make this 0 for non-nested structs
-            named_variables: Some(0),
-            indexed_variables: Some(0),
-            memory_reference: None // I think this would be neat to implement...
-          }
-        );
+        let special_registers = [
+          (mips::PC_NAME, mips.pc as u32),
+          (mips::HI_NAME, mips.mult_hi),
+          (mips::LO_NAME, mips.mult_lo),
+        ];
+        for (name, value) in special_registers {
+          registers.push(
+            Variable {
+              name: name.to_string(),
+              value: format!("0x{:X}", value),
+              type_field: None,
+              presentation_hint: None,
+              evaluate_name: None, // But I'm sure this should be something
+              variables_reference: 0, // Apparently I should make this 0 for non-nested structs
+              named_variables: Some(0),
+              indexed_variables: Some(0),
+              memory_reference: None // I think this would be neat to implement...
+            }
+          );
+        }
       }
 
       let rsp = req.success(
@@ -429,7 +1562,13 @@ loop {
     }
 
     Command::Restart(_) => {
-      mips = reset_mips(&program_data);
+      // Watchpoints and breakpoints outlive the run they were set in
+      let watchpoints = std::mem::take(&mut mips.memory.watchpoints);
+      let breakpoints = std::mem::take(&mut mips.breakpoints);
+      mips = reset_mips(&program_data, &segments, entry, &options)?;
+      mips.memory.watchpoints = watchpoints;
+      mips.breakpoints = breakpoints;
+      mips.note_watched_registers();
 
       let rsp = req.success(
         ResponseBody::Restart
@@ -445,7 +1584,7 @@ loop {
         all_threads_stopped: None,
         hit_breakpoint_ids: None
       };
-      server.send_event(Event::Stopped(stopped_event_body))?;
+      stopped(&mut server, stopped_event_body, &mips, &console)?;
     }
 
     Command::ExceptionInfo(_) => {
@@ -458,24 +1597,40 @@ loop {
       server.respond(rsp)?;
     }
 
+    // Commands typed into the debug console
+    Command::Evaluate(ref evaluate_args) => {
+      let rsp = match evaluate(&mut mips, &evaluate_args.expression, &mut console) {
+        Ok(result) => req.success(
+          ResponseBody::Evaluate(EvaluateResponse {
+            result,
+            type_field: None,
+            presentation_hint: None,
+            variables_reference: 0,
+            named_variables: None,
+            indexed_variables: None,
+            memory_reference: None
+          })
+        ),
+        Err(message) => req.error(&message),
+      };
+
+      server.respond(rsp)?;
+    }
+
     Command::Continue(_) => {
       let rsp = req.success(
         ResponseBody::Continue(ContinueResponse{ all_threads_continued: Some(true)})
       );
       server.respond(rsp)?;
 
-      // Keep stepping until something happens...
-      loop {
-        if let Err(_) = mips.step_one(&mut file) {
-          break;
-        }
-      }
+      // Keep going until something happens
+      mips.prev_ins_result = run_until(&mut mips, &mut file, options.fast, |_| false);
       // OK, what happened?
       let stopped_event_body = match mips.prev_ins_result {
         Ok(()) => unreachable!(), // It's unreachable.
         Err(what_happened) => match what_happened {
           ExecutionErrors::Event{event} => match event {
-            ExecutionEvents::ProgramComplete => {
+            ExecutionEvents::ProgramComplete | ExecutionEvents::StepLimit => {
               StoppedEventBody {
                 reason: StoppedEventReason::Step,
                 description: None,
@@ -486,6 +1641,10 @@ loop {
                 hit_breakpoint_ids: None
               }
             }
+            ExecutionEvents::Watchpoint { address, store } => watchpoint_stopped(address, store),
+            ExecutionEvents::Breakpoint { id } => breakpoint_stopped(id),
+            ExecutionEvents::Catchpoint { id } => catchpoint_stopped(&mips, id),
+            ExecutionEvents::RegisterWatchpoint { id, old, new } => register_watchpoint_stopped(&mips, id, old, new),
           },
           _ => { // Some kind of exception occurred...
             StoppedEventBody {
@@ -500,29 +1659,44 @@ loop {
           }
         }
       };
-      server.send_event(Event::Stopped(stopped_event_body))?;
+      stopped(&mut server, stopped_event_body, &mips, &console)?;
 
       // Whole second match body to figure out what to do about it
       match mips.prev_ins_result {
         Ok(()) => unreachable!(), // It's unreachable.
         Err(what_happened) => match what_happened {
           ExecutionErrors::Event{event} => match event {
-            ExecutionEvents::ProgramComplete => {
+            ExecutionEvents::ProgramComplete | ExecutionEvents::StepLimit => {
+              if event == ExecutionEvents::StepLimit {
+                report_step_limit(&mips);
+              }
               server.send_event(Event::Terminated(None))?;
-              server.send_event(Event::Exited(ExitedEventBody{ exit_code: 0 }))?;
+              server.send_event(Event::Exited(ExitedEventBody{ exit_code: mips.os.exit_code as i64 }))?;
             }
+            ExecutionEvents::Watchpoint { .. } | ExecutionEvents::Breakpoint { .. } | ExecutionEvents::Catchpoint { .. } | ExecutionEvents::RegisterWatchpoint { .. } => (),
           },
           _ => { // Some kind of exception occurred...
-            () // Don't need to do anything else for now
+            // Don't need to do anything else for now
           }
         }
       }
     }
 
     _ => ()
-    // _ => () //Err(Box::new(MyAdapterError::UnhandledCommandError))
+    // _ => () //Err(Box::new(MyAdapterError::UnhandledCommand))
   };
 }
 
-  Ok(())
+  // The program's exit status becomes name-emu's
+  drop(server);
+  if options.stats {
+    eprint!("{}", mips.stats.summary());
+  }
+  if let Some(trace) = &mut mips.trace {
+    trace.flush();
+  }
+  if let (Some(path), Some(recording)) = (&options.record, &mips.os.recording) {
+    recording.save(path)?;
+  }
+  std::process::exit(mips.os.exit_code)
 }
@@ -0,0 +1,315 @@
+// The machine's memory, made of regions like .text, .data, the heap, and
+// the stack. Each region has a base address, the length the program is
+// using, and the most it can grow to. Accessing a region past the length
+// it's using is an overrun, and accessing outside every region is illegal.
+// Regions holding code can be write protected, so a program can't store
+// over its own instructions. Regions can also start out full of garbage
+// instead of zeros, to catch programs that read memory they never wrote.
+//
+// The bytes themselves are kept in pages, which are only allocated once
+// something is written to them, so a region costs nothing until it's used
+// and growing one never copies it.
+//
+// Memory-mapped devices sit above all of that, in the MMIO region, and
+// accesses there go to them instead.
+//
+// The debugger can watch ranges of addresses. Accesses to them are noted as
+// they happen, so the machine can stop once the instruction that made them
+// is done.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::device::{Device, MMIO_START_ADDRESS};
+use crate::exception::ExecutionErrors;
+use crate::random::JavaRandom;
+
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: u32 = PAGE_SIZE as u32 - 1;
+
+#[derive(Debug)]
+struct Region {
+    base_address: u32,
+    length: u32,
+    max_length: u32,
+    writable: bool,
+    // What the region's garbage is made from, if it starts out with any
+    garbage: Option<u64>,
+}
+
+impl Region {
+    fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.base_address) < self.max_length
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u32>,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Memory {
+    regions: Vec<Region>,
+    // Allocated pages, by page number. Pages that aren't here hold zeros.
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE]>>,
+    pub devices: Vec<Box<dyn Device>>,
+    pub watchpoints: Vec<Watchpoint>,
+    // The first watched access since this was last taken, and whether it
+    // was a store
+    watch_hit: Option<(u32, bool)>,
+}
+
+// Halfword and word accesses must be naturally aligned
+pub fn check_alignment(address: u32, width: u32, store: bool) -> Result<(), ExecutionErrors> {
+    if address.is_multiple_of(width) {
+        Ok(())
+    } else if store {
+        Err(ExecutionErrors::AddressExceptionStore { address })
+    } else {
+        Err(ExecutionErrors::AddressExceptionLoad { address })
+    }
+}
+
+impl Memory {
+    // Adds a region holding `contents`, which can grow to max_length. A
+    // region already at the same base address is replaced.
+    pub fn map(&mut self, base_address: u32, max_length: u32, contents: &[u8]) {
+        if let Some(old) = self.regions.iter().position(|region| region.base_address == base_address) {
+            let old = self.regions.remove(old);
+            self.clear(old.base_address, old.length);
+        }
+
+        self.regions.push(Region { base_address, length: contents.len() as u32, max_length, writable: true, garbage: None });
+        for (i, byte) in contents.iter().enumerate() {
+            self.page(base_address + i as u32)[((base_address + i as u32) & PAGE_MASK) as usize] = *byte;
+        }
+    }
+
+    // Changes how much of the region at the given base address is in use.
+    // Space given back reads as zeros if it's used again. Returns false if
+    // there's no such region.
+    pub fn resize(&mut self, base_address: u32, length: u32) -> bool {
+        let Some(region) = self.regions.iter_mut().find(|region| region.base_address == base_address) else {
+            return false;
+        };
+
+        let old_length = std::mem::replace(&mut region.length, length.min(region.max_length));
+        if length < old_length {
+            self.clear(base_address + length, old_length - length);
+        }
+        true
+    }
+
+    // Sets whether the program can store into the region at the given base
+    // address. Returns false if there's no such region.
+    pub fn protect(&mut self, base_address: u32, writable: bool) -> bool {
+        match self.regions.iter_mut().find(|region| region.base_address == base_address) {
+            Some(region) => {
+                region.writable = writable;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Fills the region at the given base address with garbage made from a
+    // seed, as far as it hasn't been written yet. The same seed always makes
+    // the same garbage. Returns false if there's no such region.
+    pub fn fill_with_garbage(&mut self, base_address: u32, seed: u64) -> bool {
+        match self.regions.iter_mut().find(|region| region.base_address == base_address) {
+            Some(region) => {
+                region.garbage = Some(seed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Adds a device, replacing any whose registers it overlaps
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        let range = device.range();
+        self.devices.retain(|other| other.range().end <= range.start || range.end <= other.range().start);
+        self.devices.push(device);
+    }
+
+    pub fn read_byte(&mut self, address: u32) -> Result<u8, ExecutionErrors> {
+        self.watch(address, false);
+        if let Some(device) = self.map_device(address) {
+            return Ok(device.read(address));
+        }
+        let garbage = self.check(address)?.garbage;
+        Ok(match self.pages.get(&(address >> PAGE_BITS)) {
+            Some(page) => page[(address & PAGE_MASK) as usize],
+            None if garbage.is_some() => self.page(address)[(address & PAGE_MASK) as usize],
+            None => 0,
+        })
+    }
+
+    pub fn set_byte(&mut self, address: u32, value: u8) -> Result<(), ExecutionErrors> {
+        self.watch(address, true);
+        self.store_byte(address, value, true)
+    }
+
+    // Stores a byte even where the program can't, for the debugger. No
+    // watchpoints notice it.
+    pub fn poke_byte(&mut self, address: u32, value: u8) -> Result<(), ExecutionErrors> {
+        self.store_byte(address, value, false)
+    }
+
+    // Multibyte values are little endian, and must be aligned
+    pub fn read_u16(&mut self, address: u32) -> Result<u16, ExecutionErrors> {
+        check_alignment(address, 2, false)?;
+        let mut bytes = [0; 2];
+        self.read_bytes(address, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self, address: u32) -> Result<u32, ExecutionErrors> {
+        check_alignment(address, 4, false)?;
+        let mut bytes = [0; 4];
+        self.read_bytes(address, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn write_u16(&mut self, address: u32, value: u16) -> Result<(), ExecutionErrors> {
+        check_alignment(address, 2, true)?;
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, address: u32, value: u32) -> Result<(), ExecutionErrors> {
+        check_alignment(address, 4, true)?;
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+
+    // Fills a buffer with the bytes starting at an address
+    pub fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ExecutionErrors> {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(address + i as u32)?;
+        }
+        Ok(())
+    }
+
+    // Stores bytes starting at an address
+    pub fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ExecutionErrors> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.set_byte(address + i as u32, *byte)?;
+        }
+        Ok(())
+    }
+
+    // Reads a byte without anything noticing: not watchpoints, and not
+    // devices, which can't be read without disturbing them. None if the
+    // address isn't in use or belongs to a device.
+    pub fn peek_byte(&self, address: u32) -> Option<u8> {
+        if address >= MMIO_START_ADDRESS {
+            return None;
+        }
+        let garbage = self.check(address).ok()?.garbage;
+        Some(match self.pages.get(&(address >> PAGE_BITS)) {
+            Some(page) => page[(address & PAGE_MASK) as usize],
+            None => garbage.map_or(0, |seed| garbage_page(seed, address >> PAGE_BITS)[(address & PAGE_MASK) as usize]),
+        })
+    }
+
+    // How many bytes from an address on are in use, up to the end of its
+    // region. 0 if the address isn't in use.
+    pub fn in_use_from(&self, address: u32) -> u32 {
+        self.check(address).map_or(0, |region| region.base_address + region.length - address)
+    }
+
+    // Whether the program can store to an address, or None if the address
+    // isn't in use or belongs to a device
+    pub fn writable(&self, address: u32) -> Option<bool> {
+        if address >= MMIO_START_ADDRESS {
+            return None;
+        }
+        self.check(address).ok().map(|region| region.writable)
+    }
+
+    // A little-endian word, read the same way. It doesn't have to be aligned.
+    pub fn peek_u32(&self, address: u32) -> Option<u32> {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.peek_byte(address.wrapping_add(i as u32))?;
+        }
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    // The first watched access since the last time this was called
+    pub fn take_watch_hit(&mut self) -> Option<(u32, bool)> {
+        self.watch_hit.take()
+    }
+
+    fn watch(&mut self, address: u32, store: bool) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.range.contains(&address) && if store { watchpoint.write } else { watchpoint.read }
+        }) {
+            self.watch_hit = Some((address, store));
+        }
+    }
+
+    // The device whose registers hold the given address, if any does
+    fn map_device(&mut self, address: u32) -> Option<&mut Box<dyn Device>> {
+        if address < MMIO_START_ADDRESS {
+            return None;
+        }
+        self.devices.iter_mut().find(|device| device.range().contains(&address))
+    }
+
+    fn store_byte(&mut self, address: u32, value: u8, protected: bool) -> Result<(), ExecutionErrors> {
+        if let Some(device) = self.map_device(address) {
+            device.write(address, value);
+            return Ok(());
+        }
+        if !self.check(address)?.writable && protected {
+            return Err(ExecutionErrors::WriteProtected { address });
+        }
+        self.page(address)[(address & PAGE_MASK) as usize] = value;
+        Ok(())
+    }
+
+    // The region using the address
+    fn check(&self, address: u32) -> Result<&Region, ExecutionErrors> {
+        match self.regions.iter().find(|region| region.contains(address)) {
+            Some(region) if address - region.base_address < region.length => Ok(region),
+            // Although this access was within the region, the program hasn't
+            // grown the region that far, so it probably overran a buffer
+            Some(_) => Err(ExecutionErrors::MemoryObviousOverrunAccess { load_address: address }),
+            None => Err(ExecutionErrors::MemoryIllegalAccess { load_address: address }),
+        }
+    }
+
+    // The page holding an address, allocated if it hasn't been yet
+    fn page(&mut self, address: u32) -> &mut [u8; PAGE_SIZE] {
+        let garbage = self.regions.iter().find(|region| region.contains(address)).and_then(|region| region.garbage);
+        self.pages.entry(address >> PAGE_BITS).or_insert_with(|| match garbage {
+            Some(seed) => garbage_page(seed, address >> PAGE_BITS),
+            None => Box::new([0; PAGE_SIZE]),
+        })
+    }
+
+    // Zeroes a span of memory, freeing pages left holding nothing but zeros
+    fn clear(&mut self, address: u32, length: u32) {
+        for address in address..address + length {
+            if let Some(page) = self.pages.get_mut(&(address >> PAGE_BITS)) {
+                page[(address & PAGE_MASK) as usize] = 0;
+            }
+        }
+        self.pages.retain(|_, page| page.iter().any(|byte| *byte != 0));
+    }
+}
+
+// The garbage a page starts out with, which only depends on the seed and
+// where the page is
+fn garbage_page(seed: u64, page_number: u32) -> Box<[u8; PAGE_SIZE]> {
+    let mut random = JavaRandom::new(seed ^ page_number as u64);
+    let mut page = Box::new([0; PAGE_SIZE]);
+    for word in page.chunks_mut(4) {
+        word.copy_from_slice(&random.next_int().to_le_bytes());
+    }
+    page
+}
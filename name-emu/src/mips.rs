@@ -1,14 +1,35 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Cursor;
-
 use std::fs::File;
 use std::io::Write;
 
+use crate::breakpoints::{Breakpoints, Catch};
+use crate::cop0::Coprocessor0;
+use crate::cop1::{Coprocessor1, Format, FP_DIVIDE_BY_ZERO, FP_INEXACT};
 use crate::exception::{ExecutionErrors, ExecutionEvents};
+use crate::history::History;
+use crate::keyboard::Keyboard;
+use crate::memory::{check_alignment, Memory};
+use crate::os::OperatingSystem;
+use crate::random::JavaRandom;
+use crate::simulator::Hooks;
+use crate::stats::Statistics;
+use crate::trace::{Registers, Trace};
+use name_const::isa::Isa;
+use name_const::layout::Layout;
 
-pub const DOT_TEXT_START_ADDRESS: u32 = 0x00400000;
-const DOT_TEXT_MAX_LENGTH: u32 = 0x1000;
-const LEN_TEXT_INITIAL: usize = 200;
+// Where the text, data, heap, and stack segments go comes from the layout
+// the program was assembled for. Kernel text begins at the general
+// exception vector, where exceptions go when the program installs its own
+// handler.
+pub const DOT_KTEXT_START_ADDRESS: u32 = 0x80000180;
+pub const DOT_KTEXT_MAX_LENGTH: u32 = 0x1000;
+pub const DOT_KDATA_START_ADDRESS: u32 = 0x90000000;
+pub const DOT_KDATA_MAX_LENGTH: u32 = 0x10000;
+// $sp starts where MARS starts it, a little below the top of the stack
+const STACK_POINTER_GAP: u32 = 0x1004;
+// Addresses from here up can only be executed in kernel mode
+const KERNEL_SPACE_START: u32 = 0x80000000;
+// A program stopped for running too long exits as timeout(1) has a command do
+pub const STEP_LIMIT_EXIT_CODE: i32 = 124;
 const MIPS_INSTRUCTION_LENGTH: usize = 4;
 
 pub const REGISTER_NAMES: [&str; 32] = [
@@ -46,8 +67,10 @@ pub const REGISTER_NAMES: [&str; 32] = [
     "$ra"
 ];
 pub const PC_NAME: &str = "$pc";
+pub const HI_NAME: &str = "$hi";
+pub const LO_NAME: &str = "$lo";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum BranchDelays {
     NotActive,
     Set,
@@ -55,62 +78,150 @@ enum BranchDelays {
 }
 
 #[derive(Debug)]
-pub(crate) struct Mips {
+pub struct Mips {
     pub regs: [u32; 32],
-    // Note that these register sets have yet to be implemented.
-    /*
-    pub floats: [f32; 32],
+    // The system control coprocessor
+    pub cop0: Coprocessor0,
+    // The floating-point unit
+    pub cop1: Coprocessor1,
+    // Services requested with syscall
+    pub os: OperatingSystem,
+    // Where the program's segments are
+    pub layout: Layout,
+    // The instruction set the program was assembled against, if it named
+    // one. Anything outside it is a reserved instruction.
+    pub isa: Option<Isa>,
+    // What the program has done so far
+    pub stats: Statistics,
+    // Where instructions are recorded as they run, if anywhere
+    pub trace: Option<Trace>,
+    // What's told about the program as it runs
+    pub hooks: Hooks,
+    // Where the debugger stops the program
+    pub breakpoints: Breakpoints,
+    // Results of multiplication and division
     pub mult_hi: u32,
     pub mult_lo: u32,
-    */
     pub pc: usize,
 
     // Branch delay slots are implemented by filling this buffer with the
     // branch target, which will be triggered after the following instruction
     branch_delay_target: u32,
     branch_delay_status: BranchDelays,
+    // Without delay slots, as in SPIM and MARS by default, control transfers
+    // as soon as a branch or jump executes
+    pub delay_slots: bool,
+    // How many instructions the program may run before it's stopped, if
+    // there's a limit
+    pub max_steps: Option<u64>,
+    // The lowest $sp may go, if the stack is being checked. Nothing should
+    // be stored between the bottom of the stack and $sp either, since that
+    // space hasn't been allocated.
+    pub stack_limit: Option<u32>,
+    // Whether the program installed its own exception handler in .ktext.
+    // Without one, exceptions stop the program in the debugger instead.
+    pub exception_handler: bool,
+
+    // The LLbit, set by Load Linked along with the word it linked. Store
+    // Conditional only stores while it's set, and any store to the linked
+    // word or any exception in between clears it.
+    ll_bit: bool,
+    ll_address: u32,
     
 
-    // A list of vectors of memory pools, their base addresses, and their
-    // lengths.
-    // Memories allocated at runtime will actually have Vec lengths shorter
-    // than this by 0x10. This is intended to alert the user that they 
-    // probably wrote out of bounds, allowing us to return a clearer exception
-    // and explanation as to what happened.
-    pub memories: Vec<(Vec<u8>, u32, u32)>,
+    pub memory: Memory,
+    // Decoded .text instructions with their words, by word, so each is only
+    // decoded once. Stores into .text, when self-modifying code is allowed,
+    // forget what they overwrite.
+    decoded: Vec<Option<(u32, Instructions)>>,
     // The end of the MIPS program. In NAME, the program terminates when no more instructions exist
     // (as in, falling off the bottom is valid).
     pub stop_address: usize,
     
     // Memory for the result of a previous instruction (useful for tracking exceptions)
-    pub prev_ins_result: Result<(), ExecutionErrors>
+    pub prev_ins_result: Result<(), ExecutionErrors>,
+
+    // Recent steps, for stepping back, if they're being kept
+    history: Option<History<Processor>>,
+}
+
+// Everything about the machine besides its memory, as it was before a step.
+// Devices aren't rewound, and neither is anything syscalls did outside the
+// machine, like printing or writing files.
+#[derive(Debug)]
+struct Processor {
+    regs: [u32; 32],
+    cop0: Coprocessor0,
+    cop1: Coprocessor1,
+    mult_hi: u32,
+    mult_lo: u32,
+    pc: usize,
+    branch_delay_target: u32,
+    branch_delay_status: BranchDelays,
+    ll_bit: bool,
+    ll_address: u32,
+    prev_ins_result: Result<(), ExecutionErrors>,
 }
 
 
 impl Default for Mips {
     fn default() -> Self {
+        Self::new(Layout::default())
+    }
+}
+
+impl Mips {
+    // A machine with its memory laid out as given, ready for a program to be loaded
+    pub fn new(layout: Layout) -> Self {
+        let stack_top = layout.stack_base + layout.stack_size;
         Self {
-            regs: [0; 32],
-            // This is also dead code for right now
-            /*
-            floats: [0f32; 32],
+            regs: {
+                let mut regs = [0; 32];
+                regs[29] = stack_top - STACK_POINTER_GAP.min(layout.stack_size);
+                regs
+            },
+            cop0: Coprocessor0::default(),
+            cop1: Coprocessor1::default(),
+            os: OperatingSystem::default(),
+            layout,
+            isa: None,
+            stats: Statistics::default(),
+            trace: None,
+            hooks: Hooks::default(),
+            breakpoints: Breakpoints::default(),
             mult_hi: 0,
             mult_lo: 0,
-            */
-            pc: DOT_TEXT_START_ADDRESS as usize,
+            pc: layout.text_base as usize,
             branch_delay_target: 0,
             branch_delay_status: BranchDelays::NotActive,
-            memories: vec![
-                (vec![0; LEN_TEXT_INITIAL], DOT_TEXT_START_ADDRESS, DOT_TEXT_MAX_LENGTH)   
-            ],
-            stop_address: DOT_TEXT_START_ADDRESS as usize,
-            prev_ins_result: Ok(())
+            delay_slots: true,
+            max_steps: None,
+            stack_limit: Some(layout.stack_base),
+            exception_handler: false,
+            ll_bit: false,
+            ll_address: 0,
+            memory: {
+                // The program loads .text and the other segments it has.
+                // The stack is there from the start, and the heap grows
+                // from nothing.
+                let mut memory = Memory::default();
+                memory.map(layout.stack_base, layout.stack_size, &[]);
+                memory.resize(layout.stack_base, layout.stack_size);
+                memory.map(layout.heap_base, layout.heap_size, &[]);
+                memory.attach(Box::new(Keyboard::default()));
+                memory
+            },
+            decoded: vec![None; layout.text_size as usize / MIPS_INSTRUCTION_LENGTH],
+            stop_address: layout.text_base as usize,
+            prev_ins_result: Ok(()),
+            history: None,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Rtype {
+    opcode: u32,
     rs: usize,
     rt: usize,
     rd: usize,
@@ -118,7 +229,7 @@ struct Rtype {
     funct: u8
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Itype {
     opcode: u32,
     rs: usize,
@@ -126,65 +237,181 @@ struct Itype {
     imm: u16
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Jtype {
     opcode: u32,
     dest: u32
 }
 
-// struct Jtype
-// struct Ftype
+#[derive(Debug, Clone, Copy)]
+struct Ftype {
+    opcode: u32,
+    fmt: u8,
+    ft: usize,
+    fs: usize,
+    fd: usize,
+    funct: u8
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Instructions {
     R(Rtype),
     I(Itype),
-    J(Jtype)
-    //F type
+    J(Jtype),
+    F(Ftype)
 }
 
 impl Mips {
 
     fn dispatch_r(&mut self, ins: Rtype, opcode: u32) -> Result<(), ExecutionErrors> {
+        match ins.opcode {
+            0x10 => return self.dispatch_cop0(ins, opcode),
+            0x1C => return self.dispatch_special2(ins, opcode),
+            0x1F => return self.dispatch_special3(ins, opcode),
+            _ => ()
+        }
 
         match ins.funct {
             // Shift-left logical
             0x0 => {
                 self.regs[ins.rd] = self.regs[ins.rt] << ins.shamt;
             }
+            // Rotate right (rs field set)
+            0x2 if ins.rs == 1 => {
+                self.regs[ins.rd] = self.regs[ins.rt].rotate_right(ins.shamt as u32);
+            }
             // Shift-right logical
             0x2 => {
                 self.regs[ins.rd] = self.regs[ins.rt] >> ins.shamt;
             }
+            // Shift-right arithmetic
+            0x3 => {
+                self.regs[ins.rd] = ((self.regs[ins.rt] as i32) >> ins.shamt) as u32;
+            }
+            // Shift-left logical variable
+            0x4 => {
+                self.regs[ins.rd] = self.regs[ins.rt] << (self.regs[ins.rs] & 0b11111);
+            }
+            // Rotate right variable (shamt field set)
+            0x6 if ins.shamt == 1 => {
+                self.regs[ins.rd] = self.regs[ins.rt].rotate_right(self.regs[ins.rs] & 0b11111);
+            }
+            // Shift-right logical variable
+            0x6 => {
+                self.regs[ins.rd] = self.regs[ins.rt] >> (self.regs[ins.rs] & 0b11111);
+            }
+            // Shift-right arithmetic variable
+            0x7 => {
+                self.regs[ins.rd] = ((self.regs[ins.rt] as i32) >> (self.regs[ins.rs] & 0b11111)) as u32;
+            }
+            // Move on floating-point condition (false or true, picked by the low bit of rt)
+            0x1 => {
+                if self.cop1.condition(ins.rt >> 2) == (ins.rt & 1 == 1) {
+                    self.regs[ins.rd] = self.regs[ins.rs];
+                }
+            }
+            // Jump register
+            0x8 => {
+                self.branch_delay_status = BranchDelays::Set;
+                self.branch_delay_target = self.regs[ins.rs];
+            }
+            // Move conditional on zero
+            0xA => {
+                if self.regs[ins.rt] == 0 {
+                    self.regs[ins.rd] = self.regs[ins.rs];
+                }
+            }
+            // Move conditional on not zero
+            0xB => {
+                if self.regs[ins.rt] != 0 {
+                    self.regs[ins.rd] = self.regs[ins.rs];
+                }
+            }
+            // Move From HI
+            0x10 => {
+                self.regs[ins.rd] = self.mult_hi;
+            }
+            // Move To HI
+            0x11 => {
+                self.mult_hi = self.regs[ins.rs];
+            }
+            // Move From LO
+            0x12 => {
+                self.regs[ins.rd] = self.mult_lo;
+            }
+            // Move To LO
+            0x13 => {
+                self.mult_lo = self.regs[ins.rs];
+            }
+            // Multiply, with the 64-bit product split across HI and LO
+            0x18 => {
+                let product = self.regs[ins.rs] as i32 as i64 * self.regs[ins.rt] as i32 as i64;
+                self.mult_hi = (product >> 32) as u32;
+                self.mult_lo = product as u32;
+            }
+            // Multiply Unsigned
+            0x19 => {
+                let product = self.regs[ins.rs] as u64 * self.regs[ins.rt] as u64;
+                self.mult_hi = (product >> 32) as u32;
+                self.mult_lo = product as u32;
+            }
+            // Divide, with the quotient in LO and the remainder in HI.
+            // Dividing by zero is unpredictable on MIPS, NAME leaves HI and LO alone like MARS.
+            0x1A => {
+                let (dividend, divisor) = (self.regs[ins.rs] as i32, self.regs[ins.rt] as i32);
+                if divisor != 0 {
+                    self.mult_lo = dividend.wrapping_div(divisor) as u32;
+                    self.mult_hi = dividend.wrapping_rem(divisor) as u32;
+                }
+            }
+            // Divide Unsigned
+            0x1B => {
+                let (dividend, divisor) = (self.regs[ins.rs], self.regs[ins.rt]);
+                if let (Some(quotient), Some(remainder)) = (dividend.checked_div(divisor), dividend.checked_rem(divisor)) {
+                    self.mult_lo = quotient;
+                    self.mult_hi = remainder;
+                }
+            }
+            // System call
+            0xC => {
+                for hook in self.hooks.syscall.clone() {
+                    hook(self, self.regs[2]);
+                }
+                OperatingSystem::handle_syscall(self)?
+            }
+            // Trap if Greater or Equal, Greater or Equal Unsigned, Less Than,
+            // Less Than Unsigned, Equal, and Not Equal
+            0x30..=0x34 | 0x36 => {
+                let (rs, rt) = (self.regs[ins.rs], self.regs[ins.rt]);
+                let trapped = match ins.funct {
+                    0x30 => rs as i32 >= rt as i32,
+                    0x31 => rs >= rt,
+                    0x32 => (rs as i32) < rt as i32,
+                    0x33 => rs < rt,
+                    0x34 => rs == rt,
+                    _ => rs != rt,
+                };
+                if trapped {
+                    return Err(ExecutionErrors::Trap { instruction: opcode });
+                }
+            }
             // Add
             0x20 => {
-                let result = self.regs[ins.rt].checked_add(self.regs[ins.rs]);
-                match result {
-                    Some(value) => {self.regs[ins.rd] = value;}
-                    None => {
-                        return Err(ExecutionErrors::IntegerOverflow { 
-                        rt: ins.rt, 
-                        rs: ins.rs, 
-                        value1: self.regs[ins.rt],
-                        value2: self.regs[ins.rs]
-                        });
-                    }
-                }
+                let result = (self.regs[ins.rs] as i32).checked_add(self.regs[ins.rt] as i32);
+                self.regs[ins.rd] = self.check_overflow(result, ins.rs, ins.rt, self.regs[ins.rt])?;
+            }
+            // Add unsigned (never traps)
+            0x21 => {
+                self.regs[ins.rd] = self.regs[ins.rs].wrapping_add(self.regs[ins.rt]);
             }
             // Subtract
             0x22 => {
-                let result = self.regs[ins.rt].checked_sub(self.regs[ins.rs]);
-                match result {
-                    Some(value) => {self.regs[ins.rd] = value;}
-                    None => {
-                        return Err(ExecutionErrors::IntegerOverflow { 
-                        rt: ins.rt, 
-                        rs: ins.rs, 
-                        value1: self.regs[ins.rt],
-                        value2: self.regs[ins.rs]
-                        });
-                    }
-                }
+                let result = (self.regs[ins.rs] as i32).checked_sub(self.regs[ins.rt] as i32);
+                self.regs[ins.rd] = self.check_overflow(result, ins.rs, ins.rt, self.regs[ins.rt])?;
+            }
+            // Subtract unsigned (never traps)
+            0x23 => {
+                self.regs[ins.rd] = self.regs[ins.rs].wrapping_sub(self.regs[ins.rt]);
             }
             // Or
             0x25 => {
@@ -210,11 +437,160 @@ impl Mips {
         }
         Ok(())
     }
+    // Coprocessor 0 instructions, which share opcode 0x10 and are told apart by rs
+    fn dispatch_cop0(&mut self, ins: Rtype, opcode: u32) -> Result<(), ExecutionErrors> {
+        // Only select 0 of each register is modeled
+        if ins.rs < 0x10 && ins.funct & 0b111 != 0 {
+            return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode});
+        }
+
+        match ins.rs {
+            // Move from coprocessor 0
+            0x00 => {
+                self.regs[ins.rt] = self.cop0.read(ins.rd)
+                    .ok_or(ExecutionErrors::UndefinedInstruction {instruction: opcode})?;
+            }
+            // Move to coprocessor 0
+            0x04 => {
+                if !self.cop0.write(ins.rd, self.regs[ins.rt]) {
+                    return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode});
+                }
+            }
+            // Exception return, which has no delay slot
+            0x10 if ins.funct == 0x18 => {
+                self.pc = self.cop0.return_from_exception() as usize;
+                self.ll_bit = false;
+            }
+            _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+        }
+
+        Ok(())
+    }
+
+    // SPECIAL2 instructions, which share opcode 0x1C
+    fn dispatch_special2(&mut self, ins: Rtype, opcode: u32) -> Result<(), ExecutionErrors> {
+        let rs = self.regs[ins.rs];
+        let rt = self.regs[ins.rt];
+
+        match ins.funct {
+            // Multiply and add, accumulating into HI/LO
+            0x00 => {
+                let product = (rs as i32 as i64) * (rt as i32 as i64);
+                self.set_accumulator(self.accumulator().wrapping_add(product as u64));
+            }
+            // Multiply and add unsigned
+            0x01 => {
+                let product = (rs as u64) * (rt as u64);
+                self.set_accumulator(self.accumulator().wrapping_add(product));
+            }
+            // Multiply and subtract
+            0x04 => {
+                let product = (rs as i32 as i64) * (rt as i32 as i64);
+                self.set_accumulator(self.accumulator().wrapping_sub(product as u64));
+            }
+            // Multiply and subtract unsigned
+            0x05 => {
+                let product = (rs as u64) * (rt as u64);
+                self.set_accumulator(self.accumulator().wrapping_sub(product));
+            }
+            // Count leading zeros
+            0x20 => {
+                self.regs[ins.rd] = self.regs[ins.rs].leading_zeros();
+            }
+            // Count leading ones
+            0x21 => {
+                self.regs[ins.rd] = self.regs[ins.rs].leading_ones();
+            }
+            _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+        }
+
+        Ok(())
+    }
+
+    // Turns the result of signed arithmetic into the value to write, or an
+    // Integer Overflow exception if there was none. The destination register
+    // must be left untouched when this fails. For an immediate instruction,
+    // the second operand is the immediate rather than rt's value.
+    fn check_overflow(&self, result: Option<i32>, rs: usize, rt: usize, operand: u32) -> Result<u32, ExecutionErrors> {
+        result.map(|value| value as u32).ok_or(ExecutionErrors::IntegerOverflow {
+            rt,
+            rs,
+            value1: self.regs[rs],
+            value2: operand
+        })
+    }
+
+    // HI and LO taken together as a single 64-bit accumulator
+    fn accumulator(&self) -> u64 {
+        (self.mult_hi as u64) << 32 | self.mult_lo as u64
+    }
+
+    fn set_accumulator(&mut self, value: u64) {
+        self.mult_hi = (value >> 32) as u32;
+        self.mult_lo = value as u32;
+    }
+
+    // SPECIAL3 instructions, which share opcode 0x1F
+    fn dispatch_special3(&mut self, ins: Rtype, opcode: u32) -> Result<(), ExecutionErrors> {
+        // The bit field's lsb is in shamt
+        let lsb = ins.shamt as u32;
+
+        match ins.funct {
+            // Extract bit field, with size - 1 in rd
+            0x00 => {
+                let size = ins.rd as u32 + 1;
+                if lsb + size > 32 {
+                    return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode});
+                }
+                let mask = (((1u64 << size) - 1) as u32) << lsb;
+                self.regs[ins.rt] = (self.regs[ins.rs] & mask) >> lsb;
+            }
+            // Insert bit field, with its msb in rd
+            0x04 => {
+                let msb = ins.rd as u32;
+                if msb < lsb {
+                    return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode});
+                }
+                let mask = (((1u64 << (msb - lsb + 1)) - 1) as u32) << lsb;
+                self.regs[ins.rt] = (self.regs[ins.rt] & !mask) | ((self.regs[ins.rs] << lsb) & mask);
+            }
+            // BSHFL, where shamt picks the operation
+            0x20 => {
+                let value = self.regs[ins.rt];
+                self.regs[ins.rd] = match ins.shamt {
+                    // Swap the bytes within each halfword
+                    0x02 => ((value & 0x00FF00FF) << 8) | ((value & 0xFF00FF00) >> 8),
+                    // Sign-extend byte
+                    0x10 => value as u8 as i8 as i32 as u32,
+                    // Sign-extend halfword
+                    0x18 => value as u16 as i16 as i32 as u32,
+                    _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+                };
+            }
+            _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+        }
+
+        Ok(())
+    }
+
     fn dispatch_i(&mut self, ins: Itype, opcode: u32) -> Result<(), ExecutionErrors> {
 
-        let memory_address = (ins.rt as i64 + (ins.imm as i64)) as u32;
+        // Loads and stores address memory at base register + sign-extended offset
+        let memory_address = self.regs[ins.rs].wrapping_add(ins.imm as i16 as i32 as u32);
 
         match ins.opcode {
+            // REGIMM, where rt selects the operation rather than naming a register
+            0x1 => self.dispatch_regimm(ins, opcode)?,
+            // Add Immediate Unsigned (never traps, the immediate is still sign-extended)
+            0x9 => {
+                self.regs[ins.rt] = self.regs[ins.rs].wrapping_add(ins.imm as i16 as i32 as u32);
+            }
+            // Add Immediate, which traps on signed overflow
+            0x8 => {
+                let imm = ins.imm as i16 as i32;
+                let result = (self.regs[ins.rs] as i32).checked_add(imm);
+                self.regs[ins.rt] = self.check_overflow(result, ins.rs, ins.rt, imm as u32)?;
+            }
             // Set on Less Than Immediate (signed)
             // If rs is less than sign-extended 16 bit immediate using signed comparison, then set rt to 1
             // Casting on imm is to sign extend. See load byte casts
@@ -236,12 +612,36 @@ impl Mips {
             0xF => {
                 self.regs[ins.rt] = (ins.imm as u32) << 16;
             }
-            // Load word (0x23) and Load Linked (0x30).
-            // A word on Load Linked-- This is an instruction for atomic accesses
-            // across SMP processors. NAME does not implement SMP, so this is equal to
-            // Load word.
-            0x23 | 0x30 =>{
+            // Load word
+            0x23 =>{
+                self.regs[ins.rt] = self.read_w(memory_address)?;
+            }
+            // Load Linked, which loads a word and links it for a later Store Conditional.
+            // NAME has a single processor, so only this program's own stores and
+            // exceptions can break the link.
+            0x30 => {
                 self.regs[ins.rt] = self.read_w(memory_address)?;
+                self.ll_bit = true;
+                self.ll_address = memory_address;
+            }
+            // Load word left, which fills the most significant bytes of rt with the
+            // bytes from the address down to the word boundary. This is little endian,
+            // so those are the low-address bytes of the aligned word.
+            0x22 => {
+                let unaligned_by = memory_address & 0b11;
+                let word = self.read_w(memory_address - unaligned_by)?;
+                let kept_bits = 8 * (3 - unaligned_by);
+                let kept = (self.regs[ins.rt] as u64 & ((1u64 << kept_bits) - 1)) as u32;
+                self.regs[ins.rt] = (word << kept_bits) | kept;
+            }
+            // Load word right, which fills the least significant bytes of rt with the
+            // bytes from the address up to the next word boundary
+            0x26 => {
+                let unaligned_by = memory_address & 0b11;
+                let word = self.read_w(memory_address - unaligned_by)?;
+                let loaded_bits = 8 * (4 - unaligned_by);
+                let kept = self.regs[ins.rt] & !(((1u64 << loaded_bits) - 1) as u32);
+                self.regs[ins.rt] = (word >> (8 * unaligned_by)) | kept;
             }
             // Load byte unsigned
             // Note that "as u32" WILL zero extend
@@ -271,32 +671,153 @@ impl Mips {
             0x29 => {
                 self.write_h(memory_address, self.regs[ins.rt] as u16)?;
             }
-            // Store word (0x2b) and Store Conditional (0x38).
-            // Store Conditional is the second half of Load Linked, and it's an equivalent
-            // op for the same reason.
-            0x2b | 0x38 => {
+            // Store word left, the mirror of load word left
+            0x2A => {
+                let unaligned_by = memory_address & 0b11;
+                let aligned_address = memory_address - unaligned_by;
+                let stored_bits = 8 * (unaligned_by + 1);
+                let stored_mask = ((1u64 << stored_bits) - 1) as u32;
+                let word = self.read_w(aligned_address)?;
+                let stored = self.regs[ins.rt] >> (32 - stored_bits);
+                self.write_w(aligned_address, (word & !stored_mask) | stored)?;
+            }
+            // Store word right, the mirror of load word right
+            0x2E => {
+                let unaligned_by = memory_address & 0b11;
+                let aligned_address = memory_address - unaligned_by;
+                let kept_mask = ((1u64 << (8 * unaligned_by)) - 1) as u32;
+                let word = self.read_w(aligned_address)?;
+                let stored = self.regs[ins.rt] << (8 * unaligned_by);
+                self.write_w(aligned_address, (word & kept_mask) | stored)?;
+            }
+            // Store word
+            0x2b => {
                 self.write_w(memory_address, self.regs[ins.rt])?;
             }
-            // Branch if Equal
-            0x4 => {
-                if self.regs[ins.rt] == self.regs[ins.rs] {
-                    self.branch_delay_target = (ins.imm as u32) << 2;
-                    self.branch_delay_status = BranchDelays::Set;
+            // Store Conditional, which stores only if the link from Load Linked is
+            // still intact, and reports whether it did in rt
+            0x38 => {
+                check_alignment(memory_address, 4, true)?;
+                let linked = self.ll_bit && self.ll_address == memory_address;
+                if linked {
+                    self.write_w(memory_address, self.regs[ins.rt])?;
                 }
+                self.ll_bit = false;
+                self.regs[ins.rt] = linked as u32;
             }
+            // Branch if Equal
+            0x4 => self.branch(self.regs[ins.rt] == self.regs[ins.rs], ins.imm),
             // Branch if Not Equal
-            0x5 => {
-                if self.regs[ins.rt] != self.regs[ins.rs] {
-                    self.branch_delay_target = (ins.imm as u32) << 2;
-                    self.branch_delay_status = BranchDelays::Set;
-                }
+            0x5 => self.branch(self.regs[ins.rt] != self.regs[ins.rs], ins.imm),
+            // Branch if Less Than or Equal to Zero
+            0x6 => self.branch(self.regs[ins.rs] as i32 <= 0, ins.imm),
+            // Branch if Greater Than Zero
+            0x7 => self.branch(self.regs[ins.rs] as i32 > 0, ins.imm),
+            // Branch if Equal Likely
+            0x14 => self.branch_likely(self.regs[ins.rt] == self.regs[ins.rs], ins.imm),
+            // Branch if Not Equal Likely
+            0x15 => self.branch_likely(self.regs[ins.rt] != self.regs[ins.rs], ins.imm),
+            // Branch if Less Than or Equal to Zero Likely
+            0x16 => self.branch_likely(self.regs[ins.rs] as i32 <= 0, ins.imm),
+            // Branch if Greater Than Zero Likely
+            0x17 => self.branch_likely(self.regs[ins.rs] as i32 > 0, ins.imm),
+
+            _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+        }
+        Ok(())
+    }
+
+    fn dispatch_regimm(&mut self, ins: Itype, opcode: u32) -> Result<(), ExecutionErrors> {
+        let rs = self.regs[ins.rs];
+        // Immediates are sign-extended even for the unsigned comparisons
+        let imm = ins.imm as i16 as i32;
+
+        match ins.rt {
+            // Branch if Less Than Zero
+            0x0 => {
+                self.branch((rs as i32) < 0, ins.imm);
+                return Ok(());
+            }
+            // Branch if Greater Than or Equal to Zero
+            0x1 => {
+                self.branch(rs as i32 >= 0, ins.imm);
+                return Ok(());
+            }
+            // Branch if Less Than Zero Likely
+            0x2 => {
+                self.branch_likely((rs as i32) < 0, ins.imm);
+                return Ok(());
             }
-            
+            // Branch if Greater Than or Equal to Zero Likely
+            0x3 => {
+                self.branch_likely(rs as i32 >= 0, ins.imm);
+                return Ok(());
+            }
+            // Branch if Less Than Zero And Link, which links whether or not it's taken
+            0x10 => {
+                self.link();
+                self.branch((rs as i32) < 0, ins.imm);
+                return Ok(());
+            }
+            // Branch if Greater Than or Equal to Zero And Link
+            0x11 => {
+                self.link();
+                self.branch(rs as i32 >= 0, ins.imm);
+                return Ok(());
+            }
+            _ => ()
+        }
 
+        let trapped = match ins.rt {
+            // Trap if Greater or Equal Immediate
+            0x8 => rs as i32 >= imm,
+            // Trap if Greater or Equal Immediate Unsigned
+            0x9 => rs >= imm as u32,
+            // Trap if Less Than Immediate
+            0xA => (rs as i32) < imm,
+            // Trap if Less Than Immediate Unsigned
+            0xB => rs < imm as u32,
+            // Trap if Equal Immediate
+            0xC => rs as i32 == imm,
+            // Trap if Not Equal Immediate
+            0xE => rs as i32 != imm,
             _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
+        };
+
+        if trapped {
+            return Err(ExecutionErrors::Trap { instruction: opcode });
         }
         Ok(())
     }
+
+    fn branch(&mut self, taken: bool, imm: u16) {
+        if taken {
+            self.branch_delay_target = self.branch_target(imm);
+            self.branch_delay_status = BranchDelays::Set;
+        }
+    }
+
+    // $ra = register 31, returning past the delay slot the PC points at
+    fn link(&mut self) {
+        self.regs[31] = self.pc as u32 + if self.delay_slots { 4 } else { 0 };
+    }
+
+    // A branch-likely instruction runs its delay slot only when the branch is
+    // taken. Otherwise the delay slot is nullified by skipping over it.
+    fn branch_likely(&mut self, taken: bool, imm: u16) {
+        if taken {
+            self.branch_delay_target = self.branch_target(imm);
+            self.branch_delay_status = BranchDelays::Set;
+        } else if self.delay_slots {
+            self.pc += MIPS_INSTRUCTION_LENGTH;
+        }
+    }
+
+    // Branch offsets count words from the delay slot, which the PC already points at
+    fn branch_target(&self, imm: u16) -> u32 {
+        (self.pc as u32).wrapping_add(((imm as i16 as i32) << 2) as u32)
+    }
+
     fn dispatch_j(&mut self, ins: Jtype, opcode: u32) -> Result<(), ExecutionErrors> {
         // This instruction type takes the top nybble of PC and combines it with
         // a 28-bit range (26 bits as encoded shifted left twice.)
@@ -311,8 +832,7 @@ impl Mips {
             3 => {
                 self.branch_delay_status = BranchDelays::Set;
                 self.branch_delay_target = self.pc as u32 & 0xF0000000 | (ins.dest << 2);
-                // $ra = register 31
-                self.regs[31] = self.pc as u32 + 8;
+                self.link();
             }
             _ => return Err(ExecutionErrors::UndefinedInstruction {instruction: opcode})
         }
@@ -320,12 +840,191 @@ impl Mips {
         Ok(())
     }
 
+    fn dispatch_f(&mut self, ins: Ftype, opcode: u32) -> Result<(), ExecutionErrors> {
+        if ins.opcode == 0x13 {
+            return self.dispatch_cop1x(ins, opcode);
+        }
+
+        // Transfers between register files reuse the format field to pick the direction
+        match ins.fmt {
+            // Move word from floating-point register
+            0x00 => {
+                self.regs[ins.ft] = self.cop1.regs[ins.fs];
+                return Ok(());
+            }
+            // Move control word from floating-point unit
+            0x02 => {
+                self.regs[ins.ft] = self.cop1.read_control(ins.fs)
+                    .ok_or(ExecutionErrors::UndefinedInstruction { instruction: opcode })?;
+                return Ok(());
+            }
+            // Move word to floating-point register
+            0x04 => {
+                self.cop1.regs[ins.fs] = self.regs[ins.ft];
+                return Ok(());
+            }
+            // Branch on floating-point condition (false or true, picked by the
+            // low bit of ft). The offset fills the fields below ft.
+            0x08 if ins.ft & 0b10 == 0 => {
+                let offset = (ins.fs << 11 | ins.fd << 6) as u16 | ins.funct as u16;
+                self.branch(self.cop1.condition(ins.ft >> 2) == (ins.ft & 1 == 1), offset);
+                return Ok(());
+            }
+            // Move control word to floating-point unit, which raises any
+            // exception whose cause and enable bits are now both set
+            0x06 => {
+                if !self.cop1.write_control(ins.fs, self.regs[ins.ft]) {
+                    return Err(ExecutionErrors::UndefinedInstruction { instruction: opcode });
+                }
+                if self.cop1.pending_exception() {
+                    return Err(ExecutionErrors::FloatingPoint { fcsr: self.cop1.fcsr });
+                }
+                return Ok(());
+            }
+            _ => ()
+        }
+
+        // Doubles move both halves of their register pair
+        let fmt = Format::from_field(ins.fmt)
+            .ok_or(ExecutionErrors::UndefinedInstruction { instruction: opcode })?;
+
+        let condition = match ins.funct {
+            // Compare, with the condition in the low four bits of funct and
+            // the condition code in the top three bits of fd
+            0x30..=0x3F => {
+                return self.cop1.compare(fmt, ins.fd >> 2, ins.fs, ins.ft, ins.funct & 0xF);
+            }
+            // Square root
+            0x04 => {
+                return self.cop1.operation(fmt, ins.fd, &[ins.fs],
+                    |v| (v[0].sqrt(), 0),
+                    |v, r| v[0].is_infinite() || r.mul_add(r, -v[0]) == 0.0);
+            }
+            // Reciprocal
+            0x15 => {
+                return self.cop1.operation(fmt, ins.fd, &[ins.fs],
+                    |v| (1.0 / v[0], if v[0] == 0.0 { FP_DIVIDE_BY_ZERO } else { 0 }),
+                    |v, r| v[0].is_infinite() || r.mul_add(v[0], -1.0) == 0.0);
+            }
+            // Reciprocal square root
+            0x16 => {
+                return self.cop1.operation(fmt, ins.fd, &[ins.fs],
+                    |v| (1.0 / v[0].sqrt(), if v[0] == 0.0 { FP_DIVIDE_BY_ZERO } else { 0 }),
+                    |v, r| v[0].is_infinite() || (r * r).mul_add(v[0], -1.0) == 0.0);
+            }
+            // movf.fmt/movt.fmt, with the condition code and true/false bit in ft
+            0x11 => self.cop1.condition(ins.ft >> 2) == (ins.ft & 1 == 1),
+            // movz.fmt
+            0x12 => self.regs[ins.ft] == 0,
+            // movn.fmt
+            0x13 => self.regs[ins.ft] != 0,
+            _ => return Err(ExecutionErrors::UndefinedInstruction { instruction: opcode })
+        };
+
+        if condition {
+            for i in 0..fmt.width() {
+                self.cop1.regs[(ins.fd + i) % 32] = self.cop1.regs[(ins.fs + i) % 32];
+            }
+        }
+
+        Ok(())
+    }
+
+    // COP1X instructions, which share opcode 0x13
+    fn dispatch_cop1x(&mut self, ins: Ftype, opcode: u32) -> Result<(), ExecutionErrors> {
+        // Indexed loads and stores address base (in fmt) plus index (in ft)
+        if ins.funct < 0x20 {
+            let address = self.regs[ins.fmt as usize].wrapping_add(self.regs[ins.ft]);
+            return self.indexed_memory(ins, address, opcode);
+        }
+
+        // The format is in the low three bits of funct, and fr where fmt usually is
+        let fmt = match ins.funct & 0b111 {
+            0 => Format::Single,
+            1 => Format::Double,
+            _ => return Err(ExecutionErrors::UndefinedInstruction { instruction: opcode })
+        };
+        // Multiply-add operations are picked by the upper three bits of funct:
+        // whether to subtract fr, and whether to negate the result
+        let (subtract, negate) = match ins.funct >> 3 {
+            0b100 => (false, false),
+            0b101 => (true, false),
+            0b110 => (false, true),
+            0b111 => (true, true),
+            _ => return Err(ExecutionErrors::UndefinedInstruction { instruction: opcode })
+        };
+
+        // The product is rounded before fr is added, as the operations aren't fused
+        let fr = ins.fmt as usize;
+        self.cop1.operation(fmt, ins.fd, &[fr, ins.fs, ins.ft],
+            |v| {
+                let product = fmt.round(v[1] * v[2]);
+                let inexact = if product.is_finite() && v[1].mul_add(v[2], -product) != 0.0 {
+                    FP_INEXACT
+                } else {
+                    0
+                };
+                let sum = if subtract { product - v[0] } else { product + v[0] };
+                (if negate { -sum } else { sum }, inexact)
+            },
+            |v, r| {
+                let product = fmt.round(v[1] * v[2]);
+                let addend = if subtract { -v[0] } else { v[0] };
+                let rounded = if negate { -r } else { r };
+                // Exact when the sum needed no rounding, to double precision or to the format
+                let sum = product + addend;
+                let error = (product - (sum - addend)) + (addend - (sum - product));
+                error == 0.0 && rounded == sum
+            })
+    }
+
+    fn indexed_memory(&mut self, ins: Ftype, address: u32, opcode: u32) -> Result<(), ExecutionErrors> {
+        match ins.funct {
+            // Load word indexed
+            0x00 => {
+                self.cop1.regs[ins.fd] = self.read_w(address)?;
+            }
+            // Load doubleword indexed, into a register pair
+            0x01 => {
+                check_alignment(address, 8, false)?;
+                self.cop1.regs[ins.fd] = self.read_w(address)?;
+                self.cop1.regs[(ins.fd + 1) % 32] = self.read_w(address + 4)?;
+            }
+            // Load doubleword indexed unaligned, which ignores the low three bits
+            0x05 => {
+                let address = address & !0b111;
+                self.cop1.regs[ins.fd] = self.read_w(address)?;
+                self.cop1.regs[(ins.fd + 1) % 32] = self.read_w(address + 4)?;
+            }
+            // Store word indexed
+            0x08 => {
+                self.write_w(address, self.cop1.regs[ins.fs])?;
+            }
+            // Store doubleword indexed
+            0x09 => {
+                check_alignment(address, 8, true)?;
+                self.write_w(address, self.cop1.regs[ins.fs])?;
+                self.write_w(address + 4, self.cop1.regs[(ins.fs + 1) % 32])?;
+            }
+            // Store doubleword indexed unaligned
+            0x0D => {
+                let address = address & !0b111;
+                self.write_w(address, self.cop1.regs[ins.fs])?;
+                self.write_w(address + 4, self.cop1.regs[(ins.fs + 1) % 32])?;
+            }
+            _ => return Err(ExecutionErrors::UndefinedInstruction { instruction: opcode })
+        }
+
+        Ok(())
+    }
+
     fn decode(&self, instruction: u32) -> Instructions {
         let opcode = instruction >> 26 & 0b111111;
         match opcode {
-            // R-type
-            0 => {
+            // R-type, including the COP0, SPECIAL2, and SPECIAL3 opcodes
+            0 | 0x10 | 0x1C | 0x1F => {
                 Instructions::R(Rtype {
+                    opcode,
                     // These are all five-bit fields
                     rs: (instruction >> 21 & 0b11111) as usize,
                     rt: (instruction >> 16 & 0b11111) as usize,
@@ -343,6 +1042,17 @@ impl Mips {
                     dest: instruction & 0b11111111111111111111111111
                 })
             }
+            // Coprocessor 1 (floating-point), and its COP1X extension
+            0x11 | 0x13 => {
+                Instructions::F(Ftype {
+                    opcode,
+                    fmt: (instruction >> 21 & 0b11111) as u8,
+                    ft: (instruction >> 16 & 0b11111) as usize,
+                    fs: (instruction >> 11 & 0b11111) as usize,
+                    fd: (instruction >> 6 & 0b11111) as usize,
+                    funct: (instruction & 0b111111) as u8
+                })
+            }
             // I-type
             _ => {
                 Instructions::I(Itype {
@@ -355,107 +1065,468 @@ impl Mips {
         }
     }
 
-    // Given an address, return a pool of actual memory and the offset with
-    // which to access the requested data within it. Note that the offset 
-    // address is not necessarily allocated within the returned Vec, 
-    // this function just checks ranges.
-    fn map_memory(&mut self, address: u32) -> Option<(&mut Vec<u8>, u32)> {
-        // Access by the various pools of memory that exist.
-        // Note that if an address is supposedly within a region,
-        // but that region hasn't been initialized, it won't be within
-        // the Vecs size and therefore won't be addressed.
-        for (pool, base_address, max_length) in &mut self.memories {
-            if (*base_address .. *base_address + *max_length).contains(&address) {
-                return Some((pool, address - *base_address))
+    // Fills the registers, the stack, and the heap with garbage made from a
+    // seed instead of zeros, so a program that uses them before setting
+    // them goes wrong the same way every time it's run with that seed. $sp
+    // and $gp still point where they should.
+    pub fn randomize(&mut self, seed: u64) {
+        let mut random = JavaRandom::new(seed);
+        for (i, reg) in self.regs.iter_mut().enumerate() {
+            if !matches!(i, 0 | 28 | 29) {
+                *reg = random.next_int();
             }
         }
-        None
+        self.mult_hi = random.next_int();
+        self.mult_lo = random.next_int();
+        for reg in &mut self.cop1.regs {
+            *reg = random.next_int();
+        }
+
+        self.memory.fill_with_garbage(self.layout.stack_base, seed);
+        self.memory.fill_with_garbage(self.layout.heap_base, seed);
     }
 
-    // This function attempts to access a byte of memory and returns an error if that memory doesn't exist
-    pub fn read_b(&mut self, address: u32) -> Result<u8, ExecutionErrors> {
-        if let Some((memory, offset)) = self.map_memory(address) {
-            if let Some(value) = memory.get(offset as usize) {
-                Ok(*value)
-            }
-            // Although this memory access was technically within this range,
-            // the Vec did not actually fit within it. This means that the user
-            // wrote out of bounds of the buffer
-            else {
-                Err(ExecutionErrors::MemoryObviousOverrunAccess { load_address: address } )
-            }
+    // Resizes the heap to the given length
+    pub fn resize_heap(&mut self, length: u32) {
+        self.memory.resize(self.layout.heap_base, length);
+    }
+
+    // Passes arguments to the program as MARS does. The strings go at the
+    // top of the stack, and below them $sp points at argc, followed by the
+    // argv array and a null. argc and argv are also passed in $a0 and $a1.
+    // They're put there the way the debugger would, since the program
+    // hasn't started.
+    pub fn pass_arguments(&mut self, arguments: &[String]) -> Result<(), ExecutionErrors> {
+        let mut address = self.layout.stack_base + self.layout.stack_size;
+        let mut argv = vec![];
+        for argument in arguments {
+            address -= argument.len() as u32 + 1;
+            self.poke_bytes(address, argument.as_bytes())?;
+            self.poke_bytes(address + argument.len() as u32, &[0])?;
+            argv.push(address);
         }
-        else { Err(ExecutionErrors::MemoryIllegalAccess { load_address: address } ) }
+
+        // Word aligned, with room for argc, the pointers, and the null
+        let sp = (address & !0b11) - 4 * (argv.len() as u32 + 2);
+        self.poke_bytes(sp, &(argv.len() as u32).to_le_bytes())?;
+        for (i, pointer) in argv.iter().chain([&0]).enumerate() {
+            self.poke_bytes(sp + 4 * (i as u32 + 1), &pointer.to_le_bytes())?;
+        }
+
+        self.regs[29] = sp;
+        self.regs[4] = argv.len() as u32;
+        self.regs[5] = sp + 4;
+        Ok(())
+    }
+
+    // Memory accesses go through here so stores can break the link from
+    // Load Linked. Halfwords and words must be aligned.
+    pub fn read_b(&mut self, address: u32) -> Result<u8, ExecutionErrors> {
+        self.memory.read_byte(address)
     }
-    // Reads two bytes and returns a halfword
     pub fn read_h(&mut self, address: u32) -> Result<u16, ExecutionErrors> {
-        let bytes = [self.read_b(address)?, self.read_b(address + 1)?];
-        Ok(Cursor::new(bytes).read_u16::<LittleEndian>().unwrap())
+        self.memory.read_u16(address)
     }
-    // Reads four bytes and returns a word
     pub fn read_w(&mut self, address: u32) -> Result<u32, ExecutionErrors> {
-        let bytes = [self.read_b(address)?, self.read_b(address + 1)?,
-                        self.read_b(address + 2)?, self.read_b(address + 3)?];
-        Ok(Cursor::new(bytes).read_u32::<LittleEndian>().unwrap())
+        self.memory.read_u32(address)
     }
 
-    
-    // Writes one byte
     pub fn write_b(&mut self, address: u32, value: u8) -> Result<(), ExecutionErrors> {
-        if let Some((memory, offset)) = self.map_memory(address) {
-            if let Some(element) = memory.get_mut(offset as usize) {
-                *element = value;
-                Ok(())
-            }
-            else {
-                Err(ExecutionErrors::MemoryObviousOverrunAccess { load_address: address }
-                )
-            }
-        }
-        else { Err(ExecutionErrors::MemoryIllegalAccess { load_address: address } ) }
+        self.check_stack_store(address)?;
+        self.stored(address, &[value]);
+        self.memory.set_byte(address, value)
     }
-    // Writes a halfword in little endian form
     pub fn write_h(&mut self, address: u32, value: u16) -> Result<(), ExecutionErrors> {
-        let mut bytes = vec![];
-        bytes.write_u16::<LittleEndian>(value).unwrap();
-        self.write_b(address, bytes[0])?;
-        self.write_b(address, bytes[1])?;
-        Ok(())
+        self.check_stack_store(address)?;
+        self.stored(address, &value.to_le_bytes());
+        self.memory.write_u16(address, value)
     }
-    // Writes a word in little endian form
     pub fn write_w(&mut self, address: u32, value: u32) -> Result<(), ExecutionErrors> {
-        let mut bytes = vec![];
-        bytes.write_u32::<LittleEndian>(value).unwrap();
-        self.write_b(address, bytes[0])?;
-        self.write_b(address, bytes[1])?;
-        self.write_b(address, bytes[2])?;
-        self.write_b(address, bytes[3])?;
+        self.check_stack_store(address)?;
+        self.stored(address, &value.to_le_bytes());
+        self.memory.write_u32(address, value)
+    }
+    pub fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ExecutionErrors> {
+        self.check_stack_store(address)?;
+        self.stored(address, bytes);
+        self.memory.write_bytes(address, bytes)
+    }
+
+    // While $sp is in the stack, the space below it hasn't been allocated,
+    // so storing there is a mistake even though the memory exists
+    fn check_stack_store(&self, address: u32) -> Result<(), ExecutionErrors> {
+        let sp = self.regs[29];
+        let stack = self.layout.stack_base as u64..self.layout.stack_base as u64 + self.layout.stack_size as u64;
+        if self.stack_limit.is_some() && stack.contains(&(sp as u64)) && stack.contains(&(address as u64)) && address < sp {
+            return Err(ExecutionErrors::StoreBelowStackPointer { address, sp });
+        }
         Ok(())
     }
 
-    pub fn step_one(&mut self, f :&mut File) -> Result<(), ExecutionErrors> {
-        let opcode = self.read_w(self.pc as u32)?;
-        self.pc += MIPS_INSTRUCTION_LENGTH;
+    // Stores bytes for the debugger, even into .text while it's write
+    // protected. The debugger is only changing the program, so nothing is
+    // traced or kept for stepping back, and the link from Load Linked stays.
+    pub fn poke_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ExecutionErrors> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory.poke_byte(address + i as u32, *byte)?;
+        }
+        self.forget_decoded(address as u64, address as u64 + bytes.len() as u64);
+        Ok(())
+    }
+
+    // Any store into the linked word breaks the link, and a store into
+    // .text means the instructions there have to be decoded again. A trace
+    // records what was stored.
+    fn stored(&mut self, address: u32, bytes: &[u8]) {
+        if let Some(trace) = &mut self.trace {
+            trace.stored(address, bytes);
+        }
+
+        // Device registers aren't rewound, so there's nothing to keep for them
+        if let Some(history) = &mut self.history {
+            let old = (address..address.saturating_add(bytes.len() as u32))
+                .map_while(|address| self.memory.peek_byte(address))
+                .collect();
+            history.stored(address, old);
+        }
+
+        let (start, end) = (address as u64, address as u64 + bytes.len() as u64);
+        if start < self.ll_address as u64 + 4 && (self.ll_address as u64) < end {
+            self.ll_bit = false;
+        }
+        self.forget_decoded(start, end);
+    }
+
+    // Drops whatever decoded instructions overlap the given addresses
+    fn forget_decoded(&mut self, start: u64, end: u64) {
+        let text = self.layout.text_base as u64..self.layout.text_base as u64 + self.layout.text_size as u64;
+        if start < text.end && text.start < end {
+            let word = |address: u64| (address - text.start) as usize / MIPS_INSTRUCTION_LENGTH;
+            let (first, last) = (word(start.max(text.start)), word(end.min(text.end) - 1));
+            self.decoded[first..=last].fill(None);
+        }
+    }
+
+    fn registers(&self) -> Registers {
+        Registers { regs: self.regs, hi: self.mult_hi, lo: self.mult_lo, fprs: self.cop1.regs }
+    }
+
+    // The word at pc and its decoding, which comes from the cache in .text
+    fn fetch(&mut self) -> Result<(u32, Instructions), ExecutionErrors> {
+        let address = self.pc as u32;
+        let word = address.wrapping_sub(self.layout.text_base) as usize / MIPS_INSTRUCTION_LENGTH;
+        let cacheable = address.is_multiple_of(MIPS_INSTRUCTION_LENGTH as u32) && word < self.decoded.len();
+
+        if cacheable {
+            if let Some(decoded) = self.decoded[word] {
+                return Ok(decoded);
+            }
+        }
+
+        let opcode = self.read_w(address)?;
+        let decoded = (opcode, self.decode(opcode));
+        if cacheable {
+            self.decoded[word] = Some(decoded);
+        }
+        Ok(decoded)
+    }
+
+    // Hands an exception to the program's handler: CP0 records it, and
+    // execution continues at the exception vector in kernel mode
+    fn take_exception(&mut self, code: u32, bad_address: Option<u32>, in_delay_slot: bool) {
+        if let Some(address) = bad_address {
+            self.cop0.bad_vaddr = address;
+        }
+
+        // An exception in a delay slot returns to the branch, so it runs again
+        let epc = if in_delay_slot { self.pc - MIPS_INSTRUCTION_LENGTH } else { self.pc };
+        self.cop0.enter_exception(code, epc as u32, in_delay_slot);
+
+        self.branch_delay_status = BranchDelays::NotActive;
+        self.pc = DOT_KTEXT_START_ADDRESS as usize;
+    }
+
+    // Stops the program in its handler, right after it's taken an exception,
+    // if a catchpoint is waiting for that one
+    fn caught(&mut self, code: u32) -> Result<(), ExecutionErrors> {
+        match self.breakpoints.exception_hit(code) {
+            Some(catchpoint) => Err(ExecutionErrors::Event { event: ExecutionEvents::Catchpoint { id: catchpoint.id } }),
+            None => Ok(()),
+        }
+    }
+
+    // Notes the watched registers' values, giving back the first one that's
+    // changed since they were last noted, with what it was and is now
+    fn register_changed(&mut self) -> Option<(u32, u32, u32)> {
+        // Usually nothing is watched, and there's nothing to read
+        self.breakpoints.register_watches().next()?;
+        let values: Vec<u32> = self.breakpoints.register_watches().map(|watch| watch.register.read(self)).collect();
+        let (id, old) = self.breakpoints.registers_changed(&values)?;
+        let new = self.breakpoints.register_watch(id)?.value;
+        Some((id, old, new))
+    }
+
+    // Takes the watched registers as they are now, after something other
+    // than an instruction changed them, so they don't look changed by the
+    // next one
+    pub fn note_watched_registers(&mut self) {
+        let values: Vec<u32> = self.breakpoints.register_watches().map(|watch| watch.register.read(self)).collect();
+        self.breakpoints.note_registers(&values);
+    }
+
+    // Starts keeping the given number of steps, so the program can be run
+    // backwards that far. None are kept by default.
+    pub fn keep_history(&mut self, steps: usize) {
+        self.history = (steps > 0).then(|| History::new(steps));
+    }
+
+    // Undoes the last step. Returns false if there's none to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some((processor, stores)) = self.history.as_mut().and_then(|history| history.pop()) else {
+            return false;
+        };
+
+        for (address, old) in stores {
+            // These bytes were all read before being overwritten, so they're there to put back
+            for (i, byte) in old.iter().enumerate() {
+                let _ = self.memory.poke_byte(address + i as u32, *byte);
+            }
+            self.forget_decoded(address as u64, address as u64 + old.len() as u64);
+        }
+
+        let Processor { regs, cop0, cop1, mult_hi, mult_lo, pc, branch_delay_target, branch_delay_status, ll_bit, ll_address, prev_ins_result } = processor;
+        self.regs = regs;
+        self.cop0 = cop0;
+        self.cop1 = cop1;
+        self.mult_hi = mult_hi;
+        self.mult_lo = mult_lo;
+        self.pc = pc;
+        self.branch_delay_target = branch_delay_target;
+        self.branch_delay_status = branch_delay_status;
+        self.ll_bit = ll_bit;
+        self.ll_address = ll_address;
+        self.prev_ins_result = prev_ins_result;
+        self.note_watched_registers();
+        true
+    }
+
+    // Starts a step in the history, if one is being kept
+    fn checkpoint(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let processor = Processor {
+            regs: self.regs,
+            cop0: self.cop0.clone(),
+            cop1: self.cop1.clone(),
+            mult_hi: self.mult_hi,
+            mult_lo: self.mult_lo,
+            pc: self.pc,
+            branch_delay_target: self.branch_delay_target,
+            branch_delay_status: self.branch_delay_status,
+            ll_bit: self.ll_bit,
+            ll_address: self.ll_address,
+            prev_ins_result: self.prev_ins_result,
+        };
+        if let Some(history) = &mut self.history {
+            history.push(processor);
+        }
+    }
+
+    // Loads a program's segments, as name-as wrote them, to start at entry.
+    // The data segment is sized to exactly what the program declared, and
+    // kernel text is the program's exception handler.
+    pub fn load(&mut self, text: &[u8], data: &[u8], ktext: &[u8], kdata: &[u8], entry: u32) {
+        self.memory.map(self.layout.text_base, self.layout.text_size, text);
+        self.stop_address = self.layout.text_base as usize + text.len();
+        self.pc = entry as usize;
+
+        if !data.is_empty() {
+            self.memory.map(self.layout.data_base, self.layout.data_size, data);
+        }
+        if !ktext.is_empty() {
+            self.memory.map(DOT_KTEXT_START_ADDRESS, DOT_KTEXT_MAX_LENGTH, ktext);
+            self.exception_handler = true;
+        }
+        if !kdata.is_empty() {
+            self.memory.map(DOT_KDATA_START_ADDRESS, DOT_KDATA_MAX_LENGTH, kdata);
+        }
+    }
+
+    // Runs one instruction, logging it to `f` if given
+    pub fn step_one(&mut self, f: Option<&mut File>) -> Result<(), ExecutionErrors> {
+        if let Some(result) = self.between_instructions() {
+            return result;
+        }
+        self.execute(f)
+    }
+
+    // Runs a basic block: instructions in a straight line, up to wherever
+    // control flow leaves it or a breakpoint, out of the decoded instruction cache. This is
+    // the fast way to run, since devices step and interrupts are taken only
+    // between blocks, and nothing is logged. Count still keeps up, so the
+    // timer fires at the end of the block it comes due in.
+    pub fn step_block(&mut self) -> Result<(), ExecutionErrors> {
+        if let Some(result) = self.between_instructions() {
+            return result;
+        }
+
+        // A syscall that's caught is left for the debugger to stop before
+        let catching = self.breakpoints.catchpoints().any(|catchpoint| matches!(catchpoint.catch, Catch::Syscall(_)));
+        loop {
+            let next = self.pc + MIPS_INSTRUCTION_LENGTH;
+            self.execute(None)?;
+            let limited = self.max_steps.is_some_and(|max_steps| self.stats.instructions() >= max_steps);
+            let caught = catching && self.syscall_at_pc().is_some_and(|service| self.breakpoints.catches_syscall(service));
+            if self.pc != next || self.pc == self.stop_address || limited || self.breakpoints.contains(self.pc as u32) || caught {
+                return Ok(());
+            }
+            self.checkpoint();
+            self.cop0.tick();
+        }
+    }
+
+    // Where the instruction at pc returns to if it's a call: a jal, a jalr,
+    // or a branch that links. That's past its delay slot, if it has one.
+    pub fn call_at_pc(&self) -> Option<u32> {
+        let word = self.memory.peek_u32(self.pc as u32)?;
+        let (opcode, rt, funct) = (word >> 26, (word >> 16) & 0x1F, word & 0x3F);
+        // Linking branches are the REGIMM ones from bltzal to bgezall
+        let call = opcode == 0x3 || (opcode == 0 && funct == 0x9) || (opcode == 0x1 && (0x10..=0x13).contains(&rt));
+        let length = MIPS_INSTRUCTION_LENGTH as u32 * if self.delay_slots { 2 } else { 1 };
+        call.then(|| self.pc as u32 + length)
+    }
 
+    // The service the instruction at pc asks for, in $v0, if it's a syscall
+    pub fn syscall_at_pc(&self) -> Option<u32> {
+        let word = self.memory.peek_u32(self.pc as u32)?;
+        (word >> 26 == 0 && word & 0x3F == 0xC).then_some(self.regs[2])
+    }
+
+    // Whether the instruction at pc returns from a function, with jr $ra
+    pub fn return_at_pc(&self) -> bool {
+        self.memory.peek_u32(self.pc as u32).is_some_and(|word| word >> 26 == 0 && word & 0x3F == 0x8 && (word >> 21) & 0x1F == 31)
+    }
+
+    // Everything that happens before an instruction runs. Returns a result
+    // when it ends the program or takes an exception instead of running one.
+    fn between_instructions(&mut self) -> Option<Result<(), ExecutionErrors>> {
+        // Falling off the end of the program, after its last instruction has run, ends it
         if self.pc == self.stop_address {
-            return Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete });
+            return Some(Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete }));
+        }
+        if self.max_steps.is_some_and(|max_steps| self.stats.instructions() >= max_steps) {
+            self.os.exit_code = STEP_LIMIT_EXIT_CODE;
+            return Some(Err(ExecutionErrors::Event { event: ExecutionEvents::StepLimit }));
+        }
+        self.checkpoint();
+
+        // The instruction about to run is a delay slot once its branch is ready to transfer
+        let in_delay_slot = matches!(self.branch_delay_status, BranchDelays::Ready);
+
+        // Interrupts are taken between instructions, so EPC is the one that
+        // hasn't run yet. They can only be delivered to a program's own handler.
+        self.cop0.tick();
+        for device in &mut self.memory.devices {
+            let raised = device.tick();
+            self.cop0.set_interrupt(device.interrupt_line(), raised);
+        }
+        if self.exception_handler && self.cop0.interrupt_pending() {
+            self.take_exception(0, None, in_delay_slot);
+            return Some(self.caught(0));
+        }
+
+        // Kernel code can only be reached through an exception
+        if self.pc as u32 >= KERNEL_SPACE_START && !self.cop0.kernel_mode() {
+            if !self.exception_handler {
+                return Some(Err(ExecutionErrors::AddressExceptionLoad { address: self.pc as u32 }));
+            }
+            self.take_exception(4, Some(self.pc as u32), in_delay_slot);
+            return Some(self.caught(4));
+        }
+
+        None
+    }
+
+    // Runs the instruction at pc, logging it to `f` if given
+    fn execute(&mut self, f: Option<&mut File>) -> Result<(), ExecutionErrors> {
+        let in_delay_slot = matches!(self.branch_delay_status, BranchDelays::Ready);
+
+        let (opcode, instruction) = match self.fetch() {
+            Ok(fetched) => fetched,
+            Err(error) => {
+                self.raised(error);
+                return Err(error);
+            }
+        };
+        // Only the instruction's own accesses count for watchpoints, not
+        // fetching it or whatever the debugger looked at before it ran
+        self.memory.take_watch_hit();
+        let address = self.pc as u32;
+        self.pc += MIPS_INSTRUCTION_LENGTH;
+        self.stats.record(address, opcode);
+
+        // A traced instruction is recorded along with what it changes
+        let traced = self.trace.as_mut().is_some_and(|trace| trace.begin(address, opcode));
+        let before = traced.then(|| self.registers());
+
+        for hook in self.hooks.before_instruction.clone() {
+            hook(self, address, opcode);
         }
 
-        let instruction = self.decode(opcode);
-        writeln!(f,"{:?}", instruction).unwrap(); // Panic if write to file failed
+        if let Some(f) = f {
+            writeln!(f,"{:?}", instruction).unwrap(); // Panic if write to file failed
+        }
 
+        let sp = self.regs[29];
         let ins_result = match instruction {
+            _ if self.isa.is_some_and(|isa| !isa.accepts(opcode)) => Err(ExecutionErrors::UndefinedInstruction { instruction: opcode }),
             Instructions::R(rtype) => self.dispatch_r(rtype, opcode),
             Instructions::I(itype) => self.dispatch_i(itype, opcode),
-            Instructions::J(jtype) => self.dispatch_j(jtype, opcode)
+            Instructions::J(jtype) => self.dispatch_j(jtype, opcode),
+            Instructions::F(ftype) => self.dispatch_f(ftype, opcode)
         };
 
+        if matches!(self.branch_delay_status, BranchDelays::Set) {
+            self.stats.branches_taken += 1;
+        }
+
         // The zero register is ALWAYS 0.
         // If an instruction wrote to the zero register, discard that result here.
         self.regs[0] = 0;
 
-        if ins_result.is_err() {
+        // $sp going below the stack's limit means the stack overflowed. The
+        // instruction that did it doesn't take effect. A program switching
+        // to a stack of its own elsewhere moves $sp much further than that.
+        let ins_result = match self.stack_limit {
+            Some(limit) if ins_result.is_ok() && self.regs[29] < limit && limit <= sp && sp - self.regs[29] <= self.layout.stack_size => {
+                let error = ExecutionErrors::StackOverflow { sp: self.regs[29] };
+                self.regs[29] = sp;
+                Err(error)
+            }
+            _ => ins_result,
+        };
+
+        if let Some(before) = before {
+            let after = self.registers();
+            if let Some(trace) = &mut self.trace {
+                trace.end(address, opcode, &before, &after, ins_result.err().map(|error| error.to_string()));
+            }
+        }
+
+        if let Err(error) = ins_result {
             self.pc -= MIPS_INSTRUCTION_LENGTH; // 
+            // An exception breaks the link from Load Linked
+            self.ll_bit = false;
+            self.raised(error);
+
+            // A program with its own handler deals with the exception itself
+            if let Some(code) = error.exception_code().filter(|_| self.exception_handler) {
+                self.take_exception(code, error.bad_address(), in_delay_slot);
+                self.prev_ins_result = Ok(());
+                self.executed(address, opcode, Ok(()));
+                return self.caught(code);
+            }
         }
 
         // Branch delay slots are handled here. On the instruction the branch is set,
@@ -463,6 +1534,10 @@ impl Mips {
         // the next instruction the control flow transfer is triggered.
         match self.branch_delay_status {
             BranchDelays::NotActive => (),
+            BranchDelays::Set if !self.delay_slots => {
+                self.pc = self.branch_delay_target as usize;
+                self.branch_delay_status = BranchDelays::NotActive;
+            }
             BranchDelays::Set => self.branch_delay_status = BranchDelays::Ready,
             BranchDelays::Ready => {
                 self.pc = self.branch_delay_target as usize;
@@ -470,8 +1545,479 @@ impl Mips {
             }
         }
 
+        // Once the instruction is done, the machine stops if it touched a watched address
+        let ins_result = match self.memory.take_watch_hit() {
+            Some((address, store)) if ins_result.is_ok() => Err(ExecutionErrors::Event { event: ExecutionEvents::Watchpoint { address, store } }),
+            _ => ins_result,
+        };
+        // Or if it changed a watched register
+        let ins_result = match self.register_changed() {
+            Some((id, old, new)) if ins_result.is_ok() => Err(ExecutionErrors::Event { event: ExecutionEvents::RegisterWatchpoint { id, old, new } }),
+            _ => ins_result,
+        };
         self.prev_ins_result = ins_result;
+        self.executed(address, opcode, ins_result);
 
         ins_result
     }
-}
\ No newline at end of file
+
+    // Tells the hooks an instruction raised an error. Events aren't errors,
+    // so they aren't passed on.
+    fn raised(&mut self, error: ExecutionErrors) {
+        if !matches!(error, ExecutionErrors::Event { .. }) {
+            for hook in self.hooks.exception.clone() {
+                hook(self, error);
+            }
+        }
+    }
+
+    // Tells the hooks an instruction is done
+    fn executed(&mut self, address: u32, word: u32, result: Result<(), ExecutionErrors>) {
+        for hook in self.hooks.after_instruction.clone() {
+            hook(self, address, word, result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cop1::FP_INVALID;
+
+    const T0: u32 = 8;
+    const T1: u32 = 9;
+    const LH: u32 = 0x21;
+    const LHU: u32 = 0x25;
+    const LWL: u32 = 0x22;
+    const LWR: u32 = 0x26;
+    const SH: u32 = 0x29;
+    const SWL: u32 = 0x2A;
+    const SWR: u32 = 0x2E;
+
+    // `op $t1, offset($t0)`
+    fn memory_op(opcode: u32, offset: u32) -> u32 {
+        opcode << 26 | T0 << 21 | T1 << 16 | offset
+    }
+
+    // Runs the instructions against data, with $t0 pointing at the data and
+    // $t1 holding `t1`, stopping at the first error
+    fn run(instructions: &[u32], data: &[u8], t1: u32) -> (Mips, Result<(), ExecutionErrors>) {
+        let text: Vec<u8> = instructions.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut mips = Mips::default();
+        let data_base = mips.layout.data_base;
+        mips.load(&text, data, &[], &[], mips.layout.text_base);
+        mips.regs[T0 as usize] = data_base;
+        mips.regs[T1 as usize] = t1;
+
+        for _ in instructions {
+            if let Err(error) = mips.step_one(None) {
+                return (mips, Err(error));
+            }
+        }
+        (mips, Ok(()))
+    }
+
+    // Runs a single instruction on a machine prepared by `setup`
+    fn run_one(instruction: u32, setup: impl FnOnce(&mut Mips)) -> (Mips, Result<(), ExecutionErrors>) {
+        let mut mips = Mips::default();
+        mips.load(&instruction.to_le_bytes(), &[], &[], &[], mips.layout.text_base);
+        setup(&mut mips);
+        let result = mips.step_one(None);
+        (mips, result)
+    }
+
+    fn loaded(instructions: &[u32], data: &[u8], t1: u32) -> u32 {
+        let (mips, result) = run(instructions, data, t1);
+        assert_eq!(result, Ok(()));
+        mips.regs[T1 as usize]
+    }
+
+    fn stored(instructions: &[u32], data: &[u8], t1: u32) -> Vec<u8> {
+        let (mut mips, result) = run(instructions, data, t1);
+        assert_eq!(result, Ok(()));
+        let data_base = mips.layout.data_base;
+        (0..data.len() as u32).map(|offset| mips.read_b(data_base + offset).unwrap()).collect()
+    }
+
+    #[test]
+    fn load_halfword_sign_extends() {
+        assert_eq!(loaded(&[memory_op(LH, 0)], &[0x34, 0x82], 0), 0xFFFF8234);
+        assert_eq!(loaded(&[memory_op(LH, 2)], &[0, 0, 0xFF, 0x7F], 0), 0x00007FFF);
+    }
+
+    #[test]
+    fn load_halfword_unsigned_zero_extends() {
+        assert_eq!(loaded(&[memory_op(LHU, 0)], &[0x34, 0x82], 0xFFFFFFFF), 0x00008234);
+        assert_eq!(loaded(&[memory_op(LHU, 2)], &[0, 0, 0xFF, 0x7F], 0xFFFFFFFF), 0x00007FFF);
+    }
+
+    #[test]
+    fn misaligned_halfword_access_is_an_address_error() {
+        let (mips, result) = run(&[memory_op(LH, 1)], &[0; 4], 0);
+        let address = mips.layout.data_base + 1;
+        assert_eq!(result, Err(ExecutionErrors::AddressExceptionLoad { address }));
+
+        let (mips, result) = run(&[memory_op(SH, 3)], &[0; 4], 0);
+        let address = mips.layout.data_base + 3;
+        assert_eq!(result, Err(ExecutionErrors::AddressExceptionStore { address }));
+    }
+
+    // The word in memory is 0x44332211, little endian, and $t1 starts out as
+    // 0xAABBCCDD so it's clear which of its bytes are kept
+    const WORD: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
+
+    #[test]
+    fn load_word_left_at_every_offset() {
+        let expected = [0x11BBCCDD, 0x2211CCDD, 0x332211DD, 0x44332211];
+        for (offset, expected) in expected.into_iter().enumerate() {
+            assert_eq!(loaded(&[memory_op(LWL, offset as u32)], &WORD, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn load_word_right_at_every_offset() {
+        let expected = [0x44332211, 0xAA443322, 0xAABB4433, 0xAABBCC44];
+        for (offset, expected) in expected.into_iter().enumerate() {
+            assert_eq!(loaded(&[memory_op(LWR, offset as u32)], &WORD, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn store_word_left_at_every_offset() {
+        let expected = [
+            [0xAA, 0x22, 0x33, 0x44],
+            [0xBB, 0xAA, 0x33, 0x44],
+            [0xCC, 0xBB, 0xAA, 0x44],
+            [0xDD, 0xCC, 0xBB, 0xAA],
+        ];
+        for (offset, expected) in expected.into_iter().enumerate() {
+            assert_eq!(stored(&[memory_op(SWL, offset as u32)], &WORD, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn store_word_right_at_every_offset() {
+        let expected = [
+            [0xDD, 0xCC, 0xBB, 0xAA],
+            [0x11, 0xDD, 0xCC, 0xBB],
+            [0x11, 0x22, 0xDD, 0xCC],
+            [0x11, 0x22, 0x33, 0xDD],
+        ];
+        for (offset, expected) in expected.into_iter().enumerate() {
+            assert_eq!(stored(&[memory_op(SWR, offset as u32)], &WORD, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    // An unaligned word is loaded with lwr from its address and lwl from its
+    // last byte, which fall in different aligned words unless it's aligned
+    #[test]
+    fn unaligned_load_pair_at_every_offset() {
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        for offset in 0..4 {
+            let pair = [memory_op(LWR, offset), memory_op(LWL, offset + 3)];
+            let start = offset as usize;
+            let expected = u32::from_le_bytes(data[start..start + 4].try_into().unwrap());
+            assert_eq!(loaded(&pair, &data, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    // Storing with swr and swl writes exactly the four bytes from the address
+    #[test]
+    fn unaligned_store_pair_at_every_offset() {
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        for offset in 0..4 {
+            let pair = [memory_op(SWR, offset), memory_op(SWL, offset + 3)];
+            let start = offset as usize;
+            let mut expected = data;
+            expected[start..start + 4].copy_from_slice(&0xAABBCCDDu32.to_le_bytes());
+            assert_eq!(stored(&pair, &data, 0xAABBCCDD), expected, "offset {}", offset);
+        }
+    }
+
+    // Only round to nearest is implemented, so ctc1 can't select another
+    // rounding mode. Trying raises Unimplemented Operation and leaves FCSR as
+    // it was.
+    #[test]
+    fn ctc1_rejects_unimplemented_rounding_modes() {
+        // ctc1 $t1, $31
+        let ctc1 = 0x11 << 26 | 0x06 << 21 | T1 << 16 | 31 << 11;
+        for rm in 1..4 {
+            let (mips, result) = run(&[ctc1], &[], rm | FP_INEXACT << 5);
+            assert_eq!(result, Err(ExecutionErrors::FloatingPoint { fcsr: 1 << 17 }), "RM {}", rm);
+            assert_eq!(mips.cop1.fcsr, 1 << 17, "RM {}", rm);
+        }
+
+        let (mips, result) = run(&[ctc1], &[], FP_INEXACT << 5);
+        assert_eq!(result, Ok(()));
+        assert_eq!(mips.cop1.fcsr, FP_INEXACT << 5);
+    }
+
+    // A program assembled against a profile can't run instructions outside
+    // it, even ones NAME implements
+    #[test]
+    fn instructions_outside_the_profile_are_reserved() {
+        // rotr $t1, $t0, 4
+        let rotr = 1 << 21 | T0 << 16 | T1 << 11 | 4 << 6 | 0x02;
+        let reserved = Err(ExecutionErrors::UndefinedInstruction { instruction: rotr });
+        for (isa, expected) in [(None, Ok(())), (Some(Isa::Mips32R2), Ok(())), (Some(Isa::Mars), reserved)] {
+            let mut mips = Mips { isa, ..Mips::default() };
+            mips.load(&rotr.to_le_bytes(), &[], &[], &[], mips.layout.text_base);
+            assert_eq!(mips.step_one(None), expected, "{:?}", isa);
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    enum Operand {
+        Number(f64),
+        QuietNan,
+        SignalingNan,
+    }
+
+    fn set_operand(mips: &mut Mips, fmt: Format, reg: usize, operand: Operand) {
+        match (operand, fmt) {
+            (Operand::Number(value), _) => mips.cop1.write(fmt, reg, value),
+            (Operand::QuietNan, Format::Single) => mips.cop1.regs[reg] = 0x7FC0_0000,
+            (Operand::SignalingNan, Format::Single) => mips.cop1.regs[reg] = 0x7F80_0001,
+            (Operand::QuietNan, Format::Double) => mips.cop1.regs[reg..reg + 2].copy_from_slice(&[0, 0x7FF8_0000]),
+            (Operand::SignalingNan, Format::Double) => mips.cop1.regs[reg..reg + 2].copy_from_slice(&[1, 0x7FF0_0000]),
+        }
+    }
+
+    // Every c.cond.fmt predicate, by its condition field, with whether it
+    // holds when fs is less than, equal to, greater than, and unordered with ft.
+    // The second eight are the first eight again, but signal on quiet NaNs.
+    const PREDICATES: [(&str, [bool; 4]); 16] = [
+        ("f", [false, false, false, false]),
+        ("un", [false, false, false, true]),
+        ("eq", [false, true, false, false]),
+        ("ueq", [false, true, false, true]),
+        ("olt", [true, false, false, false]),
+        ("ult", [true, false, false, true]),
+        ("ole", [true, true, false, false]),
+        ("ule", [true, true, false, true]),
+        ("sf", [false, false, false, false]),
+        ("ngle", [false, false, false, true]),
+        ("seq", [false, true, false, false]),
+        ("ngl", [false, true, false, true]),
+        ("lt", [true, false, false, false]),
+        ("nge", [true, false, false, true]),
+        ("le", [true, true, false, false]),
+        ("ngt", [true, true, false, true]),
+    ];
+
+    #[test]
+    fn comparison_predicates() {
+        use Operand::*;
+        let cases = [
+            (Number(1.0), Number(2.0), 0),
+            (Number(2.0), Number(2.0), 1),
+            (Number(2.0), Number(1.0), 2),
+            (Number(1.0), QuietNan, 3),
+            (QuietNan, Number(1.0), 3),
+            (Number(1.0), SignalingNan, 3),
+            (SignalingNan, QuietNan, 3),
+        ];
+
+        for (fmt, field) in [(Format::Single, 0x10), (Format::Double, 0x11)] {
+            for (condition, (name, truth)) in PREDICATES.iter().enumerate() {
+                // c.cond.fmt $fcc0, $f0, $f2
+                let compare = 0x11 << 26 | field << 21 | 2 << 16 | 0x30 | condition as u32;
+                for (fs, ft, relation) in cases {
+                    let (mips, result) = run_one(compare, |mips| {
+                        // Start with the condition code the wrong way round
+                        mips.cop1.set_condition(0, !truth[relation]);
+                        set_operand(mips, fmt, 0, fs);
+                        set_operand(mips, fmt, 2, ft);
+                    });
+                    let case = format!("c.{}.{:?} {:?}, {:?}", name, fmt, fs, ft);
+                    assert_eq!(result, Ok(()), "{}", case);
+                    assert_eq!(mips.cop1.condition(0), truth[relation], "{}", case);
+
+                    let signaling = matches!(fs, SignalingNan) || matches!(ft, SignalingNan);
+                    let invalid = signaling || (relation == 3 && condition >= 8);
+                    assert_eq!(mips.cop1.fcsr & FP_INVALID != 0, invalid, "{}", case);
+                }
+            }
+        }
+    }
+
+    // With Invalid Operation enabled, a comparison that raises it traps
+    // without touching the condition code
+    #[test]
+    fn signaling_comparisons_trap_when_enabled() {
+        // c.seq.s $fcc0, $f0, $f2
+        let compare = 0x11 << 26 | 0x10 << 21 | 2 << 16 | 0x3A;
+        let (mips, result) = run_one(compare, |mips| {
+            mips.cop1.fcsr = FP_INVALID << 5;
+            set_operand(mips, Format::Single, 0, Operand::QuietNan);
+            set_operand(mips, Format::Single, 2, Operand::QuietNan);
+        });
+        assert_eq!(result, Err(ExecutionErrors::FloatingPoint { fcsr: FP_INVALID << 5 | FP_INVALID << 10 }));
+        assert!(!mips.cop1.condition(0));
+    }
+
+    // madd.fmt, msub.fmt, nmadd.fmt, and nmsub.fmt with fd = $f0, fr = $f2,
+    // fs = $f4, ft = $f6, returning the result's bits and FCSR
+    fn multiply_add(op: u32, fmt: Format, fr: f64, fs: f64, ft: f64) -> (u64, u32) {
+        let funct = op << 3 | if fmt == Format::Single { 0 } else { 1 };
+        let instruction = 0x13 << 26 | 2 << 21 | 6 << 16 | 4 << 11 | funct;
+        let (mips, result) = run_one(instruction, |mips| {
+            mips.cop1.write(fmt, 2, fr);
+            mips.cop1.write(fmt, 4, fs);
+            mips.cop1.write(fmt, 6, ft);
+        });
+        assert_eq!(result, Ok(()));
+        let bits = match fmt {
+            Format::Single => mips.cop1.regs[0] as u64,
+            Format::Double => mips.cop1.read(fmt, 0).to_bits(),
+        };
+        (bits, mips.cop1.fcsr)
+    }
+
+    const MADD: u32 = 0b100;
+    const MSUB: u32 = 0b101;
+    const NMADD: u32 = 0b110;
+    const NMSUB: u32 = 0b111;
+
+    fn bits(fmt: Format, value: f64) -> u64 {
+        match fmt {
+            Format::Single => (value as f32).to_bits() as u64,
+            Format::Double => value.to_bits(),
+        }
+    }
+
+    #[test]
+    fn multiply_add_is_exact_when_it_can_be() {
+        for fmt in [Format::Single, Format::Double] {
+            for (op, expected) in [(MADD, 7.0), (MSUB, 5.0), (NMADD, -7.0), (NMSUB, -5.0)] {
+                assert_eq!(multiply_add(op, fmt, 1.0, 2.0, 3.0), (bits(fmt, expected), 0), "{:?} {:b}", fmt, op);
+            }
+        }
+    }
+
+    // The operations aren't fused: the product is rounded to the format before
+    // fr is added. (1 + e)(1 - e) = 1 - e^2 rounds to 1, so subtracting 1 gives
+    // zero, where a fused operation would give -e^2. The negating forms negate
+    // that zero.
+    #[test]
+    fn multiply_add_rounds_the_product_first() {
+        for (fmt, e) in [(Format::Single, 2f64.powi(-13)), (Format::Double, 2f64.powi(-30))] {
+            let cases = [(MADD, -1.0, 0.0), (MSUB, 1.0, 0.0), (NMADD, -1.0, -0.0), (NMSUB, 1.0, -0.0)];
+            for (op, fr, expected) in cases {
+                let (result, fcsr) = multiply_add(op, fmt, fr, 1.0 + e, 1.0 - e);
+                assert_eq!(result, bits(fmt, expected), "{:?} {:b}", fmt, op);
+                assert_eq!(fcsr & FP_INEXACT, FP_INEXACT, "{:?} {:b}", fmt, op);
+            }
+        }
+    }
+
+    // An exact product still has its sum rounded to the format
+    #[test]
+    fn multiply_add_rounds_the_sum() {
+        let (result, fcsr) = multiply_add(MADD, Format::Single, 2f64.powi(-25), 1.0, 1.0);
+        assert_eq!(result, bits(Format::Single, 1.0));
+        assert_eq!(fcsr & FP_INEXACT, FP_INEXACT);
+
+        let (result, fcsr) = multiply_add(MADD, Format::Double, 2f64.powi(-54), 1.0, 1.0);
+        assert_eq!(result, bits(Format::Double, 1.0));
+        assert_eq!(fcsr & FP_INEXACT, FP_INEXACT);
+    }
+
+    // Whether an instruction traps with $t0 and $t1 holding the given values
+    fn traps(instruction: u32, t0: u32, t1: u32) -> bool {
+        let (_, result) = run_one(instruction, |mips| {
+            mips.regs[T0 as usize] = t0;
+            mips.regs[T1 as usize] = t1;
+        });
+        match result {
+            Ok(()) => false,
+            Err(ExecutionErrors::Trap { instruction: trapped }) if trapped == instruction => true,
+            Err(error) => panic!("{:08x} raised {:?}", instruction, error),
+        }
+    }
+
+    // -1 is less than 1 as a signed value, but the largest there is unsigned
+    #[test]
+    fn register_traps_compare_signed_and_unsigned() {
+        const MINUS_ONE: u32 = -1i32 as u32;
+        let cases = [
+            ("tge", 0x30, [(MINUS_ONE, 1, false), (1, MINUS_ONE, true), (1, 1, true)]),
+            ("tgeu", 0x31, [(MINUS_ONE, 1, true), (1, MINUS_ONE, false), (1, 1, true)]),
+            ("tlt", 0x32, [(MINUS_ONE, 1, true), (1, MINUS_ONE, false), (1, 1, false)]),
+            ("tltu", 0x33, [(MINUS_ONE, 1, false), (1, MINUS_ONE, true), (1, 1, false)]),
+            ("teq", 0x34, [(MINUS_ONE, 1, false), (1, MINUS_ONE, false), (1, 1, true)]),
+            ("tne", 0x36, [(MINUS_ONE, 1, true), (1, MINUS_ONE, true), (1, 1, false)]),
+        ];
+        for (name, funct, values) in cases {
+            // trap $t0, $t1
+            let instruction = T0 << 21 | T1 << 16 | funct;
+            for (rs, rt, expected) in values {
+                assert_eq!(traps(instruction, rs, rt), expected, "{} {}, {}", name, rs as i32, rt as i32);
+            }
+        }
+    }
+
+    // The immediate is sign-extended even for the unsigned comparisons, so
+    // 0xFFFF is -1 to tgei and tlti, and 0xFFFFFFFF to tgeiu and tltiu
+    #[test]
+    fn immediate_traps_compare_signed_and_unsigned() {
+        const MINUS_ONE: u32 = -1i32 as u32;
+        let cases = [
+            ("tgei", 0x8, [(5, 0xFFFF, true), (MINUS_ONE, 1, false), (MINUS_ONE, 0xFFFF, true)]),
+            ("tgeiu", 0x9, [(5, 0xFFFF, false), (MINUS_ONE, 1, true), (MINUS_ONE, 0xFFFF, true)]),
+            ("tlti", 0xA, [(5, 0xFFFF, false), (MINUS_ONE, 1, true), (MINUS_ONE, 0xFFFF, false)]),
+            ("tltiu", 0xB, [(5, 0xFFFF, true), (MINUS_ONE, 1, false), (MINUS_ONE, 0xFFFF, false)]),
+            ("teqi", 0xC, [(5, 0xFFFF, false), (MINUS_ONE, 1, false), (MINUS_ONE, 0xFFFF, true)]),
+            ("tnei", 0xE, [(5, 0xFFFF, true), (MINUS_ONE, 1, true), (MINUS_ONE, 0xFFFF, false)]),
+        ];
+        for (name, rt, values) in cases {
+            for (rs, imm, expected) in values {
+                // trap $t0, imm
+                let instruction = 0x1 << 26 | T0 << 21 | rt << 16 | imm;
+                assert_eq!(traps(instruction, rs, 0), expected, "{} {}, {:#x}", name, rs as i32, imm);
+            }
+        }
+    }
+
+    // `ext $t1, $t0, lsb, size - 1` or `ins $t1, $t0, lsb, msb`, returning $t1
+    fn bit_field(funct: u32, lsb: u32, rd: u32, t0: u32, t1: u32) -> Result<u32, ExecutionErrors> {
+        let instruction = 0x1F << 26 | T0 << 21 | T1 << 16 | rd << 11 | lsb << 6 | funct;
+        let (mips, result) = run_one(instruction, |mips| {
+            mips.regs[T0 as usize] = t0;
+            mips.regs[T1 as usize] = t1;
+        });
+        // Nothing is written when the field doesn't fit
+        if result.is_err() {
+            assert_eq!(mips.regs[T1 as usize], t1);
+        }
+        result.map(|()| mips.regs[T1 as usize])
+    }
+
+    #[test]
+    fn extract_fields_up_to_the_whole_word() {
+        let ext = |lsb, size| bit_field(0x00, lsb, size - 1, 0x8765_4321, 0xDEAD_BEEF);
+        assert_eq!(ext(0, 32), Ok(0x8765_4321));
+        assert_eq!(ext(31, 1), Ok(1));
+        assert_eq!(ext(4, 8), Ok(0x32));
+        assert_eq!(ext(0, 1), Ok(1));
+        assert_eq!(ext(16, 16), Ok(0x8765));
+        // The field has to end by bit 31
+        for (lsb, size) in [(16, 17), (31, 2), (1, 32)] {
+            let reserved = 0x1F << 26 | T0 << 21 | T1 << 16 | (size - 1) << 11 | lsb << 6;
+            assert_eq!(ext(lsb, size), Err(ExecutionErrors::UndefinedInstruction { instruction: reserved }));
+        }
+    }
+
+    #[test]
+    fn insert_fields_up_to_the_whole_word() {
+        let ins = |lsb, msb| bit_field(0x04, lsb, msb, 0x8765_4321, 0xDEAD_BEEF);
+        assert_eq!(ins(0, 31), Ok(0x8765_4321));
+        assert_eq!(ins(31, 31), Ok(0xDEAD_BEEF));
+        assert_eq!(ins(30, 31), Ok(0x5EAD_BEEF));
+        assert_eq!(ins(4, 11), Ok(0xDEAD_B21F));
+        assert_eq!(ins(0, 0), Ok(0xDEAD_BEEF));
+        // The msb can't be below the lsb
+        let reserved = 0x1F << 26 | T0 << 21 | T1 << 16 | 4 << 11 | 8 << 6 | 0x04;
+        assert_eq!(ins(8, 4), Err(ExecutionErrors::UndefinedInstruction { instruction: reserved }));
+    }
+}
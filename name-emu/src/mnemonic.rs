@@ -0,0 +1,197 @@
+// The mnemonics of the instructions the emulator can run, found from their
+// encodings, for reporting on what a program did.
+
+// Condition names for c.cond.fmt, by the low four bits of funct
+const CONDITIONS: [&str; 16] = [
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule",
+    "sf", "ngle", "seq", "ngl", "lt", "nge", "le", "ngt",
+];
+
+// The mnemonic of an instruction word the emulator can run
+pub fn mnemonic(word: u32) -> String {
+    let opcode = word >> 26;
+    let rs = word >> 21 & 0b11111;
+    let rt = word >> 16 & 0b11111;
+    let shamt = word >> 6 & 0b11111;
+    let funct = word & 0b111111;
+
+    let name = match opcode {
+        0x00 => match funct {
+            0x00 => "sll",
+            0x01 if rt & 1 == 1 => "movt",
+            0x01 => "movf",
+            0x02 if rs == 1 => "rotr",
+            0x02 => "srl",
+            0x03 => "sra",
+            0x04 => "sllv",
+            0x06 if shamt == 1 => "rotrv",
+            0x06 => "srlv",
+            0x07 => "srav",
+            0x08 => "jr",
+            0x0A => "movz",
+            0x0B => "movn",
+            0x0C => "syscall",
+            0x10 => "mfhi",
+            0x11 => "mthi",
+            0x12 => "mflo",
+            0x13 => "mtlo",
+            0x18 => "mult",
+            0x19 => "multu",
+            0x1A => "div",
+            0x1B => "divu",
+            0x20 => "add",
+            0x21 => "addu",
+            0x22 => "sub",
+            0x23 => "subu",
+            0x25 => "or",
+            0x26 => "xor",
+            0x27 => "nor",
+            0x2A => "slt",
+            0x2B => "sltu",
+            0x30 => "tge",
+            0x31 => "tgeu",
+            0x32 => "tlt",
+            0x33 => "tltu",
+            0x34 => "teq",
+            0x36 => "tne",
+            _ => "unknown",
+        },
+        0x01 => match rt {
+            0x00 => "bltz",
+            0x01 => "bgez",
+            0x02 => "bltzl",
+            0x03 => "bgezl",
+            0x08 => "tgei",
+            0x09 => "tgeiu",
+            0x0A => "tlti",
+            0x0B => "tltiu",
+            0x0C => "teqi",
+            0x0E => "tnei",
+            0x10 => "bltzal",
+            0x11 => "bgezal",
+            _ => "unknown",
+        },
+        0x02 => "j",
+        0x03 => "jal",
+        0x04 => "beq",
+        0x05 => "bne",
+        0x06 => "blez",
+        0x07 => "bgtz",
+        0x08 => "addi",
+        0x09 => "addiu",
+        0x0A => "slti",
+        0x0B => "sltiu",
+        0x0D => "ori",
+        0x0F => "lui",
+        0x10 => match rs {
+            0x00 => "mfc0",
+            0x04 => "mtc0",
+            0x10 if funct == 0x18 => "eret",
+            _ => "unknown",
+        },
+        0x11 => return cop1_mnemonic(rs, rt, funct),
+        0x13 => return cop1x_mnemonic(funct),
+        0x14 => "beql",
+        0x15 => "bnel",
+        0x16 => "blezl",
+        0x17 => "bgtzl",
+        0x1C => match funct {
+            0x00 => "madd",
+            0x01 => "maddu",
+            0x04 => "msub",
+            0x05 => "msubu",
+            0x20 => "clz",
+            0x21 => "clo",
+            _ => "unknown",
+        },
+        0x1F => match (funct, shamt) {
+            (0x00, _) => "ext",
+            (0x04, _) => "ins",
+            (0x20, 0x02) => "wsbh",
+            (0x20, 0x10) => "seb",
+            (0x20, 0x18) => "seh",
+            _ => "unknown",
+        },
+        0x20 => "lb",
+        0x21 => "lh",
+        0x22 => "lwl",
+        0x23 => "lw",
+        0x24 => "lbu",
+        0x25 => "lhu",
+        0x26 => "lwr",
+        0x28 => "sb",
+        0x29 => "sh",
+        0x2A => "swl",
+        0x2B => "sw",
+        0x2E => "swr",
+        0x30 => "ll",
+        0x38 => "sc",
+        _ => "unknown",
+    };
+    name.to_string()
+}
+
+// Floating-point instructions, where rs holds the format, the direction
+// of a transfer, or marks a branch
+fn cop1_mnemonic(fmt: u32, ft: u32, funct: u32) -> String {
+    let unformatted = match fmt {
+        0x00 => Some("mfc1"),
+        0x02 => Some("cfc1"),
+        0x04 => Some("mtc1"),
+        0x06 => Some("ctc1"),
+        0x08 if ft & 0b10 == 0 && ft & 1 == 1 => Some("bc1t"),
+        0x08 if ft & 0b10 == 0 => Some("bc1f"),
+        _ => None,
+    };
+    if let Some(name) = unformatted {
+        return name.to_string();
+    }
+
+    let suffix = match fmt {
+        0x10 => "s",
+        0x11 => "d",
+        0x14 => "w",
+        0x15 => "l",
+        _ => return "unknown".to_string(),
+    };
+    let name = match funct {
+        0x30..=0x3F => return format!("c.{}.{}", CONDITIONS[funct as usize & 0xF], suffix),
+        0x04 => "sqrt",
+        0x15 => "recip",
+        0x16 => "rsqrt",
+        0x11 if ft & 1 == 1 => "movt",
+        0x11 => "movf",
+        0x12 => "movz",
+        0x13 => "movn",
+        _ => return "unknown".to_string(),
+    };
+    format!("{}.{}", name, suffix)
+}
+
+// COP1X instructions: indexed loads and stores, and multiply-adds
+fn cop1x_mnemonic(funct: u32) -> String {
+    let name = match funct {
+        0x00 => "lwxc1",
+        0x01 => "ldxc1",
+        0x05 => "luxc1",
+        0x08 => "swxc1",
+        0x09 => "sdxc1",
+        0x0D => "suxc1",
+        _ => {
+            let suffix = match funct & 0b111 {
+                0 => "s",
+                1 => "d",
+                _ => return "unknown".to_string(),
+            };
+            let name = match funct >> 3 {
+                0b100 => "madd",
+                0b101 => "msub",
+                0b110 => "nmadd",
+                0b111 => "nmsub",
+                _ => return "unknown".to_string(),
+            };
+            return format!("{}.{}", name, suffix);
+        }
+    };
+    name.to_string()
+}
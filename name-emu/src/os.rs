@@ -0,0 +1,700 @@
+// The operating system services a program requests with syscall.
+// Services are numbered as in MARS: the number goes in $v0, arguments in
+// $a0-$a3, and results come back in $v0. SPIM shares the numbering of the
+// services it has, but prints numbers and opens files differently, so a
+// profile picks which simulator to follow.
+//
+// Each service is a handler in a table, which embedders can add to or
+// override with register_syscall. A handler gets the whole machine, and
+// reaches the operating system's own state through mips.os.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, LowerExp};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cop1::Format;
+use crate::exception::{ExecutionErrors, ExecutionEvents};
+use crate::mips::Mips;
+use crate::random::JavaRandom;
+use crate::replay::Replay;
+
+const V0: usize = 2;
+const A0: usize = 4;
+const A1: usize = 5;
+const A2: usize = 6;
+const F0: usize = 0;
+const F12: usize = 12;
+
+const SYS_PRINT_FLOAT: u32 = 2;
+const SYS_PRINT_DOUBLE: u32 = 3;
+const SYS_READ_FLOAT: u32 = 6;
+const SYS_READ_DOUBLE: u32 = 7;
+const SYS_READ_STRING: u32 = 8;
+const SYS_SBRK: u32 = 9;
+const SYS_EXIT: u32 = 10;
+const SYS_OPEN: u32 = 13;
+const SYS_READ: u32 = 14;
+const SYS_WRITE: u32 = 15;
+const SYS_CLOSE: u32 = 16;
+const SYS_EXIT2: u32 = 17;
+const SYS_TIME: u32 = 30;
+const SYS_SLEEP: u32 = 32;
+const SYS_PRINT_HEX: u32 = 34;
+const SYS_PRINT_BINARY: u32 = 35;
+const SYS_PRINT_UNSIGNED: u32 = 36;
+const SYS_SET_SEED: u32 = 40;
+const SYS_RANDOM_INT: u32 = 41;
+const SYS_RANDOM_INT_RANGE: u32 = 42;
+const SYS_RANDOM_FLOAT: u32 = 43;
+const SYS_RANDOM_DOUBLE: u32 = 44;
+
+// Flags for open, as MARS defines them
+const OPEN_READ: u32 = 0;
+const OPEN_WRITE: u32 = 1;
+const OPEN_APPEND: u32 = 9;
+
+// SPIM passes the flags for open straight to the host, so they're Linux's
+const O_ACCMODE: u32 = 0x3;
+const O_RDONLY: u32 = 0x0;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_CREAT: u32 = 0x40;
+const O_TRUNC: u32 = 0x200;
+const O_APPEND: u32 = 0x400;
+
+// What the file services return in $v0 when they fail
+const FILE_ERROR: u32 = -1i32 as u32;
+
+// Carries out one service
+pub type SyscallHandler = Rc<dyn Fn(&mut Mips) -> Result<(), ExecutionErrors>>;
+// A built-in service
+type Service = fn(&mut Mips) -> Result<(), ExecutionErrors>;
+
+// The services MARS provides, by number
+const MARS_SERVICES: &[(u32, Service)] = &[
+    (SYS_PRINT_FLOAT, sys_print_float),
+    (SYS_PRINT_DOUBLE, sys_print_double),
+    (SYS_READ_FLOAT, sys_read_float),
+    (SYS_READ_DOUBLE, sys_read_double),
+    (SYS_READ_STRING, sys_read_string),
+    (SYS_SBRK, sys_sbrk),
+    (SYS_EXIT, sys_exit),
+    (SYS_OPEN, sys_open),
+    (SYS_READ, sys_read),
+    (SYS_WRITE, sys_write),
+    (SYS_CLOSE, sys_close),
+    (SYS_EXIT2, sys_exit2),
+    (SYS_TIME, sys_time),
+    (SYS_SLEEP, sys_sleep),
+    (SYS_PRINT_HEX, sys_print_hex),
+    (SYS_PRINT_BINARY, sys_print_binary),
+    (SYS_PRINT_UNSIGNED, sys_print_unsigned),
+    (SYS_SET_SEED, sys_set_seed),
+    (SYS_RANDOM_INT, sys_random_int),
+    (SYS_RANDOM_INT_RANGE, sys_random_int_range),
+    (SYS_RANDOM_FLOAT, sys_random_float),
+    (SYS_RANDOM_DOUBLE, sys_random_double),
+];
+
+// The services SPIM provides, by number. It has none of MARS's services
+// past 17.
+const SPIM_SERVICES: &[(u32, Service)] = &[
+    (SYS_PRINT_FLOAT, sys_print_float_spim),
+    (SYS_PRINT_DOUBLE, sys_print_double_spim),
+    (SYS_READ_FLOAT, sys_read_float),
+    (SYS_READ_DOUBLE, sys_read_double),
+    (SYS_READ_STRING, sys_read_string),
+    (SYS_SBRK, sys_sbrk),
+    (SYS_EXIT, sys_exit),
+    (SYS_OPEN, sys_open_spim),
+    (SYS_READ, sys_read),
+    (SYS_WRITE, sys_write),
+    (SYS_CLOSE, sys_close),
+    (SYS_EXIT2, sys_exit2),
+];
+
+// Which simulator's services a program expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    #[default]
+    Mars,
+    Spim,
+}
+
+impl Profile {
+    fn services(self) -> &'static [(u32, Service)] {
+        match self {
+            Profile::Mars => MARS_SERVICES,
+            Profile::Spim => SPIM_SERVICES,
+        }
+    }
+}
+
+// What a file descriptor refers to
+#[derive(Debug)]
+enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct OperatingSystem {
+    // Handlers by service number
+    syscalls: HashMap<u32, SyscallHandler>,
+    // Indexed by file descriptor. Closed descriptors are None, and are
+    // reused by the next open.
+    files: Vec<Option<Descriptor>>,
+    // When set, programs can only open files beneath this directory, and
+    // paths are relative to it
+    pub sandbox: Option<PathBuf>,
+    // How much of the heap sbrk has handed out. The heap's end, the break,
+    // is this far past its base.
+    heap_length: u32,
+    // Random number generators, by the ID the program picked for each
+    generators: HashMap<u32, JavaRandom>,
+    // When set, generators start from this seed instead of the time, so
+    // runs can be reproduced
+    pub seed: Option<u64>,
+    // Sleeps last this many times as long as requested, so 0 skips them
+    pub sleep_scale: f64,
+    // When set, the time in milliseconds on a clock that starts at 0 and
+    // only moves when the program sleeps, so runs don't depend on the host
+    pub virtual_clock: Option<u64>,
+    // What the program takes in from outside is written down here while
+    // recording, and taken from here instead when replaying. Console input
+    // is replayed by replacing stdin.
+    pub recording: Option<Replay>,
+    pub replaying: Option<Replay>,
+    // The status the program exited with, which becomes name-emu's own
+    pub exit_code: i32,
+    // The program's console, which is the process's own unless replaced,
+    // so input can be fed in and output captured
+    pub stdin: Box<dyn BufRead>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+}
+
+impl Default for OperatingSystem {
+    fn default() -> Self {
+        let mut os = Self {
+            syscalls: HashMap::new(),
+            files: vec![Some(Descriptor::Stdin), Some(Descriptor::Stdout), Some(Descriptor::Stderr)],
+            sandbox: None,
+            heap_length: 0,
+            generators: HashMap::new(),
+            seed: None,
+            sleep_scale: 1.0,
+            virtual_clock: None,
+            recording: None,
+            replaying: None,
+            exit_code: 0,
+            stdin: Box::new(BufReader::new(io::stdin())),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        };
+
+        os.set_profile(Profile::default());
+        os
+    }
+}
+
+// Handlers can't be printed, so only their service numbers are, and
+// neither can the console's streams
+impl fmt::Debug for OperatingSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut services: Vec<&u32> = self.syscalls.keys().collect();
+        services.sort();
+
+        f.debug_struct("OperatingSystem")
+            .field("syscalls", &services)
+            .field("files", &self.files)
+            .field("sandbox", &self.sandbox)
+            .field("heap_length", &self.heap_length)
+            .field("generators", &self.generators)
+            .field("seed", &self.seed)
+            .field("sleep_scale", &self.sleep_scale)
+            .field("virtual_clock", &self.virtual_clock)
+            .field("recording", &self.recording)
+            .field("replaying", &self.replaying)
+            .field("exit_code", &self.exit_code)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OperatingSystem {
+    // Carries out the service requested in $v0. Services that don't exist
+    // raise a syscall exception.
+    pub fn handle_syscall(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+        let service = mips.regs[V0];
+        // The handler is cloned out of the table, so it's free to change the table
+        match mips.os.syscalls.get(&service).cloned() {
+            Some(handler) => handler(mips),
+            None => Err(ExecutionErrors::UnknownSyscall { service }),
+        }
+    }
+
+    // Replaces every handler with the services of the given simulator
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.syscalls.clear();
+        for (service, handler) in profile.services() {
+            self.register_syscall(*service, *handler);
+        }
+    }
+
+    // Installs the handler for a service, replacing the one it had
+    pub fn register_syscall(&mut self, service: u32, handler: impl Fn(&mut Mips) -> Result<(), ExecutionErrors> + 'static) {
+        self.syscalls.insert(service, Rc::new(handler));
+    }
+
+    // Writes to the console. A console that can't be written to has nobody
+    // to report the failure to, so it's ignored.
+    fn print(&mut self, text: &str) {
+        let _ = write_flushed(&mut self.stdout, text.as_bytes());
+    }
+
+    // Reads a line from the console and parses it as a number. As in MARS,
+    // input that isn't a number stops the program rather than returning an
+    // error code.
+    fn read_number<T: std::str::FromStr>(&mut self, service: u32) -> Result<T, ExecutionErrors> {
+        let mut line = String::new();
+        match self.stdin.read_line(&mut line) {
+            Ok(count) if count > 0 => {
+                self.consumed(line.as_bytes());
+                line.trim().parse().map_err(|_| ExecutionErrors::InvalidInput { service })
+            }
+            _ => Err(ExecutionErrors::InvalidInput { service }),
+        }
+    }
+
+    // Notes console input the program read, if it's being recorded
+    fn consumed(&mut self, input: &[u8]) {
+        if let Some(recording) = &mut self.recording {
+            recording.input.extend_from_slice(input);
+        }
+    }
+
+    // The generator with the given ID, which is created the first time it's used
+    fn generator(&mut self, id: u32) -> &mut JavaRandom {
+        if !self.generators.contains_key(&id) {
+            let seed = self.replaying.as_ref().and_then(|replay| replay.seeds.get(&id).copied())
+                .or(self.seed)
+                .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64));
+            if let Some(recording) = &mut self.recording {
+                recording.seeds.insert(id, seed);
+            }
+            self.generators.insert(id, JavaRandom::new(seed));
+        }
+        self.generators.get_mut(&id).unwrap()
+    }
+
+    // Gives a newly opened file the lowest free descriptor
+    fn add_file(&mut self, file: File) -> u32 {
+        let descriptor = Some(Descriptor::File(file));
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = descriptor;
+                fd as u32
+            }
+            None => {
+                self.files.push(descriptor);
+                self.files.len() as u32 - 1
+            }
+        }
+    }
+
+    // Turns a path from the program into one on the host. Inside a sandbox,
+    // paths must be relative and can't climb out with "..".
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        match &self.sandbox {
+            None => Some(path.to_path_buf()),
+            Some(root) => {
+                let escapes = path.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+                if escapes {
+                    None
+                } else {
+                    Some(root.join(path))
+                }
+            }
+        }
+    }
+}
+
+fn sys_print_float(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value = f32::from_bits(mips.cop1.regs[F12]);
+    mips.os.print(&format_java(value, value as f64));
+    Ok(())
+}
+
+fn sys_print_double(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value = mips.cop1.read(Format::Double, F12);
+    mips.os.print(&format_java(value, value));
+    Ok(())
+}
+
+// SPIM prints numbers with C's printf, floats as %.8f and doubles as %.18g
+fn sys_print_float_spim(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value = f32::from_bits(mips.cop1.regs[F12]) as f64;
+    let text = match special_c(value) {
+        Some(text) => text,
+        None => format!("{:.8}", value),
+    };
+    mips.os.print(&text);
+    Ok(())
+}
+
+fn sys_print_double_spim(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.os.print(&format_c_general(mips.cop1.read(Format::Double, F12), 18));
+    Ok(())
+}
+
+fn sys_read_float(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value: f32 = mips.os.read_number(SYS_READ_FLOAT)?;
+    mips.cop1.write(Format::Single, F0, value as f64);
+    Ok(())
+}
+
+fn sys_read_double(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value: f64 = mips.os.read_number(SYS_READ_DOUBLE)?;
+    mips.cop1.write(Format::Double, F0, value);
+    Ok(())
+}
+
+// Reads a line from the console into the buffer at $a0, which holds $a1
+// bytes, with fgets semantics: at most $a1 - 1 characters are kept,
+// including the newline if it fits, and a null terminator follows them.
+// The rest of a line too long for the buffer is discarded.
+fn sys_read_string(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let (address, length) = (mips.regs[A0], mips.regs[A1] as i32);
+    if length < 1 {
+        return Ok(());
+    }
+
+    let mut line = vec![];
+    // The end of input or a broken console reads as an empty line
+    let _ = mips.os.stdin.read_until(b'\n', &mut line);
+    mips.os.consumed(&line);
+
+    line.truncate(length as usize - 1);
+    line.push(0);
+    mips.write_bytes(address, &line)
+}
+
+// Grows the heap by $a0 bytes, rounded up to a whole word, returning the
+// address of the new space
+fn sys_sbrk(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let requested = mips.regs[A0];
+    let old_break = mips.layout.heap_base + mips.os.heap_length;
+
+    // MARS hands out nothing for a zero or negative amount
+    if requested as i32 > 0 {
+        let length = mips.os.heap_length as u64 + ((requested as u64 + 3) & !3);
+        if length > mips.layout.heap_size as u64 {
+            return Err(ExecutionErrors::HeapExhausted { requested });
+        }
+
+        mips.os.heap_length = length as u32;
+        mips.resize_heap(length as u32);
+    }
+
+    mips.regs[V0] = old_break;
+    Ok(())
+}
+
+fn sys_exit(_mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete })
+}
+
+fn sys_exit2(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.os.exit_code = mips.regs[A0] as i32;
+    Err(ExecutionErrors::Event { event: ExecutionEvents::ProgramComplete })
+}
+
+// Opens the file named by the string at $a0 with the flags in $a1,
+// returning its descriptor
+fn sys_open(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    open_with(mips, |flags| {
+        let mut options = OpenOptions::new();
+        match flags {
+            OPEN_READ => options.read(true),
+            OPEN_WRITE => options.write(true).create(true).truncate(true),
+            OPEN_APPEND => options.append(true).create(true),
+            _ => return None,
+        };
+        Some(options)
+    })
+}
+
+// Opens a file as SPIM does, with the host's flags in $a1
+fn sys_open_spim(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    open_with(mips, |flags| {
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return None,
+        };
+        options
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+        Some(options)
+    })
+}
+
+// Opens the file named by the string at $a0, with `options` turning the
+// flags in $a1 into how to open it, or None if they make no sense
+fn open_with(mips: &mut Mips, options: impl FnOnce(u32) -> Option<OpenOptions>) -> Result<(), ExecutionErrors> {
+    let name = read_string(mips, mips.regs[A0])?;
+
+    let file = mips
+        .os
+        .resolve(&String::from_utf8_lossy(&name))
+        .zip(options(mips.regs[A1]))
+        .and_then(|(path, options)| options.open(path).ok());
+
+    mips.regs[V0] = match file {
+        Some(file) => mips.os.add_file(file),
+        None => FILE_ERROR,
+    };
+    Ok(())
+}
+
+// Reads up to $a2 bytes from descriptor $a0 into the buffer at $a1,
+// returning how many were read, or 0 at the end of the file
+fn sys_read(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let (fd, address) = (mips.regs[A0], mips.regs[A1]);
+    let Some(length) = buffer_length(mips, address, mips.regs[A2]) else {
+        mips.regs[V0] = FILE_ERROR;
+        return Ok(());
+    };
+    let mut buffer = vec![0; length];
+
+    let count = match mips.os.files.get_mut(fd as usize) {
+        Some(Some(Descriptor::Stdin)) => {
+            let count = mips.os.stdin.read(&mut buffer).ok();
+            mips.os.consumed(&buffer[..count.unwrap_or(0)]);
+            count
+        }
+        Some(Some(Descriptor::File(file))) => file.read(&mut buffer).ok(),
+        _ => None,
+    };
+
+    mips.regs[V0] = match count {
+        Some(count) => {
+            mips.write_bytes(address, &buffer[..count])?;
+            count as u32
+        }
+        None => FILE_ERROR,
+    };
+    Ok(())
+}
+
+// The length of a file I/O buffer, or None if it's negative or runs past the
+// memory in use from its address, which fails the call as it does in MARS
+fn buffer_length(mips: &Mips, address: u32, length: u32) -> Option<usize> {
+    (length as i32 >= 0 && length <= mips.memory.in_use_from(address)).then_some(length as usize)
+}
+
+// Writes $a2 bytes from the buffer at $a1 to descriptor $a0, returning
+// how many were written
+fn sys_write(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let (fd, address) = (mips.regs[A0], mips.regs[A1]);
+    let Some(length) = buffer_length(mips, address, mips.regs[A2]) else {
+        mips.regs[V0] = FILE_ERROR;
+        return Ok(());
+    };
+
+    let mut buffer = vec![0; length];
+    mips.memory.read_bytes(address, &mut buffer)?;
+
+    let count = match mips.os.files.get_mut(fd as usize) {
+        Some(Some(Descriptor::Stdout)) => write_flushed(&mut mips.os.stdout, &buffer).ok(),
+        Some(Some(Descriptor::Stderr)) => write_flushed(&mut mips.os.stderr, &buffer).ok(),
+        Some(Some(Descriptor::File(file))) => file.write(&buffer).ok(),
+        _ => None,
+    };
+
+    mips.regs[V0] = count.map_or(FILE_ERROR, |count| count as u32);
+    Ok(())
+}
+
+// Closes descriptor $a0. Closing a descriptor that isn't open does nothing.
+fn sys_close(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let fd = mips.regs[A0];
+    if let Some(descriptor) = mips.os.files.get_mut(fd as usize) {
+        *descriptor = None;
+    }
+    Ok(())
+}
+
+// The time in milliseconds since the epoch, low word in $a0 and high in $a1
+fn sys_time(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let now = mips.os.replaying.as_mut().and_then(|replay| replay.times.pop_front())
+        .or(mips.os.virtual_clock)
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64));
+    if let Some(recording) = &mut mips.os.recording {
+        recording.times.push_back(now);
+    }
+    mips.regs[A0] = now as u32;
+    mips.regs[A1] = (now >> 32) as u32;
+    Ok(())
+}
+
+fn sys_sleep(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let milliseconds = mips.regs[A0] as i32;
+    if milliseconds > 0 {
+        if let Some(now) = &mut mips.os.virtual_clock {
+            *now += milliseconds as u64;
+        }
+        thread::sleep(Duration::from_millis(milliseconds as u64).mul_f64(mips.os.sleep_scale));
+    }
+    Ok(())
+}
+
+// These print every digit, as MARS does
+fn sys_print_hex(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.os.print(&format!("{:#010x}", mips.regs[A0]));
+    Ok(())
+}
+
+fn sys_print_binary(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.os.print(&format!("{:032b}", mips.regs[A0]));
+    Ok(())
+}
+
+fn sys_print_unsigned(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.os.print(&mips.regs[A0].to_string());
+    Ok(())
+}
+
+// Random numbers come from the generator with the ID in $a0
+fn sys_set_seed(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let (id, seed) = (mips.regs[A0], mips.regs[A1] as i32 as u64);
+    mips.os.generators.insert(id, JavaRandom::new(seed));
+    Ok(())
+}
+
+fn sys_random_int(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    mips.regs[A0] = mips.os.generator(mips.regs[A0]).next_int();
+    Ok(())
+}
+
+fn sys_random_int_range(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let bound = mips.regs[A1] as i32;
+    if bound <= 0 {
+        return Err(ExecutionErrors::InvalidArgument { service: SYS_RANDOM_INT_RANGE });
+    }
+    mips.regs[A0] = mips.os.generator(mips.regs[A0]).next_int_below(bound) as u32;
+    Ok(())
+}
+
+fn sys_random_float(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value = mips.os.generator(mips.regs[A0]).next_float();
+    mips.cop1.write(Format::Single, F0, value as f64);
+    Ok(())
+}
+
+fn sys_random_double(mips: &mut Mips) -> Result<(), ExecutionErrors> {
+    let value = mips.os.generator(mips.regs[A0]).next_double();
+    mips.cop1.write(Format::Double, F0, value);
+    Ok(())
+}
+
+// Reads a null-terminated string out of memory, without the terminator
+fn read_string(mips: &mut Mips, address: u32) -> Result<Vec<u8>, ExecutionErrors> {
+    let mut bytes = vec![];
+    loop {
+        match mips.read_b(address + bytes.len() as u32)? {
+            0 => return Ok(bytes),
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+// Formats a number as Java's toString does, which is how MARS prints it:
+// plain decimal for magnitudes from 10^-3 up to 10^7, and scientific
+// notation outside that, always with a fractional part. `value` is printed
+// with as few digits as identify it in its own precision, and `number` is
+// the same value widened for the range checks.
+fn format_java<T: Display + LowerExp>(value: T, number: f64) -> String {
+    if number.is_nan() {
+        return "NaN".to_string();
+    }
+    if number.is_infinite() {
+        return if number > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+
+    let with_fraction = |digits: &str| {
+        if digits.contains('.') {
+            digits.to_string()
+        } else {
+            format!("{}.0", digits)
+        }
+    };
+
+    if number == 0.0 || (1e-3..1e7).contains(&number.abs()) {
+        with_fraction(&value.to_string())
+    } else {
+        let scientific = format!("{:e}", value);
+        let (mantissa, exponent) = scientific.split_once('e').unwrap();
+        format!("{}E{}", with_fraction(mantissa), exponent)
+    }
+}
+
+// Formats a number as C's %.Ng does: scientific notation when the exponent
+// is below -4 or at least the precision, plain decimal otherwise, both with
+// `precision` significant digits and the trailing zeros taken off
+fn format_c_general(value: f64, precision: usize) -> String {
+    if let Some(text) = special_c(value) {
+        return text;
+    }
+
+    // The exponent is the one the number has after rounding
+    let scientific = format!("{:.*e}", precision - 1, value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, value)).to_string()
+    }
+}
+
+// How C prints infinities and NaNs, or None for other numbers
+fn special_c(value: f64) -> Option<String> {
+    let sign = if value.is_sign_negative() { "-" } else { "" };
+    if value.is_nan() {
+        Some(format!("{}nan", sign))
+    } else if value.is_infinite() {
+        Some(format!("{}inf", sign))
+    } else {
+        None
+    }
+}
+
+// Takes the trailing zeros off a fractional part, and the point if that's all that's left
+fn trim_fraction(text: &str) -> &str {
+    if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        text
+    }
+}
+
+// Console output is flushed right away, so it interleaves properly with input
+fn write_flushed(stream: &mut impl Write, buffer: &[u8]) -> io::Result<usize> {
+    stream.write_all(buffer)?;
+    stream.flush()?;
+    Ok(buffer.len())
+}
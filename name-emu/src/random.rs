@@ -0,0 +1,53 @@
+// The pseudorandom number generator behind the random syscalls. This is
+// the linear congruential generator from java.util.Random, which MARS uses,
+// so a seeded program produces the same numbers under either simulator.
+
+const MULTIPLIER: u64 = 0x5DEECE66D;
+const INCREMENT: u64 = 0xB;
+const MASK: u64 = (1 << 48) - 1;
+
+#[derive(Debug)]
+pub struct JavaRandom {
+    seed: u64,
+}
+
+impl JavaRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { seed: (seed ^ MULTIPLIER) & MASK }
+    }
+
+    // The top `bits` bits of the next state
+    fn next(&mut self, bits: u32) -> u32 {
+        self.seed = (self.seed.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT)) & MASK;
+        (self.seed >> (48 - bits)) as u32
+    }
+
+    pub fn next_int(&mut self) -> u32 {
+        self.next(32)
+    }
+
+    // A number from 0 up to but not including bound, which must be positive.
+    // Results that would favor the low end of the range are thrown away.
+    pub fn next_int_below(&mut self, bound: i32) -> i32 {
+        if bound & -bound == bound {
+            return ((bound as i64 * self.next(31) as i64) >> 31) as i32;
+        }
+
+        loop {
+            let bits = self.next(31) as i32;
+            let value = bits % bound;
+            if bits.wrapping_sub(value).wrapping_add(bound - 1) >= 0 {
+                return value;
+            }
+        }
+    }
+
+    pub fn next_float(&mut self) -> f32 {
+        self.next(24) as f32 / (1 << 24) as f32
+    }
+
+    pub fn next_double(&mut self) -> f64 {
+        let bits = ((self.next(26) as u64) << 27) + self.next(27) as u64;
+        bits as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,34 @@
+// Everything a run took in from outside that could be different the next
+// time: what was typed at the console, where the random number generators
+// started, and what time the program was told it was. A run can be
+// recorded to a file and played back from it, so a program that misbehaved
+// in someone's interactive session can be made to do exactly the same
+// thing again.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    // Every byte the program read from its console, in order
+    pub input: Vec<u8>,
+    // The seed each random number generator started from, by its ID
+    pub seeds: BTreeMap<u32, u64>,
+    // What each call for the time returned, in order
+    pub times: VecDeque<u64>,
+    // The seed registers and memory were filled with garbage from, if they were
+    pub garbage: Option<u64>,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| format!("Couldn't read replay file {}: {}", path, error))?;
+        serde_json::from_str(&text).map_err(|error| format!("Invalid replay file {}: {}", path, error))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let text = serde_json::to_string_pretty(self).map_err(|error| error.to_string())?;
+        std::fs::write(path, text).map_err(|error| format!("Couldn't write replay file {}: {}", path, error))
+    }
+}
@@ -0,0 +1,134 @@
+// The machine as something other code can drive and watch. A Simulator
+// loads a program the way name-as laid it out, steps it, and gives access
+// to its registers and memory. Hooks are told about each instruction
+// before and after it runs, each syscall before it's carried out, and each
+// exception as it's raised, so an observer can follow a program without
+// the machine knowing anything about it.
+//
+// Hooks are shared functions, like syscall handlers, so they're free to
+// look at the machine while it calls them. Any state of their own goes in
+// a Cell or RefCell.
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::exception::ExecutionErrors;
+use crate::mips::Mips;
+use name_const::layout::Layout;
+
+// Called with where an instruction is and its word
+pub type InstructionHook = Rc<dyn Fn(&Mips, u32, u32)>;
+// Called with where an instruction was, its word, and how it went
+pub type ResultHook = Rc<dyn Fn(&Mips, u32, u32, Result<(), ExecutionErrors>)>;
+// Called with the service requested in $v0
+pub type SyscallHook = Rc<dyn Fn(&Mips, u32)>;
+// Called with the error an instruction raised, whether or not the program
+// handles it itself
+pub type ExceptionHook = Rc<dyn Fn(&Mips, ExecutionErrors)>;
+
+#[derive(Default, Clone)]
+pub struct Hooks {
+    pub before_instruction: Vec<InstructionHook>,
+    pub after_instruction: Vec<ResultHook>,
+    pub syscall: Vec<SyscallHook>,
+    pub exception: Vec<ExceptionHook>,
+}
+
+// Hooks can't be printed, so only how many there are is
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("before_instruction", &self.before_instruction.len())
+            .field("after_instruction", &self.after_instruction.len())
+            .field("syscall", &self.syscall.len())
+            .field("exception", &self.exception.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Simulator {
+    mips: Mips,
+}
+
+impl Simulator {
+    // A machine with its memory laid out as given, with nothing loaded yet
+    pub fn new(layout: Layout) -> Self {
+        Self { mips: Mips::new(layout) }
+    }
+
+    // Loads a program's segments, as name-as wrote them, to start at entry
+    pub fn load(&mut self, text: &[u8], data: &[u8], ktext: &[u8], kdata: &[u8], entry: u32) {
+        self.mips.load(text, data, ktext, kdata, entry);
+    }
+
+    // Runs one instruction, or takes whatever happens between instructions
+    // instead, like an interrupt. Events, like the program finishing, come
+    // back as errors.
+    pub fn step(&mut self) -> Result<(), ExecutionErrors> {
+        self.mips.step_one(None)
+    }
+
+    // Steps until the program finishes or something stops it
+    pub fn run(&mut self) -> Result<(), ExecutionErrors> {
+        loop {
+            self.step()?;
+        }
+    }
+
+    pub fn register(&self, index: usize) -> u32 {
+        self.mips.regs[index]
+    }
+
+    // Writes to $zero are ignored, as they are when an instruction does it
+    pub fn set_register(&mut self, index: usize, value: u32) {
+        if index != 0 {
+            self.mips.regs[index] = value;
+        }
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.mips.pc as u32
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.mips.pc = pc as usize;
+    }
+
+    // Reads memory without disturbing devices or watchpoints. Returns None
+    // if any of it isn't there.
+    pub fn read_memory(&self, address: u32, length: u32) -> Option<Vec<u8>> {
+        (0..length).map(|i| self.mips.memory.peek_byte(address.wrapping_add(i))).collect()
+    }
+
+    // Writes memory as the debugger does, even where the program can't
+    pub fn write_memory(&mut self, address: u32, bytes: &[u8]) -> Result<(), ExecutionErrors> {
+        self.mips.poke_bytes(address, bytes)
+    }
+
+    pub fn before_instruction(&mut self, hook: impl Fn(&Mips, u32, u32) + 'static) {
+        self.mips.hooks.before_instruction.push(Rc::new(hook));
+    }
+
+    pub fn after_instruction(&mut self, hook: impl Fn(&Mips, u32, u32, Result<(), ExecutionErrors>) + 'static) {
+        self.mips.hooks.after_instruction.push(Rc::new(hook));
+    }
+
+    pub fn on_syscall(&mut self, hook: impl Fn(&Mips, u32) + 'static) {
+        self.mips.hooks.syscall.push(Rc::new(hook));
+    }
+
+    pub fn on_exception(&mut self, hook: impl Fn(&Mips, ExecutionErrors) + 'static) {
+        self.mips.hooks.exception.push(Rc::new(hook));
+    }
+
+    // The whole machine, for everything else: the operating system, the
+    // coprocessors, statistics, and settings like delay slots
+    pub fn machine(&self) -> &Mips {
+        &self.mips
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Mips {
+        &mut self.mips
+    }
+}
@@ -0,0 +1,74 @@
+// Counts of what a program did as it ran, for performance work: how many
+// instructions ran and of which kinds, how many touched memory, how many
+// branches and jumps were taken, and how many syscalls were made. The last
+// few instructions are kept too, to show where a program was going when it
+// had to be stopped.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::mnemonic::mnemonic;
+
+const LOADS: &[&str] = &["lb", "lbu", "lh", "lhu", "lw", "lwl", "lwr", "ll", "lwxc1", "ldxc1", "luxc1"];
+const STORES: &[&str] = &["sb", "sh", "sw", "swl", "swr", "sc", "swxc1", "sdxc1", "suxc1"];
+const RECENT_LENGTH: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct Statistics {
+    // How many times each instruction word ran. Words are only sorted out
+    // by mnemonic when a summary is asked for, which keeps counting cheap.
+    words: HashMap<u32, u64>,
+    pub branches_taken: u64,
+    instructions: u64,
+    // The addresses and words of the last instructions, oldest first once
+    // instructions has wrapped around
+    recent: [(u32, u32); RECENT_LENGTH],
+}
+
+impl Statistics {
+    // Counts an instruction that's about to run
+    pub fn record(&mut self, address: u32, word: u32) {
+        *self.words.entry(word).or_insert(0) += 1;
+        self.recent[self.instructions as usize % RECENT_LENGTH] = (address, word);
+        self.instructions += 1;
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // The last instructions that ran, oldest first, a line each
+    pub fn recent(&self) -> String {
+        let count = (self.instructions as usize).min(RECENT_LENGTH);
+        let mut recent = String::new();
+        for i in self.instructions as usize - count..self.instructions as usize {
+            let (address, word) = self.recent[i % RECENT_LENGTH];
+            let _ = writeln!(recent, "  0x{:08x}  0x{:08x}  {}", address, word, mnemonic(word));
+        }
+        recent
+    }
+
+    pub fn summary(&self) -> String {
+        let mut by_mnemonic: HashMap<String, u64> = HashMap::new();
+        for (word, count) in &self.words {
+            *by_mnemonic.entry(mnemonic(*word)).or_insert(0) += count;
+        }
+        let count_of = |names: &[&str]| names.iter().filter_map(|name| by_mnemonic.get(*name)).sum::<u64>();
+
+        let mut summary = String::new();
+        let _ = writeln!(summary, "Instructions: {}", self.instructions);
+        let _ = writeln!(summary, "Loads: {}", count_of(LOADS));
+        let _ = writeln!(summary, "Stores: {}", count_of(STORES));
+        let _ = writeln!(summary, "Branches and jumps taken: {}", self.branches_taken);
+        let _ = writeln!(summary, "Syscalls: {}", count_of(&["syscall"]));
+
+        // Most frequent first
+        let mut by_mnemonic: Vec<(String, u64)> = by_mnemonic.into_iter().collect();
+        by_mnemonic.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let _ = writeln!(summary, "By mnemonic:");
+        for (mnemonic, count) in by_mnemonic {
+            let _ = writeln!(summary, "  {:<10} {}", mnemonic, count);
+        }
+        summary
+    }
+}
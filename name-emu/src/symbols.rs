@@ -0,0 +1,37 @@
+// Looking up the program's labels, from the line info name-as writes or an
+// ELF executable's symbol table, to put names to addresses.
+
+use name_const::layout::Layout;
+use name_const::symbols::{SymbolBinding, SymbolInfo};
+
+use crate::mips::{DOT_KDATA_MAX_LENGTH, DOT_KDATA_START_ADDRESS, DOT_KTEXT_MAX_LENGTH, DOT_KTEXT_START_ADDRESS};
+
+// The label at or nearest before an address, preferring a global label to
+// local ones at the same address
+pub fn nearest(symbols: &[SymbolInfo], address: u32) -> Option<&SymbolInfo> {
+    symbols
+        .iter()
+        .filter(|symbol| symbol.address <= address)
+        .max_by_key(|symbol| (symbol.address, symbol.binding == SymbolBinding::Global))
+}
+
+pub fn named<'a>(symbols: &'a [SymbolInfo], name: &str) -> Option<&'a SymbolInfo> {
+    symbols.iter().find(|symbol| symbol.name == name)
+}
+
+// Which part of memory an address is in, by the program's layout, or None
+// if it's in none of them
+pub fn section(layout: &Layout, address: u32) -> Option<&'static str> {
+    let sections = [
+        (".text", layout.text_base, layout.text_size),
+        (".data", layout.data_base, layout.data_size),
+        ("the heap", layout.heap_base, layout.heap_size),
+        ("the stack", layout.stack_base, layout.stack_size),
+        (".ktext", DOT_KTEXT_START_ADDRESS, DOT_KTEXT_MAX_LENGTH),
+        (".kdata", DOT_KDATA_START_ADDRESS, DOT_KDATA_MAX_LENGTH),
+    ];
+    sections
+        .iter()
+        .find(|(_, base, size)| (*base as u64..*base as u64 + *size as u64).contains(&(address as u64)))
+        .map(|(name, _, _)| *name)
+}
@@ -0,0 +1,104 @@
+// A record of the instructions a program ran, for working out afterwards
+// what went wrong. Each line has where an instruction was, its word and
+// mnemonic, and every register and byte of memory it changed. The record
+// can be kept to an address range or to certain mnemonics, so a long run
+// only records the part being looked at.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+use crate::mips::{HI_NAME, LO_NAME, REGISTER_NAMES};
+use crate::mnemonic::mnemonic;
+
+// Everything an instruction can change besides memory and pc
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub fprs: [u32; 32],
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    out: BufWriter<File>,
+    // Only instructions in this range are recorded, if one is given
+    pub range: Option<RangeInclusive<u32>>,
+    // Only these are recorded, unless there are none
+    pub mnemonics: Vec<String>,
+    // Whether the instruction running now is being recorded, and what it
+    // has stored so far
+    recording: bool,
+    stores: Vec<(u32, Vec<u8>)>,
+}
+
+impl Trace {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            range: None,
+            mnemonics: vec![],
+            recording: false,
+            stores: vec![],
+        })
+    }
+
+    // Called before each instruction runs. Returns whether to record it.
+    pub fn begin(&mut self, address: u32, word: u32) -> bool {
+        self.recording = self.range.as_ref().is_none_or(|range| range.contains(&address))
+            && (self.mnemonics.is_empty() || self.mnemonics.contains(&mnemonic(word)));
+        self.stores.clear();
+        self.recording
+    }
+
+    // Memory writes go through here while an instruction is being recorded
+    pub fn stored(&mut self, address: u32, bytes: &[u8]) {
+        if self.recording {
+            self.stores.push((address, bytes.to_vec()));
+        }
+    }
+
+    // Writes the line for a recorded instruction, once it has run
+    pub fn end(&mut self, address: u32, word: u32, before: &Registers, after: &Registers, error: Option<String>) {
+        let mut line = format!("0x{:08x}  0x{:08x}  {:<8}", address, word, mnemonic(word));
+
+        let changed = |line: &mut String, name: &str, old: u32, new: u32| {
+            if old != new {
+                line.push_str(&format!("  {}: 0x{:08x} -> 0x{:08x}", name, old, new));
+            }
+        };
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            changed(&mut line, name, before.regs[i], after.regs[i]);
+        }
+        changed(&mut line, HI_NAME, before.hi, after.hi);
+        changed(&mut line, LO_NAME, before.lo, after.lo);
+        for i in 0..32 {
+            changed(&mut line, &format!("$f{}", i), before.fprs[i], after.fprs[i]);
+        }
+
+        // Bytes, halfwords, and words are shown as values; anything longer,
+        // like a string a syscall read, by how much was written
+        for (address, bytes) in self.stores.drain(..) {
+            let value = match bytes.len() {
+                1 => format!("0x{:02x}", bytes[0]),
+                2 => format!("0x{:04x}", u16::from_le_bytes([bytes[0], bytes[1]])),
+                4 => format!("0x{:08x}", u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                length => format!("{} bytes", length),
+            };
+            line.push_str(&format!("  [0x{:08x}] <- {}", address, value));
+        }
+
+        if let Some(error) = error {
+            line.push_str(&format!("  !! {}", error));
+        }
+
+        // A trace that can't be written isn't worth stopping the program for
+        let _ = writeln!(self.out, "{}", line.trim_end());
+        self.recording = false;
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}